@@ -18,7 +18,7 @@ use axum::body::{boxed, Body, BoxBody};
 use axum::extract::{DefaultBodyLimit, Path, Query, State};
 use axum::http::{header, HeaderName, Method, Request, Response, StatusCode};
 use axum::response::sse::{Event, KeepAlive};
-use axum::response::{Html, Sse};
+use axum::response::{Html, IntoResponse, Sse};
 use axum::routing::{get, post, put};
 use axum::{Json, Router};
 use clap::Args;
@@ -309,22 +309,69 @@ async fn finish_query(
 struct QueryPayload {
     script: String,
     params: BTreeMap<String, serde_json::Value>,
+    /// If given, the results are sent as server-sent events with at most this many rows each
+    #[serde(default)]
+    chunk_size: Option<usize>,
 }
 
 async fn text_query(
     State(st): State<DbState>,
     Json(payload): Json<QueryPayload>,
-) -> (StatusCode, Json<serde_json::Value>) {
+) -> axum::response::Response {
     let params = payload
         .params
         .into_iter()
         .map(|(k, v)| (k, DataValue::from(v)))
         .collect();
+    if let Some(chunk_size) = payload.chunk_size {
+        return chunked_query(st.db, payload.script, params, chunk_size).into_response();
+    }
     let result = spawn_blocking(move || st.db.run_script_fold_err(&payload.script, params)).await;
     match result {
         Ok(res) => wrap_json(res),
         Err(err) => internal_error(err),
     }
+    .into_response()
+}
+
+fn chunked_query(
+    db: DbInstance,
+    script: String,
+    params: BTreeMap<String, DataValue>,
+    chunk_size: usize,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+    spawn_blocking(move || {
+        let mut iter = match db.run_script_iter(&script, params, chunk_size) {
+            Ok(iter) => iter,
+            Err(err) => {
+                let _ = sender.blocking_send(format_error_as_json(err, Some(&script)));
+                return;
+            }
+        };
+        while let Some(chunk) = iter.next_chunk() {
+            let item = match chunk {
+                Ok(rows) => {
+                    let mut item = NamedRows::new(iter.headers.clone(), rows).into_json();
+                    item.as_object_mut()
+                        .unwrap()
+                        .insert("ok".to_string(), json!(true));
+                    item
+                }
+                Err(err) => format_error_as_json(err, Some(&script)),
+            };
+            // the client has gone away
+            if sender.blocking_send(item).is_err() {
+                break;
+            }
+        }
+    });
+    let stream = async_stream::stream! {
+        while let Some(item) = receiver.recv().await {
+            yield Ok(Event::default().json_data(item).unwrap());
+        }
+    };
+    Sse::new(stream).keep_alive(KeepAlive::default())
 }
 
 async fn export_relations(
@@ -6,42 +6,74 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
 
 use miette::{bail, ensure, miette, Result};
 use rand::prelude::*;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
 
-use crate::data::value::DataValue;
+use crate::data::value::{DataValue, Num};
 
 pub(crate) struct Aggregation {
-    pub(crate) name: &'static str,
+    pub(crate) name: Cow<'static, str>,
     pub(crate) is_meet: bool,
     pub(crate) meet_op: Option<Box<dyn MeetAggrObj>>,
     pub(crate) normal_op: Option<Box<dyn NormalAggrObj>>,
+    pub(crate) custom: Option<Arc<dyn CustomAggregation>>,
 }
 
 impl Clone for Aggregation {
     fn clone(&self) -> Self {
         Self {
-            name: self.name,
+            name: self.name.clone(),
             is_meet: self.is_meet,
             meet_op: None,
             normal_op: None,
+            custom: self.custom.clone(),
         }
     }
 }
 
-pub(crate) trait NormalAggrObj: Send + Sync {
+/// State of a normal aggregation, created once for each group.
+pub trait NormalAggrObj: Send + Sync {
+    /// Feed a value of the group into the aggregation.
     fn set(&mut self, value: &DataValue) -> Result<()>;
+    /// The result of the aggregation over all values fed so far.
     fn get(&self) -> Result<DataValue>;
 }
 
-pub(crate) trait MeetAggrObj: Send + Sync {
+/// Operator of a meet aggregation, which must be idempotent, commutative and associative.
+pub trait MeetAggrObj: Send + Sync {
+    /// The value of the aggregation over no values.
     fn init_val(&self) -> DataValue;
+    /// Merge `right` into `left`, returning whether `left` was changed.
     fn update(&self, left: &mut DataValue, right: &DataValue) -> Result<bool>;
 }
 
+/// A user-defined aggregation, registered with [crate::Db::register_aggregation].
+///
+/// Every aggregation has a normal implementation. Aggregations that are idempotent,
+/// commutative and associative can also declare themselves meet aggregations,
+/// which lets them be used in recursive rules.
+pub trait CustomAggregation: Send + Sync {
+    /// Whether this is a meet aggregation, in which case
+    /// [meet_init](Self::meet_init) must be implemented.
+    fn is_meet(&self) -> bool {
+        false
+    }
+    /// Create the state for aggregating a group. `args` are the additional
+    /// constant arguments given to the aggregation in the rule head.
+    fn normal_init(&self, args: &[DataValue]) -> Result<Box<dyn NormalAggrObj>>;
+    /// Create the meet operator.
+    fn meet_init(&self, _args: &[DataValue]) -> Result<Box<dyn MeetAggrObj>> {
+        bail!("this aggregation is not a meet aggregation")
+    }
+}
+
 impl PartialEq for Aggregation {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
@@ -57,10 +89,11 @@ impl Debug for Aggregation {
 macro_rules! define_aggr {
     ($name:ident, $is_meet:expr) => {
         const $name: Aggregation = Aggregation {
-            name: stringify!($name),
+            name: Cow::Borrowed(stringify!($name)),
             is_meet: $is_meet,
             meet_op: None,
             normal_op: None,
+            custom: None,
         };
     };
 }
@@ -502,6 +535,7 @@ define_aggr!(AGGR_MEAN, false);
 pub(crate) struct AggrMean {
     count: i64,
     sum: f64,
+    exact: ExactSum,
 }
 
 impl NormalAggrObj for AggrMean {
@@ -509,6 +543,12 @@ impl NormalAggrObj for AggrMean {
         match value {
             DataValue::Num(n) => {
                 self.sum += n.get_float();
+                self.exact.add(value, "mean")?;
+                self.count += 1;
+            }
+            DataValue::Decimal(d) => {
+                self.sum += d.to_f64().unwrap_or(f64::NAN);
+                self.exact.add(value, "mean")?;
                 self.count += 1;
             }
             v => bail!("cannot compute 'mean': encountered value {:?}", v),
@@ -517,15 +557,63 @@ impl NormalAggrObj for AggrMean {
     }
 
     fn get(&self) -> Result<DataValue> {
+        if let Some(sum) = self.exact.get() {
+            if self.count > 0 {
+                return Ok(DataValue::Decimal(
+                    sum.checked_div(Decimal::from(self.count))
+                        .ok_or_else(|| miette!("decimal overflow in 'mean'"))?,
+                ));
+            }
+        }
         Ok(DataValue::from(self.sum / (self.count as f64)))
     }
 }
 
+/// The exact sum of the values seen by an aggregation, which is its result instead of the
+/// float sum if decimals but no floats were seen
+#[derive(Default)]
+struct ExactSum {
+    sum: Decimal,
+    has_decimal: bool,
+    has_float: bool,
+}
+
+impl ExactSum {
+    fn add(&mut self, value: &DataValue, name: &str) -> Result<()> {
+        let d = match value {
+            DataValue::Decimal(d) => {
+                self.has_decimal = true;
+                *d
+            }
+            DataValue::Num(Num::Int(i)) => Decimal::from(*i),
+            _ => {
+                self.has_float = true;
+                return Ok(());
+            }
+        };
+        if !self.has_float {
+            self.sum = self
+                .sum
+                .checked_add(d)
+                .ok_or_else(|| miette!("decimal overflow in '{}'", name))?;
+        }
+        Ok(())
+    }
+    fn get(&self) -> Option<Decimal> {
+        if self.has_decimal && !self.has_float {
+            Some(self.sum)
+        } else {
+            None
+        }
+    }
+}
+
 define_aggr!(AGGR_SUM, false);
 
 #[derive(Default)]
 pub(crate) struct AggrSum {
     sum: f64,
+    exact: ExactSum,
 }
 
 impl NormalAggrObj for AggrSum {
@@ -533,6 +621,11 @@ impl NormalAggrObj for AggrSum {
         match value {
             DataValue::Num(n) => {
                 self.sum += n.get_float();
+                self.exact.add(value, "sum")?;
+            }
+            DataValue::Decimal(d) => {
+                self.sum += d.to_f64().unwrap_or(f64::NAN);
+                self.exact.add(value, "sum")?;
             }
             v => bail!("cannot compute 'sum': encountered value {:?}", v),
         }
@@ -540,7 +633,10 @@ impl NormalAggrObj for AggrSum {
     }
 
     fn get(&self) -> Result<DataValue> {
-        Ok(DataValue::from(self.sum))
+        Ok(match self.exact.get() {
+            Some(sum) => DataValue::Decimal(sum),
+            None => DataValue::from(self.sum),
+        })
     }
 }
 
@@ -1187,8 +1283,28 @@ pub(crate) fn parse_aggr(name: &str) -> Option<&'static Aggregation> {
 }
 
 impl Aggregation {
-    pub(crate) fn meet_init(&mut self, _args: &[DataValue]) -> Result<()> {
-        self.meet_op.replace(match self.name {
+    pub(crate) fn new_custom(name: &str, custom: Arc<dyn CustomAggregation>) -> Self {
+        Self {
+            name: Cow::Owned(name.to_string()),
+            is_meet: custom.is_meet(),
+            meet_op: None,
+            normal_op: None,
+            custom: Some(custom),
+        }
+    }
+    /// The name as written in queries
+    pub(crate) fn display_name(&self) -> String {
+        match self.name.strip_prefix("AGGR_") {
+            Some(name) if self.custom.is_none() => name.to_ascii_lowercase(),
+            _ => self.name.to_string(),
+        }
+    }
+    pub(crate) fn meet_init(&mut self, args: &[DataValue]) -> Result<()> {
+        if let Some(custom) = &self.custom {
+            self.meet_op.replace(custom.meet_init(args)?);
+            return Ok(());
+        }
+        self.meet_op.replace(match &*self.name {
             name if name == AGGR_AND.name => Box::new(MeetAggrAnd),
             name if name == AGGR_OR.name => Box::new(MeetAggrOr),
             name if name == AGGR_MIN.name => Box::new(MeetAggrMin),
@@ -1205,8 +1321,12 @@ impl Aggregation {
         Ok(())
     }
     pub(crate) fn normal_init(&mut self, args: &[DataValue]) -> Result<()> {
+        if let Some(custom) = &self.custom {
+            self.normal_op.replace(custom.normal_init(args)?);
+            return Ok(());
+        }
         #[allow(clippy::box_default)]
-        self.normal_op.replace(match self.name {
+        self.normal_op.replace(match &*self.name {
             name if name == AGGR_AND.name => Box::new(AggrAnd::default()),
             name if name == AGGR_OR.name => Box::new(AggrOr::default()),
             name if name == AGGR_COUNT.name => Box::new(AggrCount::default()),
@@ -10,6 +10,8 @@ use std::cmp::{max, min};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 
 use itertools::Itertools;
 use miette::{bail, miette, Diagnostic, Result};
@@ -19,7 +21,6 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::functions::*;
-use crate::data::relation::NullableColType;
 use crate::data::symb::Symbol;
 use crate::data::value::{DataValue, LARGEST_UTF_CHAR};
 use crate::parse::expr::expr2bytecode;
@@ -45,6 +46,20 @@ pub enum Bytecode {
         #[serde(skip)]
         span: SourceSpan,
     },
+    /// pop n, push 1
+    #[serde(skip)]
+    CustomApply {
+        op: Arc<CustomFunction>,
+        arity: usize,
+        span: SourceSpan,
+    },
+    /// push 1
+    #[serde(skip)]
+    Param {
+        name: SmartString<LazyCompact>,
+        cell: ParamCell,
+        span: SourceSpan,
+    },
     /// pop 1
     JumpIfFalse {
         jump_to: usize,
@@ -64,6 +79,11 @@ pub enum Bytecode {
 #[diagnostic(code(eval::unbound))]
 struct UnboundVariableError(String, #[label] SourceSpan);
 
+#[derive(Error, Diagnostic, Debug)]
+#[error("Parameter ${0} of the prepared query is not given")]
+#[diagnostic(code(eval::param_not_bound))]
+pub(crate) struct ParamNotBoundError(pub(crate) String, #[label] pub(crate) SourceSpan);
+
 #[derive(Error, Diagnostic, Debug)]
 #[error("The tuple bound by variable '{0}' is too short: index is {1}, length is {2}")]
 #[diagnostic(help("This is definitely a bug. Please report it."))]
@@ -135,6 +155,22 @@ pub fn eval_bytecode(
                 stack.push(result);
                 pointer += 1;
             }
+            Bytecode::CustomApply { op, arity, span } => {
+                let frame_start = stack.len() - *arity;
+                let args_frame = &stack[frame_start..];
+                let result = (op.inner)(args_frame)
+                    .map_err(|err| EvalRaisedError(*span, err.to_string()))?;
+                stack.truncate(frame_start);
+                stack.push(result);
+                pointer += 1;
+            }
+            Bytecode::Param { name, cell, span } => {
+                let val = cell
+                    .get()
+                    .ok_or_else(|| ParamNotBoundError(name.to_string(), *span))?;
+                stack.push(val);
+                pointer += 1;
+            }
             Bytecode::JumpIfFalse { jump_to, span } => {
                 let val = stack.pop().unwrap();
                 let cond = val
@@ -192,6 +228,26 @@ pub enum Expr {
         #[serde(skip)]
         span: SourceSpan,
     },
+    /// Application of a function registered at runtime, cannot be persisted
+    #[serde(skip)]
+    CustomApply {
+        /// The registered function to apply
+        op: Arc<CustomFunction>,
+        /// Arguments to the application
+        args: Box<[Expr]>,
+        /// Source span
+        span: SourceSpan,
+    },
+    /// Parameter of a prepared query, whose value is supplied on each execution
+    #[serde(skip)]
+    Param {
+        /// The parameter name, without the leading `$`
+        name: SmartString<LazyCompact>,
+        /// Holds the value for the current execution
+        cell: ParamCell,
+        /// Source span
+        span: SourceSpan,
+    },
     /// Conditional expressions
     Cond {
         /// Conditional clauses, the first expression in each tuple should evaluate to a boolean
@@ -232,6 +288,16 @@ impl Display for Expr {
                 }
                 writer.finish()
             }
+            Expr::CustomApply { op, args, .. } => {
+                let mut writer = f.debug_tuple(&op.name);
+                for arg in args.iter() {
+                    writer.field(arg);
+                }
+                writer.finish()
+            }
+            Expr::Param { name, .. } => {
+                write!(f, "${name}")
+            }
             Expr::Cond { clauses, .. } => {
                 let mut writer = f.debug_tuple("cond");
                 for (cond, expr) in clauses {
@@ -275,7 +341,9 @@ impl Expr {
         match self {
             Expr::Binding { var, .. } => var.span,
             Expr::Const { span, .. } | Expr::Apply { span, .. } | Expr::Cond { span, .. } => *span,
-            Expr::UnboundApply { span, .. } => *span,
+            Expr::UnboundApply { span, .. }
+            | Expr::CustomApply { span, .. }
+            | Expr::Param { span, .. } => *span,
         }
     }
     pub(crate) fn get_binding(&self) -> Option<&Symbol> {
@@ -343,8 +411,8 @@ impl Expr {
                     .ok_or_else(|| BadBindingError(var.to_string(), var.span))?;
                 *tuple_pos = Some(found_idx)
             }
-            Expr::Const { .. } => {}
-            Expr::Apply { args, .. } => {
+            Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } | Expr::CustomApply { args, .. } => {
                 for arg in args.iter_mut() {
                     arg.fill_binding_indices(binding_map)?;
                 }
@@ -375,8 +443,8 @@ impl Expr {
                     coll.insert(*idx);
                 }
             }
-            Expr::Const { .. } => {}
-            Expr::Apply { args, .. } => {
+            Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } | Expr::CustomApply { args, .. } => {
                 for arg in args.iter() {
                     arg.do_binding_indices(coll)?;
                 }
@@ -403,14 +471,114 @@ impl Expr {
         #[diagnostic(code(eval::not_constant))]
         struct NotConstError;
 
+        self.bind_params()?;
         self.partial_eval()?;
         match self {
             Expr::Const { val, .. } => Ok(val),
             _ => bail!(NotConstError),
         }
     }
+    /// Replaces parameters of a prepared query by their current values,
+    /// marking them as fixed into the compiled plan.
+    fn bind_params(&mut self) -> Result<()> {
+        match self {
+            Expr::Param { name, cell, span } => {
+                let val = cell
+                    .bind()
+                    .ok_or_else(|| ParamNotBoundError(name.to_string(), *span))?;
+                *self = Expr::Const { val, span: *span };
+            }
+            Expr::Apply { args, .. } | Expr::CustomApply { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.bind_params()?;
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.bind_params()?;
+                    val.bind_params()?;
+                }
+            }
+            Expr::Binding { .. } | Expr::Const { .. } | Expr::UnboundApply { .. } => {}
+        }
+        Ok(())
+    }
+    /// Replaces applications of registered functions by their names,
+    /// so that the expression can be persisted.
+    pub(crate) fn unbind_custom_functions(&mut self) {
+        match self {
+            Expr::CustomApply { op, args, span } => {
+                for arg in args.iter_mut() {
+                    arg.unbind_custom_functions();
+                }
+                *self = Expr::UnboundApply {
+                    op: SmartString::from(op.name.as_str()),
+                    args: mem::take(args),
+                    span: *span,
+                };
+            }
+            Expr::Apply { args, .. } | Expr::UnboundApply { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.unbind_custom_functions();
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.unbind_custom_functions();
+                    val.unbind_custom_functions();
+                }
+            }
+            Expr::Binding { .. } | Expr::Const { .. } | Expr::Param { .. } => {}
+        }
+    }
+    /// Resolves functions persisted by name against the registered ones,
+    /// the reverse of [Expr::unbind_custom_functions].
+    pub(crate) fn resolve_custom_functions(
+        &mut self,
+        functions: &BTreeMap<String, Arc<CustomFunction>>,
+    ) -> Result<()> {
+        #[derive(Error, Diagnostic, Debug)]
+        #[error("Registered function '{0}' now takes {1} argument(s)")]
+        #[diagnostic(code(eval::custom_func_arity_changed))]
+        struct CustomFunctionArityChanged(String, usize, #[label] SourceSpan);
+
+        match self {
+            Expr::UnboundApply { op, args, span } => {
+                for arg in args.iter_mut() {
+                    arg.resolve_custom_functions(functions)?;
+                }
+                if let Some(func) = functions.get(op.as_str()) {
+                    if func.arity != args.len() {
+                        bail!(CustomFunctionArityChanged(
+                            op.to_string(),
+                            func.arity,
+                            *span
+                        ))
+                    }
+                    *self = Expr::CustomApply {
+                        op: func.clone(),
+                        args: mem::take(args),
+                        span: *span,
+                    };
+                }
+            }
+            Expr::Apply { args, .. } | Expr::CustomApply { args, .. } => {
+                for arg in args.iter_mut() {
+                    arg.resolve_custom_functions(functions)?;
+                }
+            }
+            Expr::Cond { clauses, .. } => {
+                for (cond, val) in clauses {
+                    cond.resolve_custom_functions(functions)?;
+                    val.resolve_custom_functions(functions)?;
+                }
+            }
+            Expr::Binding { .. } | Expr::Const { .. } | Expr::Param { .. } => {}
+        }
+        Ok(())
+    }
     pub(crate) fn partial_eval(&mut self) -> Result<()> {
-        if let Expr::Apply { args, span, .. } = self {
+        if let Expr::Apply { args, span, .. } | Expr::CustomApply { args, span, .. } = self {
             let span = *span;
             let mut all_evaluated = true;
             for arg in args.iter_mut() {
@@ -455,8 +623,8 @@ impl Expr {
             Expr::Binding { var, .. } => {
                 coll.insert(var.clone());
             }
-            Expr::Const { .. } => {}
-            Expr::Apply { args, .. } => {
+            Expr::Const { .. } | Expr::Param { .. } => {}
+            Expr::Apply { args, .. } | Expr::CustomApply { args, .. } => {
                 for arg in args.iter() {
                     arg.collect_bindings(coll)?;
                 }
@@ -501,6 +669,17 @@ impl Expr {
                 Ok((op.inner)(&args)
                     .map_err(|err| EvalRaisedError(self.span(), err.to_string()))?)
             }
+            Expr::CustomApply { op, args, .. } => {
+                let args: Box<[DataValue]> = args
+                    .iter()
+                    .map(|v| v.eval(bindings.as_ref()))
+                    .try_collect()?;
+                Ok((op.inner)(&args)
+                    .map_err(|err| EvalRaisedError(self.span(), err.to_string()))?)
+            }
+            Expr::Param { name, cell, span } => Ok(cell
+                .get()
+                .ok_or_else(|| ParamNotBoundError(name.to_string(), *span))?),
             Expr::Cond { clauses, .. } => {
                 for (cond, val) in clauses {
                     let cond_val = cond.eval(bindings.as_ref())?;
@@ -521,7 +700,11 @@ impl Expr {
     }
     pub(crate) fn extract_bound(&self, target: &Symbol) -> Result<ValueRange> {
         Ok(match self {
-            Expr::Binding { .. } | Expr::Const { .. } | Expr::Cond { .. } => ValueRange::default(),
+            Expr::Binding { .. }
+            | Expr::Const { .. }
+            | Expr::Cond { .. }
+            | Expr::CustomApply { .. }
+            | Expr::Param { .. } => ValueRange::default(),
             Expr::Apply { op, args, .. } => match op.name {
                 n if n == OP_GE.name || n == OP_GT.name => {
                     if let Some(symb) = args[0].get_binding() {
@@ -704,13 +887,69 @@ pub struct Op {
     pub(crate) inner: fn(&[DataValue]) -> Result<DataValue>,
 }
 
-/// Used as `Arc<dyn CustomOp>`
-pub trait CustomOp {
-    fn name(&self) -> &'static str;
-    fn min_arity(&self) -> usize;
-    fn vararg(&self) -> bool;
-    fn return_type(&self) -> NullableColType;
-    fn call(&self, args: &[DataValue]) -> Result<DataValue>;
+/// A scalar function registered at runtime, see [crate::Db::register_function]
+pub struct CustomFunction {
+    pub(crate) name: String,
+    pub(crate) arity: usize,
+    #[allow(clippy::type_complexity)]
+    pub(crate) inner: Box<dyn Fn(&[DataValue]) -> Result<DataValue> + Send + Sync>,
+}
+
+impl PartialEq for CustomFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for CustomFunction {}
+
+impl Debug for CustomFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Value slot of a parameter in a prepared query, see [crate::Db::prepare].
+/// All occurrences of the same parameter share one slot.
+#[derive(Clone, Default)]
+pub struct ParamCell(Arc<ParamCellInner>);
+
+#[derive(Default)]
+struct ParamCellInner {
+    value: RwLock<Option<DataValue>>,
+    bound: AtomicBool,
+}
+
+impl ParamCell {
+    pub(crate) fn get(&self) -> Option<DataValue> {
+        self.0.value.read().unwrap().clone()
+    }
+    pub(crate) fn set(&self, val: Option<DataValue>) {
+        *self.0.value.write().unwrap() = val;
+    }
+    /// Reads the value for use as a constant during compilation
+    fn bind(&self) -> Option<DataValue> {
+        self.0.bound.store(true, Ordering::Release);
+        self.get()
+    }
+    /// Whether the value was used as a constant since the last call
+    pub(crate) fn take_bound(&self) -> bool {
+        self.0.bound.swap(false, Ordering::AcqRel)
+    }
+}
+
+impl PartialEq for ParamCell {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ParamCell {}
+
+impl Debug for ParamCell {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.get())
+    }
 }
 
 impl serde::Serialize for &'_ Op {
@@ -785,6 +1024,7 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "floor" => &OP_FLOOR,
         "ceil" => &OP_CEIL,
         "round" => &OP_ROUND,
+        "round_decimal" => &OP_ROUND_DECIMAL,
         "mod" => &OP_MOD,
         "max" => &OP_MAX,
         "min" => &OP_MIN,
@@ -845,6 +1085,10 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "is_nan" => &OP_IS_NAN,
         "is_uuid" => &OP_IS_UUID,
         "is_vec" => &OP_IS_VEC,
+        "is_timestamp" => &OP_IS_TIMESTAMP,
+        "is_duration" => &OP_IS_DURATION,
+        "is_decimal" => &OP_IS_DECIMAL,
+        "is_geometry" => &OP_IS_GEOMETRY,
         "length" => &OP_LENGTH,
         "sorted" => &OP_SORTED,
         "reverse" => &OP_REVERSE,
@@ -855,6 +1099,17 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "haversine_deg_input" => &OP_HAVERSINE_DEG_INPUT,
         "deg_to_rad" => &OP_DEG_TO_RAD,
         "rad_to_deg" => &OP_RAD_TO_DEG,
+        "to_geometry" => &OP_TO_GEOMETRY,
+        "st_point" => &OP_ST_POINT,
+        "st_x" => &OP_ST_X,
+        "st_y" => &OP_ST_Y,
+        "st_astext" => &OP_ST_AS_TEXT,
+        "st_asgeojson" => &OP_ST_AS_GEOJSON,
+        "st_bbox" => &OP_ST_BBOX,
+        "st_contains" => &OP_ST_CONTAINS,
+        "st_intersects" => &OP_ST_INTERSECTS,
+        "st_distance" => &OP_ST_DISTANCE,
+        "st_within_bbox" => &OP_ST_WITHIN_BBOX,
         "get" => &OP_GET,
         "maybe_get" => &OP_MAYBE_GET,
         "chars" => &OP_CHARS,
@@ -875,6 +1130,7 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "windows" => &OP_WINDOWS,
         "to_int" => &OP_TO_INT,
         "to_float" => &OP_TO_FLOAT,
+        "to_decimal" => &OP_TO_DECIMAL,
         "to_string" => &OP_TO_STRING,
         "l2_dist" => &OP_L2_DIST,
         "l2_normalize" => &OP_L2_NORMALIZE,
@@ -898,6 +1154,10 @@ pub(crate) fn get_op(name: &str) -> Option<&'static Op> {
         "now" => &OP_NOW,
         "format_timestamp" => &OP_FORMAT_TIMESTAMP,
         "parse_timestamp" => &OP_PARSE_TIMESTAMP,
+        "to_timestamp" => &OP_TO_TIMESTAMP,
+        "to_duration" => &OP_TO_DURATION,
+        "date_trunc" => &OP_DATE_TRUNC,
+        "extract" => &OP_EXTRACT,
         "vec" => &OP_VEC,
         "rand_vec" => &OP_RAND_VEC,
         _ => return None,
@@ -6,7 +6,7 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::cmp::Reverse;
+use std::cmp::{Ordering, Reverse};
 use std::collections::BTreeSet;
 use std::ops::{Div, Rem};
 use std::str::FromStr;
@@ -14,23 +14,28 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
 use itertools::Itertools;
 #[cfg(target_arch = "wasm32")]
 use js_sys::Date;
 use miette::{bail, ensure, miette, IntoDiagnostic, Result};
 use num_traits::FloatConst;
 use rand::prelude::*;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde_json::{json, Value};
 use smartstring::SmartString;
 use unicode_normalization::UnicodeNormalization;
 use uuid::v1::Timestamp;
 
 use crate::data::expr::Op;
+use crate::data::geo::{BBox, Geometry};
 use crate::data::json::JsonValue;
 use crate::data::relation::VecElementType;
 use crate::data::value::{
-    DataValue, JsonData, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs, Vector,
+    datetime_to_micros, micros_to_datetime, parse_duration, DataValue, JsonData, Num, RegexWrapper,
+    UuidWrapper, Validity, ValidityTs, Vector,
 };
 
 macro_rules! define_op {
@@ -56,6 +61,12 @@ fn ensure_same_value_type(a: &DataValue, b: &DataValue) -> Result<()> {
             | (Regex(_), Regex(_))
             | (List(_), List(_))
             | (Set(_), Set(_))
+            | (Timestamp(_), Timestamp(_))
+            | (Duration(_), Duration(_))
+            | (Decimal(_), Decimal(_))
+            | (Decimal(_), Num(_))
+            | (Num(_), Decimal(_))
+            | (Geometry(_), Geometry(_))
             | (Bot, Bot)
     ) {
         bail!(
@@ -67,6 +78,47 @@ fn ensure_same_value_type(a: &DataValue, b: &DataValue) -> Result<()> {
     Ok(())
 }
 
+/// The exact value of a decimal or an integer
+fn exact_decimal(v: &DataValue) -> Option<Decimal> {
+    match v {
+        DataValue::Decimal(d) => Some(*d),
+        DataValue::Num(Num::Int(i)) => Some(Decimal::from(*i)),
+        _ => None,
+    }
+}
+
+/// Compares numbers when at least one of them is a decimal: exactly if the other one is
+/// a decimal or an integer, and as floats otherwise
+fn cmp_decimal(a: &DataValue, b: &DataValue) -> Option<Ordering> {
+    if !matches!(a, DataValue::Decimal(_)) && !matches!(b, DataValue::Decimal(_)) {
+        return None;
+    }
+    match (exact_decimal(a), exact_decimal(b)) {
+        (Some(a), Some(b)) => Some(a.cmp(&b)),
+        _ => a.get_float()?.partial_cmp(&b.get_float()?),
+    }
+}
+
+/// Arithmetic involving a decimal, which is exact if both sides are decimals or integers,
+/// and is done with floats otherwise
+fn decimal_arith(
+    a: &DataValue,
+    b: &DataValue,
+    op_name: &str,
+    exact: fn(Decimal, Decimal) -> Option<Decimal>,
+    float: fn(f64, f64) -> f64,
+) -> Result<DataValue> {
+    match (exact_decimal(a), exact_decimal(b)) {
+        (Some(a), Some(b)) => exact(a, b)
+            .map(DataValue::Decimal)
+            .ok_or_else(|| miette!("decimal {} out of range", op_name)),
+        _ => match (a.get_float(), b.get_float()) {
+            (Some(a), Some(b)) => Ok(DataValue::from(float(a, b))),
+            _ => bail!("{} requires numbers", op_name),
+        },
+    }
+}
+
 define_op!(OP_LIST, 0, true);
 pub(crate) fn op_list(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::List(args.to_vec()))
@@ -258,6 +310,10 @@ fn to_json(d: &DataValue) -> JsonValue {
         DataValue::Validity(vld) => {
             json!([vld.timestamp.0, vld.is_assert.0])
         }
+        DataValue::Timestamp(_)
+        | DataValue::Duration(_)
+        | DataValue::Decimal(_)
+        | DataValue::Geometry(_) => JsonValue::from(d.clone()),
         DataValue::Bot => {
             json!(null)
         }
@@ -295,6 +351,9 @@ pub(crate) fn op_coalesce(args: &[DataValue]) -> Result<DataValue> {
 
 define_op!(OP_EQ, 2, false);
 pub(crate) fn op_eq(args: &[DataValue]) -> Result<DataValue> {
+    if let Some(o) = cmp_decimal(&args[0], &args[1]) {
+        return Ok(DataValue::from(o == Ordering::Equal));
+    }
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(f)), DataValue::Num(Num::Int(i)))
         | (DataValue::Num(Num::Int(i)), DataValue::Num(Num::Float(f))) => *i as f64 == *f,
@@ -331,6 +390,9 @@ pub(crate) fn op_is_in(args: &[DataValue]) -> Result<DataValue> {
 
 define_op!(OP_NEQ, 2, false);
 pub(crate) fn op_neq(args: &[DataValue]) -> Result<DataValue> {
+    if let Some(o) = cmp_decimal(&args[0], &args[1]) {
+        return Ok(DataValue::from(o != Ordering::Equal));
+    }
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(f)), DataValue::Num(Num::Int(i)))
         | (DataValue::Num(Num::Int(i)), DataValue::Num(Num::Float(f))) => *i as f64 != *f,
@@ -341,6 +403,9 @@ pub(crate) fn op_neq(args: &[DataValue]) -> Result<DataValue> {
 define_op!(OP_GT, 2, false);
 pub(crate) fn op_gt(args: &[DataValue]) -> Result<DataValue> {
     ensure_same_value_type(&args[0], &args[1])?;
+    if let Some(o) = cmp_decimal(&args[0], &args[1]) {
+        return Ok(DataValue::from(o == Ordering::Greater));
+    }
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l > *r as f64,
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => *l as f64 > *r,
@@ -351,6 +416,9 @@ pub(crate) fn op_gt(args: &[DataValue]) -> Result<DataValue> {
 define_op!(OP_GE, 2, false);
 pub(crate) fn op_ge(args: &[DataValue]) -> Result<DataValue> {
     ensure_same_value_type(&args[0], &args[1])?;
+    if let Some(o) = cmp_decimal(&args[0], &args[1]) {
+        return Ok(DataValue::from(o != Ordering::Less));
+    }
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l >= *r as f64,
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => *l as f64 >= *r,
@@ -361,6 +429,9 @@ pub(crate) fn op_ge(args: &[DataValue]) -> Result<DataValue> {
 define_op!(OP_LT, 2, false);
 pub(crate) fn op_lt(args: &[DataValue]) -> Result<DataValue> {
     ensure_same_value_type(&args[0], &args[1])?;
+    if let Some(o) = cmp_decimal(&args[0], &args[1]) {
+        return Ok(DataValue::from(o == Ordering::Less));
+    }
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l < (*r as f64),
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => (*l as f64) < *r,
@@ -371,6 +442,9 @@ pub(crate) fn op_lt(args: &[DataValue]) -> Result<DataValue> {
 define_op!(OP_LE, 2, false);
 pub(crate) fn op_le(args: &[DataValue]) -> Result<DataValue> {
     ensure_same_value_type(&args[0], &args[1])?;
+    if let Some(o) = cmp_decimal(&args[0], &args[1]) {
+        return Ok(DataValue::from(o != Ordering::Greater));
+    }
     Ok(DataValue::from(match (&args[0], &args[1]) {
         (DataValue::Num(Num::Float(l)), DataValue::Num(Num::Int(r))) => *l <= (*r as f64),
         (DataValue::Num(Num::Int(l)), DataValue::Num(Num::Float(r))) => (*l as f64) <= *r,
@@ -387,6 +461,12 @@ pub(crate) fn op_add(args: &[DataValue]) -> Result<DataValue> {
             DataValue::Num(Num::Int(i)) => i_accum += i,
             DataValue::Num(Num::Float(f)) => f_accum += f,
             DataValue::Vec(_) => return add_vecs(args),
+            DataValue::Timestamp(_) | DataValue::Duration(_) => return add_times(args),
+            DataValue::Decimal(_) => {
+                return args.iter().try_fold(DataValue::from(0), |accum, arg| {
+                    decimal_arith(&accum, arg, "addition", Decimal::checked_add, |a, b| a + b)
+                })
+            }
             _ => bail!("addition requires numbers"),
         }
     }
@@ -449,6 +529,43 @@ fn add_vecs(args: &[DataValue]) -> Result<DataValue> {
     }
 }
 
+fn add_times(args: &[DataValue]) -> Result<DataValue> {
+    let mut ts = None;
+    let mut total = 0i64;
+    for arg in args {
+        match arg {
+            DataValue::Timestamp(t) => {
+                ensure!(ts.is_none(), "cannot add timestamps together");
+                ts = Some(*t);
+            }
+            DataValue::Duration(d) => {
+                total = total
+                    .checked_add(*d)
+                    .ok_or_else(|| miette!("duration out of range"))?
+            }
+            _ => bail!("only durations can be added to timestamps and durations"),
+        }
+    }
+    match ts {
+        None => Ok(DataValue::Duration(total)),
+        Some(ts) => shift_timestamp(ts, total),
+    }
+}
+
+fn shift_timestamp(ts: i64, by: i64) -> Result<DataValue> {
+    ts.checked_add(by)
+        .and_then(DataValue::timestamp)
+        .ok_or_else(|| miette!("timestamp out of range"))
+}
+
+fn duration_from_float(micros: f64) -> Result<DataValue> {
+    ensure!(
+        micros.is_finite() && micros.abs() < i64::MAX as f64,
+        "duration out of range"
+    );
+    Ok(DataValue::Duration(micros.round() as i64))
+}
+
 define_op!(OP_MAX, 1, true);
 pub(crate) fn op_max(args: &[DataValue]) -> Result<DataValue> {
     let res = args
@@ -536,6 +653,22 @@ pub(crate) fn op_sub(args: &[DataValue]) -> Result<DataValue> {
                 }
             }
         }
+        (DataValue::Timestamp(a), DataValue::Timestamp(b)) => DataValue::Duration(
+            a.checked_sub(*b)
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        (DataValue::Timestamp(a), DataValue::Duration(b)) => shift_timestamp(
+            *a,
+            b.checked_neg()
+                .ok_or_else(|| miette!("duration out of range"))?,
+        )?,
+        (DataValue::Duration(a), DataValue::Duration(b)) => DataValue::Duration(
+            a.checked_sub(*b)
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        (a @ DataValue::Decimal(_), b) | (a, b @ DataValue::Decimal(_)) => {
+            decimal_arith(a, b, "subtraction", Decimal::checked_sub, |a, b| a - b)?
+        }
         _ => bail!("subtraction requires numbers"),
     })
 }
@@ -549,6 +682,18 @@ pub(crate) fn op_mul(args: &[DataValue]) -> Result<DataValue> {
             DataValue::Num(Num::Int(i)) => i_accum *= i,
             DataValue::Num(Num::Float(f)) => f_accum *= f,
             DataValue::Vec(_) => return mul_vecs(args),
+            DataValue::Duration(_) => return mul_duration(args),
+            DataValue::Decimal(_) => {
+                return args.iter().try_fold(DataValue::from(1), |accum, arg| {
+                    decimal_arith(
+                        &accum,
+                        arg,
+                        "multiplication",
+                        Decimal::checked_mul,
+                        |a, b| a * b,
+                    )
+                })
+            }
             _ => bail!("multiplication requires numbers"),
         }
     }
@@ -611,6 +756,32 @@ fn mul_vecs(args: &[DataValue]) -> Result<DataValue> {
     }
 }
 
+fn mul_duration(args: &[DataValue]) -> Result<DataValue> {
+    let mut micros = None;
+    let mut factor = 1.0f64;
+    for arg in args {
+        match arg {
+            DataValue::Duration(d) if micros.is_none() => micros = Some(*d),
+            DataValue::Num(n) => factor *= n.get_float(),
+            _ => bail!("durations can only be multiplied by numbers"),
+        }
+    }
+    let micros = micros.unwrap();
+    if args
+        .iter()
+        .all(|arg| matches!(arg, DataValue::Duration(_) | DataValue::Num(Num::Int(_))))
+    {
+        let exact = args.iter().try_fold(micros, |accum, arg| match arg {
+            DataValue::Num(Num::Int(i)) => accum.checked_mul(*i),
+            _ => Some(accum),
+        });
+        if let Some(exact) = exact {
+            return Ok(DataValue::Duration(exact));
+        }
+    }
+    duration_from_float(micros as f64 * factor)
+}
+
 define_op!(OP_DIV, 2, false);
 pub(crate) fn op_div(args: &[DataValue]) -> Result<DataValue> {
     Ok(match (&args[0], &args[1]) {
@@ -662,6 +833,19 @@ pub(crate) fn op_div(args: &[DataValue]) -> Result<DataValue> {
                 Vector::F64(v) => DataValue::Vec(Vector::F64(a / v)),
             }
         }
+        (DataValue::Duration(a), DataValue::Duration(b)) => {
+            DataValue::Num(Num::Float(*a as f64 / *b as f64))
+        }
+        (DataValue::Duration(a), DataValue::Num(b)) => {
+            duration_from_float(*a as f64 / b.get_float())?
+        }
+        (a @ DataValue::Decimal(_), b) | (a, b @ DataValue::Decimal(_)) => {
+            ensure!(
+                exact_decimal(b) != Some(Decimal::ZERO),
+                "decimal division by zero"
+            );
+            decimal_arith(a, b, "division", Decimal::checked_div, |a, b| a / b)?
+        }
         _ => bail!("division requires numbers"),
     })
 }
@@ -673,6 +857,11 @@ pub(crate) fn op_minus(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(-(*f))),
         DataValue::Vec(Vector::F64(v)) => DataValue::Vec(Vector::F64(0. - v)),
         DataValue::Vec(Vector::F32(v)) => DataValue::Vec(Vector::F32(0. - v)),
+        DataValue::Duration(d) => DataValue::Duration(
+            d.checked_neg()
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        DataValue::Decimal(d) => DataValue::Decimal(-*d),
         _ => bail!("minus can only be applied to numbers"),
     })
 }
@@ -684,6 +873,11 @@ pub(crate) fn op_abs(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.abs())),
         DataValue::Vec(Vector::F64(v)) => DataValue::Vec(Vector::F64(v.mapv(|x| x.abs()))),
         DataValue::Vec(Vector::F32(v)) => DataValue::Vec(Vector::F32(v.mapv(|x| x.abs()))),
+        DataValue::Duration(d) => DataValue::Duration(
+            d.checked_abs()
+                .ok_or_else(|| miette!("duration out of range"))?,
+        ),
+        DataValue::Decimal(d) => DataValue::Decimal(d.abs()),
         _ => bail!("'abs' requires numbers"),
     })
 }
@@ -703,6 +897,13 @@ pub(crate) fn op_signum(args: &[DataValue]) -> Result<DataValue> {
                 DataValue::from(f64::NAN)
             }
         }
+        DataValue::Decimal(d) => DataValue::from(if d.is_zero() {
+            0
+        } else if d.is_sign_negative() {
+            -1
+        } else {
+            1
+        }),
         _ => bail!("'signum' requires numbers"),
     })
 }
@@ -712,6 +913,7 @@ pub(crate) fn op_floor(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.floor())),
+        DataValue::Decimal(d) => DataValue::Decimal(d.floor()),
         _ => bail!("'floor' requires numbers"),
     })
 }
@@ -721,6 +923,7 @@ pub(crate) fn op_ceil(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.ceil())),
+        DataValue::Decimal(d) => DataValue::Decimal(d.ceil()),
         _ => bail!("'ceil' requires numbers"),
     })
 }
@@ -730,10 +933,38 @@ pub(crate) fn op_round(args: &[DataValue]) -> Result<DataValue> {
     Ok(match &args[0] {
         DataValue::Num(Num::Int(i)) => DataValue::Num(Num::Int(*i)),
         DataValue::Num(Num::Float(f)) => DataValue::Num(Num::Float(f.round())),
+        DataValue::Decimal(d) => {
+            DataValue::Decimal(d.round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero))
+        }
         _ => bail!("'round' requires numbers"),
     })
 }
 
+define_op!(OP_ROUND_DECIMAL, 2, true);
+pub(crate) fn op_round_decimal(args: &[DataValue]) -> Result<DataValue> {
+    let d = val2decimal(&args[0])?;
+    let places = args[1]
+        .get_non_neg_int()
+        .ok_or_else(|| miette!("'round_decimal' requires a non-negative number of places"))?;
+    let strategy = match args.get(2) {
+        None => RoundingStrategy::MidpointNearestEven,
+        Some(mode) => match mode.get_str() {
+            Some("half_even") => RoundingStrategy::MidpointNearestEven,
+            Some("half_up") => RoundingStrategy::MidpointAwayFromZero,
+            Some("half_down") => RoundingStrategy::MidpointTowardZero,
+            Some("up") => RoundingStrategy::AwayFromZero,
+            Some("down") => RoundingStrategy::ToZero,
+            Some("ceil") => RoundingStrategy::ToPositiveInfinity,
+            Some("floor") => RoundingStrategy::ToNegativeInfinity,
+            _ => bail!("unknown rounding mode {} for 'round_decimal'", mode),
+        },
+    };
+    Ok(DataValue::Decimal(d.round_dp_with_strategy(
+        places.min(u32::MAX as u64) as u32,
+        strategy,
+    )))
+}
+
 define_op!(OP_EXP, 1, false);
 pub(crate) fn op_exp(args: &[DataValue]) -> Result<DataValue> {
     let a = match &args[0] {
@@ -1481,10 +1712,20 @@ define_op!(OP_IS_NUM, 1, false);
 pub(crate) fn op_is_num(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(matches!(
         args[0],
-        DataValue::Num(Num::Int(_)) | DataValue::Num(Num::Float(_))
+        DataValue::Num(Num::Int(_)) | DataValue::Num(Num::Float(_)) | DataValue::Decimal(_)
     )))
 }
 
+define_op!(OP_IS_DECIMAL, 1, false);
+pub(crate) fn op_is_decimal(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::from(matches!(args[0], DataValue::Decimal(_))))
+}
+
+define_op!(OP_IS_GEOMETRY, 1, false);
+pub(crate) fn op_is_geometry(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::from(matches!(args[0], DataValue::Geometry(_))))
+}
+
 define_op!(OP_IS_FINITE, 1, false);
 pub(crate) fn op_is_finite(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(match &args[0] {
@@ -1528,6 +1769,16 @@ pub(crate) fn op_is_vec(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(matches!(args[0], DataValue::Vec(_))))
 }
 
+define_op!(OP_IS_TIMESTAMP, 1, false);
+pub(crate) fn op_is_timestamp(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::from(matches!(args[0], DataValue::Timestamp(_))))
+}
+
+define_op!(OP_IS_DURATION, 1, false);
+pub(crate) fn op_is_duration(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::from(matches!(args[0], DataValue::Duration(_))))
+}
+
 define_op!(OP_APPEND, 2, false);
 pub(crate) fn op_append(args: &[DataValue]) -> Result<DataValue> {
     match &args[0] {
@@ -1659,6 +1910,116 @@ pub(crate) fn op_rad_to_deg(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::from(x * 180. / f64::PI()))
 }
 
+/// Converts the value to a geometry. Strings may be WKT or GeoJSON.
+pub(crate) fn val2geometry(v: &DataValue) -> Result<Geometry> {
+    Ok(match v {
+        DataValue::Geometry(g) => g.clone(),
+        DataValue::Str(s) if s.trim_start().starts_with('{') => {
+            let j: JsonValue =
+                serde_json::from_str(s).map_err(|_| miette!("bad GeoJSON geometry: {}", s))?;
+            Geometry::from_geojson(&j)?
+        }
+        DataValue::Str(s) => Geometry::from_wkt(s)?,
+        DataValue::Json(JsonData(j)) => Geometry::from_geojson(j)?,
+        v => bail!("cannot interpret {:?} as a geometry", v),
+    })
+}
+
+/// Converts the value to a bounding box. Lists are taken as `[min_x, min_y, max_x, max_y]`,
+/// and geometries give their own bounding boxes.
+pub(crate) fn val2bbox(v: &DataValue) -> Result<BBox> {
+    match v {
+        DataValue::List(l) => match l.as_slice() {
+            [min_x, min_y, max_x, max_y] => {
+                let f = |v: &DataValue| {
+                    v.get_float()
+                        .ok_or_else(|| miette!("bounding boxes must contain numbers"))
+                };
+                BBox::new(f(min_x)?, f(min_y)?, f(max_x)?, f(max_y)?)
+            }
+            _ => bail!("bounding boxes must be given as [min_x, min_y, max_x, max_y]"),
+        },
+        v => Ok(val2geometry(v)?.bbox()),
+    }
+}
+
+define_op!(OP_TO_GEOMETRY, 1, false);
+pub(crate) fn op_to_geometry(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Geometry(val2geometry(&args[0])?))
+}
+
+define_op!(OP_ST_POINT, 2, false);
+pub(crate) fn op_st_point(args: &[DataValue]) -> Result<DataValue> {
+    let miette = || miette!("'st_point' requires numbers");
+    let x = args[0].get_float().ok_or_else(miette)?;
+    let y = args[1].get_float().ok_or_else(miette)?;
+    Ok(DataValue::Geometry(Geometry::point(x, y)?))
+}
+
+define_op!(OP_ST_X, 1, false);
+pub(crate) fn op_st_x(args: &[DataValue]) -> Result<DataValue> {
+    match val2geometry(&args[0])? {
+        Geometry::Point(p) => Ok(DataValue::from(p.x)),
+        _ => bail!("'st_x' requires a point"),
+    }
+}
+
+define_op!(OP_ST_Y, 1, false);
+pub(crate) fn op_st_y(args: &[DataValue]) -> Result<DataValue> {
+    match val2geometry(&args[0])? {
+        Geometry::Point(p) => Ok(DataValue::from(p.y)),
+        _ => bail!("'st_y' requires a point"),
+    }
+}
+
+define_op!(OP_ST_AS_TEXT, 1, false);
+pub(crate) fn op_st_as_text(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::from(val2geometry(&args[0])?.to_string()))
+}
+
+define_op!(OP_ST_AS_GEOJSON, 1, false);
+pub(crate) fn op_st_as_geojson(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Json(JsonData(
+        val2geometry(&args[0])?.to_geojson(),
+    )))
+}
+
+define_op!(OP_ST_BBOX, 1, false);
+pub(crate) fn op_st_bbox(args: &[DataValue]) -> Result<DataValue> {
+    let bbox = val2geometry(&args[0])?.bbox();
+    Ok(DataValue::List(
+        bbox.to_vec().into_iter().map(DataValue::from).collect_vec(),
+    ))
+}
+
+define_op!(OP_ST_CONTAINS, 2, false);
+pub(crate) fn op_st_contains(args: &[DataValue]) -> Result<DataValue> {
+    let a = val2geometry(&args[0])?;
+    let b = val2geometry(&args[1])?;
+    Ok(DataValue::from(a.contains(&b)))
+}
+
+define_op!(OP_ST_INTERSECTS, 2, false);
+pub(crate) fn op_st_intersects(args: &[DataValue]) -> Result<DataValue> {
+    let a = val2geometry(&args[0])?;
+    let b = val2geometry(&args[1])?;
+    Ok(DataValue::from(a.intersects(&b)))
+}
+
+define_op!(OP_ST_DISTANCE, 2, false);
+pub(crate) fn op_st_distance(args: &[DataValue]) -> Result<DataValue> {
+    let a = val2geometry(&args[0])?;
+    let b = val2geometry(&args[1])?;
+    Ok(DataValue::from(a.distance(&b)))
+}
+
+define_op!(OP_ST_WITHIN_BBOX, 2, false);
+pub(crate) fn op_st_within_bbox(args: &[DataValue]) -> Result<DataValue> {
+    let g = val2geometry(&args[0])?;
+    let bbox = val2bbox(&args[1])?;
+    Ok(DataValue::from(bbox.contains(&g.bbox())))
+}
+
 define_op!(OP_FIRST, 1, false);
 pub(crate) fn op_first(args: &[DataValue]) -> Result<DataValue> {
     Ok(args[0]
@@ -1916,6 +2277,10 @@ pub(crate) fn op_to_bool(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Set(s) => !s.is_empty(),
         DataValue::Vec(_) => true,
         DataValue::Validity(vld) => vld.is_assert.0,
+        DataValue::Timestamp(_) => true,
+        DataValue::Duration(d) => *d != 0,
+        DataValue::Decimal(d) => !d.is_zero(),
+        DataValue::Geometry(_) => true,
         DataValue::Bot => false,
         DataValue::Json(json) => match &json.0 {
             Value::Null => false,
@@ -1942,6 +2307,10 @@ pub(crate) fn op_to_unity(args: &[DataValue]) -> Result<DataValue> {
         DataValue::Set(s) => i64::from(!s.is_empty()),
         DataValue::Vec(_) => 1,
         DataValue::Validity(vld) => i64::from(vld.is_assert.0),
+        DataValue::Timestamp(_) => 1,
+        DataValue::Duration(d) => i64::from(*d != 0),
+        DataValue::Decimal(d) => i64::from(!d.is_zero()),
+        DataValue::Geometry(_) => 1,
         DataValue::Bot => 0,
         DataValue::Json(json) => match &json.0 {
             Value::Null => 0,
@@ -1973,6 +2342,13 @@ pub(crate) fn op_to_int(args: &[DataValue]) -> Result<DataValue> {
                 .into()
         }
         DataValue::Validity(vld) => DataValue::Num(Num::Int(vld.timestamp.0 .0)),
+        DataValue::Timestamp(ts) => DataValue::Num(Num::Int(*ts)),
+        DataValue::Duration(d) => DataValue::Num(Num::Int(*d)),
+        DataValue::Decimal(d) => {
+            DataValue::Num(Num::Int(d.trunc().to_i64().ok_or_else(|| {
+                miette!("decimal {} is out of range for 'to_int'", d)
+            })?))
+        }
         v => bail!("'to_int' does not recognize {:?}", v),
     })
 }
@@ -1993,6 +2369,10 @@ pub(crate) fn op_to_float(args: &[DataValue]) -> Result<DataValue> {
                 .map_err(|_| miette!("The string cannot be interpreted as float"))?
                 .into(),
         },
+        DataValue::Decimal(d) => d
+            .to_f64()
+            .ok_or_else(|| miette!("decimal {} is out of range for 'to_float'", d))?
+            .into(),
         v => bail!("'to_float' does not recognize {:?}", v),
     })
 }
@@ -2006,6 +2386,7 @@ fn val2str(arg: &DataValue) -> String {
     match arg {
         DataValue::Str(s) => s.to_string(),
         DataValue::Json(JsonData(JsonValue::String(s))) => s.clone(),
+        DataValue::Decimal(d) => d.to_string(),
         v => {
             let jv = to_json(v);
             jv.to_string()
@@ -2385,6 +2766,29 @@ pub(crate) fn op_intersection(args: &[DataValue]) -> Result<DataValue> {
     Ok(DataValue::List(start.into_iter().collect()))
 }
 
+/// Converts the value to a decimal. Floats are converted from their shortest
+/// representation, so that `0.1` becomes exactly `0.1`.
+pub(crate) fn val2decimal(v: &DataValue) -> Result<Decimal> {
+    Ok(match v {
+        DataValue::Decimal(d) => *d,
+        DataValue::Num(Num::Int(i)) => Decimal::from(*i),
+        DataValue::Num(Num::Float(f)) => {
+            ensure!(f.is_finite(), "cannot convert {} to a decimal", f);
+            Decimal::from_str_exact(&f.to_string())
+                .map_err(|_| miette!("float {} is out of range for decimals", f))?
+        }
+        DataValue::Str(s) => Decimal::from_str_exact(s.trim())
+            .or_else(|_| Decimal::from_scientific(s.trim()))
+            .map_err(|_| miette!("bad decimal: {}", s))?,
+        v => bail!("cannot interpret {:?} as a decimal", v),
+    })
+}
+
+define_op!(OP_TO_DECIMAL, 1, false);
+pub(crate) fn op_to_decimal(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Decimal(val2decimal(&args[0])?))
+}
+
 define_op!(OP_TO_UUID, 1, false);
 pub(crate) fn op_to_uuid(args: &[DataValue]) -> Result<DataValue> {
     match &args[0] {
@@ -2432,20 +2836,22 @@ pub(crate) const TERMINAL_VALIDITY: Validity = Validity {
 
 define_op!(OP_FORMAT_TIMESTAMP, 1, true);
 pub(crate) fn op_format_timestamp(args: &[DataValue]) -> Result<DataValue> {
-    let dt = {
-        let millis = match &args[0] {
-            DataValue::Validity(vld) => vld.timestamp.0 .0 / 1000,
-            v => {
-                let f = v
-                    .get_float()
-                    .ok_or_else(|| miette!("'format_timestamp' expects a number"))?;
-                (f * 1000.) as i64
-            }
-        };
-        Utc.timestamp_millis_opt(millis)
-            .latest()
-            .ok_or_else(|| miette!("bad time: {}", &args[0]))?
-    };
+    let dt = match &args[0] {
+        DataValue::Timestamp(ts) => micros_to_datetime(*ts),
+        v => {
+            let millis = match v {
+                DataValue::Validity(vld) => vld.timestamp.0 .0 / 1000,
+                v => {
+                    let f = v
+                        .get_float()
+                        .ok_or_else(|| miette!("'format_timestamp' expects a number"))?;
+                    (f * 1000.) as i64
+                }
+            };
+            Utc.timestamp_millis_opt(millis).latest()
+        }
+    }
+    .ok_or_else(|| miette!("bad time: {}", &args[0]))?;
     match args.get(1) {
         Some(tz_v) => {
             let tz_s = tz_v.get_str().ok_or_else(|| {
@@ -2476,6 +2882,165 @@ pub(crate) fn op_parse_timestamp(args: &[DataValue]) -> Result<DataValue> {
     ))
 }
 
+fn get_tz(op_name: &str, arg: Option<&DataValue>) -> Result<Tz> {
+    match arg {
+        None => Ok(chrono_tz::UTC),
+        Some(tz_v) => {
+            let tz_s = tz_v
+                .get_str()
+                .ok_or_else(|| miette!("'{}' timezone specification requires a string", op_name))?;
+            Tz::from_str(tz_s).map_err(|_| miette!("bad timezone specification: {}", tz_s))
+        }
+    }
+}
+
+/// Interprets the value as a timestamp, in microseconds since the UNIX epoch.
+/// Strings are parsed as RFC 3339, or else as a date and time without offset in `tz`.
+/// Numbers are seconds since the UNIX epoch, as returned by `now()`.
+pub(crate) fn val2timestamp(v: &DataValue, tz: &Tz) -> Result<i64> {
+    let micros = match v {
+        DataValue::Timestamp(ts) => return Ok(*ts),
+        DataValue::Validity(vld) => Some(vld.timestamp.0 .0),
+        DataValue::Num(Num::Int(i)) => i.checked_mul(1_000_000),
+        DataValue::Num(Num::Float(f)) => {
+            let micros = (f * 1_000_000.).round();
+            if micros.is_finite() && micros.abs() < i64::MAX as f64 {
+                Some(micros as i64)
+            } else {
+                None
+            }
+        }
+        DataValue::Str(s) => {
+            let s = s.trim();
+            match DateTime::parse_from_rfc3339(s) {
+                Ok(dt) => Some(datetime_to_micros(&dt)),
+                Err(_) => {
+                    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+                        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f"))
+                        .or_else(|_| {
+                            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                                .map(|d| d.and_hms_opt(0, 0, 0).unwrap())
+                        })
+                        .map_err(|_| miette!("bad datetime: {}", s))?;
+                    let dt = tz.from_local_datetime(&naive).earliest().ok_or_else(|| {
+                        miette!("datetime {} does not exist in timezone {}", s, tz)
+                    })?;
+                    Some(datetime_to_micros(&dt))
+                }
+            }
+        }
+        v => bail!("cannot interpret {:?} as a timestamp", v),
+    };
+    micros
+        .filter(|micros| micros_to_datetime(*micros).is_some())
+        .ok_or_else(|| miette!("timestamp out of range: {:?}", v))
+}
+
+/// Interprets the value as a duration, in microseconds. Strings are as in `1h30m`,
+/// and numbers are seconds.
+pub(crate) fn val2duration(v: &DataValue) -> Result<i64> {
+    match v {
+        DataValue::Duration(d) => Ok(*d),
+        DataValue::Str(s) => parse_duration(s).ok_or_else(|| miette!("bad duration: {}", s)),
+        DataValue::Num(Num::Int(i)) => i
+            .checked_mul(1_000_000)
+            .ok_or_else(|| miette!("duration out of range")),
+        DataValue::Num(Num::Float(f)) => {
+            let micros = (f * 1_000_000.).round();
+            ensure!(
+                micros.is_finite() && micros.abs() < i64::MAX as f64,
+                "duration out of range"
+            );
+            Ok(micros as i64)
+        }
+        v => bail!("cannot interpret {:?} as a duration", v),
+    }
+}
+
+define_op!(OP_TO_TIMESTAMP, 1, true);
+pub(crate) fn op_to_timestamp(args: &[DataValue]) -> Result<DataValue> {
+    let tz = get_tz("to_timestamp", args.get(1))?;
+    Ok(DataValue::Timestamp(val2timestamp(&args[0], &tz)?))
+}
+
+define_op!(OP_TO_DURATION, 1, false);
+pub(crate) fn op_to_duration(args: &[DataValue]) -> Result<DataValue> {
+    Ok(DataValue::Duration(val2duration(&args[0])?))
+}
+
+define_op!(OP_DATE_TRUNC, 2, true);
+pub(crate) fn op_date_trunc(args: &[DataValue]) -> Result<DataValue> {
+    let unit = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'date_trunc' requires a string as the unit"))?;
+    let dt = args[1]
+        .get_timestamp()
+        .ok_or_else(|| miette!("'date_trunc' requires a timestamp"))?;
+    let tz = get_tz("date_trunc", args.get(2))?;
+    let local = dt.with_timezone(&tz).naive_local();
+    let date = local.date();
+    let truncated = match unit {
+        "year" => NaiveDate::from_ymd_opt(date.year(), 1, 1).and_then(|d| d.and_hms_opt(0, 0, 0)),
+        "quarter" => NaiveDate::from_ymd_opt(date.year(), date.month0() / 3 * 3 + 1, 1)
+            .and_then(|d| d.and_hms_opt(0, 0, 0)),
+        "month" => NaiveDate::from_ymd_opt(date.year(), date.month(), 1)
+            .and_then(|d| d.and_hms_opt(0, 0, 0)),
+        "week" => (date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64))
+            .and_hms_opt(0, 0, 0),
+        "day" => date.and_hms_opt(0, 0, 0),
+        "hour" => date.and_hms_opt(local.hour(), 0, 0),
+        "minute" => date.and_hms_opt(local.hour(), local.minute(), 0),
+        "second" => date.and_hms_opt(local.hour(), local.minute(), local.second()),
+        "millisecond" => date.and_hms_micro_opt(
+            local.hour(),
+            local.minute(),
+            local.second(),
+            local.nanosecond() / 1_000_000 * 1000,
+        ),
+        u => bail!("unknown unit {} for 'date_trunc'", u),
+    }
+    .ok_or_else(|| miette!("bad time: {}", &args[1]))?;
+    let dt = tz
+        .from_local_datetime(&truncated)
+        .earliest()
+        .ok_or_else(|| {
+            miette!(
+                "truncated time {} does not exist in timezone {}",
+                truncated,
+                tz
+            )
+        })?;
+    Ok(DataValue::Timestamp(datetime_to_micros(&dt)))
+}
+
+define_op!(OP_EXTRACT, 2, true);
+pub(crate) fn op_extract(args: &[DataValue]) -> Result<DataValue> {
+    let field = args[0]
+        .get_str()
+        .ok_or_else(|| miette!("'extract' requires a string as the field"))?;
+    let dt = args[1]
+        .get_timestamp()
+        .ok_or_else(|| miette!("'extract' requires a timestamp"))?;
+    let tz = get_tz("extract", args.get(2))?;
+    let local = dt.with_timezone(&tz);
+    Ok(DataValue::from(match field {
+        "year" => local.year() as i64,
+        "quarter" => (local.month0() / 3 + 1) as i64,
+        "month" => local.month() as i64,
+        "week" => local.iso_week().week() as i64,
+        "day" => local.day() as i64,
+        "dow" => local.weekday().num_days_from_sunday() as i64,
+        "doy" => local.ordinal() as i64,
+        "hour" => local.hour() as i64,
+        "minute" => local.minute() as i64,
+        "second" => local.second() as i64,
+        "millisecond" => (local.nanosecond() / 1_000_000) as i64,
+        "microsecond" => (local.nanosecond() / 1_000) as i64,
+        "epoch" => return Ok(DataValue::from(datetime_to_micros(&dt) as f64 / 1_000_000.)),
+        f => bail!("unknown field {} for 'extract'", f),
+    }))
+}
+
 pub(crate) fn str2vld(s: &str) -> Result<ValidityTs> {
     let dt = DateTime::parse_from_rfc3339(s).map_err(|_| miette!("bad datetime: {}", s))?;
     let st: SystemTime = dt.into();
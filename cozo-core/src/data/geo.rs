@@ -0,0 +1,577 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Geometries in the plane: points, line strings and polygons, read from and written to
+//! WKT and GeoJSON. Coordinates are treated as planar, so distances are in coordinate units.
+
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+
+use itertools::Itertools;
+use miette::{bail, ensure, miette, Result};
+use serde_json::{json, Value as JsonValue};
+
+/// A point of a geometry. Coordinates are finite, and negative zeros are stored as zeros,
+/// so that equal points are also equal as keys.
+#[derive(Copy, Clone, Debug, serde_derive::Deserialize, serde_derive::Serialize)]
+pub struct GeoCoord {
+    /// The horizontal coordinate, or the longitude
+    pub x: f64,
+    /// The vertical coordinate, or the latitude
+    pub y: f64,
+}
+
+impl GeoCoord {
+    /// Creates a coordinate, failing if either value is not finite
+    pub fn new(x: f64, y: f64) -> Result<Self> {
+        ensure!(
+            x.is_finite() && y.is_finite(),
+            "geometry coordinates must be finite, got ({}, {})",
+            x,
+            y
+        );
+        // adding zero turns negative zeros into zeros
+        Ok(Self {
+            x: x + 0.0,
+            y: y + 0.0,
+        })
+    }
+    fn dist_sq(self, other: Self) -> f64 {
+        (self.x - other.x).powi(2) + (self.y - other.y).powi(2)
+    }
+}
+
+impl PartialEq for GeoCoord {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for GeoCoord {}
+
+impl PartialOrd for GeoCoord {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for GeoCoord {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.x
+            .total_cmp(&other.x)
+            .then_with(|| self.y.total_cmp(&other.y))
+    }
+}
+
+impl Hash for GeoCoord {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.x.to_bits().hash(state);
+        self.y.to_bits().hash(state);
+    }
+}
+
+/// A planar geometry
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde_derive::Deserialize,
+    serde_derive::Serialize,
+)]
+pub enum Geometry {
+    /// A single point
+    Point(GeoCoord),
+    /// Connected line segments through at least two points
+    LineString(Vec<GeoCoord>),
+    /// The exterior ring followed by the holes. Rings are closed: the last coordinate
+    /// repeats the first one.
+    Polygon(Vec<Vec<GeoCoord>>),
+}
+
+/// An axis-aligned bounding box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct BBox {
+    pub(crate) min_x: f64,
+    pub(crate) min_y: f64,
+    pub(crate) max_x: f64,
+    pub(crate) max_y: f64,
+}
+
+impl BBox {
+    pub(crate) fn new(min_x: f64, min_y: f64, max_x: f64, max_y: f64) -> Result<Self> {
+        ensure!(
+            [min_x, min_y, max_x, max_y].iter().all(|v| v.is_finite()),
+            "bounding box [{}, {}, {}, {}] must have finite bounds",
+            min_x,
+            min_y,
+            max_x,
+            max_y
+        );
+        ensure!(
+            min_x <= max_x && min_y <= max_y,
+            "bounding box [{}, {}, {}, {}] must be given as [min_x, min_y, max_x, max_y]",
+            min_x,
+            min_y,
+            max_x,
+            max_y
+        );
+        Ok(Self {
+            min_x,
+            min_y,
+            max_x,
+            max_y,
+        })
+    }
+    pub(crate) fn union(&self, other: &Self) -> Self {
+        Self {
+            min_x: self.min_x.min(other.min_x),
+            min_y: self.min_y.min(other.min_y),
+            max_x: self.max_x.max(other.max_x),
+            max_y: self.max_y.max(other.max_y),
+        }
+    }
+    pub(crate) fn area(&self) -> f64 {
+        (self.max_x - self.min_x) * (self.max_y - self.min_y)
+    }
+    /// Half the perimeter of the box
+    pub(crate) fn margin(&self) -> f64 {
+        (self.max_x - self.min_x) + (self.max_y - self.min_y)
+    }
+    pub(crate) fn intersects(&self, other: &Self) -> bool {
+        self.min_x <= other.max_x
+            && other.min_x <= self.max_x
+            && self.min_y <= other.max_y
+            && other.min_y <= self.max_y
+    }
+    pub(crate) fn contains(&self, other: &Self) -> bool {
+        self.min_x <= other.min_x
+            && self.min_y <= other.min_y
+            && self.max_x >= other.max_x
+            && self.max_y >= other.max_y
+    }
+    /// The shortest distance between the two boxes, zero if they intersect
+    pub(crate) fn distance(&self, other: &Self) -> f64 {
+        let dx = (other.min_x - self.max_x)
+            .max(self.min_x - other.max_x)
+            .max(0.);
+        let dy = (other.min_y - self.max_y)
+            .max(self.min_y - other.max_y)
+            .max(0.);
+        (dx * dx + dy * dy).sqrt()
+    }
+    pub(crate) fn to_geometry(self) -> Geometry {
+        let ring = vec![
+            GeoCoord::new(self.min_x, self.min_y).unwrap(),
+            GeoCoord::new(self.max_x, self.min_y).unwrap(),
+            GeoCoord::new(self.max_x, self.max_y).unwrap(),
+            GeoCoord::new(self.min_x, self.max_y).unwrap(),
+            GeoCoord::new(self.min_x, self.min_y).unwrap(),
+        ];
+        Geometry::Polygon(vec![ring])
+    }
+    pub(crate) fn to_vec(self) -> Vec<f64> {
+        vec![self.min_x, self.min_y, self.max_x, self.max_y]
+    }
+}
+
+type Segment = (GeoCoord, GeoCoord);
+
+impl Geometry {
+    /// Creates a point
+    pub fn point(x: f64, y: f64) -> Result<Self> {
+        Ok(Geometry::Point(GeoCoord::new(x, y)?))
+    }
+    /// Creates a line string of at least two points
+    pub fn line_string(coords: Vec<GeoCoord>) -> Result<Self> {
+        ensure!(coords.len() >= 2, "a line string needs at least two points");
+        Ok(Geometry::LineString(coords))
+    }
+    /// Creates a polygon from its exterior ring and its holes. Rings that are not closed
+    /// are closed by repeating their first coordinate.
+    pub fn polygon(rings: Vec<Vec<GeoCoord>>) -> Result<Self> {
+        ensure!(!rings.is_empty(), "a polygon needs an exterior ring");
+        let mut closed = Vec::with_capacity(rings.len());
+        for mut ring in rings {
+            if ring.first() != ring.last() {
+                ring.push(ring[0]);
+            }
+            ensure!(
+                ring.len() >= 4,
+                "a polygon ring needs at least three distinct points"
+            );
+            closed.push(ring);
+        }
+        Ok(Geometry::Polygon(closed))
+    }
+    fn coords(&self) -> Box<dyn Iterator<Item = &GeoCoord> + '_> {
+        match self {
+            Geometry::Point(p) => Box::new(std::iter::once(p)),
+            Geometry::LineString(l) => Box::new(l.iter()),
+            Geometry::Polygon(rings) => Box::new(rings.iter().flatten()),
+        }
+    }
+    pub(crate) fn bbox(&self) -> BBox {
+        let mut coords = self.coords();
+        let first = coords.next().unwrap();
+        let mut ret = BBox {
+            min_x: first.x,
+            min_y: first.y,
+            max_x: first.x,
+            max_y: first.y,
+        };
+        for c in coords {
+            ret.min_x = ret.min_x.min(c.x);
+            ret.min_y = ret.min_y.min(c.y);
+            ret.max_x = ret.max_x.max(c.x);
+            ret.max_y = ret.max_y.max(c.y);
+        }
+        ret
+    }
+    /// Segments of lines and polygon boundaries. A point is a segment of length zero.
+    fn segments(&self) -> Vec<Segment> {
+        match self {
+            Geometry::Point(p) => vec![(*p, *p)],
+            Geometry::LineString(l) => l.iter().copied().tuple_windows().collect(),
+            Geometry::Polygon(rings) => rings
+                .iter()
+                .flat_map(|ring| ring.iter().copied().tuple_windows())
+                .collect(),
+        }
+    }
+    /// Whether the point is inside or on the boundary of the geometry
+    fn covers_point(&self, p: GeoCoord) -> bool {
+        match self {
+            Geometry::Point(q) => *q == p,
+            Geometry::LineString(_) => self.segments().iter().any(|s| on_segment(p, *s)),
+            Geometry::Polygon(rings) => {
+                if rings
+                    .iter()
+                    .flat_map(|ring| ring.iter().copied().tuple_windows())
+                    .any(|s| on_segment(p, s))
+                {
+                    return true;
+                }
+                ring_contains(&rings[0], p) && !rings[1..].iter().any(|h| ring_contains(h, p))
+            }
+        }
+    }
+    /// Whether the two geometries share at least one point
+    pub(crate) fn intersects(&self, other: &Geometry) -> bool {
+        if !self.bbox().intersects(&other.bbox()) {
+            return false;
+        }
+        let own_segments = self.segments();
+        let other_segments = other.segments();
+        if own_segments
+            .iter()
+            .any(|a| other_segments.iter().any(|b| segments_intersect(*a, *b)))
+        {
+            return true;
+        }
+        // without crossing boundaries, one geometry may still lie inside the other
+        let first_of = |g: &Geometry| *g.coords().next().unwrap();
+        other.covers_point(first_of(self)) || self.covers_point(first_of(other))
+    }
+    /// Whether no point of `other` lies outside this geometry
+    pub(crate) fn contains(&self, other: &Geometry) -> bool {
+        if !self.bbox().contains(&other.bbox()) {
+            return false;
+        }
+        if !other.coords().all(|c| self.covers_point(*c)) {
+            return false;
+        }
+        match self {
+            Geometry::Point(_) => true,
+            Geometry::LineString(_) => other
+                .segments()
+                .iter()
+                .all(|(a, b)| self.covers_point(midpoint(*a, *b))),
+            Geometry::Polygon(rings) => {
+                let boundary = self.segments();
+                let crosses = other.segments().iter().any(|s| {
+                    boundary.iter().any(|b| segments_cross(*s, *b))
+                        || !self.covers_point(midpoint(s.0, s.1))
+                });
+                if crosses {
+                    return false;
+                }
+                // a polygon covering a hole is not contained
+                match other {
+                    Geometry::Polygon(other_rings) => !rings[1..].iter().any(|hole| {
+                        hole.iter().any(|c| {
+                            ring_contains(&other_rings[0], *c)
+                                && !other_rings[0]
+                                    .iter()
+                                    .copied()
+                                    .tuple_windows()
+                                    .any(|s| on_segment(*c, s))
+                        })
+                    }),
+                    _ => true,
+                }
+            }
+        }
+    }
+    /// The shortest distance between the two geometries, zero if they intersect
+    pub(crate) fn distance(&self, other: &Geometry) -> f64 {
+        if self.intersects(other) {
+            return 0.;
+        }
+        let other_segments = other.segments();
+        self.segments()
+            .iter()
+            .flat_map(|a| other_segments.iter().map(|b| segment_distance(*a, *b)))
+            .fold(f64::INFINITY, f64::min)
+    }
+    pub(crate) fn from_wkt(s: &str) -> Result<Self> {
+        let mut parser = WktParser {
+            src: s.as_bytes(),
+            pos: 0,
+        };
+        let ret = parser.geometry()?;
+        parser.skip_ws();
+        ensure!(
+            parser.pos == parser.src.len(),
+            "unexpected trailing characters in WKT: {}",
+            s
+        );
+        Ok(ret)
+    }
+    pub(crate) fn from_geojson(j: &JsonValue) -> Result<Self> {
+        let bad = || miette!("bad GeoJSON geometry: {}", j);
+        let coords = j.get("coordinates").ok_or_else(bad)?;
+        let coord = |v: &JsonValue| -> Result<GeoCoord> {
+            match v.as_array().map(|a| a.as_slice()) {
+                Some([x, y]) => {
+                    GeoCoord::new(x.as_f64().ok_or_else(bad)?, y.as_f64().ok_or_else(bad)?)
+                }
+                _ => Err(bad()),
+            }
+        };
+        let coord_list = |v: &JsonValue| -> Result<Vec<GeoCoord>> {
+            v.as_array().ok_or_else(bad)?.iter().map(coord).collect()
+        };
+        match j.get("type").and_then(|t| t.as_str()) {
+            Some("Point") => Ok(Geometry::Point(coord(coords)?)),
+            Some("LineString") => Geometry::line_string(coord_list(coords)?),
+            Some("Polygon") => Geometry::polygon(
+                coords
+                    .as_array()
+                    .ok_or_else(bad)?
+                    .iter()
+                    .map(coord_list)
+                    .try_collect()?,
+            ),
+            _ => Err(bad()),
+        }
+    }
+    pub(crate) fn to_geojson(&self) -> JsonValue {
+        let coord = |c: &GeoCoord| json!([c.x, c.y]);
+        match self {
+            Geometry::Point(p) => json!({"type": "Point", "coordinates": coord(p)}),
+            Geometry::LineString(l) => json!({
+                "type": "LineString",
+                "coordinates": l.iter().map(coord).collect_vec()
+            }),
+            Geometry::Polygon(rings) => json!({
+                "type": "Polygon",
+                "coordinates": rings
+                    .iter()
+                    .map(|ring| ring.iter().map(coord).collect_vec())
+                    .collect_vec()
+            }),
+        }
+    }
+}
+
+/// Writes the geometry as WKT
+impl Display for Geometry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let coords = |cs: &[GeoCoord]| cs.iter().map(|c| format!("{} {}", c.x, c.y)).join(", ");
+        match self {
+            Geometry::Point(p) => write!(f, "POINT ({} {})", p.x, p.y),
+            Geometry::LineString(l) => write!(f, "LINESTRING ({})", coords(l)),
+            Geometry::Polygon(rings) => write!(
+                f,
+                "POLYGON ({})",
+                rings.iter().map(|r| format!("({})", coords(r))).join(", ")
+            ),
+        }
+    }
+}
+
+fn midpoint(a: GeoCoord, b: GeoCoord) -> GeoCoord {
+    GeoCoord {
+        x: (a.x + b.x) / 2.,
+        y: (a.y + b.y) / 2.,
+    }
+}
+
+/// Positive if `a`, `b`, `c` turn counter-clockwise, negative if clockwise, zero if collinear
+fn orientation(a: GeoCoord, b: GeoCoord, c: GeoCoord) -> f64 {
+    (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+fn on_segment(p: GeoCoord, (a, b): Segment) -> bool {
+    orientation(a, b, p) == 0.
+        && p.x >= a.x.min(b.x)
+        && p.x <= a.x.max(b.x)
+        && p.y >= a.y.min(b.y)
+        && p.y <= a.y.max(b.y)
+}
+
+fn segments_intersect(s: Segment, t: Segment) -> bool {
+    let d1 = orientation(t.0, t.1, s.0);
+    let d2 = orientation(t.0, t.1, s.1);
+    let d3 = orientation(s.0, s.1, t.0);
+    let d4 = orientation(s.0, s.1, t.1);
+    if ((d1 > 0. && d2 < 0.) || (d1 < 0. && d2 > 0.))
+        && ((d3 > 0. && d4 < 0.) || (d3 < 0. && d4 > 0.))
+    {
+        return true;
+    }
+    on_segment(s.0, t) || on_segment(s.1, t) || on_segment(t.0, s) || on_segment(t.1, s)
+}
+
+/// Whether the segments cross at a single point inside both of them
+fn segments_cross(s: Segment, t: Segment) -> bool {
+    let d1 = orientation(t.0, t.1, s.0);
+    let d2 = orientation(t.0, t.1, s.1);
+    let d3 = orientation(s.0, s.1, t.0);
+    let d4 = orientation(s.0, s.1, t.1);
+    d1 * d2 < 0. && d3 * d4 < 0.
+}
+
+fn point_segment_distance(p: GeoCoord, (a, b): Segment) -> f64 {
+    let len_sq = a.dist_sq(b);
+    if len_sq == 0. {
+        return p.dist_sq(a).sqrt();
+    }
+    let t = (((p.x - a.x) * (b.x - a.x) + (p.y - a.y) * (b.y - a.y)) / len_sq).clamp(0., 1.);
+    let proj = GeoCoord {
+        x: a.x + t * (b.x - a.x),
+        y: a.y + t * (b.y - a.y),
+    };
+    p.dist_sq(proj).sqrt()
+}
+
+fn segment_distance(s: Segment, t: Segment) -> f64 {
+    if segments_intersect(s, t) {
+        return 0.;
+    }
+    point_segment_distance(s.0, t)
+        .min(point_segment_distance(s.1, t))
+        .min(point_segment_distance(t.0, s))
+        .min(point_segment_distance(t.1, s))
+}
+
+/// Even-odd rule, for points not on the ring
+fn ring_contains(ring: &[GeoCoord], p: GeoCoord) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().tuple_windows() {
+        if (a.y > p.y) != (b.y > p.y) {
+            let x = a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x);
+            if p.x < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+struct WktParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl WktParser<'_> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+    fn expect(&mut self, c: u8) -> Result<()> {
+        self.skip_ws();
+        if self.src.get(self.pos) != Some(&c) {
+            bail!("bad WKT: expected '{}' at position {}", c as char, self.pos);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+    fn try_consume(&mut self, c: u8) -> bool {
+        self.skip_ws();
+        if self.src.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn word(&mut self) -> String {
+        self.skip_ws();
+        let start = self.pos;
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_alphabetic() {
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.src[start..self.pos]).to_ascii_uppercase()
+    }
+    fn number(&mut self) -> Result<f64> {
+        self.skip_ws();
+        let start = self.pos;
+        while self.pos < self.src.len()
+            && (self.src[self.pos].is_ascii_digit()
+                || matches!(self.src[self.pos], b'-' | b'+' | b'.' | b'e' | b'E'))
+        {
+            self.pos += 1;
+        }
+        let s = String::from_utf8_lossy(&self.src[start..self.pos]);
+        s.parse()
+            .map_err(|_| miette!("bad WKT: expected a number at position {}", start))
+    }
+    fn coord(&mut self) -> Result<GeoCoord> {
+        let x = self.number()?;
+        let y = self.number()?;
+        GeoCoord::new(x, y)
+    }
+    fn coord_list(&mut self) -> Result<Vec<GeoCoord>> {
+        self.expect(b'(')?;
+        let mut ret = vec![self.coord()?];
+        while self.try_consume(b',') {
+            ret.push(self.coord()?);
+        }
+        self.expect(b')')?;
+        Ok(ret)
+    }
+    fn geometry(&mut self) -> Result<Geometry> {
+        match self.word().as_str() {
+            "POINT" => {
+                self.expect(b'(')?;
+                let p = self.coord()?;
+                self.expect(b')')?;
+                Ok(Geometry::Point(p))
+            }
+            "LINESTRING" => Geometry::line_string(self.coord_list()?),
+            "POLYGON" => {
+                self.expect(b'(')?;
+                let mut rings = vec![self.coord_list()?];
+                while self.try_consume(b',') {
+                    rings.push(self.coord_list()?);
+                }
+                self.expect(b')')?;
+                Geometry::polygon(rings)
+            }
+            "" => bail!("bad WKT: expected a geometry type"),
+            t => bail!("unsupported WKT geometry type {}", t),
+        }
+    }
+}
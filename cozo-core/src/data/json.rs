@@ -11,7 +11,9 @@ use base64::Engine;
 use serde_json::json;
 pub(crate) use serde_json::Value as JsonValue;
 
-use crate::data::value::{DataValue, Num, Vector};
+use crate::data::value::{
+    format_datetime, format_duration, micros_to_datetime, DataValue, Num, Vector,
+};
 use crate::JsonData;
 
 impl From<JsonValue> for DataValue {
@@ -96,6 +98,13 @@ impl From<DataValue> for JsonValue {
                 json!([v.timestamp.0, v.is_assert])
             }
             DataValue::Json(j) => j.0,
+            DataValue::Timestamp(ts) => match micros_to_datetime(ts) {
+                Some(dt) => json!(format_datetime(&dt)),
+                None => json!(ts),
+            },
+            DataValue::Duration(d) => json!(format_duration(d)),
+            DataValue::Decimal(d) => JsonValue::String(d.to_string()),
+            DataValue::Geometry(g) => g.to_geojson(),
         }
     }
 }
@@ -13,7 +13,9 @@ use std::str::FromStr;
 
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use regex::Regex;
+use rust_decimal::Decimal;
 
+use crate::data::geo::{GeoCoord, Geometry};
 use crate::data::value::{
     DataValue, JsonData, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs, Vector,
 };
@@ -32,11 +34,26 @@ const LIST_TAG: u8 = 0x0A;
 const SET_TAG: u8 = 0x0B;
 const VLD_TAG: u8 = 0x0C;
 const JSON_TAG: u8 = 0x0D;
+const TIMESTAMP_TAG: u8 = 0x0E;
+const DURATION_TAG: u8 = 0x0F;
+const DECIMAL_TAG: u8 = 0x10;
+const GEOMETRY_TAG: u8 = 0x11;
 const BOT_TAG: u8 = 0xFF;
 
 const VEC_F32: u8 = 0x01;
 const VEC_F64: u8 = 0x02;
 
+const DECIMAL_NEG: u8 = 0x00;
+const DECIMAL_ZERO: u8 = 0x01;
+const DECIMAL_POS: u8 = 0x02;
+const DECIMAL_EXP_BIAS: i32 = 0x80;
+
+const GEO_POINT: u8 = 0x01;
+const GEO_LINE_STRING: u8 = 0x02;
+const GEO_POLYGON: u8 = 0x03;
+const GEO_END: u8 = 0x00;
+const GEO_MORE: u8 = 0x01;
+
 const IS_FLOAT: u8 = 0b00010000;
 const IS_APPROX_INT: u8 = 0b00000100;
 const IS_EXACT_INT: u8 = 0b00000000;
@@ -121,6 +138,22 @@ pub(crate) trait MemCmpEncoder: Write {
                 self.write_u64::<BigEndian>(ts_flipped).unwrap();
                 self.write_u8(!vld.is_assert.0 as u8).unwrap();
             }
+            DataValue::Timestamp(ts) => {
+                self.write_u8(TIMESTAMP_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_i64(*ts)).unwrap();
+            }
+            DataValue::Duration(d) => {
+                self.write_u8(DURATION_TAG).unwrap();
+                self.write_u64::<BigEndian>(order_encode_i64(*d)).unwrap();
+            }
+            DataValue::Decimal(d) => {
+                self.write_u8(DECIMAL_TAG).unwrap();
+                self.encode_decimal(*d);
+            }
+            DataValue::Geometry(g) => {
+                self.write_u8(GEOMETRY_TAG).unwrap();
+                self.encode_geometry(g);
+            }
             DataValue::Bot => self.write_u8(BOT_TAG).unwrap(),
         }
     }
@@ -144,6 +177,67 @@ pub(crate) trait MemCmpEncoder: Write {
         }
     }
 
+    /// Writes the sign, then the exponent and the digits of the value written as `0.ddd * 10^exp`,
+    /// all inverted for negative values. Trailing zeros are dropped, so that equal values
+    /// with different scales, such as `1.0` and `1.00`, are encoded the same.
+    fn encode_decimal(&mut self, d: Decimal) {
+        if d.is_zero() {
+            self.write_u8(DECIMAL_ZERO).unwrap();
+            return;
+        }
+        let all_digits = d.mantissa().unsigned_abs().to_string();
+        let digits = all_digits.trim_end_matches('0');
+        let exp = all_digits.len() as i32 - d.scale() as i32 + DECIMAL_EXP_BIAS;
+        if d.is_sign_negative() {
+            self.write_u8(DECIMAL_NEG).unwrap();
+            self.write_u8(!(exp as u8)).unwrap();
+            for b in digits.bytes() {
+                self.write_u8(!b).unwrap();
+            }
+            self.write_u8(0xFF).unwrap();
+        } else {
+            self.write_u8(DECIMAL_POS).unwrap();
+            self.write_u8(exp as u8).unwrap();
+            self.write_all(digits.as_bytes()).unwrap();
+            self.write_u8(0x00).unwrap();
+        }
+    }
+
+    /// Writes the kind of the geometry, then its coordinates. Each coordinate in a list,
+    /// and each ring of a polygon, is preceded by a marker, and the lists end with a
+    /// terminator, so that the keys sort as the geometries do.
+    fn encode_geometry(&mut self, g: &Geometry) {
+        match g {
+            Geometry::Point(p) => {
+                self.write_u8(GEO_POINT).unwrap();
+                self.encode_geo_coord(p);
+            }
+            Geometry::LineString(l) => {
+                self.write_u8(GEO_LINE_STRING).unwrap();
+                self.encode_geo_coords(l);
+            }
+            Geometry::Polygon(rings) => {
+                self.write_u8(GEO_POLYGON).unwrap();
+                for ring in rings {
+                    self.write_u8(GEO_MORE).unwrap();
+                    self.encode_geo_coords(ring);
+                }
+                self.write_u8(GEO_END).unwrap();
+            }
+        }
+    }
+    fn encode_geo_coords(&mut self, coords: &[GeoCoord]) {
+        for c in coords {
+            self.write_u8(GEO_MORE).unwrap();
+            self.encode_geo_coord(c);
+        }
+        self.write_u8(GEO_END).unwrap();
+    }
+    fn encode_geo_coord(&mut self, c: &GeoCoord) {
+        self.write_u64::<BigEndian>(order_encode_f64(c.x)).unwrap();
+        self.write_u64::<BigEndian>(order_encode_f64(c.y)).unwrap();
+    }
+
     fn encode_bytes(&mut self, key: &[u8]) {
         let len = key.len();
         let mut index = 0;
@@ -254,6 +348,86 @@ impl Num {
     }
 }
 
+fn decode_decimal(bs: &[u8]) -> (Decimal, &[u8]) {
+    let (sign, remaining) = bs.split_first().unwrap();
+    let negative = match *sign {
+        DECIMAL_ZERO => return (Decimal::ZERO, remaining),
+        DECIMAL_NEG => true,
+        DECIMAL_POS => false,
+        _ => unreachable!(),
+    };
+    let flip = |b: u8| if negative { !b } else { b };
+    let (exp, remaining) = remaining.split_first().unwrap();
+    let exp = flip(*exp) as i32 - DECIMAL_EXP_BIAS;
+    let end = remaining.iter().position(|b| *b == flip(0x00)).unwrap();
+    let digits: String = remaining[..end].iter().map(|b| flip(*b) as char).collect();
+    let mut mantissa: i128 = digits.parse().unwrap();
+    let mut scale = digits.len() as i32 - exp;
+    while scale < 0 {
+        mantissa *= 10;
+        scale += 1;
+    }
+    if negative {
+        mantissa = -mantissa;
+    }
+    (
+        Decimal::from_i128_with_scale(mantissa, scale as u32),
+        &remaining[end + 1..],
+    )
+}
+
+fn decode_geometry(bs: &[u8]) -> (Geometry, &[u8]) {
+    let (kind, remaining) = bs.split_first().unwrap();
+    match *kind {
+        GEO_POINT => {
+            let (p, remaining) = decode_geo_coord(remaining);
+            (Geometry::Point(p), remaining)
+        }
+        GEO_LINE_STRING => {
+            let (l, remaining) = decode_geo_coords(remaining);
+            (Geometry::LineString(l), remaining)
+        }
+        GEO_POLYGON => {
+            let mut rings = vec![];
+            let mut remaining = remaining;
+            loop {
+                let (marker, rest) = remaining.split_first().unwrap();
+                if *marker == GEO_END {
+                    return (Geometry::Polygon(rings), rest);
+                }
+                let (ring, rest) = decode_geo_coords(rest);
+                rings.push(ring);
+                remaining = rest;
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn decode_geo_coords(bs: &[u8]) -> (Vec<GeoCoord>, &[u8]) {
+    let mut coords = vec![];
+    let mut remaining = bs;
+    loop {
+        let (marker, rest) = remaining.split_first().unwrap();
+        if *marker == GEO_END {
+            return (coords, rest);
+        }
+        let (c, rest) = decode_geo_coord(rest);
+        coords.push(c);
+        remaining = rest;
+    }
+}
+
+fn decode_geo_coord(bs: &[u8]) -> (GeoCoord, &[u8]) {
+    let (x_bytes, remaining) = bs.split_at(8);
+    let (y_bytes, remaining) = remaining.split_at(8);
+    let c = GeoCoord {
+        x: order_decode_f64(BigEndian::read_u64(x_bytes)),
+        y: order_decode_f64(BigEndian::read_u64(y_bytes)),
+    };
+    (c, remaining)
+}
+
 impl DataValue {
     pub(crate) fn decode_from_key(bs: &[u8]) -> (Self, &[u8]) {
         let (tag, remaining) = bs.split_first().unwrap();
@@ -334,6 +508,24 @@ impl DataValue {
                     rest,
                 )
             }
+            TIMESTAMP_TAG => {
+                let (ts_bytes, rest) = remaining.split_at(8);
+                let ts = order_decode_i64(BigEndian::read_u64(ts_bytes));
+                (DataValue::Timestamp(ts), rest)
+            }
+            DURATION_TAG => {
+                let (d_bytes, rest) = remaining.split_at(8);
+                let d = order_decode_i64(BigEndian::read_u64(d_bytes));
+                (DataValue::Duration(d), rest)
+            }
+            DECIMAL_TAG => {
+                let (d, rest) = decode_decimal(remaining);
+                (DataValue::Decimal(d), rest)
+            }
+            GEOMETRY_TAG => {
+                let (g, rest) = decode_geometry(remaining);
+                (DataValue::Geometry(g), rest)
+            }
             BOT_TAG => (DataValue::Bot, remaining),
             VEC_TAG => {
                 let (t_tag, remaining) = remaining.split_first().unwrap();
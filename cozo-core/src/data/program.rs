@@ -31,8 +31,10 @@ use crate::runtime::minhash_lsh::{LshSearch, MinHashLshIndexManifest};
 use crate::runtime::relation::{
     AccessLevel, InputRelationHandle, InsufficientAccessLevel, RelationHandle,
 };
+use crate::runtime::rtree::{RtreeIndexManifest, RtreeSearch};
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
+use crate::runtime::view::ViewHandle;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub(crate) enum QueryAssertion {
@@ -46,11 +48,27 @@ pub(crate) struct QueryOutOptions {
     pub(crate) offset: Option<usize>,
     pub(crate) timeout: Option<f64>,
     pub(crate) sleep: Option<f64>,
+    pub(crate) max_rows: Option<usize>,
+    pub(crate) max_memory: Option<usize>,
     pub(crate) sorters: Vec<(Symbol, SortDir)>,
     pub(crate) store_relation: Option<(InputRelationHandle, RelationOp)>,
+    /// Mutations fed by named rules instead of the entry, run after `store_relation`
+    pub(crate) rule_mutations: Vec<RuleMutation>,
+    /// Return the rows changed by the mutation instead of a status row
+    pub(crate) returning: bool,
     pub(crate) assertion: Option<QueryAssertion>,
 }
 
+/// A mutation of a stored relation with the rows of a named rule, as in `:put r {..} from rule`
+#[derive(Clone, PartialEq)]
+pub(crate) struct RuleMutation {
+    pub(crate) handle: InputRelationHandle,
+    pub(crate) op: RelationOp,
+    pub(crate) source: Symbol,
+    /// The head of the source rule, naming the columns of its rows
+    pub(crate) headers: Vec<Symbol>,
+}
+
 impl Debug for QueryOutOptions {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{self}")
@@ -68,6 +86,12 @@ impl Display for QueryOutOptions {
         if let Some(l) = self.timeout {
             writeln!(f, ":timeout {l};")?;
         }
+        if let Some(l) = self.max_rows {
+            writeln!(f, ":max_rows {l};")?;
+        }
+        if let Some(l) = self.max_memory {
+            writeln!(f, ":max_memory {l};")?;
+        }
         for (symb, dir) in &self.sorters {
             write!(f, ":order ")?;
             if *dir == SortDir::Dsc {
@@ -75,71 +99,17 @@ impl Display for QueryOutOptions {
             }
             writeln!(f, "{symb};")?;
         }
-        if let Some((
-            InputRelationHandle {
-                name,
-                metadata: StoredRelationMetadata { keys, non_keys },
-                key_bindings,
-                dep_bindings,
-                ..
-            },
-            op,
-        )) = &self.store_relation
-        {
-            match op {
-                RelationOp::Create => {
-                    write!(f, ":create ")?;
-                }
-                RelationOp::Replace => {
-                    write!(f, ":replace ")?;
-                }
-                RelationOp::Put => {
-                    write!(f, ":put ")?;
-                }
-                RelationOp::Update => {
-                    write!(f, ":update ")?;
-                }
-                RelationOp::Rm => {
-                    write!(f, ":rm ")?;
-                }
-                RelationOp::Ensure => {
-                    write!(f, ":ensure ")?;
-                }
-                RelationOp::EnsureNot => {
-                    write!(f, ":ensure_not ")?;
-                }
-            }
-            write!(f, "{name} {{")?;
-            let mut is_first = true;
-            for (col, bind) in keys.iter().zip(key_bindings) {
-                if is_first {
-                    is_first = false
-                } else {
-                    write!(f, ", ")?;
-                }
-                write!(f, "{}: {}", col.name, col.typing)?;
-                if let Some(gen) = &col.default_gen {
-                    write!(f, " default {gen}")?;
-                } else {
-                    write!(f, " = {bind}")?;
-                }
-            }
-            write!(f, " => ")?;
-            let mut is_first = true;
-            for (col, bind) in non_keys.iter().zip(dep_bindings) {
-                if is_first {
-                    is_first = false
-                } else {
-                    write!(f, ", ")?;
-                }
-                write!(f, "{}: {}", col.name, col.typing)?;
-                if let Some(gen) = &col.default_gen {
-                    write!(f, " default {gen}")?;
-                } else {
-                    write!(f, " = {bind}")?;
-                }
-            }
-            writeln!(f, "}};")?;
+        if let Some((handle, op)) = &self.store_relation {
+            write_relation_option(f, handle, op)?;
+            writeln!(f, ";")?;
+        }
+        for mutation in &self.rule_mutations {
+            write_relation_option(f, &mutation.handle, &mutation.op)?;
+            writeln!(f, " from {};", mutation.source)?;
+        }
+
+        if self.returning {
+            writeln!(f, ":returning;")?;
         }
 
         if let Some(a) = &self.assertion {
@@ -157,6 +127,99 @@ impl Display for QueryOutOptions {
     }
 }
 
+fn write_relation_option(
+    f: &mut Formatter<'_>,
+    handle: &InputRelationHandle,
+    op: &RelationOp,
+) -> std::fmt::Result {
+    let InputRelationHandle {
+        name,
+        metadata:
+            StoredRelationMetadata {
+                keys,
+                non_keys,
+                foreign_keys,
+            },
+        key_bindings,
+        dep_bindings,
+        ..
+    } = handle;
+    match op {
+        RelationOp::Create => {
+            write!(f, ":create ")?;
+        }
+        RelationOp::Replace => {
+            write!(f, ":replace ")?;
+        }
+        RelationOp::Put => {
+            write!(f, ":put ")?;
+        }
+        RelationOp::Update => {
+            write!(f, ":update ")?;
+        }
+        RelationOp::Rm => {
+            write!(f, ":rm ")?;
+        }
+        RelationOp::Ensure => {
+            write!(f, ":ensure ")?;
+        }
+        RelationOp::EnsureNot => {
+            write!(f, ":ensure_not ")?;
+        }
+    }
+    write!(f, "{name} {{")?;
+    let mut is_first = true;
+    for (col, bind) in keys.iter().zip(key_bindings) {
+        if is_first {
+            is_first = false
+        } else {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}: {}", col.name, col.typing)?;
+        if let Some(gen) = &col.default_gen {
+            write!(f, " default {gen}")?;
+        } else {
+            write!(f, " = {bind}")?;
+        }
+        if let Some(fk) = foreign_keys.iter().find(|fk| fk.column == col.name) {
+            write!(
+                f,
+                " references {}.{} on delete {}",
+                fk.ref_relation, fk.ref_column, fk.on_delete
+            )?;
+        }
+        if let Some(check) = &col.check {
+            write!(f, " check {check}")?;
+        }
+    }
+    write!(f, " => ")?;
+    let mut is_first = true;
+    for (col, bind) in non_keys.iter().zip(dep_bindings) {
+        if is_first {
+            is_first = false
+        } else {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}: {}", col.name, col.typing)?;
+        if let Some(gen) = &col.default_gen {
+            write!(f, " default {gen}")?;
+        } else {
+            write!(f, " = {bind}")?;
+        }
+        if let Some(fk) = foreign_keys.iter().find(|fk| fk.column == col.name) {
+            write!(
+                f,
+                " references {}.{} on delete {}",
+                fk.ref_relation, fk.ref_column, fk.on_delete
+            )?;
+        }
+        if let Some(check) = &col.check {
+            write!(f, " check {check}")?;
+        }
+    }
+    write!(f, "}}")
+}
+
 impl QueryOutOptions {
     pub(crate) fn num_to_take(&self) -> Option<usize> {
         match (self.limit, self.offset) {
@@ -537,18 +600,69 @@ struct EntryHeadNotExplicitlyDefinedError(#[label] SourceSpan);
 pub(crate) struct NoEntryError;
 
 impl InputProgram {
-    pub(crate) fn needs_write_lock(&self) -> Option<SmartString<LazyCompact>> {
-        if let Some((h, _)) = &self.out_opts.store_relation {
+    /// Collects the names of the stored relations the program writes to
+    pub(crate) fn needs_write_locks(&self, collector: &mut BTreeSet<SmartString<LazyCompact>>) {
+        let targets = self
+            .out_opts
+            .store_relation
+            .iter()
+            .map(|(h, _)| h)
+            .chain(self.out_opts.rule_mutations.iter().map(|m| &m.handle));
+        for h in targets {
             if !h.name.name.starts_with('_') {
-                Some(h.name.name.clone())
-            } else {
-                None
+                collector.insert(h.name.name.clone());
+                // creating the relation registers it with the targets of its foreign keys
+                for fk in &h.metadata.foreign_keys {
+                    collector.insert(fk.ref_relation.clone());
+                }
             }
-        } else {
-            None
         }
     }
 
+    /// Names of the stored relations and views the program reads from or writes to,
+    /// together with the applied rules that are not defined in the program
+    pub(crate) fn referenced_names(&self) -> BTreeSet<SmartString<LazyCompact>> {
+        let mut ret = BTreeSet::new();
+        for rules_or_fixed in self.prog.values() {
+            match rules_or_fixed {
+                InputInlineRulesOrFixed::Rules { rules } => {
+                    for rule in rules {
+                        for atom in &rule.body {
+                            atom.visit_leaves(&mut |atom| {
+                                let name = match atom {
+                                    InputAtom::Rule { inner } => &inner.name,
+                                    InputAtom::Relation { inner } => &inner.name,
+                                    InputAtom::NamedFieldRelation { inner } => &inner.name,
+                                    InputAtom::Search { inner } => &inner.relation,
+                                    _ => return,
+                                };
+                                ret.insert(name.name.clone());
+                            });
+                        }
+                    }
+                }
+                InputInlineRulesOrFixed::Fixed { fixed } => {
+                    for arg in &fixed.rule_args {
+                        match arg {
+                            FixedRuleArg::InMem { name, .. }
+                            | FixedRuleArg::Stored { name, .. }
+                            | FixedRuleArg::NamedStored { name, .. } => {
+                                ret.insert(name.name.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for name in self.prog.keys() {
+            ret.remove(&name.name);
+        }
+        if let Some((meta, _)) = &self.out_opts.store_relation {
+            ret.insert(meta.name.name.clone());
+        }
+        ret
+    }
+
     pub(crate) fn get_entry_arity(&self) -> Result<usize> {
         if let Some(entry) = self.prog.get(&Symbol::new(PROG_ENTRY, SourceSpan(0, 0))) {
             return match entry {
@@ -571,7 +685,10 @@ impl InputProgram {
         }
     }
     pub(crate) fn get_entry_out_head(&self) -> Result<Vec<Symbol>> {
-        if let Some(entry) = self.prog.get(&Symbol::new(PROG_ENTRY, SourceSpan(0, 0))) {
+        self.get_rule_out_head(&Symbol::new(PROG_ENTRY, SourceSpan(0, 0)))
+    }
+    pub(crate) fn get_rule_out_head(&self, name: &Symbol) -> Result<Vec<Symbol>> {
+        if let Some(entry) = self.prog.get(name) {
             return match entry {
                 InputInlineRulesOrFixed::Rules { rules } => {
                     let head = &rules.last().unwrap().head;
@@ -580,14 +697,7 @@ impl InputProgram {
                     for (symb, aggr) in head.iter().zip(aggrs.iter()) {
                         if let Some((aggr, _)) = aggr {
                             ret.push(Symbol::new(
-                                format!(
-                                    "{}({})",
-                                    aggr.name
-                                        .strip_prefix("AGGR_")
-                                        .unwrap()
-                                        .to_ascii_lowercase(),
-                                    symb
-                                ),
+                                format!("{}({})", aggr.display_name(), symb),
                                 symb.span,
                             ))
                         } else {
@@ -608,6 +718,246 @@ impl InputProgram {
 
         Err(NoEntryError.into())
     }
+    /// Inlines the rules of the stored views applied in the program.
+    /// The entry rule of a view `v` becomes the rule `v`, and its other rules `r` become `v.r`.
+    /// Views may be applied like rules (`v[..]`) or like stored relations (`*v[..]`, `*v{..}`).
+    pub(crate) fn inline_views(
+        &mut self,
+        tx: &SessionTx<'_>,
+        parse_view: &dyn Fn(&str) -> Result<InputProgram>,
+    ) -> Result<()> {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("View {0} is shadowed by a rule of the same name")]
+        #[diagnostic(code(eval::view_shadowed))]
+        struct ViewShadowedError(String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("View {0} cannot be applied with time travel")]
+        #[diagnostic(code(eval::time_travel_on_view))]
+        struct TimeTravelOnViewError(String, #[label] SourceSpan);
+
+        let mut known: BTreeMap<Symbol, Vec<SmartString<LazyCompact>>> = BTreeMap::new();
+        loop {
+            let defined: BTreeSet<Symbol> = self.prog.keys().cloned().collect();
+            let mut pending: BTreeMap<Symbol, ViewHandle> = BTreeMap::new();
+            let mut find_view = |name: &Symbol,
+                                 as_relation: bool|
+             -> Result<Option<Vec<SmartString<LazyCompact>>>> {
+                if let Some(cols) = known.get(name) {
+                    return Ok(Some(cols.clone()));
+                }
+                if let Some(view) = pending.get(name) {
+                    return Ok(Some(view.columns.clone()));
+                }
+                if name.is_temp_store_name()
+                    || (!as_relation && defined.contains(name))
+                    || !tx.view_exists(name)?
+                {
+                    return Ok(None);
+                }
+                let view = tx.get_view(name)?;
+                // the rows of materialized views are read from their stored relations
+                if view.materialized {
+                    return Ok(None);
+                }
+                ensure!(
+                    !defined.contains(name),
+                    ViewShadowedError(name.to_string(), name.span)
+                );
+                let cols = view.columns.clone();
+                pending.insert(name.clone(), view);
+                Ok(Some(cols))
+            };
+            for rules_or_fixed in self.prog.values_mut() {
+                match rules_or_fixed {
+                    InputInlineRulesOrFixed::Rules { rules } => {
+                        let mut convert_atom = |atom: &mut InputAtom| -> Result<()> {
+                            let new_atom = match atom {
+                                InputAtom::Rule { inner } => {
+                                    find_view(&inner.name, false)?;
+                                    return Ok(());
+                                }
+                                InputAtom::Relation { inner } => {
+                                    if find_view(&inner.name, true)?.is_none() {
+                                        return Ok(());
+                                    }
+                                    ensure!(
+                                        inner.valid_at.is_none(),
+                                        TimeTravelOnViewError(inner.name.to_string(), inner.span)
+                                    );
+                                    InputRuleApplyAtom {
+                                        name: inner.name.clone(),
+                                        args: std::mem::take(&mut inner.args),
+                                        span: inner.span,
+                                    }
+                                }
+                                InputAtom::NamedFieldRelation { inner } => {
+                                    let cols = match find_view(&inner.name, true)? {
+                                        None => return Ok(()),
+                                        Some(cols) => cols,
+                                    };
+                                    ensure!(
+                                        inner.valid_at.is_none(),
+                                        TimeTravelOnViewError(inner.name.to_string(), inner.span)
+                                    );
+                                    for k in inner.args.keys() {
+                                        ensure!(
+                                            cols.contains(k),
+                                            NamedFieldNotFound(
+                                                inner.name.to_string(),
+                                                k.to_string(),
+                                                inner.span
+                                            )
+                                        );
+                                    }
+                                    let span = inner.span;
+                                    let args = cols
+                                        .iter()
+                                        .map(|col| {
+                                            inner.args.remove(col).unwrap_or_else(|| {
+                                                Expr::Binding {
+                                                    var: Symbol::new("_", span),
+                                                    tuple_pos: None,
+                                                }
+                                            })
+                                        })
+                                        .collect();
+                                    InputRuleApplyAtom {
+                                        name: inner.name.clone(),
+                                        args,
+                                        span,
+                                    }
+                                }
+                                _ => return Ok(()),
+                            };
+                            *atom = InputAtom::Rule { inner: new_atom };
+                            Ok(())
+                        };
+                        for rule in rules {
+                            for atom in rule.body.iter_mut() {
+                                atom.visit_leaves_mut(&mut convert_atom)?;
+                            }
+                        }
+                    }
+                    InputInlineRulesOrFixed::Fixed { fixed } => {
+                        for arg in fixed.rule_args.iter_mut() {
+                            let new_arg = match arg {
+                                FixedRuleArg::InMem { name, .. } => {
+                                    find_view(name, false)?;
+                                    continue;
+                                }
+                                FixedRuleArg::Stored {
+                                    name,
+                                    bindings,
+                                    valid_at,
+                                    span,
+                                } => {
+                                    if find_view(name, true)?.is_none() {
+                                        continue;
+                                    }
+                                    ensure!(
+                                        valid_at.is_none(),
+                                        TimeTravelOnViewError(name.to_string(), *span)
+                                    );
+                                    FixedRuleArg::InMem {
+                                        name: name.clone(),
+                                        bindings: std::mem::take(bindings),
+                                        span: *span,
+                                    }
+                                }
+                                FixedRuleArg::NamedStored {
+                                    name,
+                                    bindings,
+                                    valid_at,
+                                    span,
+                                } => {
+                                    let cols = match find_view(name, true)? {
+                                        None => continue,
+                                        Some(cols) => cols,
+                                    };
+                                    ensure!(
+                                        valid_at.is_none(),
+                                        TimeTravelOnViewError(name.to_string(), *span)
+                                    );
+                                    for k in bindings.keys() {
+                                        ensure!(
+                                            cols.contains(k),
+                                            NamedFieldNotFound(
+                                                name.to_string(),
+                                                k.to_string(),
+                                                *span
+                                            )
+                                        );
+                                    }
+                                    FixedRuleArg::InMem {
+                                        name: name.clone(),
+                                        bindings: cols
+                                            .iter()
+                                            .enumerate()
+                                            .map(|(i, col)| match bindings.get(col) {
+                                                None => Symbol::new(
+                                                    SmartString::from(format!("{i}")),
+                                                    Default::default(),
+                                                ),
+                                                Some(k) => k.clone(),
+                                            })
+                                            .collect(),
+                                        span: *span,
+                                    }
+                                }
+                            };
+                            *arg = new_arg;
+                        }
+                    }
+                }
+            }
+
+            if pending.is_empty() {
+                return Ok(());
+            }
+
+            for (view_name, view) in pending {
+                let view_prog = parse_view(&view.script)?;
+                let local: BTreeSet<Symbol> = view_prog.prog.keys().cloned().collect();
+                let rename = |name: &Symbol| {
+                    if name.is_prog_entry() {
+                        Symbol::new(view_name.name.clone(), name.span)
+                    } else {
+                        Symbol::new(format!("{}.{}", view_name, name), name.span)
+                    }
+                };
+                for (name, mut rules_or_fixed) in view_prog.prog {
+                    match &mut rules_or_fixed {
+                        InputInlineRulesOrFixed::Rules { rules } => {
+                            for rule in rules {
+                                for atom in rule.body.iter_mut() {
+                                    atom.visit_leaves_mut(&mut |a| {
+                                        if let InputAtom::Rule { inner } = a {
+                                            if local.contains(&inner.name) {
+                                                inner.name = rename(&inner.name);
+                                            }
+                                        }
+                                        Ok(())
+                                    })?;
+                                }
+                            }
+                        }
+                        InputInlineRulesOrFixed::Fixed { fixed } => {
+                            for arg in fixed.rule_args.iter_mut() {
+                                if let FixedRuleArg::InMem { name, .. } = arg {
+                                    if local.contains(name) {
+                                        *name = rename(name);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    self.prog.insert(rename(&name), rules_or_fixed);
+                }
+                known.insert(view_name, view.columns);
+            }
+        }
+    }
     pub(crate) fn into_normalized_program(
         self,
         tx: &SessionTx<'_>,
@@ -663,7 +1013,7 @@ impl InputProgram {
                                 aggr: rule.aggr.clone(),
                                 body,
                             };
-                            collected_rules.push(normalized_rule.convert_to_well_ordered_rule()?);
+                            collected_rules.push(normalized_rule.convert_to_well_ordered_rule(tx)?);
                         }
                     }
                     prog.insert(
@@ -969,6 +1319,7 @@ pub(crate) struct HnswSearch {
 pub(crate) enum FtsScoreKind {
     TfIdf,
     Tf,
+    Bm25,
 }
 
 #[derive(Clone, Debug)]
@@ -978,11 +1329,21 @@ pub(crate) struct FtsSearch {
     pub(crate) manifest: FtsIndexManifest,
     pub(crate) bindings: Vec<Symbol>,
     pub(crate) k: usize,
-    // pub(crate) k1: f64,
-    // pub(crate) b: f64,
+    /// Term frequency saturation for BM25
+    pub(crate) k1: f64,
+    /// Document length normalization for BM25
+    pub(crate) b: f64,
     pub(crate) query: Symbol,
     pub(crate) score_kind: FtsScoreKind,
     pub(crate) bind_score: Option<Symbol>,
+    /// Binds the byte ranges of the tokens matching the query
+    pub(crate) bind_highlights: Option<Symbol>,
+    /// Binds an excerpt of the document around the tokens matching the query
+    pub(crate) bind_snippet: Option<Symbol>,
+    /// Inserted before and after each matching token in the snippet
+    pub(crate) snippet_markers: [SmartString<LazyCompact>; 2],
+    /// Number of tokens in the snippet
+    pub(crate) snippet_size: usize,
     // pub(crate) lax_mode: bool,
     pub(crate) filter: Option<Expr>,
     pub(crate) span: SourceSpan,
@@ -1001,7 +1362,14 @@ impl HnswSearch {
 
 impl FtsSearch {
     pub(crate) fn all_bindings(&self) -> impl Iterator<Item = &Symbol> {
-        self.bindings.iter().chain(self.bind_score.iter())
+        self.bindings
+            .iter()
+            .chain(self.bind_score.iter())
+            .chain(self.bind_highlights.iter())
+            .chain(self.bind_snippet.iter())
+    }
+    pub(crate) fn needs_highlights(&self) -> bool {
+        self.bind_highlights.is_some() || self.bind_snippet.is_some()
     }
 }
 
@@ -1150,6 +1518,198 @@ impl SearchInput {
 
         Ok(Disjunction::conj(conj))
     }
+    fn normalize_rtree(
+        mut self,
+        base_handle: RelationHandle,
+        idx_handle: RelationHandle,
+        manifest: RtreeIndexManifest,
+        gen: &mut TempSymbGen,
+    ) -> Result<Disjunction> {
+        let mut conj = Vec::with_capacity(self.bindings.len() + 8);
+        let mut bindings = Vec::with_capacity(self.bindings.len());
+        let mut seen_variables = BTreeSet::new();
+
+        for col in base_handle
+            .metadata
+            .keys
+            .iter()
+            .chain(base_handle.metadata.non_keys.iter())
+        {
+            if let Some(arg) = self.bindings.remove(&col.name) {
+                match arg {
+                    Expr::Binding { var, .. } => {
+                        if var.is_ignored_symbol() {
+                            bindings.push(gen.next_ignored(var.span));
+                        } else if seen_variables.insert(var.clone()) {
+                            bindings.push(var);
+                        } else {
+                            let span = var.span;
+                            let dup = gen.next(span);
+                            let unif = NormalFormAtom::Unification(Unification {
+                                binding: dup.clone(),
+                                expr: Expr::Binding {
+                                    var,
+                                    tuple_pos: None,
+                                },
+                                one_many_unif: false,
+                                span,
+                            });
+                            conj.push(unif);
+                            bindings.push(dup);
+                        }
+                    }
+                    expr => {
+                        let span = expr.span();
+                        let kw = gen.next(span);
+                        bindings.push(kw.clone());
+                        let unif = NormalFormAtom::Unification(Unification {
+                            binding: kw,
+                            expr,
+                            one_many_unif: false,
+                            span,
+                        });
+                        conj.push(unif)
+                    }
+                }
+            } else {
+                bindings.push(gen.next_ignored(self.span));
+            }
+        }
+
+        if let Some((name, _)) = self.bindings.pop_first() {
+            bail!(NamedFieldNotFound(
+                self.relation.name.to_string(),
+                name.to_string(),
+                self.span
+            ));
+        }
+
+        let mut inputs = vec![];
+        for param in ["bbox", "query"] {
+            inputs.push(match self.parameters.remove(param) {
+                None => None,
+                Some(Expr::Binding { var, .. }) => Some(var),
+                Some(expr) => {
+                    let span = expr.span();
+                    let kw = gen.next(span);
+                    let unif = NormalFormAtom::Unification(Unification {
+                        binding: kw.clone(),
+                        expr,
+                        one_many_unif: false,
+                        span,
+                    });
+                    conj.push(unif);
+                    Some(kw)
+                }
+            });
+        }
+        let query = inputs.pop().unwrap();
+        let bbox = inputs.pop().unwrap();
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("R-tree search requires `bbox` or `query`")]
+        #[diagnostic(code(parser::rtree_input_required))]
+        struct RtreeInputRequired(#[label] SourceSpan);
+
+        ensure!(
+            bbox.is_some() || query.is_some(),
+            RtreeInputRequired(self.span)
+        );
+
+        let k = match self.parameters.remove("k") {
+            None => None,
+            Some(k_expr) => {
+                let k = k_expr.eval_to_const()?;
+                let k = k.get_int().ok_or(ExpectedPosIntForRtreeK(self.span))?;
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Expected positive integer for `k`")]
+                #[diagnostic(code(parser::expected_int_for_rtree_k))]
+                struct ExpectedPosIntForRtreeK(#[label] SourceSpan);
+
+                ensure!(k > 0, ExpectedPosIntForRtreeK(self.span));
+                Some(k as usize)
+            }
+        };
+
+        let radius = match self.parameters.remove("radius") {
+            None => None,
+            Some(expr) => {
+                let r = expr.eval_to_const()?;
+                let r = r
+                    .get_float()
+                    .ok_or(ExpectedFloatForRtreeRadius(self.span))?;
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Expected non-negative float for `radius`")]
+                #[diagnostic(code(parser::expected_float_for_rtree_radius))]
+                struct ExpectedFloatForRtreeRadius(#[label] SourceSpan);
+
+                ensure!(r >= 0.0, ExpectedFloatForRtreeRadius(self.span));
+                Some(r)
+            }
+        };
+
+        let bind_distance = match self.parameters.remove("bind_distance") {
+            None => None,
+            Some(Expr::Binding { var, .. }) => Some(var),
+            Some(expr) => {
+                let span = expr.span();
+                let kw = gen.next(span);
+                let unif = NormalFormAtom::Unification(Unification {
+                    binding: kw.clone(),
+                    expr,
+                    one_many_unif: false,
+                    span,
+                });
+                conj.push(unif);
+                Some(kw)
+            }
+        };
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("`{0}` requires `query` for R-tree search")]
+        #[diagnostic(code(parser::rtree_query_required))]
+        struct RtreeQueryRequired(&'static str, #[label] SourceSpan);
+
+        if query.is_none() {
+            ensure!(
+                bind_distance.is_none(),
+                RtreeQueryRequired("bind_distance", self.span)
+            );
+            ensure!(radius.is_none(), RtreeQueryRequired("radius", self.span));
+        }
+
+        let filter = self.parameters.remove("filter");
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Extra parameters for R-tree search: {0:?}")]
+        #[diagnostic(code(parser::extra_parameters_for_rtree_search))]
+        struct ExtraParametersForRtreeSearch(Vec<String>, #[label] SourceSpan);
+
+        if !self.parameters.is_empty() {
+            bail!(ExtraParametersForRtreeSearch(
+                self.parameters.keys().map(|s| s.to_string()).collect(),
+                self.span
+            ));
+        }
+
+        conj.push(NormalFormAtom::RtreeSearch(RtreeSearch {
+            base_handle,
+            idx_handle,
+            manifest,
+            bindings,
+            bbox,
+            query,
+            k,
+            radius,
+            bind_distance,
+            filter,
+            span: self.span,
+        }));
+
+        Ok(Disjunction::conj(conj))
+    }
     fn normalize_fts(
         mut self,
         base_handle: RelationHandle,
@@ -1266,15 +1826,39 @@ impl SearchInput {
                 match r {
                     "tf_idf" => FtsScoreKind::TfIdf,
                     "tf" => FtsScoreKind::Tf,
+                    "bm25" => FtsScoreKind::Bm25,
                     s => bail!("Unknown score kind for FTS: {}", s),
                 }
             }
             None => FtsScoreKind::TfIdf,
         };
 
+        let mut bm25_param = |name: &str, default: f64| -> Result<f64> {
+            match self.parameters.remove(name) {
+                None => Ok(default),
+                Some(expr) => {
+                    ensure!(
+                        score_kind == FtsScoreKind::Bm25,
+                        "`{}` only applies to BM25 scoring",
+                        name
+                    );
+                    expr.eval_to_const()?
+                        .get_float()
+                        .ok_or_else(|| miette!("`{}` for FTS must be a number", name))
+                }
+            }
+        };
+        let k1 = bm25_param("k1", 1.2)?;
+        let b = bm25_param("b", 0.75)?;
+        ensure!(k1 >= 0., "`k1` for FTS must not be negative");
+        ensure!(
+            (0. ..=1.).contains(&b),
+            "`b` for FTS must be between 0 and 1"
+        );
+
         let filter = self.parameters.remove("filter");
 
-        let bind_score = match self.parameters.remove("bind_score") {
+        let mut bind_param = |name: &str| match self.parameters.remove(name) {
             None => None,
             Some(Expr::Binding { var, .. }) => Some(var),
             Some(expr) => {
@@ -1290,6 +1874,39 @@ impl SearchInput {
                 Some(kw)
             }
         };
+        let bind_score = bind_param("bind_score");
+        let bind_highlights = bind_param("bind_highlights");
+        let bind_snippet = bind_param("bind_snippet");
+
+        if bind_snippet.is_none() {
+            ensure!(
+                !self.parameters.contains_key("snippet_markers")
+                    && !self.parameters.contains_key("snippet_size"),
+                "Snippet options for FTS require `bind_snippet`"
+            );
+        }
+        let snippet_markers = match self.parameters.remove("snippet_markers") {
+            None => [SmartString::from("<b>"), SmartString::from("</b>")],
+            Some(expr) => {
+                let markers = expr.eval_to_const()?;
+                match markers.get_slice() {
+                    Some([DataValue::Str(open), DataValue::Str(close)]) => {
+                        [open.clone(), close.clone()]
+                    }
+                    _ => bail!("`snippet_markers` for FTS must be a list of two strings"),
+                }
+            }
+        };
+        let snippet_size = match self.parameters.remove("snippet_size") {
+            None => 20,
+            Some(expr) => {
+                let size = expr.eval_to_const()?;
+                match size.get_int() {
+                    Some(i) if i > 0 => i as usize,
+                    _ => bail!("`snippet_size` for FTS must be a positive integer"),
+                }
+            }
+        };
 
         if !self.parameters.is_empty() {
             bail!("Unknown parameters for FTS: {:?}", self.parameters.keys());
@@ -1304,9 +1921,13 @@ impl SearchInput {
             query,
             score_kind,
             bind_score,
+            bind_highlights,
+            bind_snippet,
+            snippet_markers,
+            snippet_size,
             // lax_mode,
-            // k1,
-            // b,
+            k1,
+            b,
             filter,
             span: self.span,
         }));
@@ -1569,6 +2190,11 @@ impl SearchInput {
         {
             return self.normalize_lsh(base_handle, idx_handle, manifest, gen);
         }
+        if let Some((idx_handle, manifest)) =
+            base_handle.rtree_indices.get(&self.index.name).cloned()
+        {
+            return self.normalize_rtree(base_handle, idx_handle, manifest, gen);
+        }
         #[derive(Debug, Error, Diagnostic)]
         #[error("Index {name} not found on relation {relation}")]
         #[diagnostic(code(eval::hnsw_index_not_found))]
@@ -1683,6 +2309,34 @@ impl InputAtom {
     //         _ => false,
     //     }
     // }
+    /// Calls `f` on every atom that is not a negation, conjunction or disjunction
+    pub(crate) fn visit_leaves(&self, f: &mut impl FnMut(&InputAtom)) {
+        match self {
+            InputAtom::Negation { inner, .. } => inner.visit_leaves(f),
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                for atom in inner {
+                    atom.visit_leaves(f);
+                }
+            }
+            atom => f(atom),
+        }
+    }
+    /// Calls `f` on every atom that is not a negation, conjunction or disjunction
+    pub(crate) fn visit_leaves_mut(
+        &mut self,
+        f: &mut impl FnMut(&mut InputAtom) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            InputAtom::Negation { inner, .. } => inner.visit_leaves_mut(f),
+            InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+                for atom in inner {
+                    atom.visit_leaves_mut(f)?;
+                }
+                Ok(())
+            }
+            atom => f(atom),
+        }
+    }
     pub(crate) fn span(&self) -> SourceSpan {
         match self {
             InputAtom::Negation { span, .. }
@@ -1709,6 +2363,7 @@ pub(crate) enum NormalFormAtom {
     HnswSearch(HnswSearch),
     FtsSearch(FtsSearch),
     LshSearch(LshSearch),
+    RtreeSearch(RtreeSearch),
 }
 
 #[derive(Debug, Clone)]
@@ -1722,6 +2377,7 @@ pub(crate) enum MagicAtom {
     HnswSearch(HnswSearch),
     FtsSearch(FtsSearch),
     LshSearch(LshSearch),
+    RtreeSearch(RtreeSearch),
 }
 
 #[derive(Clone, Debug)]
@@ -7,7 +7,9 @@
  */
 
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD;
@@ -19,7 +21,8 @@ use serde_json::json;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::expr::Expr;
+use crate::data::expr::{CustomFunction, Expr};
+use crate::data::functions::{val2decimal, val2duration, val2geometry, val2timestamp};
 use crate::data::value::{DataValue, JsonData, UuidWrapper, Validity, ValidityTs, Vector};
 use crate::Num;
 
@@ -71,6 +74,10 @@ impl Display for NullableColType {
             ColType::Json => {
                 f.write_str("Json")?;
             }
+            ColType::Timestamp => f.write_str("Timestamp")?,
+            ColType::Duration => f.write_str("Duration")?,
+            ColType::Decimal => f.write_str("Decimal")?,
+            ColType::Geometry => f.write_str("Geometry")?,
         }
         if self.nullable {
             f.write_str("?")?;
@@ -99,6 +106,10 @@ pub enum ColType {
     Tuple(Vec<NullableColType>),
     Validity,
     Json,
+    Timestamp,
+    Duration,
+    Decimal,
+    Geometry,
 }
 
 #[derive(
@@ -114,15 +125,67 @@ pub(crate) struct ColumnDef {
     pub(crate) name: SmartString<LazyCompact>,
     pub(crate) typing: NullableColType,
     pub(crate) default_gen: Option<Expr>,
+    /// Constraint evaluated against the whole row when writing, must return `true`
+    #[serde(default)]
+    pub(crate) check: Option<Expr>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
 pub(crate) struct StoredRelationMetadata {
     pub(crate) keys: Vec<ColumnDef>,
     pub(crate) non_keys: Vec<ColumnDef>,
+    #[serde(default)]
+    pub(crate) foreign_keys: Vec<ForeignKey>,
+}
+
+/// A column referencing the single key column of another stored relation
+#[derive(Debug, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub(crate) struct ForeignKey {
+    pub(crate) column: SmartString<LazyCompact>,
+    pub(crate) ref_relation: SmartString<LazyCompact>,
+    pub(crate) ref_column: SmartString<LazyCompact>,
+    pub(crate) on_delete: ForeignKeyAction,
+}
+
+/// What happens to referencing rows when the referenced row is removed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+pub(crate) enum ForeignKeyAction {
+    Restrict,
+    Cascade,
+    SetNull,
+}
+
+impl Display for ForeignKeyAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForeignKeyAction::Restrict => write!(f, "restrict"),
+            ForeignKeyAction::Cascade => write!(f, "cascade"),
+            ForeignKeyAction::SetNull => write!(f, "set null"),
+        }
+    }
 }
 
 impl StoredRelationMetadata {
+    /// Column defaults and checks are persisted with registered functions unbound,
+    /// these are resolved again before the columns are written to.
+    pub(crate) fn resolve_custom_functions(
+        &mut self,
+        functions: &BTreeMap<String, Arc<CustomFunction>>,
+    ) -> Result<()> {
+        for col in self.keys.iter_mut().chain(self.non_keys.iter_mut()) {
+            for expr in col.default_gen.iter_mut().chain(col.check.iter_mut()) {
+                expr.resolve_custom_functions(functions)?;
+            }
+        }
+        Ok(())
+    }
+    pub(crate) fn unbind_custom_functions(&mut self) {
+        for col in self.keys.iter_mut().chain(self.non_keys.iter_mut()) {
+            for expr in col.default_gen.iter_mut().chain(col.check.iter_mut()) {
+                expr.unbind_custom_functions();
+            }
+        }
+    }
     pub(crate) fn satisfied_by_required_col(&self, col: &ColumnDef) -> Result<()> {
         for target in self.keys.iter().chain(self.non_keys.iter()) {
             if target.name == col.name {
@@ -170,6 +233,21 @@ impl StoredRelationMetadata {
 }
 
 impl NullableColType {
+    /// The value of the column type that is numerically equal to a number of another type
+    /// looked up in a typed key column, so that `1` finds the key `to_decimal(1)` in a
+    /// `Decimal` column. Only the keys of stored relations are typed: rules and joins
+    /// compare values as they are, where numbers of different types are distinct keys.
+    pub(crate) fn coerce_for_lookup(&self, data: &DataValue) -> Option<DataValue> {
+        match (&self.coltype, data) {
+            (ColType::Decimal, DataValue::Num(_)) => val2decimal(data).ok().map(DataValue::Decimal),
+            (ColType::Int, DataValue::Decimal(_)) => match data.get_int() {
+                Some(i) => Some(DataValue::from(i)),
+                None => data.get_float().map(DataValue::from),
+            },
+            (ColType::Float, DataValue::Decimal(_)) => data.get_float().map(DataValue::from),
+            _ => None,
+        }
+    }
     pub(crate) fn coerce(&self, data: DataValue, cur_vld: ValidityTs) -> Result<DataValue> {
         if matches!(data, DataValue::Null) {
             return if self.nullable {
@@ -306,6 +384,10 @@ impl NullableColType {
 
                 match data {
                     vld @ DataValue::Validity(_) => vld,
+                    DataValue::Timestamp(ts) => DataValue::Validity(Validity {
+                        timestamp: ValidityTs(Reverse(ts)),
+                        is_assert: Reverse(true),
+                    }),
                     DataValue::Str(s) => match &s as &str {
                         "ASSERT" => DataValue::Validity(Validity {
                             timestamp: cur_vld,
@@ -355,6 +437,12 @@ impl NullableColType {
                     v => bail!(InvalidValidity(v)),
                 }
             }
+            ColType::Timestamp => {
+                DataValue::Timestamp(val2timestamp(&data, &chrono_tz::UTC).map_err(|_| make_err())?)
+            }
+            ColType::Duration => DataValue::Duration(val2duration(&data).map_err(|_| make_err())?),
+            ColType::Decimal => DataValue::Decimal(val2decimal(&data).map_err(|_| make_err())?),
+            ColType::Geometry => DataValue::Geometry(val2geometry(&data).map_err(|_| make_err())?),
             ColType::Json => DataValue::Json(JsonData(match data {
                 DataValue::Null => {
                     json!(null)
@@ -416,6 +504,10 @@ impl NullableColType {
                 DataValue::Validity(vld) => {
                     json!([vld.timestamp.0, vld.is_assert.0])
                 }
+                d @ (DataValue::Timestamp(_)
+                | DataValue::Duration(_)
+                | DataValue::Decimal(_)
+                | DataValue::Geometry(_)) => d.into(),
                 DataValue::Bot => {
                     json!(null)
                 }
@@ -1482,3 +1482,247 @@ fn test_range() {
         .into_json();
     assert_eq!(res["rows"][0][0], json!([15, 13, 11, 9, 7, 5]));
 }
+
+#[test]
+fn test_timestamps_and_durations() {
+    let ts = op_to_timestamp(&[DataValue::from("2023-03-26T00:30:00.25Z")]).unwrap();
+    assert_eq!(ts, DataValue::Timestamp(1_679_790_600_250_000));
+    assert_eq!(
+        op_to_timestamp(&[DataValue::from(1_679_790_600.25)]).unwrap(),
+        ts
+    );
+    assert_eq!(
+        op_to_timestamp(&[
+            DataValue::from("2023-03-26 01:30:00.25"),
+            DataValue::from("Europe/Paris")
+        ])
+        .unwrap(),
+        ts
+    );
+    // skipped when the clocks went forward
+    assert!(op_to_timestamp(&[
+        DataValue::from("2023-03-26 01:30:00"),
+        DataValue::from("Europe/London")
+    ])
+    .is_err());
+    assert!(op_to_timestamp(&[DataValue::from("yesterday")]).is_err());
+    assert!(op_is_timestamp(&[ts.clone()]).unwrap().get_bool().unwrap());
+
+    let d = op_to_duration(&[DataValue::from("1d2h30m0.5s")]).unwrap();
+    assert_eq!(d, DataValue::Duration(95_400_500_000));
+    assert_eq!(
+        op_to_duration(&[DataValue::from(-90)]).unwrap(),
+        op_to_duration(&[DataValue::from("-1m30s")]).unwrap()
+    );
+    assert!(op_to_duration(&[DataValue::from("3 weeks")]).is_err());
+    assert!(op_is_duration(&[d.clone()]).unwrap().get_bool().unwrap());
+
+    let later = op_add(&[ts.clone(), d.clone()]).unwrap();
+    assert_eq!(
+        later,
+        op_to_timestamp(&[DataValue::from("2023-03-27T03:00:00.75Z")]).unwrap()
+    );
+    assert_eq!(op_sub(&[later.clone(), ts.clone()]).unwrap(), d);
+    assert_eq!(op_sub(&[later, d.clone()]).unwrap(), ts);
+    assert_eq!(
+        op_mul(&[d.clone(), DataValue::from(2)]).unwrap(),
+        DataValue::Duration(190_801_000_000)
+    );
+    assert_eq!(
+        op_div(&[d.clone(), DataValue::from(2.)]).unwrap(),
+        DataValue::Duration(47_700_250_000)
+    );
+    assert_eq!(
+        op_div(&[d.clone(), d.clone()]).unwrap(),
+        DataValue::from(1.)
+    );
+    assert_eq!(
+        op_minus(&[d.clone()]).unwrap(),
+        DataValue::Duration(-95_400_500_000)
+    );
+    assert!(op_add(&[ts.clone(), ts.clone()]).is_err());
+    assert!(op_add(&[ts.clone(), DataValue::from(1)]).is_err());
+    assert!(
+        op_lt(&[ts.clone(), DataValue::Timestamp(1_679_790_600_250_001)])
+            .unwrap()
+            .get_bool()
+            .unwrap()
+    );
+    assert!(op_lt(&[ts.clone(), d.clone()]).is_err());
+    assert_eq!(op_to_int(&[d]).unwrap(), DataValue::from(95_400_500_000));
+
+    let trunc = |unit: &str, tz: Option<&str>| {
+        let mut args = vec![DataValue::from(unit), ts.clone()];
+        if let Some(tz) = tz {
+            args.push(DataValue::from(tz));
+        }
+        op_format_timestamp(&[op_date_trunc(&args).unwrap()])
+            .unwrap()
+            .get_str()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(trunc("year", None), "2023-01-01T00:00:00+00:00");
+    assert_eq!(trunc("quarter", None), "2023-01-01T00:00:00+00:00");
+    assert_eq!(trunc("week", None), "2023-03-20T00:00:00+00:00");
+    assert_eq!(trunc("hour", None), "2023-03-26T00:00:00+00:00");
+    // days start at 15:00 UTC in Tokyo
+    assert_eq!(
+        trunc("day", Some("Asia/Tokyo")),
+        "2023-03-25T15:00:00+00:00"
+    );
+    assert!(op_date_trunc(&[DataValue::from("fortnight"), ts.clone()]).is_err());
+
+    let extract = |field: &str, tz: Option<&str>| {
+        let mut args = vec![DataValue::from(field), ts.clone()];
+        if let Some(tz) = tz {
+            args.push(DataValue::from(tz));
+        }
+        op_extract(&args).unwrap()
+    };
+    assert_eq!(extract("year", None), DataValue::from(2023));
+    assert_eq!(extract("dow", None), DataValue::from(0));
+    assert_eq!(extract("doy", None), DataValue::from(85));
+    assert_eq!(extract("millisecond", None), DataValue::from(250));
+    assert_eq!(
+        extract("hour", Some("America/New_York")),
+        DataValue::from(20)
+    );
+    assert_eq!(
+        extract("day", Some("America/New_York")),
+        DataValue::from(25)
+    );
+    assert_eq!(extract("epoch", None), DataValue::from(1_679_790_600.25));
+    assert!(op_extract(&[DataValue::from("hour"), DataValue::from(1)]).is_err());
+}
+
+#[test]
+fn test_decimals() {
+    let d = |s: &str| op_to_decimal(&[DataValue::from(s)]).unwrap();
+    assert_eq!(
+        op_add(&[d("0.1"), d("0.2")]).unwrap(),
+        op_to_decimal(&[DataValue::from(0.3)]).unwrap()
+    );
+    assert_eq!(
+        op_sub(&[d("10.00"), DataValue::from(3)]).unwrap(),
+        d("7.00")
+    );
+    assert_eq!(
+        op_mul(&[d("1.5"), d("1.5"), DataValue::from(2)]).unwrap(),
+        d("4.5")
+    );
+    assert_eq!(op_div(&[d("1"), d("8")]).unwrap(), d("0.125"));
+    assert!(op_div(&[d("1"), DataValue::from(0)]).is_err());
+    assert_eq!(
+        op_add(&[d("0.5"), DataValue::from(0.25)]).unwrap(),
+        DataValue::from(0.75)
+    );
+    assert_eq!(op_minus(&[d("1.5")]).unwrap(), d("-1.5"));
+
+    assert_eq!(
+        op_eq(&[d("1.0"), DataValue::from(1)]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_lt(&[d("0.1"), DataValue::from(0.2)]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(op_gt(&[d("2"), d("10")]).unwrap(), DataValue::from(false));
+
+    assert_eq!(op_round(&[d("2.5")]).unwrap(), d("3"));
+    assert_eq!(op_floor(&[d("-2.5")]).unwrap(), d("-3"));
+    assert_eq!(
+        op_round_decimal(&[d("2.345"), DataValue::from(2)]).unwrap(),
+        d("2.34")
+    );
+    assert_eq!(
+        op_round_decimal(&[d("2.345"), DataValue::from(2), DataValue::from("half_up")]).unwrap(),
+        d("2.35")
+    );
+    assert_eq!(
+        op_round_decimal(&[d("-2.341"), DataValue::from(2), DataValue::from("floor")]).unwrap(),
+        d("-2.35")
+    );
+    assert!(op_round_decimal(&[d("2.345"), DataValue::from(-1)]).is_err());
+    assert!(op_round_decimal(&[d("2.345"), DataValue::from(1), DataValue::from("bad")]).is_err());
+
+    assert_eq!(op_to_int(&[d("-2.9")]).unwrap(), DataValue::from(-2));
+    assert_eq!(op_to_float(&[d("2.5")]).unwrap(), DataValue::from(2.5));
+    assert_eq!(op_to_string(&[d("2.50")]).unwrap(), DataValue::from("2.50"));
+    assert_eq!(op_is_decimal(&[d("2.5")]).unwrap(), DataValue::from(true));
+    assert_eq!(op_is_num(&[d("2.5")]).unwrap(), DataValue::from(true));
+    assert!(op_to_decimal(&[DataValue::from("abc")]).is_err());
+}
+
+#[test]
+fn test_geometry() {
+    let g = |s: &str| op_to_geometry(&[DataValue::from(s)]).unwrap();
+    let square = g("POLYGON ((0 0, 10 0, 10 10, 0 10, 0 0))");
+    assert_eq!(
+        op_st_as_text(&[g("point(1 2)")]).unwrap(),
+        DataValue::from("POINT (1 2)")
+    );
+    assert_eq!(
+        g(r#"{"type": "LineString", "coordinates": [[0, 0], [3, 4]]}"#),
+        g("LINESTRING (0 0, 3 4)")
+    );
+    assert_eq!(
+        op_st_as_geojson(&[g("POINT (1 2)")]).unwrap(),
+        DataValue::from(json!({"type": "Point", "coordinates": [1.0, 2.0]}))
+    );
+    assert_eq!(
+        op_st_point(&[DataValue::from(1), DataValue::from(2.5)]).unwrap(),
+        g("POINT (1 2.5)")
+    );
+    assert_eq!(
+        op_st_y(&[g("POINT (1 2.5)")]).unwrap(),
+        DataValue::from(2.5)
+    );
+    assert!(op_st_x(&[square.clone()]).is_err());
+    assert!(op_to_geometry(&[DataValue::from("POINT (1)")]).is_err());
+    assert!(op_to_geometry(&[DataValue::from("LINESTRING (1 1)")]).is_err());
+
+    assert_eq!(
+        op_st_contains(&[square.clone(), g("POINT (5 5)")]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_st_contains(&[square.clone(), g("LINESTRING (5 5, 15 5)")]).unwrap(),
+        DataValue::from(false)
+    );
+    assert_eq!(
+        op_st_intersects(&[square.clone(), g("LINESTRING (5 5, 15 5)")]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_st_distance(&[square.clone(), g("POINT (13 14)")]).unwrap(),
+        DataValue::from(5.0)
+    );
+    assert_eq!(
+        op_st_distance(&[square.clone(), g("POINT (3 4)")]).unwrap(),
+        DataValue::from(0.0)
+    );
+    assert_eq!(
+        op_st_bbox(&[g("LINESTRING (3 -1, 0 4)")]).unwrap(),
+        DataValue::List(vec![
+            DataValue::from(0.0),
+            DataValue::from(-1.0),
+            DataValue::from(3.0),
+            DataValue::from(4.0)
+        ])
+    );
+    let bbox = DataValue::List(vec![
+        DataValue::from(0),
+        DataValue::from(0),
+        DataValue::from(4),
+        DataValue::from(4),
+    ]);
+    assert_eq!(
+        op_st_within_bbox(&[g("LINESTRING (1 1, 3 3)"), bbox.clone()]).unwrap(),
+        DataValue::from(true)
+    );
+    assert_eq!(
+        op_st_within_bbox(&[square, bbox]).unwrap(),
+        DataValue::from(false)
+    );
+}
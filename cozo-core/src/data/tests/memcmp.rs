@@ -135,3 +135,94 @@ fn encode_decode_datavalues() {
     assert!(remaining.is_empty());
     assert_eq!(decoded, v);
 }
+
+#[test]
+fn encode_decode_times() {
+    let values = [
+        DataValue::Timestamp(i64::MIN),
+        DataValue::Timestamp(-1),
+        DataValue::Timestamp(0),
+        DataValue::Timestamp(1_700_000_000_000_000),
+        DataValue::Duration(-86_400_000_000),
+        DataValue::Duration(0),
+        DataValue::Duration(1),
+        DataValue::Duration(i64::MAX),
+    ];
+    let mut collected = vec![];
+    for v in values.iter() {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert!(remaining.is_empty());
+        assert_eq!(&decoded, v);
+        collected.push(encoder);
+    }
+    assert!(collected.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn encode_decode_decimals() {
+    let values = [
+        "-12345.678",
+        "-100",
+        "-99.5",
+        "-1",
+        "-0.0001",
+        "0",
+        "0.0001",
+        "0.1",
+        "1",
+        "1.5",
+        "9.99",
+        "10",
+        "100",
+        "12345.678",
+    ];
+    let mut collected = vec![];
+    for v in values.iter() {
+        let v = DataValue::Decimal(v.parse().unwrap());
+        let mut encoder = vec![];
+        encoder.encode_datavalue(&v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert!(remaining.is_empty());
+        assert_eq!(decoded, v);
+        collected.push(encoder);
+    }
+    assert!(collected.windows(2).all(|w| w[0] < w[1]));
+
+    // equal values with different scales have the same key
+    let mut a = vec![];
+    a.encode_datavalue(&DataValue::Decimal("1.50".parse().unwrap()));
+    let mut b = vec![];
+    b.encode_datavalue(&DataValue::Decimal("1.5".parse().unwrap()));
+    assert_eq!(a, b);
+}
+
+#[test]
+fn encode_decode_geometries() {
+    use crate::data::geo::Geometry;
+
+    let mut values = [
+        "POINT (-1.5 2)",
+        "POINT (0 0)",
+        "POINT (0 1)",
+        "POINT (3 -2)",
+        "LINESTRING (0 0, 1 1)",
+        "LINESTRING (0 0, 1 1, 2 0)",
+        "LINESTRING (0 1, 0 0)",
+        "POLYGON ((0 0, 1 0, 1 1, 0 0))",
+        "POLYGON ((0 0, 4 0, 4 4, 0 4, 0 0), (1 1, 2 1, 2 2, 1 1))",
+    ]
+    .map(|s| DataValue::Geometry(Geometry::from_wkt(s).unwrap()));
+    values.sort();
+    let mut collected = vec![];
+    for v in values.iter() {
+        let mut encoder = vec![];
+        encoder.encode_datavalue(v);
+        let (decoded, remaining) = DataValue::decode_from_key(&encoder);
+        assert!(remaining.is_empty());
+        assert_eq!(&decoded, v);
+        collected.push(encoder);
+    }
+    assert!(collected.windows(2).all(|w| w[0] < w[1]));
+}
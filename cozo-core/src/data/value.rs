@@ -8,6 +8,7 @@
 
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use ndarray::Array1;
 use std::cmp::{Ordering, Reverse};
 use std::collections::BTreeSet;
@@ -15,10 +16,13 @@ use std::fmt::{Debug, Display, Formatter};
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 
+use crate::data::geo::Geometry;
 use crate::data::json::JsonValue;
 use crate::data::relation::VecElementType;
 use ordered_float::OrderedFloat;
 use regex::Regex;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
 use serde::de::{SeqAccess, Visitor};
 use serde::ser::SerializeTuple;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -160,6 +164,14 @@ pub enum DataValue {
     Json(JsonData),
     /// validity,
     Validity(Validity),
+    /// timestamp in microseconds since the UNIX epoch, in UTC
+    Timestamp(i64),
+    /// duration in microseconds
+    Duration(i64),
+    /// exact decimal number
+    Decimal(Decimal),
+    /// point, line string or polygon
+    Geometry(Geometry),
     /// bottom type, used internally only
     Bot,
 }
@@ -599,6 +611,13 @@ impl Display for DataValue {
                 .field("timestamp", &v.timestamp.0)
                 .field("retracted", &v.is_assert)
                 .finish(),
+            DataValue::Timestamp(ts) => match micros_to_datetime(*ts) {
+                Some(dt) => write!(f, "to_timestamp({:?})", format_datetime(&dt)),
+                None => write!(f, "to_timestamp({})", *ts as f64 / 1_000_000.),
+            },
+            DataValue::Duration(d) => write!(f, "to_duration({:?})", format_duration(*d)),
+            DataValue::Decimal(d) => write!(f, "to_decimal(\"{d}\")"),
+            DataValue::Geometry(g) => write!(f, "to_geometry(\"{g}\")"),
             DataValue::Vec(a) => match a {
                 Vector::F32(a) => {
                     write!(f, "vec({:?})", a.to_vec())
@@ -644,6 +663,13 @@ impl DataValue {
     pub fn get_int(&self) -> Option<i64> {
         match self {
             DataValue::Num(n) => n.get_int(),
+            DataValue::Decimal(d) => {
+                if d.fract().is_zero() {
+                    d.to_i64()
+                } else {
+                    None
+                }
+            }
             _ => None,
         }
     }
@@ -659,6 +685,7 @@ impl DataValue {
     pub fn get_float(&self) -> Option<f64> {
         match self {
             DataValue::Num(n) => Some(n.get_float()),
+            DataValue::Decimal(d) => d.to_f64(),
             _ => None,
         }
     }
@@ -679,6 +706,120 @@ impl DataValue {
             _ => None,
         }
     }
+    /// Makes a timestamp from microseconds since the UNIX epoch,
+    /// if it falls within the range of representable dates
+    pub(crate) fn timestamp(micros: i64) -> Option<Self> {
+        micros_to_datetime(micros).map(|_| DataValue::Timestamp(micros))
+    }
+    /// Returns the date and time in UTC if this one is a Timestamp
+    pub(crate) fn get_timestamp(&self) -> Option<DateTime<Utc>> {
+        match self {
+            DataValue::Timestamp(ts) => micros_to_datetime(*ts),
+            _ => None,
+        }
+    }
+}
+
+const MICROS_PER_SEC: i64 = 1_000_000;
+
+/// Units accepted in duration strings, longest suffix first
+const DURATION_UNITS: [(&str, i64); 6] = [
+    ("d", 86_400 * MICROS_PER_SEC),
+    ("h", 3_600 * MICROS_PER_SEC),
+    ("ms", 1_000),
+    ("m", 60 * MICROS_PER_SEC),
+    ("us", 1),
+    ("s", MICROS_PER_SEC),
+];
+
+pub(crate) fn micros_to_datetime(micros: i64) -> Option<DateTime<Utc>> {
+    let secs = micros.div_euclid(MICROS_PER_SEC);
+    let nanos = micros.rem_euclid(MICROS_PER_SEC) as u32 * 1000;
+    Utc.timestamp_opt(secs, nanos).single()
+}
+
+/// Microseconds since the UNIX epoch, rounded towards the past
+pub(crate) fn datetime_to_micros<Tz: TimeZone>(dt: &DateTime<Tz>) -> i64 {
+    dt.timestamp() * MICROS_PER_SEC + (dt.timestamp_subsec_nanos() / 1000) as i64
+}
+
+/// Formats as RFC 3339 in UTC, with as many fractional digits as needed
+pub(crate) fn format_datetime<Tz: TimeZone>(dt: &DateTime<Tz>) -> String
+where
+    Tz::Offset: Display,
+{
+    dt.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// Formats a duration as in `1d2h3m4.5s`, the format accepted by [parse_duration]
+pub(crate) fn format_duration(micros: i64) -> String {
+    if micros == 0 {
+        return "0s".to_string();
+    }
+    let mut ret = String::new();
+    if micros < 0 {
+        ret.push('-');
+    }
+    let mut rest = micros.unsigned_abs();
+    for (unit, unit_micros) in [("d", 86_400), ("h", 3_600), ("m", 60)] {
+        let unit_micros = unit_micros * MICROS_PER_SEC as u64;
+        if rest >= unit_micros {
+            ret += &format!("{}{unit}", rest / unit_micros);
+            rest %= unit_micros;
+        }
+    }
+    if rest > 0 {
+        let secs = rest / MICROS_PER_SEC as u64;
+        let frac = rest % MICROS_PER_SEC as u64;
+        if frac == 0 {
+            ret += &format!("{secs}s");
+        } else {
+            let frac = format!("{frac:06}");
+            ret += &format!("{secs}.{}s", frac.trim_end_matches('0'));
+        }
+    }
+    ret
+}
+
+/// Parses a duration such as `1h30m`, `-2d`, `1.5s` or `300ms` into microseconds.
+/// The units are `d`, `h`, `m`, `s`, `ms` and `us`.
+pub(crate) fn parse_duration(s: &str) -> Option<i64> {
+    let (negative, mut rest) = match s.trim().strip_prefix('-') {
+        Some(r) => (true, r),
+        None => (false, s.trim()),
+    };
+    if rest.is_empty() {
+        return None;
+    }
+    let mut total: i128 = 0;
+    while !rest.is_empty() {
+        let num_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (num, after) = rest.split_at(num_len);
+        let (unit, unit_micros) = DURATION_UNITS
+            .iter()
+            .find(|(unit, _)| after.starts_with(unit))?;
+        let (int_part, frac_part) = num.split_once('.').unwrap_or((num, ""));
+        if int_part.is_empty() && frac_part.is_empty() {
+            return None;
+        }
+        let int_part: i128 = if int_part.is_empty() {
+            0
+        } else {
+            int_part.parse().ok()?
+        };
+        let mut amount = int_part.checked_mul(*unit_micros as i128)?;
+        if !frac_part.is_empty() {
+            let digits = frac_part.len().min(18) as u32;
+            let frac: i128 = frac_part[..digits as usize].parse().ok()?;
+            amount += frac * *unit_micros as i128 / 10i128.pow(digits);
+        }
+        total = total.checked_add(amount)?;
+        rest = &after[unit.len()..];
+    }
+    let total = if negative { -total } else { total };
+    i64::try_from(total).ok()
 }
 
 pub(crate) const LARGEST_UTF_CHAR: char = '\u{10ffff}';
@@ -89,7 +89,7 @@ impl<'a, 'b> FixedRuleInputRelation<'a, 'b> {
                 let store = self.stores.get(name).ok_or_else(|| {
                     RuleNotFoundError(name.symbol().to_string(), name.symbol().span)
                 })?;
-                Box::new(store.all_iter().map(|t| Ok(t.into_tuple())))
+                Box::new(store.all_iter().map_ok(|t| t.into_tuple()))
             }
             MagicFixedRuleRuleArg::Stored { name, valid_at, .. } => {
                 let relation = self.tx.get_relation(name, false)?;
@@ -109,7 +109,7 @@ impl<'a, 'b> FixedRuleInputRelation<'a, 'b> {
                     RuleNotFoundError(name.symbol().to_string(), name.symbol().span)
                 })?;
                 let t = vec![prefix.clone()];
-                Box::new(store.prefix_iter(&t).map(|t| Ok(t.into_tuple())))
+                Box::new(store.prefix_iter(&t).map_ok(|t| t.into_tuple()))
             }
             MagicFixedRuleRuleArg::Stored { name, valid_at, .. } => {
                 let relation = self.tx.get_relation(name, false)?;
@@ -14,6 +14,8 @@ use smartstring::{LazyCompact, SmartString};
 pub(crate) struct FtsLiteral {
     pub(crate) value: SmartString<LazyCompact>,
     pub(crate) is_prefix: bool,
+    /// Maximum edit distance of matching terms, exact matching if zero
+    pub(crate) fuzzy_distance: u32,
     pub(crate) booster: OrderedFloat<f64>,
 }
 
@@ -29,10 +31,48 @@ impl FtsLiteral {
             coll.push(FtsLiteral {
                 value: SmartString::from(&t.text),
                 is_prefix: false,
+                fuzzy_distance: self.fuzzy_distance,
                 booster: self.booster,
             })
         }
     }
+
+    /// The edit distance between the literal and the term, if the term matches
+    pub(crate) fn match_distance(&self, term: &str) -> Option<u32> {
+        if self.is_prefix {
+            term.starts_with(self.value.as_str()).then_some(0)
+        } else if self.fuzzy_distance > 0 {
+            bounded_levenshtein(&self.value, term, self.fuzzy_distance)
+        } else {
+            (term == self.value).then_some(0)
+        }
+    }
+}
+
+/// The Levenshtein distance between the strings counted in chars, if it is at most `max`
+fn bounded_levenshtein(a: &str, b: &str, max: u32) -> Option<u32> {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let max = max as usize;
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut cur = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + usize::from(ca != cb);
+            cur[j + 1] = subst.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        // every later row is at least the minimum of this one
+        if cur.iter().all(|d| *d > max) {
+            return None;
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    let distance = prev[b.len()];
+    (distance <= max).then_some(distance as u32)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -61,6 +101,21 @@ impl FtsExpr {
     //     }
     // }
 
+    /// Collects the literals that documents found by this expression may contain,
+    /// i.e. those that are not negated
+    pub(crate) fn positive_literals<'a>(&'a self, coll: &mut Vec<&'a FtsLiteral>) {
+        match self {
+            FtsExpr::Literal(l) => coll.push(l),
+            FtsExpr::Near(FtsNear { literals, .. }) => coll.extend(literals),
+            FtsExpr::And(v) | FtsExpr::Or(v) => {
+                for e in v {
+                    e.positive_literals(coll);
+                }
+            }
+            FtsExpr::Not(lhs, _) => lhs.positive_literals(coll),
+        }
+    }
+
     pub(crate) fn tokenize(self, tokenizer: &TextAnalyzer) -> Self {
         self.do_tokenize(tokenizer).flatten()
     }
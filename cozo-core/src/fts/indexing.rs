@@ -12,23 +12,26 @@ use crate::data::tuple::{decode_tuple_from_key, Tuple, ENCODED_KEY_MIN_LEN};
 use crate::data::value::LARGEST_UTF_CHAR;
 use crate::fts::ast::{FtsExpr, FtsLiteral, FtsNear};
 use crate::fts::tokenizer::TextAnalyzer;
+use crate::fts::{FtsIndexManifest, TokenizerConfig};
 use crate::parse::fts::parse_fts_query;
 use crate::runtime::relation::RelationHandle;
 use crate::runtime::transact::SessionTx;
 use crate::{DataValue, SourceSpan};
 use itertools::Itertools;
-use miette::{bail, miette, Diagnostic, Result};
+use miette::{bail, ensure, miette, Diagnostic, Result};
 use ordered_float::OrderedFloat;
 use rustc_hash::{FxHashMap, FxHashSet};
 use smartstring::{LazyCompact, SmartString};
 use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Default)]
 pub(crate) struct FtsCache {
     total_n_cache: FxHashMap<SmartString<LazyCompact>, usize>,
+    corpus_cache: FxHashMap<SmartString<LazyCompact>, CorpusStats>,
 }
 
 impl FtsCache {
@@ -44,6 +47,134 @@ impl FtsCache {
             Entry::Occupied(o) => *o.get(),
         })
     }
+    fn get_corpus_stats(
+        &mut self,
+        manifest: &FtsIndexManifest,
+        tx: &SessionTx<'_>,
+    ) -> Result<CorpusStats> {
+        Ok(match self.corpus_cache.entry(manifest.index_name.clone()) {
+            Entry::Vacant(v) => {
+                #[derive(Debug, Diagnostic, Error)]
+                #[error("FTS index {0} has no document statistics for BM25 scoring")]
+                #[diagnostic(code(eval::fts::no_bm25_stats))]
+                #[diagnostic(help("Drop and create the index again to enable BM25"))]
+                struct NoBm25Stats(String);
+
+                let stats_handle = manifest
+                    .stats_handle
+                    .as_ref()
+                    .ok_or_else(|| NoBm25Stats(manifest.index_name.to_string()))?;
+                let (n_docs, total_length) = tx.fts_corpus_totals(stats_handle)?;
+                let stats = CorpusStats {
+                    n_docs: n_docs as usize,
+                    avg_doc_len: if n_docs > 0 {
+                        total_length as f64 / n_docs as f64
+                    } else {
+                        0.
+                    },
+                };
+                v.insert(stats);
+                stats
+            }
+            Entry::Occupied(o) => *o.get(),
+        })
+    }
+}
+
+/// The tokens of a found document, marking those matching the query
+struct DocTokens {
+    /// Byte ranges of the tokens, in document order
+    ranges: Vec<(usize, usize)>,
+    matched: Vec<bool>,
+}
+
+impl DocTokens {
+    fn new(text: &str, literals: &[&FtsLiteral], tokenizer: &TextAnalyzer) -> Self {
+        let mut ranges = vec![];
+        let mut matched = vec![];
+        let mut token_stream = tokenizer.token_stream(text);
+        while let Some(token) = token_stream.next() {
+            ranges.push((token.offset_from, token.offset_to));
+            let is_match = literals
+                .iter()
+                .any(|l| l.match_distance(&token.text).is_some());
+            matched.push(is_match);
+        }
+        Self { ranges, matched }
+    }
+    fn highlights(&self) -> DataValue {
+        DataValue::List(
+            self.ranges
+                .iter()
+                .zip(self.matched.iter())
+                .filter(|(_, m)| **m)
+                .map(|((from, to), _)| {
+                    DataValue::List(vec![
+                        DataValue::from(*from as i64),
+                        DataValue::from(*to as i64),
+                    ])
+                })
+                .collect(),
+        )
+    }
+    /// The window of `snippet_size` tokens containing the most matches, with matches
+    /// surrounded by the markers
+    fn snippet(&self, text: &str, config: &FtsSearch) -> DataValue {
+        let n = self.ranges.len();
+        if n == 0 {
+            return DataValue::from("");
+        }
+        let size = config.snippet_size.min(n);
+        let mut n_matched = self.matched[..size].iter().filter(|m| **m).count();
+        let mut best = (n_matched, 0);
+        for start in 1..=(n - size) {
+            if self.matched[start - 1] {
+                n_matched -= 1;
+            }
+            if self.matched[start + size - 1] {
+                n_matched += 1;
+            }
+            if n_matched > best.0 {
+                best = (n_matched, start);
+            }
+        }
+        let start = best.1;
+        let end = start + size;
+
+        let [open, close] = &config.snippet_markers;
+        let mut ret = String::new();
+        if start > 0 {
+            ret.push_str("...");
+        }
+        let mut cursor = self.ranges[start].0;
+        for i in start..end {
+            let (from, to) = self.ranges[i];
+            // tokens may overlap when a filter produces several tokens for one word
+            if self.matched[i] && from >= cursor {
+                ret.push_str(&text[cursor..from]);
+                ret.push_str(open);
+                ret.push_str(&text[from..to]);
+                ret.push_str(close);
+                cursor = to;
+            }
+        }
+        let snippet_end = self.ranges[end - 1].1;
+        if snippet_end > cursor {
+            ret.push_str(&text[cursor..snippet_end]);
+        }
+        if end < n {
+            ret.push_str("...");
+        }
+        DataValue::from(ret)
+    }
+}
+
+/// The statistics of the documents used in scoring
+#[derive(Copy, Clone, Default)]
+struct CorpusStats {
+    n_docs: usize,
+    /// Only known for BM25 scoring
+    avg_doc_len: f64,
 }
 
 struct PositionInfo {
@@ -55,15 +186,124 @@ struct PositionInfo {
 struct LiteralStats {
     key: Tuple,
     position_info: Vec<PositionInfo>,
-    // doc_len: u32,
+    doc_len: u32,
+    /// Edit distance between the query term and the indexed term, for fuzzy matching
+    edit_distance: u32,
 }
 
 impl<'a> SessionTx<'a> {
+    /// Gets the analyzer of a text index, loading the dictionaries of filters from relations
+    pub(crate) fn get_tokenizer(
+        &self,
+        index_name: &str,
+        tokenizer: &TokenizerConfig,
+        filters: &[TokenizerConfig],
+    ) -> Result<Arc<TextAnalyzer>> {
+        if filters.iter().all(|f| f.dictionary_relation().is_none()) {
+            return self.tokenizers.get(index_name, tokenizer, filters);
+        }
+        // the dictionaries are only scanned again after they are mutated
+        let mut key = tokenizer.config_hash(filters).as_ref().to_vec();
+        let mut dict_handles = Vec::with_capacity(filters.len());
+        for filter in filters {
+            let dict_handle = match filter.dictionary_relation() {
+                None => None,
+                Some(rel_name) => {
+                    let dict_handle = self.get_relation(rel_name, false)?;
+                    key.extend_from_slice(&dict_handle.dictionary_version.to_be_bytes());
+                    Some(dict_handle)
+                }
+            };
+            dict_handles.push(dict_handle);
+        }
+        self.tokenizers
+            .get_with_dictionaries(index_name, key, tokenizer, || {
+                let mut loaded = Vec::with_capacity(filters.len());
+                for (filter, dict_handle) in filters.iter().zip(dict_handles) {
+                    let args = match dict_handle {
+                        None => filter.args.clone(),
+                        Some(dict_handle) => {
+                            let n_cols = if filter.name == "Synonyms" { 2 } else { 1 };
+                            ensure!(
+                                dict_handle.arity() >= n_cols,
+                                "relation {} for filter {} must have at least {} columns",
+                                dict_handle.name,
+                                filter.name,
+                                n_cols
+                            );
+                            let mut entries = vec![];
+                            for tuple in dict_handle.scan_all(self) {
+                                let mut tuple = tuple?;
+                                entries.push(if n_cols == 1 {
+                                    tuple.swap_remove(0)
+                                } else {
+                                    tuple.truncate(n_cols);
+                                    DataValue::List(tuple)
+                                });
+                            }
+                            vec![DataValue::List(entries)]
+                        }
+                    };
+                    loaded.push(TokenizerConfig {
+                        name: filter.name.clone(),
+                        args,
+                    });
+                }
+                Ok(loaded)
+            })
+    }
+    /// Indexed terms within the edit distance of a fuzzy literal, found by
+    /// jumping from one distinct term of the index to the next
+    fn fts_fuzzy_terms(
+        &self,
+        literal: &FtsLiteral,
+        idx_handle: &RelationHandle,
+    ) -> Result<Vec<(SmartString<LazyCompact>, u32)>> {
+        let mut ret = vec![];
+        let mut start = idx_handle.encode_partial_key_for_store(&[]);
+        let end = idx_handle.encode_partial_key_for_store(&[DataValue::Bot]);
+        loop {
+            let term = match self.store_tx.range_scan(&start, &end).next() {
+                None => break,
+                Some(item) => {
+                    let (kvec, _) = item?;
+                    let key_tuple = decode_tuple_from_key(&kvec, idx_handle.metadata.keys.len());
+                    match key_tuple.into_iter().next() {
+                        Some(DataValue::Str(s)) => s,
+                        _ => bail!("corrupted index"),
+                    }
+                }
+            };
+            if let Some(distance) = literal.match_distance(&term) {
+                ret.push((term.clone(), distance));
+            }
+            let past_term = [DataValue::Str(term), DataValue::Bot];
+            start = idx_handle.encode_partial_key_for_store(&past_term);
+        }
+        Ok(ret)
+    }
     fn fts_search_literal(
         &self,
         literal: &FtsLiteral,
         idx_handle: &RelationHandle,
     ) -> Result<Vec<LiteralStats>> {
+        if literal.fuzzy_distance > 0 {
+            let mut results = vec![];
+            for (term, distance) in self.fts_fuzzy_terms(literal, idx_handle)? {
+                let exact = FtsLiteral {
+                    value: term,
+                    is_prefix: false,
+                    fuzzy_distance: 0,
+                    booster: literal.booster,
+                };
+                for mut stats in self.fts_search_literal(&exact, idx_handle)? {
+                    stats.edit_distance = distance;
+                    results.push(stats);
+                }
+            }
+            return Ok(results);
+        }
+
         let start_key_str = &literal.value as &str;
         let start_key = vec![DataValue::Str(SmartString::from(start_key_str))];
         let mut end_key_str = literal.value.clone();
@@ -88,7 +328,7 @@ impl<'a> SessionTx<'a> {
             let froms = vals[0].get_slice().unwrap();
             let tos = vals[1].get_slice().unwrap();
             let positions = vals[2].get_slice().unwrap();
-            // let total_length = vals[3].get_int().unwrap();
+            let total_length = vals[3].get_int().unwrap();
             let position_info = froms
                 .iter()
                 .zip(tos.iter())
@@ -102,31 +342,60 @@ impl<'a> SessionTx<'a> {
             results.push(LiteralStats {
                 key: key_tuple[1..].to_vec(),
                 position_info,
-                // doc_len: total_length as u32,
+                doc_len: total_length as u32,
+                edit_distance: 0,
             });
         }
         Ok(results)
     }
+    /// The positions of the literal in each document, together with the length of the document,
+    /// merged over all the terms matched by a prefix or fuzzily
+    fn fts_search_positions(
+        &self,
+        literal: &FtsLiteral,
+        idx_handle: &RelationHandle,
+    ) -> Result<FxHashMap<Tuple, (Vec<u32>, u32)>> {
+        Ok(self
+            .fts_search_literal(literal, idx_handle)?
+            .into_iter()
+            .fold(FxHashMap::default(), |mut ret, found| {
+                let (positions, _) = ret
+                    .entry(found.key)
+                    .or_insert_with(|| (vec![], found.doc_len));
+                positions.extend(found.position_info.into_iter().map(|el| el.position));
+                ret
+            }))
+    }
     fn fts_search_impl(
         &self,
         ast: &FtsExpr,
         config: &FtsSearch,
-        n: usize,
+        corpus: &CorpusStats,
     ) -> Result<FxHashMap<Tuple, f64>> {
         Ok(match ast {
             FtsExpr::Literal(l) => {
                 let mut res = FxHashMap::default();
                 let found_docs = self.fts_search_literal(l, &config.idx_handle)?;
-                let found_docs_len = found_docs.len();
+                // the terms matched by a prefix or fuzzily may be found in the same document
+                let found_docs_len = found_docs
+                    .iter()
+                    .map(|el| &el.key)
+                    .collect::<FxHashSet<_>>()
+                    .len();
                 for el in found_docs {
+                    // terms matched fuzzily score less the more edits they are away
+                    let penalty = 1. / (1. + el.edit_distance as f64);
                     let score = Self::fts_compute_score(
                         el.position_info.len(),
+                        el.doc_len,
                         found_docs_len,
-                        n,
-                        l.booster.0,
+                        corpus,
+                        l.booster.0 * penalty,
                         config,
                     );
-                    res.insert(el.key, score);
+                    // a document may contain several of the terms matched by a prefix or fuzzily
+                    let old_score = res.entry(el.key).or_insert(score);
+                    *old_score = f64::max(*old_score, score);
                 }
                 res
             }
@@ -135,10 +404,10 @@ impl<'a> SessionTx<'a> {
                 let mut res = self.fts_search_impl(
                     l_iter.next().unwrap(),
                     config,
-                    n,
+                    corpus,
                 )?;
                 for nxt in l_iter {
-                    let nxt_res = self.fts_search_impl(nxt, config, n)?;
+                    let nxt_res = self.fts_search_impl(nxt, config, corpus)?;
                     res = res
                         .into_iter()
                         .filter_map(|(k, v)| nxt_res.get(&k).map(|nxt_v| (k, v + nxt_v)))
@@ -149,7 +418,7 @@ impl<'a> SessionTx<'a> {
             FtsExpr::Or(ls) => {
                 let mut res: FxHashMap<Tuple, f64> = FxHashMap::default();
                 for nxt in ls {
-                    let nxt_res = self.fts_search_impl(nxt, config, n)?;
+                    let nxt_res = self.fts_search_impl(nxt, config, corpus)?;
                     for (k, v) in nxt_res {
                         if let Some(old_v) = res.get_mut(&k) {
                             *old_v = (*old_v).max(v);
@@ -162,28 +431,18 @@ impl<'a> SessionTx<'a> {
             }
             FtsExpr::Near(FtsNear { literals, distance }) => {
                 let mut l_it = literals.iter();
-                let mut coll: FxHashMap<_, _> = FxHashMap::default();
-                for first_el in self.fts_search_literal(l_it.next().unwrap(), &config.idx_handle)? {
-                    coll.insert(
-                        first_el.key,
-                        first_el
-                            .position_info
-                            .into_iter()
-                            .map(|el| el.position)
-                            .collect_vec(),
-                    );
-                }
+                let mut coll =
+                    self.fts_search_positions(l_it.next().unwrap(), &config.idx_handle)?;
                 for lit_nxt in literals {
-                    let el_res = self.fts_search_literal(lit_nxt, &config.idx_handle)?;
-                    coll = el_res
+                    coll = self
+                        .fts_search_positions(lit_nxt, &config.idx_handle)?
                         .into_iter()
-                        .filter_map(|x| match coll.remove(&x.key) {
+                        .filter_map(|(key, (positions, _))| match coll.remove(&key) {
                             None => None,
-                            Some(prev_pos) => {
+                            Some((prev_pos, doc_len)) => {
                                 let mut inner_coll = FxHashSet::default();
                                 for p in prev_pos {
-                                    for pi in x.position_info.iter() {
-                                        let cur = pi.position;
+                                    for &cur in positions.iter() {
                                         if cur > p {
                                             if cur - p <= *distance {
                                                 inner_coll.insert(p);
@@ -196,7 +455,7 @@ impl<'a> SessionTx<'a> {
                                 if inner_coll.is_empty() {
                                     None
                                 } else {
-                                    Some((x.key, inner_coll.into_iter().collect_vec()))
+                                    Some((key, (inner_coll.into_iter().collect_vec(), doc_len)))
                                 }
                             }
                         })
@@ -208,18 +467,23 @@ impl<'a> SessionTx<'a> {
                 }
                 let coll_len = coll.len();
                 coll.into_iter()
-                    .map(|(k, cands)| {
-                        (
-                            k,
-                            Self::fts_compute_score(cands.len(), coll_len, n, booster, config),
-                        )
+                    .map(|(k, (cands, doc_len))| {
+                        let score = Self::fts_compute_score(
+                            cands.len(),
+                            doc_len,
+                            coll_len,
+                            corpus,
+                            booster,
+                            config,
+                        );
+                        (k, score)
                     })
                     .collect()
             }
             FtsExpr::Not(fst, snd) => {
-                let mut res = self.fts_search_impl(fst, config, n)?;
+                let mut res = self.fts_search_impl(fst, config, corpus)?;
                 for el in self
-                    .fts_search_impl(snd, config, n)?
+                    .fts_search_impl(snd, config, corpus)?
                     .keys()
                 {
                     res.remove(el);
@@ -230,26 +494,64 @@ impl<'a> SessionTx<'a> {
     }
     fn fts_compute_score(
         tf: usize,
+        doc_len: u32,
         n_found_docs: usize,
-        n_total: usize,
+        corpus: &CorpusStats,
         booster: f64,
         config: &FtsSearch,
     ) -> f64 {
         let tf = tf as f64;
+        let idf = || {
+            let n_found_docs = n_found_docs as f64;
+            (1.0 + (corpus.n_docs as f64 - n_found_docs + 0.5) / (n_found_docs + 0.5)).ln()
+        };
         match config.score_kind {
             FtsScoreKind::Tf => tf * booster,
-            FtsScoreKind::TfIdf => {
-                let n_found_docs = n_found_docs as f64;
-                let idf = (1.0 + (n_total as f64 - n_found_docs + 0.5) / (n_found_docs + 0.5)).ln();
-                tf * idf * booster
+            FtsScoreKind::TfIdf => tf * idf() * booster,
+            FtsScoreKind::Bm25 => {
+                let len_ratio = if corpus.avg_doc_len > 0. {
+                    doc_len as f64 / corpus.avg_doc_len
+                } else {
+                    1.
+                };
+                let tf_part = tf * (config.k1 + 1.)
+                    / (tf + config.k1 * (1. - config.b + config.b * len_ratio));
+                tf_part * idf() * booster
             }
         }
     }
+    /// The number of indexed documents and the sum of their lengths
+    fn fts_corpus_totals(&self, stats_handle: &RelationHandle) -> Result<(i64, i64)> {
+        Ok(match stats_handle.get(self, &[])? {
+            None => (0, 0),
+            Some(tuple) => (tuple[0].get_int().unwrap(), tuple[1].get_int().unwrap()),
+        })
+    }
+    fn fts_update_corpus_totals(
+        &mut self,
+        manifest: &FtsIndexManifest,
+        n_docs_delta: i64,
+        length_delta: i64,
+    ) -> Result<()> {
+        let stats_handle = match &manifest.stats_handle {
+            None => return Ok(()),
+            Some(h) => h,
+        };
+        let (n_docs, total_length) = self.fts_corpus_totals(stats_handle)?;
+        let tuple = vec![
+            DataValue::from(n_docs + n_docs_delta),
+            DataValue::from(total_length + length_delta),
+        ];
+        let key = stats_handle.encode_key_for_store(&tuple, Default::default())?;
+        let val = stats_handle.encode_val_for_store(&tuple, Default::default())?;
+        self.store_tx.put(&key, &val)
+    }
     pub(crate) fn fts_search(
         &self,
         q: &str,
         config: &FtsSearch,
         filter_code: &Option<(Vec<Bytecode>, SourceSpan)>,
+        extractor_code: &Option<Vec<Bytecode>>,
         tokenizer: &TextAnalyzer,
         stack: &mut Vec<DataValue>,
         cache: &mut FtsCache,
@@ -258,13 +560,18 @@ impl<'a> SessionTx<'a> {
         if ast.is_empty() {
             return Ok(vec![]);
         }
-        let n = if config.score_kind == FtsScoreKind::TfIdf {
-            cache.get_n_for_relation(&config.base_handle, self)?
-        } else {
-            0
+        let mut literals = vec![];
+        ast.positive_literals(&mut literals);
+        let corpus = match config.score_kind {
+            FtsScoreKind::Tf => CorpusStats::default(),
+            FtsScoreKind::TfIdf => CorpusStats {
+                n_docs: cache.get_n_for_relation(&config.base_handle, self)?,
+                avg_doc_len: 0.,
+            },
+            FtsScoreKind::Bm25 => cache.get_corpus_stats(&config.manifest, self)?,
         };
         let mut result: Vec<_> = self
-            .fts_search_impl(&ast, config, n)?
+            .fts_search_impl(&ast, config, &corpus)?
             .into_iter()
             .collect();
         result.sort_by_key(|(_, score)| Reverse(OrderedFloat(*score)));
@@ -279,10 +586,34 @@ impl<'a> SessionTx<'a> {
                 .get(self, &found_key)?
                 .ok_or_else(|| miette!("corrupted index"))?;
 
+            let doc = match extractor_code {
+                None => None,
+                Some(code) => match eval_bytecode(code, &cand_tuple, stack)? {
+                    DataValue::Str(s) => Some(s),
+                    _ => None,
+                },
+            };
+
             if config.bind_score.is_some() {
                 cand_tuple.push(DataValue::from(score));
             }
 
+            if config.needs_highlights() {
+                let (highlights, snippet) = match &doc {
+                    None => (DataValue::Null, DataValue::Null),
+                    Some(text) => {
+                        let tokens = DocTokens::new(text, &literals, tokenizer);
+                        (tokens.highlights(), tokens.snippet(text, config))
+                    }
+                };
+                if config.bind_highlights.is_some() {
+                    cand_tuple.push(highlights);
+                }
+                if config.bind_snippet.is_some() {
+                    cand_tuple.push(snippet);
+                }
+            }
+
             if let Some((code, span)) = filter_code {
                 if !eval_bytecode_pred(code, &cand_tuple, stack, *span)? {
                     continue;
@@ -304,6 +635,7 @@ impl<'a> SessionTx<'a> {
         tokenizer: &TextAnalyzer,
         rel_handle: &RelationHandle,
         idx_handle: &RelationHandle,
+        manifest: &FtsIndexManifest,
     ) -> Result<()> {
         let to_index = match eval_bytecode(extractor, tuple, stack)? {
             DataValue::Null => return Ok(()),
@@ -348,6 +680,10 @@ impl<'a> SessionTx<'a> {
             let val_bytes = idx_handle.encode_val_only_for_store(&val, Default::default())?;
             self.store_tx.put(&key_bytes, &val_bytes)?;
         }
+        // documents without any token cannot be found, and are left out of the statistics
+        if count > 0 {
+            self.fts_update_corpus_totals(manifest, 1, count)?;
+        }
         Ok(())
     }
     pub(crate) fn del_fts_index_item(
@@ -358,6 +694,7 @@ impl<'a> SessionTx<'a> {
         tokenizer: &TextAnalyzer,
         rel_handle: &RelationHandle,
         idx_handle: &RelationHandle,
+        manifest: &FtsIndexManifest,
     ) -> Result<()> {
         let to_index = match eval_bytecode(extractor, tuple, stack)? {
             DataValue::Null => return Ok(()),
@@ -373,10 +710,13 @@ impl<'a> SessionTx<'a> {
         };
         let mut token_stream = tokenizer.token_stream(&to_index);
         let mut collector = FxHashSet::default();
+        let mut count = 0i64;
         while let Some(token) = token_stream.next() {
             let text = SmartString::<LazyCompact>::from(&token.text);
             collector.insert(text);
+            count += 1;
         }
+        let mut was_indexed = false;
         let mut key = Vec::with_capacity(1 + rel_handle.metadata.keys.len());
         key.push(DataValue::Bot);
         for k in &tuple[..rel_handle.metadata.keys.len()] {
@@ -385,8 +725,14 @@ impl<'a> SessionTx<'a> {
         for text in collector {
             key[0] = DataValue::Str(text);
             let key_bytes = idx_handle.encode_key_for_store(&key, Default::default())?;
+            if !was_indexed {
+                was_indexed = self.store_tx.exists(&key_bytes, false)?;
+            }
             self.store_tx.del(&key_bytes)?;
         }
+        if was_indexed {
+            self.fts_update_corpus_totals(manifest, -1, -count)?;
+        }
         Ok(())
     }
 }
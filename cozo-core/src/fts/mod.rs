@@ -11,8 +11,9 @@ use crate::fts::cangjie::tokenizer::CangJieTokenizer;
 use crate::fts::tokenizer::{
     AlphaNumOnlyFilter, AsciiFoldingFilter, BoxTokenFilter, Language, LowerCaser, NgramTokenizer,
     RawTokenizer, RemoveLongFilter, SimpleTokenizer, SplitCompoundWords, Stemmer, StopWordFilter,
-    TextAnalyzer, Tokenizer, WhitespaceTokenizer,
+    SynonymFilter, TextAnalyzer, Tokenizer, WhitespaceTokenizer,
 };
+use crate::runtime::relation::RelationHandle;
 use crate::DataValue;
 use jieba_rs::Jieba;
 use miette::{bail, ensure, miette, Result};
@@ -34,6 +35,10 @@ pub(crate) struct FtsIndexManifest {
     pub(crate) extractor: String,
     pub(crate) tokenizer: TokenizerConfig,
     pub(crate) filters: Vec<TokenizerConfig>,
+    /// Holds the number of indexed documents and their total length, for BM25 scoring.
+    /// Indices created before BM25 was supported do not have it.
+    #[serde(default)]
+    pub(crate) stats_handle: Option<Box<RelationHandle>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, serde_derive::Serialize, serde_derive::Deserialize)]
@@ -62,6 +67,17 @@ impl TokenizerConfig {
         }
         hasher.finalize_fixed()
     }
+    /// The stored relation holding the dictionary of the filter, given as `'*relation'`
+    /// in place of the list of stopwords or synonyms
+    pub(crate) fn dictionary_relation(&self) -> Option<&str> {
+        match &self.name as &str {
+            "Stopwords" | "Synonyms" => match self.args.first() {
+                Some(DataValue::Str(s)) => s.strip_prefix('*'),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
     pub(crate) fn build(&self, filters: &[Self]) -> Result<TextAnalyzer> {
         let tokenizer = self.construct_tokenizer()?;
         let token_filters = filters
@@ -229,6 +245,26 @@ impl TokenizerConfig {
                     _ => bail!("Filter Stopwords requires language name or a list of stopwords"),
                 }
             }
+            "Synonyms" => {
+                let mut synonyms = Vec::new();
+                match self.args.first() {
+                    Some(DataValue::List(l)) => {
+                        for v in l {
+                            match v.get_slice() {
+                                Some([DataValue::Str(word), DataValue::Str(synonym)]) => {
+                                    synonyms.push((word.to_string(), synonym.to_string()))
+                                }
+                                _ => bail!(
+                                    "Synonyms must be pairs of a word and its replacement, got {}",
+                                    v
+                                ),
+                            }
+                        }
+                    }
+                    _ => bail!("Filter Synonyms requires a list of synonyms or a relation"),
+                }
+                SynonymFilter::new(synonyms).into()
+            }
             _ => bail!("Unknown token filter: {:?}", self.name),
         })
     }
@@ -247,6 +283,9 @@ pub(crate) struct FtsIndexConfig {
 pub(crate) struct TokenizerCache {
     named_cache: RwLock<HashMap<SmartString<LazyCompact>, Arc<TextAnalyzer>>>,
     hashed_cache: RwLock<HashMap<Vec<u8>, Arc<TextAnalyzer>>>,
+    /// Analyzers of the indices with filters reading their dictionaries from relations,
+    /// with the keys of the configuration and the versions of the dictionaries they were built from
+    dictionary_cache: RwLock<HashMap<SmartString<LazyCompact>, (Vec<u8>, Arc<TextAnalyzer>)>>,
 }
 
 impl TokenizerCache {
@@ -280,4 +319,27 @@ impl TokenizerCache {
             Ok(analyzer)
         }
     }
+    /// Unlike `get`, rebuilds the analyzer of the index whenever `key` changes,
+    /// replacing the one built from the previous versions of the dictionaries.
+    /// The filters with the dictionaries filled in are only loaded when rebuilding.
+    pub(crate) fn get_with_dictionaries(
+        &self,
+        tokenizer_name: &str,
+        key: Vec<u8>,
+        tokenizer: &TokenizerConfig,
+        load_filters: impl FnOnce() -> Result<Vec<TokenizerConfig>>,
+    ) -> Result<Arc<TextAnalyzer>> {
+        {
+            let dict_cache = self.dictionary_cache.read().unwrap();
+            if let Some((cached_key, analyzer)) = dict_cache.get(tokenizer_name) {
+                if *cached_key == key {
+                    return Ok(analyzer.clone());
+                }
+            }
+        }
+        let analyzer = Arc::new(tokenizer.build(&load_filters()?)?);
+        let mut dict_cache = self.dictionary_cache.write().unwrap();
+        dict_cache.insert(tokenizer_name.into(), (key, analyzer.clone()));
+        Ok(analyzer)
+    }
 }
@@ -134,6 +134,7 @@ mod simple_tokenizer;
 mod split_compound_words;
 mod stemmer;
 mod stop_word_filter;
+mod synonym_filter;
 mod tokenized_string;
 mod tokenizer_impl;
 mod whitespace_tokenizer;
@@ -148,6 +149,7 @@ pub(crate) use self::simple_tokenizer::SimpleTokenizer;
 pub(crate) use self::split_compound_words::SplitCompoundWords;
 pub(crate) use self::stemmer::{Language, Stemmer};
 pub(crate) use self::stop_word_filter::StopWordFilter;
+pub(crate) use self::synonym_filter::SynonymFilter;
 // pub(crate) use self::tokenized_string::{PreTokenizedStream, PreTokenizedString};
 pub(crate) use self::tokenizer_impl::{
     BoxTokenFilter, BoxTokenStream, TextAnalyzer, Token, TokenFilter, TokenStream, Tokenizer,
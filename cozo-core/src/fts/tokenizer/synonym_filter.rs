@@ -0,0 +1,100 @@
+//! # Example
+//! ```text
+//! let tokenizer = TextAnalyzer::from(SimpleTokenizer).filter(SynonymFilter::new(vec![(
+//!     "automobile".to_string(),
+//!     "car".to_string(),
+//! )]));
+//!
+//! let mut stream = tokenizer.token_stream("red automobile");
+//! assert_eq!(stream.next().unwrap().text, "red");
+//! assert_eq!(stream.next().unwrap().text, "car");
+//! assert!(stream.next().is_none());
+//! ```
+use std::sync::Arc;
+
+use rustc_hash::FxHashMap;
+
+use super::{BoxTokenStream, Token, TokenFilter, TokenStream};
+
+/// `TokenFilter` that replaces each word having a synonym by the synonym,
+/// so that all words of a group of synonyms are indexed and searched as one
+#[derive(Clone)]
+pub(crate) struct SynonymFilter {
+    synonyms: Arc<FxHashMap<String, String>>,
+}
+
+impl SynonymFilter {
+    /// Creates a `SynonymFilter` given pairs of words and their replacements
+    pub(crate) fn new<W: IntoIterator<Item = (String, String)>>(synonyms: W) -> SynonymFilter {
+        SynonymFilter {
+            synonyms: Arc::new(synonyms.into_iter().collect()),
+        }
+    }
+}
+
+pub(crate) struct SynonymFilterStream<'a> {
+    synonyms: Arc<FxHashMap<String, String>>,
+    tail: BoxTokenStream<'a>,
+}
+
+impl TokenFilter for SynonymFilter {
+    fn transform<'a>(&self, token_stream: BoxTokenStream<'a>) -> BoxTokenStream<'a> {
+        BoxTokenStream::from(SynonymFilterStream {
+            synonyms: self.synonyms.clone(),
+            tail: token_stream,
+        })
+    }
+}
+
+impl<'a> TokenStream for SynonymFilterStream<'a> {
+    fn advance(&mut self) -> bool {
+        if !self.tail.advance() {
+            return false;
+        }
+        if let Some(synonym) = self.synonyms.get(&self.tail.token().text) {
+            let token = self.tail.token_mut();
+            token.text.clear();
+            token.text.push_str(synonym);
+        }
+        true
+    }
+
+    fn token(&self) -> &Token {
+        self.tail.token()
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.tail.token_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fts::tokenizer::tests::assert_token;
+    use crate::fts::tokenizer::{SimpleTokenizer, SynonymFilter, TextAnalyzer, Token};
+
+    #[test]
+    fn test_synonyms() {
+        let tokens = token_stream_helper("a fast auto and a quick car");
+        assert_eq!(tokens.len(), 7);
+        assert_token(&tokens[1], 1, "quick", 2, 6);
+        assert_token(&tokens[2], 2, "car", 7, 11);
+        assert_token(&tokens[5], 5, "quick", 18, 23);
+        assert_token(&tokens[6], 6, "car", 24, 27);
+    }
+
+    fn token_stream_helper(text: &str) -> Vec<Token> {
+        let synonyms = vec![
+            ("fast".to_string(), "quick".to_string()),
+            ("auto".to_string(), "car".to_string()),
+        ];
+        let a = TextAnalyzer::from(SimpleTokenizer).filter(SynonymFilter::new(synonyms));
+        let mut token_stream = a.token_stream(text);
+        let mut tokens: Vec<Token> = vec![];
+        let mut add_token = |token: &Token| {
+            tokens.push(token.clone());
+        };
+        token_stream.process(&mut add_token);
+        tokens
+    }
+}
@@ -46,12 +46,14 @@ use miette::{
     bail, miette, GraphicalReportHandler, GraphicalTheme, IntoDiagnostic, JSONReportHandler,
     Result, ThemeCharacters, ThemeStyles,
 };
+pub use rust_decimal::Decimal;
 use serde_json::json;
 
 pub use data::value::{DataValue, Num, RegexWrapper, UuidWrapper, Validity, ValidityTs};
 pub use fixed_rule::{FixedRule, FixedRuleInputRelation, FixedRulePayload};
 pub use runtime::db::Db;
 pub use runtime::db::NamedRows;
+pub use runtime::prepared::PreparedQuery;
 pub use runtime::relation::decode_tuple_from_kv;
 pub use runtime::temp_store::RegularTempStore;
 pub use storage::mem::{new_cozo_mem, MemStorage};
@@ -65,7 +67,9 @@ pub use storage::sqlite::{new_cozo_sqlite, SqliteStorage};
 pub use storage::tikv::{new_cozo_tikv, TiKvStorage};
 pub use storage::{Storage, StoreTx};
 
+pub use crate::data::aggr::{CustomAggregation, MeetAggrObj, NormalAggrObj};
 pub use crate::data::expr::Expr;
+pub use crate::data::geo::{GeoCoord, Geometry};
 use crate::data::json::JsonValue;
 pub use crate::data::symb::Symbol;
 pub use crate::data::value::{Vector, JsonData};
@@ -73,6 +77,7 @@ pub use crate::fixed_rule::SimpleFixedRule;
 pub use crate::parse::SourceSpan;
 pub use crate::runtime::callback::CallbackOp;
 pub use crate::runtime::db::Poison;
+pub use crate::runtime::db::QueryLimits;
 pub use crate::runtime::db::TransactionPayload;
 
 pub(crate) mod data;
@@ -179,6 +184,21 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.run_script(payload, params),
         }
     }
+    /// Prepare a query for repeated execution with different parameters.
+    /// See [crate::Db::prepare].
+    pub fn prepare(&self, payload: &str) -> Result<PreparedQueryInstance> {
+        Ok(match self {
+            DbInstance::Mem(db) => PreparedQueryInstance::Mem(db.prepare(payload)?),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => PreparedQueryInstance::Sqlite(db.prepare(payload)?),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => PreparedQueryInstance::RocksDb(db.prepare(payload)?),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => PreparedQueryInstance::Sled(db.prepare(payload)?),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => PreparedQueryInstance::TiKv(db.prepare(payload)?),
+        })
+    }
     /// Run the CozoScript passed in. The `params` argument is a map of parameters.
     /// Fold any error into the return JSON itself.
     /// See [crate::Db::run_script].
@@ -425,6 +445,34 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.unregister_callback(id),
         }
     }
+    /// Dispatcher method. See [crate::Db::set_temp_store_memory_budget].
+    pub fn set_temp_store_memory_budget(&self, bytes: Option<usize>) {
+        match self {
+            DbInstance::Mem(db) => db.set_temp_store_memory_budget(bytes),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_temp_store_memory_budget(bytes),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_temp_store_memory_budget(bytes),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_temp_store_memory_budget(bytes),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_temp_store_memory_budget(bytes),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::set_default_query_limits].
+    pub fn set_default_query_limits(&self, limits: QueryLimits) {
+        match self {
+            DbInstance::Mem(db) => db.set_default_query_limits(limits),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.set_default_query_limits(limits),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.set_default_query_limits(limits),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.set_default_query_limits(limits),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.set_default_query_limits(limits),
+        }
+    }
     /// Dispatcher method. See [crate::Db::register_fixed_rule].
     pub fn register_fixed_rule<R>(&self, name: String, rule_impl: R) -> Result<()>
     where
@@ -442,6 +490,68 @@ impl DbInstance {
             DbInstance::TiKv(db) => db.register_fixed_rule(name, rule_impl),
         }
     }
+    /// Dispatcher method. See [crate::Db::register_function].
+    pub fn register_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+    where
+        F: Fn(&[DataValue]) -> Result<DataValue> + Send + Sync + 'static,
+    {
+        match self {
+            DbInstance::Mem(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.register_function(name, arity, func),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.register_function(name, arity, func),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::unregister_function]
+    pub fn unregister_function(&self, name: &str) -> Result<bool> {
+        match self {
+            DbInstance::Mem(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.unregister_function(name),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.unregister_function(name),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::register_aggregation].
+    pub fn register_aggregation<A>(&self, name: String, aggr_impl: A) -> Result<()>
+    where
+        A: CustomAggregation + 'static,
+    {
+        match self {
+            DbInstance::Mem(db) => db.register_aggregation(name, aggr_impl),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.register_aggregation(name, aggr_impl),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.register_aggregation(name, aggr_impl),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.register_aggregation(name, aggr_impl),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.register_aggregation(name, aggr_impl),
+        }
+    }
+    /// Dispatcher method. See [crate::Db::unregister_aggregation]
+    pub fn unregister_aggregation(&self, name: &str) -> Result<bool> {
+        match self {
+            DbInstance::Mem(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.unregister_aggregation(name),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.unregister_aggregation(name),
+        }
+    }
     /// Dispatcher method. See [crate::Db::unregister_fixed_rule]
     pub fn unregister_fixed_rule(&self, name: &str) -> Result<bool> {
         match self {
@@ -488,6 +598,87 @@ impl DbInstance {
             receiver: db2app_recv,
         }
     }
+    /// Dispatcher method. See [crate::Db::run_script_streaming]
+    pub fn run_script_streaming(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        chunk_size: usize,
+        results: Sender<Result<NamedRows>>,
+    ) {
+        match self {
+            DbInstance::Mem(db) => db.run_script_streaming(payload, params, chunk_size, results),
+            #[cfg(feature = "storage-sqlite")]
+            DbInstance::Sqlite(db) => db.run_script_streaming(payload, params, chunk_size, results),
+            #[cfg(feature = "storage-rocksdb")]
+            DbInstance::RocksDb(db) => {
+                db.run_script_streaming(payload, params, chunk_size, results)
+            }
+            #[cfg(feature = "storage-sled")]
+            DbInstance::Sled(db) => db.run_script_streaming(payload, params, chunk_size, results),
+            #[cfg(feature = "storage-tikv")]
+            DbInstance::TiKv(db) => db.run_script_streaming(payload, params, chunk_size, results),
+        }
+    }
+    /// A higher-level, blocking wrapper for [crate::Db::run_script_streaming]. Runs the script on a dedicated thread.
+    /// Returns when the first chunk of rows is available, or with the error if the script fails before that.
+    pub fn run_script_iter(
+        &self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        chunk_size: usize,
+    ) -> Result<NamedRowsIter> {
+        let (sender, receiver) = bounded(1);
+        let db = self.clone();
+        let payload = payload.to_string();
+        thread::spawn(move || db.run_script_streaming(&payload, params, chunk_size, sender));
+        let first = match receiver.recv() {
+            Ok(r) => r?,
+            Err(err) => bail!(err),
+        };
+        Ok(NamedRowsIter {
+            headers: first.headers,
+            rows: first.rows.into_iter(),
+            chunk_taken: false,
+            receiver,
+        })
+    }
+}
+
+/// A prepared query on a [DbInstance], see [crate::PreparedQuery].
+pub enum PreparedQueryInstance {
+    /// On in memory storage
+    Mem(PreparedQuery<MemStorage>),
+    #[cfg(feature = "storage-sqlite")]
+    /// On Sqlite storage
+    Sqlite(PreparedQuery<SqliteStorage>),
+    #[cfg(feature = "storage-rocksdb")]
+    /// On RocksDB storage
+    RocksDb(PreparedQuery<RocksDbStorage>),
+    #[cfg(feature = "storage-sled")]
+    /// On Sled storage
+    Sled(PreparedQuery<SledStorage>),
+    #[cfg(feature = "storage-tikv")]
+    /// On TiKV storage
+    TiKv(PreparedQuery<TiKvStorage>),
+}
+
+impl PreparedQueryInstance {
+    /// Execute the prepared query with the given parameters.
+    /// See [crate::PreparedQuery::execute].
+    pub fn execute(&self, params: BTreeMap<String, DataValue>) -> Result<NamedRows> {
+        match self {
+            PreparedQueryInstance::Mem(q) => q.execute(params),
+            #[cfg(feature = "storage-sqlite")]
+            PreparedQueryInstance::Sqlite(q) => q.execute(params),
+            #[cfg(feature = "storage-rocksdb")]
+            PreparedQueryInstance::RocksDb(q) => q.execute(params),
+            #[cfg(feature = "storage-sled")]
+            PreparedQueryInstance::Sled(q) => q.execute(params),
+            #[cfg(feature = "storage-tikv")]
+            PreparedQueryInstance::TiKv(q) => q.execute(params),
+        }
+    }
 }
 
 /// A multi-transaction handle.
@@ -539,6 +730,50 @@ impl MultiTransaction {
     }
 }
 
+/// Rows of a script run by [DbInstance::run_script_iter], received in chunks as they are produced.
+/// Dropping the iterator stops the evaluation.
+pub struct NamedRowsIter {
+    /// The headers
+    pub headers: Vec<String>,
+    rows: std::vec::IntoIter<Vec<DataValue>>,
+    chunk_taken: bool,
+    receiver: Receiver<Result<NamedRows>>,
+}
+
+impl NamedRowsIter {
+    /// Returns the rows of the current chunk not yet iterated over,
+    /// or the next chunk if there are none.
+    /// The first call always returns the current chunk, which is empty if there are no results.
+    pub fn next_chunk(&mut self) -> Option<Result<Vec<Vec<DataValue>>>> {
+        if !self.chunk_taken || !self.rows.as_slice().is_empty() {
+            self.chunk_taken = true;
+            return Some(Ok(self.rows.by_ref().collect()));
+        }
+        match self.receiver.recv() {
+            Ok(Ok(chunk)) => Some(Ok(chunk.rows)),
+            Ok(Err(err)) => Some(Err(err)),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Iterator for NamedRowsIter {
+    type Item = Result<Vec<DataValue>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+            match self.receiver.recv() {
+                Ok(Ok(chunk)) => self.rows = chunk.rows.into_iter(),
+                Ok(Err(err)) => return Some(Err(err)),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
 /// Convert error raised by the database into friendly JSON format
 pub fn format_error_as_json(mut err: Report, source: Option<&str>) -> JsonValue {
     if err.source_code().is_none() {
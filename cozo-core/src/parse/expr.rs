@@ -6,8 +6,6 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::BTreeMap;
-
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use miette::{bail, ensure, Diagnostic, Result};
@@ -22,7 +20,7 @@ use crate::data::functions::{
 };
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
-use crate::parse::{ExtractSpan, Pair, Rule, SourceSpan};
+use crate::parse::{ExtractSpan, Pair, ParseEnv, Rule, SourceSpan};
 
 lazy_static! {
     static ref PRATT_PARSER: PrattParser<Rule> = {
@@ -105,6 +103,22 @@ pub(crate) fn expr2bytecode(expr: &Expr, collector: &mut Vec<Bytecode>) -> Resul
                 }
             }
         }
+        Expr::CustomApply { op, args, span } => {
+            let arity = args.len();
+            for arg in args.iter() {
+                expr2bytecode(arg, collector)?;
+            }
+            collector.push(Bytecode::CustomApply {
+                op: op.clone(),
+                arity,
+                span: *span,
+            })
+        }
+        Expr::Param { name, cell, span } => collector.push(Bytecode::Param {
+            name: name.clone(),
+            cell: cell.clone(),
+            span: *span,
+        }),
         Expr::UnboundApply { op, span, .. } => {
             bail!(NoImplementationError(*span, op.to_string()));
         }
@@ -112,14 +126,14 @@ pub(crate) fn expr2bytecode(expr: &Expr, collector: &mut Vec<Bytecode>) -> Resul
     Ok(())
 }
 
-pub(crate) fn build_expr(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Expr> {
+pub(crate) fn build_expr(pair: Pair<'_>, env: &ParseEnv<'_>) -> Result<Expr> {
     ensure!(
         pair.as_rule() == Rule::expr,
         InvalidExpression(pair.extract_span())
     );
 
     PRATT_PARSER
-        .map_primary(|v| build_term(v, param_pool))
+        .map_primary(|v| build_term(v, env))
         .map_infix(build_expr_infix)
         .map_prefix(|op, rhs| {
             let rhs = rhs?;
@@ -172,7 +186,7 @@ fn build_expr_infix(lhs: Result<Expr>, op: Pair<'_>, rhs: Result<Expr>) -> Resul
     })
 }
 
-fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Result<Expr> {
+fn build_term(pair: Pair<'_>, env: &ParseEnv<'_>) -> Result<Expr> {
     let span = pair.extract_span();
     let op = pair.as_rule();
     Ok(match op {
@@ -187,8 +201,16 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
             struct ParamNotFoundError(String, #[label] SourceSpan);
 
             let param_str = pair.as_str().strip_prefix('$').unwrap();
+            if let Some(slots) = env.prepared {
+                return Ok(Expr::Param {
+                    name: SmartString::from(param_str),
+                    cell: slots.cell(param_str, env.params.get(param_str)),
+                    span,
+                });
+            }
             Expr::Const {
-                val: param_pool
+                val: env
+                    .params
                     .get(param_str)
                     .ok_or_else(|| ParamNotFoundError(param_str.to_string(), span))?
                     .clone(),
@@ -266,7 +288,7 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
         Rule::list => {
             let mut collected = vec![];
             for p in pair.into_inner() {
-                collected.push(build_expr(p, param_pool)?)
+                collected.push(build_expr(p, env)?)
             }
             Expr::Apply {
                 op: &OP_LIST,
@@ -280,8 +302,8 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 let mut p = p.into_inner();
                 let k = p.next().unwrap();
                 let v = p.next().unwrap();
-                let k = build_expr(k, param_pool)?;
-                let v = build_expr(v, param_pool)?;
+                let k = build_expr(k, env)?;
+                let v = build_expr(v, env)?;
                 args.push(k);
                 args.push(v);
             }
@@ -299,8 +321,13 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|v| build_expr(v, param_pool))
+                .map(|v| build_expr(v, env))
                 .try_collect()?;
+            #[derive(Error, Diagnostic, Debug)]
+            #[error("Wrong number of arguments for function '{0}'")]
+            #[diagnostic(code(parser::func_wrong_num_args))]
+            struct WrongNumArgsError(String, #[label] SourceSpan, #[help] String);
+
             #[derive(Error, Diagnostic, Debug)]
             #[error("Named function '{0}' not found")]
             #[diagnostic(code(parser::func_not_function))]
@@ -376,17 +403,30 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                     Expr::Cond { clauses, span }
                 }
                 _ => match get_op(ident) {
-                    None => Expr::UnboundApply {
-                        op: ident.into(),
-                        args: args.into(),
-                        span,
+                    None => match env.functions.get(ident) {
+                        None => Expr::UnboundApply {
+                            op: ident.into(),
+                            args: args.into(),
+                            span,
+                        },
+                        Some(op) => {
+                            ensure!(
+                                op.arity == args.len(),
+                                WrongNumArgsError(
+                                    ident.to_string(),
+                                    span,
+                                    format!("Need exactly {} argument(s)", op.arity)
+                                )
+                            );
+                            Expr::CustomApply {
+                                op: op.clone(),
+                                args: args.into(),
+                                span,
+                            }
+                        }
                     },
                     Some(op) => {
                         op.post_process_args(&mut args);
-                        #[derive(Error, Diagnostic, Debug)]
-                        #[error("Wrong number of arguments for function '{0}'")]
-                        #[diagnostic(code(parser::func_wrong_num_args))]
-                        struct WrongNumArgsError(String, #[label] SourceSpan, #[help] String);
 
                         if op.vararg {
                             ensure!(
@@ -416,7 +456,7 @@ fn build_term(pair: Pair<'_>, param_pool: &BTreeMap<String, DataValue>) -> Resul
                 },
             }
         }
-        Rule::grouping => build_expr(pair.into_inner().next().unwrap(), param_pool)?,
+        Rule::grouping => build_expr(pair.into_inner().next().unwrap(), env)?,
         r => unreachable!("Encountered unknown op {:?}", r),
     })
 }
@@ -92,10 +92,19 @@ fn build_phrase(pair: Pair<'_>) -> Result<FtsLiteral> {
         _ => unreachable!("unexpected rule: {:?}", kernel.as_rule()),
     };
     let mut is_quoted = false;
+    let mut fuzzy_distance = 0;
     let mut booster = 1.0;
     for pair in inner {
         match pair.as_rule() {
             Rule::fts_prefix_marker => is_quoted = true,
+            Rule::fts_fuzzy => {
+                let distance = pair.into_inner().next().unwrap();
+                fuzzy_distance = distance
+                    .as_str()
+                    .replace('_', "")
+                    .parse::<u32>()
+                    .into_diagnostic()?;
+            }
             Rule::fts_booster => {
                 let boosted = pair.into_inner().next().unwrap();
                 match boosted.as_rule() {
@@ -124,6 +133,7 @@ fn build_phrase(pair: Pair<'_>) -> Result<FtsLiteral> {
     Ok(FtsLiteral {
         value: core_text,
         is_prefix: is_quoted,
+        fuzzy_distance,
         booster: booster.into(),
     })
 }
@@ -159,5 +169,14 @@ mod tests {
         let res = parse_fts_query(src).unwrap().flatten();
         assert!(matches!(res, FtsExpr::Near(FtsNear { distance: 10, .. })));
         println!("{:#?}", res);
+        let src = " helo~1 wrold~2^0.5";
+        let res = parse_fts_query(src).unwrap().flatten();
+        match res {
+            FtsExpr::And(ls) => {
+                assert!(matches!(&ls[0], FtsExpr::Literal(l) if l.fuzzy_distance == 1));
+                assert!(matches!(&ls[1], FtsExpr::Literal(l) if l.fuzzy_distance == 2));
+            }
+            _ => panic!("expected AND, got {:?}", res),
+        }
     }
 }
@@ -17,12 +17,14 @@ use smartstring::SmartString;
 use thiserror::Error;
 
 use crate::parse::query::parse_query;
-use crate::parse::{ExtractSpan, ImperativeProgram, ImperativeStmt, Pair, Rule, SourceSpan};
-use crate::{DataValue, FixedRule, ValidityTs};
+use crate::parse::{
+    ExtractSpan, ImperativeProgram, ImperativeStmt, Pair, ParseEnv, Rule, SourceSpan,
+};
+use crate::{FixedRule, ValidityTs};
 
 pub(crate) fn parse_imperative_block(
     src: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<ImperativeProgram> {
@@ -32,12 +34,7 @@ pub(crate) fn parse_imperative_block(
         if pair.as_rule() == Rule::EOI {
             break;
         }
-        collected.push(parse_imperative_stmt(
-            pair,
-            param_pool,
-            fixed_rules,
-            cur_vld,
-        )?);
+        collected.push(parse_imperative_stmt(pair, env, fixed_rules, cur_vld)?);
     }
 
     Ok(collected)
@@ -55,7 +52,7 @@ struct DuplicateMarker(#[label] SourceSpan);
 
 fn parse_imperative_stmt(
     pair: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<ImperativeStmt> {
@@ -86,7 +83,7 @@ fn parse_imperative_stmt(
                         rets.push(Right(rel));
                     }
                     Rule::query_script_inner => {
-                        let prog = parse_query(p.into_inner(), param_pool, fixed_rules, cur_vld)?;
+                        let prog = parse_query(p.into_inner(), env, fixed_rules, cur_vld)?;
                         rets.push(Left(prog))
                     }
                     _ => unreachable!(),
@@ -102,7 +99,7 @@ fn parse_imperative_stmt(
                 Rule::underscore_ident => Left(SmartString::from(condition.as_str())),
                 Rule::query_script_inner => Right(parse_query(
                     condition.into_inner(),
-                    param_pool,
+                    env,
                     fixed_rules,
                     cur_vld,
                 )?),
@@ -112,13 +109,13 @@ fn parse_imperative_stmt(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|p| parse_imperative_stmt(p, param_pool, fixed_rules, cur_vld))
+                .map(|p| parse_imperative_stmt(p, env, fixed_rules, cur_vld))
                 .try_collect()?;
             let else_body = match inner.next() {
                 None => vec![],
                 Some(rest) => rest
                     .into_inner()
-                    .map(|p| parse_imperative_stmt(p, param_pool, fixed_rules, cur_vld))
+                    .map(|p| parse_imperative_stmt(p, env, fixed_rules, cur_vld))
                     .try_collect()?,
             };
             ImperativeStmt::If {
@@ -136,7 +133,7 @@ fn parse_imperative_stmt(
                 mark = Some(SmartString::from(nxt.as_str()));
                 nxt = inner.next().unwrap();
             }
-            let body = parse_imperative_block(nxt, param_pool, fixed_rules, cur_vld)?;
+            let body = parse_imperative_block(nxt, env, fixed_rules, cur_vld)?;
             ImperativeStmt::Loop { label: mark, body }
         }
         Rule::temp_swap => {
@@ -162,12 +159,12 @@ fn parse_imperative_stmt(
             }
         }
         Rule::query_script_inner => {
-            let prog = parse_query(pair.into_inner(), param_pool, fixed_rules, cur_vld)?;
+            let prog = parse_query(pair.into_inner(), env, fixed_rules, cur_vld)?;
             ImperativeStmt::Program { prog }
         }
         Rule::ignore_error_script => {
             let pair = pair.into_inner().next().unwrap();
-            let prog = parse_query(pair.into_inner(), param_pool, fixed_rules, cur_vld)?;
+            let prog = parse_query(pair.into_inner(), env, fixed_rules, cur_vld)?;
             ImperativeStmt::IgnoreErrorProgram { prog }
         }
         r => unreachable!("{r:?}"),
@@ -9,7 +9,8 @@
 use std::cmp::{max, min};
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Display, Formatter};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use either::{Either, Left};
 use miette::{bail, Diagnostic, IntoDiagnostic, Result};
@@ -18,6 +19,8 @@ use pest::Parser;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::aggr::CustomAggregation;
+use crate::data::expr::{CustomFunction, ParamCell};
 use crate::data::program::InputProgram;
 use crate::data::relation::NullableColType;
 use crate::data::value::{DataValue, ValidityTs};
@@ -28,11 +31,11 @@ use crate::parse::sys::{parse_sys, SysOp};
 use crate::FixedRule;
 
 pub(crate) mod expr;
+pub(crate) mod fts;
 pub(crate) mod imperative;
 pub(crate) mod query;
 pub(crate) mod schema;
 pub(crate) mod sys;
-pub(crate) mod fts;
 
 #[derive(pest_derive::Parser)]
 #[grammar = "cozoscript.pest"]
@@ -41,8 +44,67 @@ pub(crate) struct CozoScriptParser;
 pub(crate) type Pair<'a> = pest::iterators::Pair<'a, Rule>;
 pub(crate) type Pairs<'a> = pest::iterators::Pairs<'a, Rule>;
 
+static EMPTY_PARAMS: BTreeMap<String, DataValue> = BTreeMap::new();
+static EMPTY_FUNCTIONS: BTreeMap<String, Arc<CustomFunction>> = BTreeMap::new();
+static EMPTY_AGGREGATIONS: BTreeMap<String, Arc<dyn CustomAggregation>> = BTreeMap::new();
+
+/// Names provided by the caller of a script that the parser resolves:
+/// the parameters, and the functions and aggregations registered at runtime.
+/// When `prepared` is set, parameters are parsed into placeholders instead.
+#[derive(Copy, Clone)]
+pub(crate) struct ParseEnv<'a> {
+    pub(crate) params: &'a BTreeMap<String, DataValue>,
+    pub(crate) functions: &'a BTreeMap<String, Arc<CustomFunction>>,
+    pub(crate) aggregations: &'a BTreeMap<String, Arc<dyn CustomAggregation>>,
+    pub(crate) prepared: Option<&'a PreparedParams>,
+}
+
+impl Default for ParseEnv<'_> {
+    fn default() -> Self {
+        Self {
+            params: &EMPTY_PARAMS,
+            functions: &EMPTY_FUNCTIONS,
+            aggregations: &EMPTY_AGGREGATIONS,
+            prepared: None,
+        }
+    }
+}
+
+/// Parameter placeholders collected when parsing a prepared query
+#[derive(Default)]
+pub(crate) struct PreparedParams {
+    cells: Mutex<BTreeMap<String, ParamCell>>,
+    uses_now: AtomicBool,
+}
+
+impl PreparedParams {
+    /// The placeholder for the parameter `name`, initially holding `val`
+    pub(crate) fn cell(&self, name: &str, val: Option<&DataValue>) -> ParamCell {
+        self.cells
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let cell = ParamCell::default();
+                cell.set(val.cloned());
+                cell
+            })
+            .clone()
+    }
+    pub(crate) fn cells(&self) -> BTreeMap<String, ParamCell> {
+        self.cells.lock().unwrap().clone()
+    }
+    fn mark_uses_now(&self) {
+        self.uses_now.store(true, Ordering::Release)
+    }
+    /// Whether the script reads the current time during parsing, as in `@ 'NOW'`
+    pub(crate) fn uses_now(&self) -> bool {
+        self.uses_now.load(Ordering::Acquire)
+    }
+}
+
 pub(crate) enum CozoScript {
-    Single(InputProgram),
+    Single(Box<InputProgram>),
     Imperative(ImperativeProgram),
     Sys(SysOp),
 }
@@ -95,16 +157,12 @@ impl ImperativeStmt {
         match self {
             ImperativeStmt::Program { prog, .. }
             | ImperativeStmt::IgnoreErrorProgram { prog, .. } => {
-                if let Some(name) = prog.needs_write_lock() {
-                    collector.insert(name);
-                }
+                prog.needs_write_locks(collector);
             }
             ImperativeStmt::Return { returns, .. } => {
                 for ret in returns {
                     if let Left(prog) = ret {
-                        if let Some(name) = prog.needs_write_lock() {
-                            collector.insert(name);
-                        }
+                        prog.needs_write_locks(collector);
                     }
                 }
             }
@@ -115,9 +173,7 @@ impl ImperativeStmt {
                 ..
             } => {
                 if let ImperativeCondition::Right(prog) = condition {
-                    if let Some(name) = prog.needs_write_lock() {
-                        collector.insert(name);
-                    }
+                    prog.needs_write_locks(collector);
                 }
                 for prog in then_branch.iter().chain(else_branch.iter()) {
                     prog.needs_write_locks(collector);
@@ -143,7 +199,7 @@ impl CozoScript {
         #[diagnostic(code(parser::expect_singleton))]
         struct ExpectSingleProgram;
         match self {
-            CozoScript::Single(s) => Ok(s),
+            CozoScript::Single(s) => Ok(*s),
             CozoScript::Imperative(_) | CozoScript::Sys(_) => {
                 bail!(ExpectSingleProgram)
             }
@@ -205,7 +261,7 @@ pub(crate) fn parse_type(src: &str) -> Result<NullableColType> {
 
 pub(crate) fn parse_script(
     src: &str,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<CozoScript> {
@@ -221,20 +277,17 @@ pub(crate) fn parse_script(
         .unwrap();
     Ok(match parsed.as_rule() {
         Rule::query_script => {
-            let q = parse_query(parsed.into_inner(), param_pool, fixed_rules, cur_vld)?;
-            CozoScript::Single(q)
+            let q = parse_query(parsed.into_inner(), env, fixed_rules, cur_vld)?;
+            CozoScript::Single(Box::new(q))
         }
         Rule::imperative_script => {
-            let p = parse_imperative_block(parsed, param_pool, fixed_rules, cur_vld)?;
+            let p = parse_imperative_block(parsed, env, fixed_rules, cur_vld)?;
             CozoScript::Imperative(p)
         }
 
-        Rule::sys_script => CozoScript::Sys(parse_sys(
-            parsed.into_inner(),
-            param_pool,
-            fixed_rules,
-            cur_vld,
-        )?),
+        Rule::sys_script => {
+            CozoScript::Sys(parse_sys(parsed.into_inner(), env, fixed_rules, cur_vld)?)
+        }
         _ => unreachable!(),
     })
 }
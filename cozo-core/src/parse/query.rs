@@ -25,7 +25,7 @@ use crate::data::functions::{str2vld, MAX_VALIDITY_TS};
 use crate::data::program::{
     FixedRuleApply, FixedRuleArg, InputAtom, InputInlineRule, InputInlineRulesOrFixed,
     InputNamedFieldRelationApplyAtom, InputProgram, InputRelationApplyAtom, InputRuleApplyAtom,
-    QueryAssertion, QueryOutOptions, RelationOp, SearchInput, SortDir, Unification,
+    QueryAssertion, QueryOutOptions, RelationOp, RuleMutation, SearchInput, SortDir, Unification,
 };
 use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
 use crate::data::symb::{Symbol, PROG_ENTRY};
@@ -34,7 +34,7 @@ use crate::fixed_rule::utilities::constant::Constant;
 use crate::fixed_rule::{FixedRuleHandle, FixedRuleNotFoundError};
 use crate::parse::expr::build_expr;
 use crate::parse::schema::parse_schema;
-use crate::parse::{ExtractSpan, Pair, Pairs, Rule, SourceSpan};
+use crate::parse::{ExtractSpan, Pair, Pairs, ParseEnv, Rule, SourceSpan};
 use crate::runtime::relation::InputRelationHandle;
 use crate::FixedRule;
 
@@ -104,20 +104,22 @@ fn merge_spans(symbs: &[Symbol]) -> SourceSpan {
 
 pub(crate) fn parse_query(
     src: Pairs<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<InputProgram> {
     let mut progs: BTreeMap<Symbol, InputInlineRulesOrFixed> = Default::default();
     let mut out_opts: QueryOutOptions = Default::default();
     let mut disable_magic_rewrite = false;
+    let mut returning_span = None;
 
     let mut stored_relation = None;
+    let mut rule_mutations = vec![];
 
     for pair in src {
         match pair.as_rule() {
             Rule::rule => {
-                let (name, rule) = parse_rule(pair, param_pool, cur_vld)?;
+                let (name, rule) = parse_rule(pair, env, cur_vld)?;
 
                 match progs.entry(name) {
                     Entry::Vacant(e) => {
@@ -161,7 +163,7 @@ pub(crate) fn parse_query(
             }
             Rule::fixed_rule => {
                 let rule_span = pair.extract_span();
-                let (name, apply) = parse_fixed_rule(pair, param_pool, fixed_rules, cur_vld)?;
+                let (name, apply) = parse_fixed_rule(pair, env, fixed_rules, cur_vld)?;
 
                 match progs.entry(name) {
                     Entry::Vacant(e) => {
@@ -183,7 +185,7 @@ pub(crate) fn parse_query(
             Rule::const_rule => {
                 let span = pair.extract_span();
                 let mut src = pair.into_inner();
-                let (name, head, aggr) = parse_rule_head(src.next().unwrap(), param_pool)?;
+                let (name, head, aggr) = parse_rule_head(src.next().unwrap(), env)?;
 
                 if let Some(found) = progs.get(&name) {
                     let mut found_span = match found {
@@ -210,7 +212,7 @@ pub(crate) fn parse_query(
                     ensure!(a.is_none(), AggrInConstRuleError(v.span));
                 }
 
-                let data = build_expr(src.next().unwrap(), param_pool)?;
+                let data = build_expr(src.next().unwrap(), env)?;
                 let mut options = BTreeMap::new();
                 options.insert(SmartString::from("data"), data);
                 let handle = FixedRuleHandle {
@@ -243,7 +245,7 @@ pub(crate) fn parse_query(
             Rule::timeout_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let timeout = build_expr(pair, param_pool)?
+                let timeout = build_expr(pair, env)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("timeout", span, [err]))?
                     .get_float()
@@ -262,7 +264,7 @@ pub(crate) fn parse_query(
                 {
                     let pair = pair.into_inner().next().unwrap();
                     let span = pair.extract_span();
-                    let sleep = build_expr(pair, param_pool)?
+                    let sleep = build_expr(pair, env)?
                         .eval_to_const()
                         .map_err(|err| OptionNotConstantError("sleep", span, [err]))?
                         .get_float()
@@ -271,10 +273,34 @@ pub(crate) fn parse_query(
                     out_opts.sleep = Some(sleep);
                 }
             }
+            Rule::max_rows_option => {
+                let pair = pair.into_inner().next().unwrap();
+                let span = pair.extract_span();
+                let max_rows = build_expr(pair, env)?
+                    .eval_to_const()
+                    .map_err(|err| OptionNotConstantError("max_rows", span, [err]))?
+                    .get_non_neg_int()
+                    .ok_or(OptionNotNonNegIntError("max_rows", span))?;
+                out_opts.max_rows = Some(max_rows as usize);
+            }
+            Rule::max_memory_option => {
+                let pair = pair.into_inner().next().unwrap();
+                let span = pair.extract_span();
+                let max_memory = build_expr(pair, env)?
+                    .eval_to_const()
+                    .map_err(|err| OptionNotConstantError("max_memory", span, [err]))?
+                    .get_non_neg_int()
+                    .ok_or(OptionNotNonNegIntError("max_memory", span))?;
+                out_opts.max_memory = Some(max_memory as usize);
+            }
+            Rule::returning_option => {
+                returning_span = Some(pair.extract_span());
+                out_opts.returning = true;
+            }
             Rule::limit_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let limit = build_expr(pair, param_pool)?
+                let limit = build_expr(pair, env)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("limit", span, [err]))?
                     .get_non_neg_int()
@@ -284,7 +310,7 @@ pub(crate) fn parse_query(
             Rule::offset_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let offset = build_expr(pair, param_pool)?
+                let offset = build_expr(pair, env)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("offset", span, [err]))?
                     .get_non_neg_int()
@@ -326,27 +352,63 @@ pub(crate) fn parse_query(
 
                 let name_p = args.next().unwrap();
                 let name = Symbol::new(name_p.as_str(), name_p.extract_span());
-                match args.next() {
-                    None => stored_relation = Some(Left((name, span, op))),
-                    Some(schema_p) => {
-                        let (mut metadata, mut key_bindings, mut dep_bindings) =
-                            parse_schema(schema_p)?;
-                        if !matches!(op, RelationOp::Create | RelationOp::Replace) {
-                            key_bindings.extend(dep_bindings);
-                            dep_bindings = vec![];
-                            metadata.keys.extend(metadata.non_keys);
-                            metadata.non_keys = vec![];
+                let mut target = Left((name, span, op));
+                let mut source = None;
+                for arg in args {
+                    match arg.as_rule() {
+                        Rule::table_schema => {
+                            let name = match target {
+                                Left((name, _, _)) => name,
+                                Right(_) => unreachable!(),
+                            };
+                            let (mut metadata, mut key_bindings, mut dep_bindings) =
+                                parse_schema(arg, env)?;
+                            if !matches!(op, RelationOp::Create | RelationOp::Replace) {
+                                key_bindings.extend(dep_bindings);
+                                dep_bindings = vec![];
+                                metadata.keys.extend(metadata.non_keys);
+                                metadata.non_keys = vec![];
+                            }
+                            target = Right((
+                                InputRelationHandle {
+                                    name,
+                                    metadata,
+                                    key_bindings,
+                                    dep_bindings,
+                                    span,
+                                },
+                                op,
+                            ))
+                        }
+                        Rule::relation_source => {
+                            let source_p = arg.into_inner().nth(1).unwrap();
+                            source = Some(Symbol::new(source_p.as_str(), source_p.extract_span()));
                         }
-                        stored_relation = Some(Right((
-                            InputRelationHandle {
-                                name,
-                                metadata,
-                                key_bindings,
-                                dep_bindings,
-                                span,
-                            },
-                            op,
-                        )))
+                        _ => unreachable!(),
+                    }
+                }
+                match source {
+                    None => {
+                        #[derive(Debug, Error, Diagnostic)]
+                        #[error("Only one mutation can take the rows of the entry rule")]
+                        #[diagnostic(code(parser::duplicate_entry_mutation))]
+                        #[diagnostic(help("Use 'from <rule>' to feed other mutations"))]
+                        struct DuplicateEntryMutation(#[label] SourceSpan);
+
+                        ensure!(stored_relation.is_none(), DuplicateEntryMutation(span));
+                        stored_relation = Some(target)
+                    }
+                    Some(source) => {
+                        #[derive(Debug, Error, Diagnostic)]
+                        #[error("Only ':put', ':update', ':rm', ':ensure' and ':ensure_not' can take the rows of a named rule")]
+                        #[diagnostic(code(parser::bad_rule_mutation_op))]
+                        struct BadRuleMutationOp(#[label] SourceSpan);
+
+                        ensure!(
+                            !matches!(op, RelationOp::Create | RelationOp::Replace),
+                            BadRuleMutationOp(span)
+                        );
+                        rule_mutations.push((target, source))
                     }
                 }
             }
@@ -367,7 +429,7 @@ pub(crate) fn parse_query(
             Rule::disable_magic_rewrite_option => {
                 let pair = pair.into_inner().next().unwrap();
                 let span = pair.extract_span();
-                let val = build_expr(pair, param_pool)?
+                let val = build_expr(pair, env)?
                     .eval_to_const()
                     .map_err(|err| OptionNotConstantError("disable_magic_rewrite", span, [err]))?
                     .get_bool()
@@ -407,37 +469,61 @@ pub(crate) fn parse_query(
         None => {}
         Some(Left((name, span, op))) => {
             let head = prog.get_entry_out_head()?;
-            for symb in &head {
-                symb.ensure_valid_field()?;
-            }
-
-            let metadata = StoredRelationMetadata {
-                keys: head
-                    .iter()
-                    .map(|s| ColumnDef {
-                        name: s.name.clone(),
-                        typing: NullableColType {
-                            coltype: ColType::Any,
-                            nullable: true,
-                        },
-                        default_gen: None,
-                    })
-                    .collect(),
-                non_keys: vec![],
-            };
-
-            let handle = InputRelationHandle {
-                name,
-                metadata,
-                key_bindings: head,
-                dep_bindings: vec![],
-                span,
-            };
+            let handle = relation_handle_from_head(name, span, head)?;
             prog.out_opts.store_relation = Some((handle, op))
         }
         Some(Right(r)) => prog.out_opts.store_relation = Some(r),
     }
 
+    for (target, source) in rule_mutations {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Rule '{0}' feeding the mutation is not defined")]
+        #[diagnostic(code(parser::rule_mutation_source_not_found))]
+        struct RuleMutationSourceNotFound(String, #[label] SourceSpan);
+
+        ensure!(
+            prog.prog.contains_key(&source),
+            RuleMutationSourceNotFound(source.to_string(), source.span)
+        );
+        let headers = prog.get_rule_out_head(&source)?;
+        let (handle, op) = match target {
+            Left((name, span, op)) => (relation_handle_from_head(name, span, headers.clone())?, op),
+            Right(r) => r,
+        };
+        prog.out_opts.rule_mutations.push(RuleMutation {
+            handle,
+            op,
+            source,
+            headers,
+        });
+    }
+
+    if let Some(span) = returning_span {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("':returning' can only be used with ':put', ':update' or ':rm'")]
+        #[diagnostic(code(parser::returning_without_mutation))]
+        struct ReturningWithoutMutation(#[label] SourceSpan);
+
+        ensure!(
+            matches!(
+                prog.out_opts.store_relation,
+                Some((_, RelationOp::Put | RelationOp::Update | RelationOp::Rm))
+            ),
+            ReturningWithoutMutation(span)
+        );
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("':returning' cannot be used with mutations fed by named rules")]
+        #[diagnostic(code(parser::returning_with_rule_mutations))]
+        #[diagnostic(help("Run the mutations fed by named rules in separate queries"))]
+        struct ReturningWithRuleMutations(#[label] SourceSpan);
+
+        ensure!(
+            prog.out_opts.rule_mutations.is_empty(),
+            ReturningWithRuleMutations(span)
+        );
+    }
+
     if prog.prog.is_empty() {
         if let Some((handle, RelationOp::Create)) = &prog.out_opts.store_relation {
             let mut bindings = handle.dep_bindings.clone();
@@ -465,16 +551,52 @@ pub(crate) fn parse_query(
     Ok(prog)
 }
 
+/// A relation with a column for each symbol of the head, all of which are keys
+fn relation_handle_from_head(
+    name: Symbol,
+    span: SourceSpan,
+    head: Vec<Symbol>,
+) -> Result<InputRelationHandle> {
+    for symb in &head {
+        symb.ensure_valid_field()?;
+    }
+
+    let metadata = StoredRelationMetadata {
+        keys: head
+            .iter()
+            .map(|s| ColumnDef {
+                name: s.name.clone(),
+                typing: NullableColType {
+                    coltype: ColType::Any,
+                    nullable: true,
+                },
+                default_gen: None,
+                check: None,
+            })
+            .collect(),
+        non_keys: vec![],
+        foreign_keys: vec![],
+    };
+
+    Ok(InputRelationHandle {
+        name,
+        metadata,
+        key_bindings: head,
+        dep_bindings: vec![],
+        span,
+    })
+}
+
 fn parse_rule(
     src: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     cur_vld: ValidityTs,
 ) -> Result<(Symbol, InputInlineRule)> {
     let span = src.extract_span();
     let mut src = src.into_inner();
     let head = src.next().unwrap();
     let head_span = head.extract_span();
-    let (name, head, aggr) = parse_rule_head(head, param_pool)?;
+    let (name, head, aggr) = parse_rule_head(head, env)?;
 
     #[derive(Debug, Error, Diagnostic)]
     #[error("Horn-clause rule cannot have empty rule head")]
@@ -488,7 +610,7 @@ fn parse_rule(
     for atom_src in body.into_inner() {
         body_clauses.push(parse_disjunction(
             atom_src,
-            param_pool,
+            env,
             cur_vld,
             &mut ignored_counter,
         )?)
@@ -507,7 +629,7 @@ fn parse_rule(
 
 fn parse_disjunction(
     pair: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     cur_vld: ValidityTs,
     ignored_counter: &mut u32,
 ) -> Result<InputAtom> {
@@ -516,7 +638,7 @@ fn parse_disjunction(
         .into_inner()
         .filter_map(|v| match v.as_rule() {
             Rule::or_op => None,
-            _ => Some(parse_atom(v, param_pool, cur_vld, ignored_counter)),
+            _ => Some(parse_atom(v, env, cur_vld, ignored_counter)),
         })
         .try_collect()?;
     Ok(if res.len() == 1 {
@@ -528,7 +650,7 @@ fn parse_disjunction(
 
 fn parse_atom(
     src: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     cur_vld: ValidityTs,
     ignored_counter: &mut u32,
 ) -> Result<InputAtom> {
@@ -537,26 +659,26 @@ fn parse_atom(
             let span = src.extract_span();
             let grouped: Vec<_> = src
                 .into_inner()
-                .map(|v| parse_disjunction(v, param_pool, cur_vld, ignored_counter))
+                .map(|v| parse_disjunction(v, env, cur_vld, ignored_counter))
                 .try_collect()?;
             InputAtom::Conjunction {
                 inner: grouped,
                 span,
             }
         }
-        Rule::disjunction => parse_disjunction(src, param_pool, cur_vld, ignored_counter)?,
+        Rule::disjunction => parse_disjunction(src, env, cur_vld, ignored_counter)?,
         Rule::negation => {
             let span = src.extract_span();
             let mut src = src.into_inner();
             src.next().unwrap();
-            let inner = parse_atom(src.next().unwrap(), param_pool, cur_vld, ignored_counter)?;
+            let inner = parse_atom(src.next().unwrap(), env, cur_vld, ignored_counter)?;
             InputAtom::Negation {
                 inner: inner.into(),
                 span,
             }
         }
         Rule::expr => {
-            let expr = build_expr(src, param_pool)?;
+            let expr = build_expr(src, env)?;
             InputAtom::Predicate { inner: expr }
         }
         Rule::unify => {
@@ -568,7 +690,7 @@ fn parse_atom(
                 symb.name = format!("*^*{}", *ignored_counter).into();
                 *ignored_counter += 1;
             }
-            let expr = build_expr(src.next().unwrap(), param_pool)?;
+            let expr = build_expr(src.next().unwrap(), env)?;
             InputAtom::Unification {
                 inner: Unification {
                     binding: symb,
@@ -588,7 +710,7 @@ fn parse_atom(
                 *ignored_counter += 1;
             }
             src.next().unwrap();
-            let expr = build_expr(src.next().unwrap(), param_pool)?;
+            let expr = build_expr(src.next().unwrap(), env)?;
             InputAtom::Unification {
                 inner: Unification {
                     binding: symb,
@@ -606,7 +728,7 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|v| build_expr(v, param_pool))
+                .map(|v| build_expr(v, env))
                 .try_collect()?;
             InputAtom::Rule {
                 inner: InputRuleApplyAtom {
@@ -624,13 +746,13 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|v| build_expr(v, param_pool))
+                .map(|v| build_expr(v, env))
                 .try_collect()?;
             let valid_at = match src.next() {
                 None => None,
                 Some(vld_clause) => {
-                    let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), param_pool)?;
-                    Some(expr2vld_spec(vld_expr, cur_vld)?)
+                    let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), env)?;
+                    Some(expr2vld_spec(vld_expr, env, cur_vld)?)
                 }
             };
             InputAtom::Relation {
@@ -663,10 +785,10 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|arg| extract_named_apply_arg(arg, param_pool))
+                .map(|arg| extract_named_apply_arg(arg, env))
                 .try_collect()?;
             let parameters: BTreeMap<SmartString<LazyCompact>, Expr> = src
-                .map(|arg| extract_named_apply_arg(arg, param_pool))
+                .map(|arg| extract_named_apply_arg(arg, env))
                 .try_collect()?;
 
             let opts = SearchInput {
@@ -688,13 +810,13 @@ fn parse_atom(
                 .next()
                 .unwrap()
                 .into_inner()
-                .map(|arg| extract_named_apply_arg(arg, param_pool))
+                .map(|arg| extract_named_apply_arg(arg, env))
                 .try_collect()?;
             let valid_at = match src.next() {
                 None => None,
                 Some(vld_clause) => {
-                    let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), param_pool)?;
-                    Some(expr2vld_spec(vld_expr, cur_vld)?)
+                    let vld_expr = build_expr(vld_clause.into_inner().next().unwrap(), env)?;
+                    Some(expr2vld_spec(vld_expr, env, cur_vld)?)
                 }
             };
             InputAtom::NamedFieldRelation {
@@ -712,13 +834,13 @@ fn parse_atom(
 
 fn extract_named_apply_arg(
     pair: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
 ) -> Result<(SmartString<LazyCompact>, Expr)> {
     let mut inner = pair.into_inner();
     let name_p = inner.next().unwrap();
     let name = SmartString::from(name_p.as_str());
     let arg = match inner.next() {
-        Some(a) => build_expr(a, param_pool)?,
+        Some(a) => build_expr(a, env)?,
         None => Expr::Binding {
             var: Symbol::new(name.clone(), name_p.extract_span()),
             tuple_pos: None,
@@ -729,7 +851,7 @@ fn extract_named_apply_arg(
 
 fn parse_rule_head(
     src: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
 ) -> Result<(
     Symbol,
     Vec<Symbol>,
@@ -740,7 +862,7 @@ fn parse_rule_head(
     let mut args = vec![];
     let mut aggrs = vec![];
     for p in src {
-        let (arg, aggr) = parse_rule_head_arg(p, param_pool)?;
+        let (arg, aggr) = parse_rule_head_arg(p, env)?;
         args.push(arg);
         aggrs.push(aggr);
     }
@@ -754,7 +876,7 @@ struct AggrNotFound(String, #[label] SourceSpan);
 
 fn parse_rule_head_arg(
     src: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
 ) -> Result<(Symbol, Option<(Aggregation, Vec<DataValue>)>)> {
     let src = src.into_inner().next().unwrap();
     Ok(match src.as_rule() {
@@ -765,14 +887,20 @@ fn parse_rule_head_arg(
             let aggr_name = aggr_p.as_str();
             let var = inner.next().unwrap();
             let args: Vec<_> = inner
-                .map(|v| -> Result<DataValue> { build_expr(v, param_pool)?.eval_to_const() })
+                .map(|v| -> Result<DataValue> { build_expr(v, env)?.eval_to_const() })
                 .try_collect()?;
             (
                 Symbol::new(var.as_str(), var.extract_span()),
                 Some((
-                    parse_aggr(aggr_name)
-                        .ok_or_else(|| AggrNotFound(aggr_name.to_string(), aggr_p.extract_span()))?
-                        .clone(),
+                    match parse_aggr(aggr_name) {
+                        Some(aggr) => aggr.clone(),
+                        None => {
+                            let custom = env.aggregations.get(aggr_name).ok_or_else(|| {
+                                AggrNotFound(aggr_name.to_string(), aggr_p.extract_span())
+                            })?;
+                            Aggregation::new_custom(aggr_name, custom.clone())
+                        }
+                    },
                     args,
                 )),
             )
@@ -788,12 +916,12 @@ struct BadValiditySpecification(#[label] SourceSpan);
 
 fn parse_fixed_rule(
     src: Pair<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     fixed_rules: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<(Symbol, FixedRuleApply)> {
     let mut src = src.into_inner();
-    let (out_symbol, head, aggr) = parse_rule_head(src.next().unwrap(), param_pool)?;
+    let (out_symbol, head, aggr) = parse_rule_head(src.next().unwrap(), env)?;
 
     #[derive(Debug, Error, Diagnostic)]
     #[error("fixed rule cannot be combined with aggregation")]
@@ -875,8 +1003,8 @@ fn parse_fixed_rule(
                                 }
                                 Rule::validity_clause => {
                                     let vld_inner = v.into_inner().next().unwrap();
-                                    let vld_expr = build_expr(vld_inner, param_pool)?;
-                                    valid_at = Some(expr2vld_spec(vld_expr, cur_vld)?)
+                                    let vld_expr = build_expr(vld_inner, env)?;
+                                    valid_at = Some(expr2vld_spec(vld_expr, env, cur_vld)?)
                                 }
                                 _ => unreachable!(),
                             }
@@ -920,8 +1048,8 @@ fn parse_fixed_rule(
                                 }
                                 Rule::validity_clause => {
                                     let vld_inner = p.into_inner().next().unwrap();
-                                    let vld_expr = build_expr(vld_inner, param_pool)?;
-                                    valid_at = Some(expr2vld_spec(vld_expr, cur_vld)?)
+                                    let vld_expr = build_expr(vld_inner, env)?;
+                                    valid_at = Some(expr2vld_spec(vld_expr, env, cur_vld)?)
                                 }
                                 _ => unreachable!(),
                             }
@@ -944,7 +1072,7 @@ fn parse_fixed_rule(
                 let mut inner = nxt.into_inner();
                 let name = inner.next().unwrap().as_str();
                 let val = inner.next().unwrap();
-                let val = build_expr(val, param_pool)?;
+                let val = build_expr(val, env)?;
                 options.insert(SmartString::from(name), val);
             }
             _ => unreachable!(),
@@ -1017,7 +1145,7 @@ fn make_empty_const_rule(prog: &mut InputProgram, bindings: &[Symbol]) {
     );
 }
 
-fn expr2vld_spec(expr: Expr, cur_vld: ValidityTs) -> Result<ValidityTs> {
+fn expr2vld_spec(expr: Expr, env: &ParseEnv<'_>, cur_vld: ValidityTs) -> Result<ValidityTs> {
     let vld_span = expr.span();
     match expr.eval_to_const()? {
         DataValue::Num(n) => {
@@ -1025,7 +1153,12 @@ fn expr2vld_spec(expr: Expr, cur_vld: ValidityTs) -> Result<ValidityTs> {
             Ok(ValidityTs(Reverse(microseconds)))
         }
         DataValue::Str(s) => match &s as &str {
-            "NOW" => Ok(cur_vld),
+            "NOW" => {
+                if let Some(prepared) = env.prepared {
+                    prepared.mark_uses_now();
+                }
+                Ok(cur_vld)
+            }
             "END" => Ok(MAX_VALIDITY_TS),
             s => Ok(str2vld(s).map_err(|_| BadValiditySpecification(vld_span))?),
         },
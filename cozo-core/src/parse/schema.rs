@@ -13,14 +13,18 @@ use miette::{bail, ensure, Diagnostic, Result, IntoDiagnostic};
 use smartstring::SmartString;
 use thiserror::Error;
 
-use crate::data::relation::{VecElementType, ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::relation::{
+    ColType, ColumnDef, ForeignKey, ForeignKeyAction, NullableColType, StoredRelationMetadata,
+    VecElementType,
+};
 use crate::data::symb::Symbol;
 use crate::data::value::DataValue;
 use crate::parse::expr::{build_expr};
-use crate::parse::{ExtractSpan, Pair, Rule, SourceSpan};
+use crate::parse::{ExtractSpan, Pair, ParseEnv, Rule, SourceSpan};
 
 pub(crate) fn parse_schema(
     pair: Pair<'_>,
+    env: &ParseEnv<'_>,
 ) -> Result<(StoredRelationMetadata, Vec<Symbol>, Vec<Symbol>)> {
     // assert_eq!(pair.as_rule(), Rule::table_schema);
     let span = pair.extract_span();
@@ -31,27 +35,54 @@ pub(crate) fn parse_schema(
     let mut key_bindings = vec![];
     let mut dep_bindings = vec![];
     let mut seen_names = BTreeSet::new();
+    let mut foreign_keys = vec![];
 
     #[derive(Debug, Error, Diagnostic)]
     #[error("Column {0} is defined multiple times")]
     #[diagnostic(code(parser::dup_name_in_cols))]
     struct DuplicateNameInCols(String, #[label] SourceSpan);
+
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Column {0} cannot be set to null on delete")]
+    #[diagnostic(code(parser::bad_set_null_col))]
+    #[diagnostic(help("Only nullable non-key columns can use 'on delete set null'"))]
+    struct BadSetNullColumn(String, #[label] SourceSpan);
+
+    // defaults and checks are persisted, so parameters are fixed to their current values
+    let env = ParseEnv {
+        params: env.params,
+        functions: env.functions,
+        ..Default::default()
+    };
+
     for p in src.next().unwrap().into_inner() {
         let span = p.extract_span();
-        let (col, ident) = parse_col(p)?;
+        let (col, ident, fk) = parse_col(p, &env)?;
         if !seen_names.insert(col.name.clone()) {
             bail!(DuplicateNameInCols(col.name.to_string(), span));
         }
+        if let Some(fk) = fk {
+            if fk.on_delete == ForeignKeyAction::SetNull {
+                bail!(BadSetNullColumn(col.name.to_string(), span));
+            }
+            foreign_keys.push(fk);
+        }
         keys.push(col);
         key_bindings.push(ident)
     }
     if let Some(ps) = src.next() {
         for p in ps.into_inner() {
             let span = p.extract_span();
-            let (col, ident) = parse_col(p)?;
+            let (col, ident, fk) = parse_col(p, &env)?;
             if !seen_names.insert(col.name.clone()) {
                 bail!(DuplicateNameInCols(col.name.to_string(), span));
             }
+            if let Some(fk) = fk {
+                if fk.on_delete == ForeignKeyAction::SetNull && !col.typing.nullable {
+                    bail!(BadSetNullColumn(col.name.to_string(), span));
+                }
+                foreign_keys.push(fk);
+            }
             dependents.push(col);
             dep_bindings.push(ident)
         }
@@ -65,17 +96,39 @@ pub(crate) fn parse_schema(
         bail!(EmptySchema(span))
     }
 
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("Check constraint of column {1} refers to unknown column {0}")]
+    #[diagnostic(code(parser::unknown_col_in_check))]
+    struct UnknownColumnInCheck(String, String, #[label] SourceSpan);
+    for col in keys.iter().chain(dependents.iter()) {
+        if let Some(check) = &col.check {
+            for binding in check.bindings()? {
+                if !seen_names.contains(&binding.name) {
+                    bail!(UnknownColumnInCheck(
+                        binding.name.to_string(),
+                        col.name.to_string(),
+                        binding.span
+                    ));
+                }
+            }
+        }
+    }
+
     Ok((
         StoredRelationMetadata {
             keys,
             non_keys: dependents,
+            foreign_keys,
         },
         key_bindings,
         dep_bindings,
     ))
 }
 
-fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
+fn parse_col(
+    pair: Pair<'_>,
+    env: &ParseEnv<'_>,
+) -> Result<(ColumnDef, Symbol, Option<ForeignKey>)> {
     let mut src = pair.into_inner();
     let name_p = src.next().unwrap();
     let name = SmartString::from(name_p.as_str());
@@ -84,14 +137,43 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
         nullable: true,
     };
     let mut default_gen = None;
+    let mut check = None;
+    let mut reference = None;
     let mut binding_candidate = None;
     for nxt in src {
         match nxt.as_rule() {
             Rule::col_type => typing = parse_nullable_type(nxt)?,
-            Rule::expr => default_gen = Some(build_expr(nxt, &Default::default())?),
+            Rule::expr => default_gen = Some(build_expr(nxt, env)?),
             Rule::out_arg => {
                 binding_candidate = Some(Symbol::new(nxt.as_str(), nxt.extract_span()))
             }
+            Rule::col_reference => {
+                let mut inner = nxt.into_inner();
+                let target_p = inner.next().unwrap();
+
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("Foreign key must reference a column as 'relation.column'")]
+                #[diagnostic(code(parser::bad_fk_target))]
+                struct BadForeignKeyTarget(#[label] SourceSpan);
+
+                let (ref_relation, ref_column) = target_p
+                    .as_str()
+                    .rsplit_once('.')
+                    .ok_or_else(|| BadForeignKeyTarget(target_p.extract_span()))?;
+                let on_delete = match inner.next().map(|p| p.as_rule()) {
+                    None | Some(Rule::fk_restrict) => ForeignKeyAction::Restrict,
+                    Some(Rule::fk_cascade) => ForeignKeyAction::Cascade,
+                    Some(Rule::fk_set_null) => ForeignKeyAction::SetNull,
+                    r => unreachable!("{:?}", r),
+                };
+                reference = Some(ForeignKey {
+                    column: name.clone(),
+                    ref_relation: SmartString::from(ref_relation),
+                    ref_column: SmartString::from(ref_column),
+                    on_delete,
+                })
+            }
+            Rule::col_check => check = Some(build_expr(nxt.into_inner().next().unwrap(), env)?),
             r => unreachable!("{:?}", r),
         }
     }
@@ -102,8 +184,10 @@ fn parse_col(pair: Pair<'_>) -> Result<(ColumnDef, Symbol)> {
             name,
             typing,
             default_gen,
+            check,
         },
         binding,
+        reference,
     ))
 }
 
@@ -124,6 +208,10 @@ fn parse_type_inner(pair: Pair<'_>) -> Result<ColType> {
         Rule::uuid_type => ColType::Uuid,
         Rule::json_type => ColType::Json,
         Rule::validity_type => ColType::Validity,
+        Rule::timestamp_type => ColType::Timestamp,
+        Rule::duration_type => ColType::Duration,
+        Rule::decimal_type => ColType::Decimal,
+        Rule::geometry_type => ColType::Geometry,
         Rule::list_type => {
             let mut inner = pair.into_inner();
             let eltype = parse_nullable_type(inner.next().unwrap())?;
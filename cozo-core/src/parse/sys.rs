@@ -16,13 +16,14 @@ use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
 use crate::data::program::InputProgram;
-use crate::data::relation::VecElementType;
+use crate::data::relation::{ColType, ColumnDef, NullableColType, VecElementType};
 use crate::data::symb::Symbol;
-use crate::data::value::{DataValue, ValidityTs};
+use crate::data::value::ValidityTs;
 use crate::fts::TokenizerConfig;
 use crate::parse::expr::{build_expr, parse_string};
 use crate::parse::query::parse_query;
-use crate::parse::{ExtractSpan, Pairs, Rule, SourceSpan};
+use crate::parse::schema::parse_nullable_type;
+use crate::parse::{ExtractSpan, Pairs, ParseEnv, Rule, SourceSpan};
 use crate::runtime::relation::AccessLevel;
 use crate::{Expr, FixedRule};
 
@@ -35,17 +36,31 @@ pub(crate) enum SysOp {
     ListFixedRules,
     KillRunning(u64),
     Explain(Box<InputProgram>),
+    ExplainAnalyze(Box<InputProgram>),
+    AnalyzeRelations(Vec<Symbol>),
     RemoveRelation(Vec<Symbol>),
     RenameRelation(Vec<(Symbol, Symbol)>),
     ShowTrigger(Symbol),
     SetTriggers(Symbol, Vec<String>, Vec<String>, Vec<String>),
     SetAccessLevel(Vec<Symbol>, AccessLevel),
-    CreateIndex(Symbol, Symbol, Vec<Symbol>),
+    CreateIndex(Symbol, Symbol, Vec<Symbol>, bool),
     CreateVectorIndex(HnswIndexConfig),
     CreateFtsIndex(FtsIndexConfig),
     CreateMinHashLshIndex(MinHashLshConfig),
+    CreateRtreeIndex(RtreeIndexConfig),
     RemoveIndex(Symbol, Symbol),
-    DescribeRelation(Symbol, SmartString<LazyCompact>)
+    DescribeRelation(Symbol, SmartString<LazyCompact>),
+    AlterRelation(Symbol, Vec<AlterRelationOp>),
+    CreateView(Symbol, String, Vec<Symbol>),
+    CreateMaterializedView(Symbol, String, Box<InputProgram>),
+    DropView(Symbol),
+}
+
+pub(crate) enum AlterRelationOp {
+    Add(ColumnDef),
+    Drop(Symbol),
+    Rename(Symbol, Symbol),
+    Retype(Symbol, NullableColType),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -71,6 +86,14 @@ pub(crate) struct MinHashLshConfig {
     pub(crate) target_threshold: OrderedFloat<f64>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RtreeIndexConfig {
+    pub(crate) base_relation: SmartString<LazyCompact>,
+    pub(crate) index_name: SmartString<LazyCompact>,
+    pub(crate) extractor: String,
+    pub(crate) max_entries: usize,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct HnswIndexConfig {
     pub(crate) base_relation: SmartString<LazyCompact>,
@@ -102,7 +125,7 @@ struct ProcessIdError(String, #[label] SourceSpan);
 
 pub(crate) fn parse_sys(
     mut src: Pairs<'_>,
-    param_pool: &BTreeMap<String, DataValue>,
+    env: &ParseEnv<'_>,
     algorithms: &BTreeMap<String, Arc<Box<dyn FixedRule>>>,
     cur_vld: ValidityTs,
 ) -> Result<SysOp> {
@@ -112,7 +135,7 @@ pub(crate) fn parse_sys(
         Rule::running_op => SysOp::ListRunning,
         Rule::kill_op => {
             let i_expr = inner.into_inner().next().unwrap();
-            let i_val = build_expr(i_expr, param_pool)?;
+            let i_val = build_expr(i_expr, env)?;
             let i_val = i_val.eval_to_const()?;
             let i_val = i_val
                 .get_int()
@@ -120,13 +143,18 @@ pub(crate) fn parse_sys(
             SysOp::KillRunning(i_val as u64)
         }
         Rule::explain_op => {
-            let prog = parse_query(
-                inner.into_inner().next().unwrap().into_inner(),
-                param_pool,
-                algorithms,
-                cur_vld,
-            )?;
-            SysOp::Explain(Box::new(prog))
+            let mut inner = inner.into_inner();
+            let mut src = inner.next().unwrap();
+            let analyze = src.as_rule() == Rule::explain_analyze;
+            if analyze {
+                src = inner.next().unwrap();
+            }
+            let prog = parse_query(src.into_inner(), env, algorithms, cur_vld)?;
+            if analyze {
+                SysOp::ExplainAnalyze(Box::new(prog))
+            } else {
+                SysOp::Explain(Box::new(prog))
+            }
         }
         Rule::describe_relation_op => {
             let mut inner = inner.into_inner();
@@ -138,6 +166,13 @@ pub(crate) fn parse_sys(
             };
             SysOp::DescribeRelation(rel, description)
         }
+        Rule::analyze_op => {
+            let rels = inner
+                .into_inner()
+                .map(|rels_p| Symbol::new(rels_p.as_str(), rels_p.extract_span()))
+                .collect_vec();
+            SysOp::AnalyzeRelations(rels)
+        }
         Rule::list_relations_op => SysOp::ListRelations,
         Rule::remove_relations_op => {
             let rel = inner
@@ -244,7 +279,7 @@ pub(crate) fn parse_sys(
                         let opt_val = opt_inner.next().unwrap();
                         match opt_name.as_str() {
                             "false_positive_weight" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 false_positive_weight = v.get_float().ok_or_else(|| {
@@ -252,7 +287,7 @@ pub(crate) fn parse_sys(
                                 })?;
                             }
                             "false_negative_weight" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 false_negative_weight = v.get_float().ok_or_else(|| {
@@ -260,7 +295,7 @@ pub(crate) fn parse_sys(
                                 })?;
                             }
                             "n_gram" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 n_gram = v
@@ -269,7 +304,7 @@ pub(crate) fn parse_sys(
                                     as usize;
                             }
                             "n_perm" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 n_perm = v
@@ -278,7 +313,7 @@ pub(crate) fn parse_sys(
                                     as usize;
                             }
                             "target_threshold" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 let v = expr.eval_to_const()?;
                                 target_threshold = v
@@ -286,17 +321,17 @@ pub(crate) fn parse_sys(
                                     .ok_or_else(|| miette!("target_threshold must be a float"))?;
                             }
                             "extractor" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, env)?;
                                 ex.partial_eval()?;
                                 extractor = ex.to_string();
                             }
                             "extract_filter" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, env)?;
                                 ex.partial_eval()?;
                                 extract_filter = ex.to_string();
                             }
                             "tokenizer" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::UnboundApply { op, args, .. } => {
@@ -316,7 +351,7 @@ pub(crate) fn parse_sys(
                                 }
                             }
                             "filters" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::Apply { op, args, .. } => {
@@ -400,6 +435,74 @@ pub(crate) fn parse_sys(
                 r => unreachable!("{:?}", r),
             }
         }
+        Rule::rtree_idx_op => {
+            let inner = inner.into_inner().next().unwrap();
+            match inner.as_rule() {
+                Rule::index_create_adv => {
+                    let mut inner = inner.into_inner();
+                    let rel = inner.next().unwrap();
+                    let name = inner.next().unwrap();
+                    let mut extractor = "".to_string();
+                    let mut extract_filter = "".to_string();
+                    let mut max_entries = 16;
+                    for opt_pair in inner {
+                        let mut opt_inner = opt_pair.into_inner();
+                        let opt_name = opt_inner.next().unwrap();
+                        let opt_val = opt_inner.next().unwrap();
+                        match opt_name.as_str() {
+                            "max_entries" => {
+                                let mut expr = build_expr(opt_val, env)?;
+                                expr.partial_eval()?;
+                                let v = expr.eval_to_const()?;
+                                max_entries = v
+                                    .get_int()
+                                    .and_then(|i| usize::try_from(i).ok())
+                                    .ok_or_else(|| {
+                                        miette!("max_entries must be a non-negative integer")
+                                    })?;
+                            }
+                            "extractor" => {
+                                let mut ex = build_expr(opt_val, env)?;
+                                ex.partial_eval()?;
+                                extractor = ex.to_string();
+                            }
+                            "extract_filter" => {
+                                let mut ex = build_expr(opt_val, env)?;
+                                ex.partial_eval()?;
+                                extract_filter = ex.to_string();
+                            }
+                            _ => bail!("Unknown option {} for R-tree index", opt_name.as_str()),
+                        }
+                    }
+                    ensure!(
+                        !extractor.is_empty(),
+                        "extractor must be given for R-tree index"
+                    );
+                    ensure!(max_entries >= 4, "max_entries must be at least 4");
+
+                    if !extract_filter.is_empty() {
+                        extractor = format!("if({}, {})", extract_filter, extractor);
+                    }
+
+                    SysOp::CreateRtreeIndex(RtreeIndexConfig {
+                        base_relation: SmartString::from(rel.as_str()),
+                        index_name: SmartString::from(name.as_str()),
+                        extractor,
+                        max_entries,
+                    })
+                }
+                Rule::index_drop => {
+                    let mut inner = inner.into_inner();
+                    let rel = inner.next().unwrap();
+                    let name = inner.next().unwrap();
+                    SysOp::RemoveIndex(
+                        Symbol::new(rel.as_str(), rel.extract_span()),
+                        Symbol::new(name.as_str(), name.extract_span()),
+                    )
+                }
+                r => unreachable!("{:?}", r),
+            }
+        }
         Rule::fts_idx_op => {
             let inner = inner.into_inner().next().unwrap();
             match inner.as_rule() {
@@ -420,17 +523,17 @@ pub(crate) fn parse_sys(
                         let opt_val = opt_inner.next().unwrap();
                         match opt_name.as_str() {
                             "extractor" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, env)?;
                                 ex.partial_eval()?;
                                 extractor = ex.to_string();
                             }
                             "extract_filter" => {
-                                let mut ex = build_expr(opt_val, param_pool)?;
+                                let mut ex = build_expr(opt_val, env)?;
                                 ex.partial_eval()?;
                                 extract_filter = ex.to_string();
                             }
                             "tokenizer" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::UnboundApply { op, args, .. } => {
@@ -450,7 +553,7 @@ pub(crate) fn parse_sys(
                                 }
                             }
                             "filters" => {
-                                let mut expr = build_expr(opt_val, param_pool)?;
+                                let mut expr = build_expr(opt_val, env)?;
                                 expr.partial_eval()?;
                                 match expr {
                                     Expr::Apply { op, args, .. } => {
@@ -535,7 +638,7 @@ pub(crate) fn parse_sys(
                         let opt_val_str = opt_val.as_str();
                         match opt_name.as_str() {
                             "dim" => {
-                                let v = build_expr(opt_val, param_pool)?
+                                let v = build_expr(opt_val, env)?
                                     .eval_to_const()?
                                     .get_int()
                                     .ok_or_else(|| miette!("Invalid vec_dim: {}", opt_val_str))?;
@@ -543,7 +646,7 @@ pub(crate) fn parse_sys(
                                 vec_dim = v as usize;
                             }
                             "ef_construction" | "ef" => {
-                                let v = build_expr(opt_val, param_pool)?
+                                let v = build_expr(opt_val, env)?
                                     .eval_to_const()?
                                     .get_int()
                                     .ok_or_else(|| {
@@ -553,7 +656,7 @@ pub(crate) fn parse_sys(
                                 ef_construction = v as usize;
                             }
                             "m_neighbours" | "m" => {
-                                let v = build_expr(opt_val, param_pool)?
+                                let v = build_expr(opt_val, env)?
                                     .eval_to_const()?
                                     .get_int()
                                     .ok_or_else(|| {
@@ -637,7 +740,14 @@ pub(crate) fn parse_sys(
             match inner.as_rule() {
                 Rule::index_create => {
                     let span = inner.extract_span();
-                    let mut inner = inner.into_inner();
+                    let mut inner = inner.into_inner().peekable();
+                    let unique = match inner.peek() {
+                        Some(p) if p.as_rule() == Rule::index_unique => {
+                            inner.next();
+                            true
+                        }
+                        _ => false,
+                    };
                     let rel = inner.next().unwrap();
                     let name = inner.next().unwrap();
                     let cols = inner
@@ -654,6 +764,7 @@ pub(crate) fn parse_sys(
                         Symbol::new(rel.as_str(), rel.extract_span()),
                         Symbol::new(name.as_str(), name.extract_span()),
                         cols,
+                        unique,
                     )
                 }
                 Rule::index_drop => {
@@ -668,6 +779,111 @@ pub(crate) fn parse_sys(
                 _ => unreachable!(),
             }
         }
+        Rule::alter_relation_op => {
+            let mut src = inner.into_inner();
+            let rels_p = src.next().unwrap();
+            let rel = Symbol::new(rels_p.as_str(), rels_p.extract_span());
+            let mut ops = vec![];
+            for clause in src {
+                let clause_rule = clause.as_rule();
+                let mut clause_inner = clause.into_inner();
+                let name_p = clause_inner.next().unwrap();
+                let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+                ops.push(match clause_rule {
+                    Rule::alter_add => {
+                        let mut typing = NullableColType {
+                            coltype: ColType::Any,
+                            nullable: true,
+                        };
+                        let mut default_gen = None;
+                        for nxt in clause_inner {
+                            match nxt.as_rule() {
+                                Rule::col_type => typing = parse_nullable_type(nxt)?,
+                                Rule::expr => {
+                                    // defaults are persisted, so parameters are fixed
+                                    // to their current values
+                                    let env = ParseEnv {
+                                        params: env.params,
+                                        functions: env.functions,
+                                        ..Default::default()
+                                    };
+                                    default_gen = Some(build_expr(nxt, &env)?)
+                                }
+                                r => unreachable!("{:?}", r),
+                            }
+                        }
+                        AlterRelationOp::Add(ColumnDef {
+                            name: name.name,
+                            typing,
+                            default_gen,
+                            check: None,
+                        })
+                    }
+                    Rule::alter_drop => AlterRelationOp::Drop(name),
+                    Rule::alter_rename => {
+                        let new_p = clause_inner.next().unwrap();
+                        let new_name = Symbol::new(new_p.as_str(), new_p.extract_span());
+                        AlterRelationOp::Rename(name, new_name)
+                    }
+                    Rule::alter_retype => {
+                        let typing = parse_nullable_type(clause_inner.next().unwrap())?;
+                        AlterRelationOp::Retype(name, typing)
+                    }
+                    r => unreachable!("{:?}", r),
+                });
+            }
+
+            #[derive(Debug, Diagnostic, Error)]
+            #[error("alter must specify at least one operation")]
+            #[diagnostic(code(parser::empty_alter))]
+            struct EmptyAlter(#[label] SourceSpan);
+
+            ensure!(!ops.is_empty(), EmptyAlter(rel.span));
+            SysOp::AlterRelation(rel, ops)
+        }
+        Rule::view_op => {
+            let inner = inner.into_inner().next().unwrap();
+            let view_kind = inner.as_rule();
+            match view_kind {
+                Rule::view_create | Rule::view_materialize => {
+                    let mut inner = inner.into_inner();
+                    let name_p = inner.next().unwrap();
+                    let name = Symbol::new(name_p.as_str(), name_p.extract_span());
+                    let script = inner.next().unwrap();
+                    let script_str = script.as_str();
+                    // views are persisted as text and parsed again when used,
+                    // so parameters cannot be captured
+                    let view_env = ParseEnv {
+                        params: &Default::default(),
+                        prepared: None,
+                        ..*env
+                    };
+                    let prog = parse_query(script.into_inner(), &view_env, algorithms, cur_vld)?;
+
+                    #[derive(Debug, Diagnostic, Error)]
+                    #[error("View {0} cannot specify query options")]
+                    #[diagnostic(code(parser::options_in_view))]
+                    #[diagnostic(help("A view may only contain rules"))]
+                    struct OptionsInView(String, #[label] SourceSpan);
+
+                    ensure!(
+                        prog.out_opts == Default::default(),
+                        OptionsInView(name.name.to_string(), name.span)
+                    );
+                    if view_kind == Rule::view_materialize {
+                        SysOp::CreateMaterializedView(name, script_str.to_string(), Box::new(prog))
+                    } else {
+                        let columns = prog.get_entry_out_head_or_default()?;
+                        SysOp::CreateView(name, script_str.to_string(), columns)
+                    }
+                }
+                Rule::view_drop => {
+                    let name_p = inner.into_inner().next().unwrap();
+                    SysOp::DropView(Symbol::new(name_p.as_str(), name_p.extract_span()))
+                }
+                r => unreachable!("{:?}", r),
+            }
+        }
         Rule::list_fixed_rules => SysOp::ListFixedRules,
         r => unreachable!("{:?}", r),
     })
@@ -23,6 +23,7 @@ use crate::data::value::DataValue;
 use crate::parse::SourceSpan;
 use crate::query::ra::RelAlgebra;
 use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel};
+use crate::runtime::stats::SEEK_COST;
 use crate::runtime::transact::SessionTx;
 
 pub(crate) type CompiledProgram = BTreeMap<MagicSymbol, CompiledRuleSet>;
@@ -176,6 +177,9 @@ impl<'a> SessionTx<'a> {
             serial_id += 1;
             ret
         };
+        // estimated number of rows produced so far, known only when
+        // the rule has been reading from analyzed stored relations
+        let mut est_rows = Some(1.);
         for atom in &rule.body {
             match atom {
                 MagicAtom::Rule(rule_app) => {
@@ -215,6 +219,7 @@ impl<'a> SessionTx<'a> {
                         RelAlgebra::derived(right_vars, rule_app.name.clone(), rule_app.span);
                     debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                     ret = ret.join(right, prev_joiner_vars, right_joiner_vars, rule_app.span);
+                    est_rows = None;
                 }
                 MagicAtom::Relation(rel_app) => {
                     let store = self.get_relation(&rel_app.name, false)?;
@@ -264,6 +269,23 @@ impl<'a> SessionTx<'a> {
                         }
                     }
 
+                    // a single scan of the relation beats a lookup for each row so far
+                    // when there are many more rows than there are in the relation
+                    let scan_is_cheaper = match (est_rows, &store.stats) {
+                        (Some(rows), Some(stats)) => {
+                            est_rows = Some(
+                                rows * stats.rows_per_probe(
+                                    join_indices.iter().map(|u| *u == IndexPositionUse::Join),
+                                ),
+                            );
+                            !prev_joiner_vars.is_empty() && rows * SEEK_COST > stats.rows as f64
+                        }
+                        _ => {
+                            est_rows = None;
+                            false
+                        }
+                    };
+
                     let chosen_index =
                         store.choose_index(&join_indices, rel_app.valid_at.is_some());
 
@@ -279,6 +301,9 @@ impl<'a> SessionTx<'a> {
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                             ret =
                                 ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
+                            if scan_is_cheaper {
+                                ret.materialize_right();
+                            }
                         }
                         Some((chosen_index, mapper, false)) => {
                             // index-only
@@ -295,6 +320,9 @@ impl<'a> SessionTx<'a> {
                             debug_assert_eq!(prev_joiner_vars.len(), right_joiner_vars.len());
                             ret =
                                 ret.join(right, prev_joiner_vars, right_joiner_vars, rel_app.span);
+                            if scan_is_cheaper {
+                                ret.materialize_right();
+                            }
                         }
                         Some((chosen_index, mapper, true)) => {
                             // index-with-join
@@ -478,6 +506,7 @@ impl<'a> SessionTx<'a> {
                     ret = ret.filter(p.clone())?;
                 }
                 MagicAtom::HnswSearch(s) => {
+                    est_rows = None;
                     debug_assert!(
                         seen_variables.contains(&s.query),
                         "HNSW search query must be bound"
@@ -512,6 +541,7 @@ impl<'a> SessionTx<'a> {
                     }
                 }
                 MagicAtom::FtsSearch(s) => {
+                    est_rows = None;
                     debug_assert!(
                         seen_variables.contains(&s.query),
                         "FTS search query must be bound"
@@ -546,6 +576,7 @@ impl<'a> SessionTx<'a> {
                     }
                 }
                 MagicAtom::LshSearch(s) => {
+                    est_rows = None;
                     debug_assert!(
                         seen_variables.contains(&s.query),
                         "FTS search query must be bound"
@@ -579,7 +610,45 @@ impl<'a> SessionTx<'a> {
                         ret = ret.filter(Expr::build_and(post_filters, s.span))?;
                     }
                 }
+                MagicAtom::RtreeSearch(s) => {
+                    est_rows = None;
+                    debug_assert!(
+                        s.input_bindings().all(|b| seen_variables.contains(b)),
+                        "R-tree search input must be bound"
+                    );
+                    let mut own_bindings = vec![];
+                    let mut post_filters = vec![];
+                    for var in s.all_bindings() {
+                        if seen_variables.contains(var) {
+                            let rk = gen_symb(var.span);
+                            post_filters.push(Expr::build_equate(
+                                vec![
+                                    Expr::Binding {
+                                        var: var.clone(),
+                                        tuple_pos: None,
+                                    },
+                                    Expr::Binding {
+                                        var: rk.clone(),
+                                        tuple_pos: None,
+                                    },
+                                ],
+                                var.span,
+                            ));
+                            own_bindings.push(rk);
+                        } else {
+                            seen_variables.insert(var.clone());
+                            own_bindings.push(var.clone());
+                        }
+                    }
+                    ret = ret.rtree_search(s.clone(), own_bindings)?;
+                    if !post_filters.is_empty() {
+                        ret = ret.filter(Expr::build_and(post_filters, s.span))?;
+                    }
+                }
                 MagicAtom::Unification(u) => {
+                    if u.one_many_unif {
+                        est_rows = None;
+                    }
                     if seen_variables.contains(&u.binding) {
                         let expr = if u.one_many_unif {
                             Expr::build_is_in(
@@ -62,6 +62,8 @@ impl QueryLimiter {
 }
 
 impl<'a> SessionTx<'a> {
+    /// Returns the store of the entry, the other stores alive at the end,
+    /// and whether early return is activated
     pub(crate) fn stratified_magic_evaluate(
         &self,
         strata: &[CompiledProgram],
@@ -69,27 +71,58 @@ impl<'a> SessionTx<'a> {
         total_num_to_take: Option<usize>,
         num_to_skip: Option<usize>,
         poison: Poison,
-    ) -> Result<(EpochStore, bool)> {
+    ) -> Result<(EpochStore, BTreeMap<MagicSymbol, EpochStore>, bool)> {
+        let (mut stores, early_return) = self.evaluate_strata(
+            strata,
+            store_lifetimes,
+            total_num_to_take,
+            num_to_skip,
+            poison,
+        )?;
+        let entry_symbol = MagicSymbol::Muggle {
+            inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
+        };
+        let ret_area = stores.remove(&entry_symbol).ok_or(NoEntryError)?;
+        Ok((ret_area, stores, early_return))
+    }
+    /// Evaluates all strata, returning the stores that are still alive at the end
+    pub(crate) fn evaluate_strata(
+        &self,
+        strata: &[CompiledProgram],
+        store_lifetimes: BTreeMap<MagicSymbol, usize>,
+        total_num_to_take: Option<usize>,
+        num_to_skip: Option<usize>,
+        poison: Poison,
+    ) -> Result<(BTreeMap<MagicSymbol, EpochStore>, bool)> {
         let mut stores: BTreeMap<MagicSymbol, EpochStore> = BTreeMap::new();
         let mut early_return = false;
         for (stratum, cur_prog) in strata.iter().enumerate() {
             if stratum > 0 {
                 // remove stores that have outlived their usefulness!
-                stores.retain(|name, _| match store_lifetimes.get(name) {
-                    None => false,
-                    Some(n) => *n >= stratum,
+                stores.retain(|name, store| {
+                    let keep = match store_lifetimes.get(name) {
+                        None => false,
+                        Some(n) => *n >= stratum,
+                    };
+                    if !keep {
+                        let (rows, bytes) = store.size();
+                        self.usage.release(rows, bytes);
+                    }
+                    keep
                 });
                 trace!("{:?}", stores);
             }
             for (rule_name, rule_set) in cur_prog {
                 let store = match rule_set.aggr_kind() {
-                    AggrKind::None | AggrKind::Normal => EpochStore::new_normal(rule_set.arity()),
+                    AggrKind::None | AggrKind::Normal => {
+                        EpochStore::new_normal(rule_set.arity(), self.temp_store_budget)
+                    }
                     AggrKind::Meet => {
                         let rs = match rule_set {
                             CompiledRuleSet::Rules(rs) => rs,
                             _ => unreachable!(),
                         };
-                        EpochStore::new_meet(&rs[0].aggr)?
+                        EpochStore::new_meet(&rs[0].aggr, self.temp_store_budget)?
                     }
                 };
                 stores.insert(rule_name.clone(), store);
@@ -103,11 +136,7 @@ impl<'a> SessionTx<'a> {
                 poison.clone(),
             )?;
         }
-        let entry_symbol = MagicSymbol::Muggle {
-            inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
-        };
-        let ret_area = stores.remove(&entry_symbol).ok_or(NoEntryError)?;
-        Ok((ret_area, early_return))
+        Ok((stores, early_return))
     }
     /// returns true if early return is activated
     fn semi_naive_magic_evaluate(
@@ -176,6 +205,8 @@ impl<'a> SessionTx<'a> {
                                 tx: self,
                             };
                             fixed_impl.run(payload, &mut out, poison.clone())?;
+                            let (rows, bytes) = out.size();
+                            self.usage.charge(rows, bytes)?;
                             out.wrap()
                         }
                     };
@@ -291,7 +322,7 @@ impl<'a> SessionTx<'a> {
             let mut changed = false;
             for (k, new_store) in to_merge {
                 let old_store = stores.get_mut(k).unwrap();
-                old_store.merge_in(new_store)?;
+                old_store.merge_in(new_store, &self.usage)?;
                 trace!("delta for {}: {}", k, old_store.has_delta());
                 changed |= old_store.has_delta();
             }
@@ -301,6 +332,11 @@ impl<'a> SessionTx<'a> {
         }
         Ok(used_limiter.load(Ordering::Acquire))
     }
+    fn record_iteration(&self, rule: &CompiledRule) {
+        if let Some(profile) = &self.profile {
+            profile.record_iteration(rule);
+        }
+    }
     /// returns true is early return is activated
     fn initial_rule_non_aggr_eval(
         &self,
@@ -310,20 +346,21 @@ impl<'a> SessionTx<'a> {
         limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_budget(self.temp_store_budget);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
 
         for (rule_n, rule) in ruleset.iter().enumerate() {
             debug!("initial calculation for rule {:?}.{}", rule_symb, rule_n);
+            self.record_iteration(rule);
             for item_res in rule.relation.iter(self, None, stores)? {
                 let item = item_res?;
                 trace!("item for {:?}.{}: {:?} at {}", rule_symb, rule_n, item, 0);
                 if should_check_limit {
-                    if !out_store.exists(&item) {
+                    if !out_store.exists(&item)? {
                         if limiter.should_skip_next() {
-                            out_store.put_with_skip(item);
+                            out_store.put_charged(item, true, &self.usage)?;
                         } else {
-                            out_store.put(item);
+                            out_store.put_charged(item, false, &self.usage)?;
                         }
                         if limiter.incr_and_should_stop() {
                             trace!("early stopping due to result count limit exceeded");
@@ -331,7 +368,7 @@ impl<'a> SessionTx<'a> {
                         }
                     }
                 } else {
-                    out_store.put(item);
+                    out_store.put_charged(item, false, &self.usage)?;
                 }
             }
             poison.check()?;
@@ -346,10 +383,11 @@ impl<'a> SessionTx<'a> {
         stores: &BTreeMap<MagicSymbol, EpochStore>,
         poison: Poison,
     ) -> Result<MeetAggrStore> {
-        let mut out_store = MeetAggrStore::new(ruleset[0].aggr.clone())?;
+        let mut out_store = MeetAggrStore::new(ruleset[0].aggr.clone(), self.temp_store_budget)?;
 
         for (rule_n, rule) in ruleset.iter().enumerate() {
             debug!("initial calculation for rule {:?}.{}", rule_symb, rule_n);
+            self.record_iteration(rule);
             let mut aggr = rule.aggr.clone();
             for (aggr, args) in aggr.iter_mut().flatten() {
                 aggr.meet_init(args)?;
@@ -357,7 +395,7 @@ impl<'a> SessionTx<'a> {
             for item_res in rule.relation.iter(self, None, stores)? {
                 let item = item_res?;
                 trace!("item for {:?}.{}: {:?} at {}", rule_symb, rule_n, item, 0);
-                out_store.meet_put(item)?;
+                out_store.meet_put(item, &self.usage)?;
             }
             poison.check()?;
         }
@@ -374,7 +412,7 @@ impl<'a> SessionTx<'a> {
                     Ok(op.init_val())
                 })
                 .try_collect()?;
-            out_store.meet_put(value)?;
+            out_store.meet_put(value, &self.usage)?;
         }
         Ok(out_store)
     }
@@ -386,7 +424,7 @@ impl<'a> SessionTx<'a> {
         limiter: &QueryLimiter,
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_budget(self.temp_store_budget);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
         let mut aggr_work: BTreeMap<Vec<DataValue>, Vec<Aggregation>> = BTreeMap::new();
 
@@ -396,6 +434,7 @@ impl<'a> SessionTx<'a> {
                 rule_symb, rule_n
             );
             trace!("{:?}", rule);
+            self.record_iteration(rule);
 
             let keys_indices = rule
                 .aggr
@@ -471,7 +510,7 @@ impl<'a> SessionTx<'a> {
                     op.get()
                 })
                 .try_collect()?;
-            out_store.put(empty_result);
+            out_store.put_charged(empty_result, false, &self.usage)?;
         }
 
         for (keys, aggrs) in aggr_work {
@@ -487,11 +526,11 @@ impl<'a> SessionTx<'a> {
                 .try_collect()?;
             let tuple = tuple_data;
             if should_check_limit {
-                if !out_store.exists(&tuple) {
+                if !out_store.exists(&tuple)? {
                     if limiter.should_skip_next() {
-                        out_store.put_with_skip(tuple);
+                        out_store.put_charged(tuple, true, &self.usage)?;
                     } else {
-                        out_store.put(tuple);
+                        out_store.put_charged(tuple, false, &self.usage)?;
                     }
                     if limiter.incr_and_should_stop() {
                         return Ok((true, out_store));
@@ -499,7 +538,7 @@ impl<'a> SessionTx<'a> {
                 }
                 // else, do nothing
             } else {
-                out_store.put(tuple);
+                out_store.put_charged(tuple, false, &self.usage)?;
             }
         }
         Ok((should_check_limit, out_store))
@@ -514,7 +553,7 @@ impl<'a> SessionTx<'a> {
         poison: Poison,
     ) -> Result<(bool, RegularTempStore)> {
         let prev_store = stores.get(rule_symb).unwrap();
-        let mut out_store = RegularTempStore::default();
+        let mut out_store = RegularTempStore::with_budget(self.temp_store_budget);
         let should_check_limit = limiter.total.is_some() && rule_symb.is_prog_entry();
         for (rule_n, rule) in ruleset.iter().enumerate() {
             let mut need_complete_run = false;
@@ -533,13 +572,14 @@ impl<'a> SessionTx<'a> {
             if !dependencies_changed {
                 continue;
             }
+            self.record_iteration(rule);
 
             if need_complete_run {
                 debug!("complete rule for rule {:?}.{}", rule_symb, rule_n);
                 for item_res in rule.relation.iter(self, None, stores)? {
                     let item = item_res?;
                     // improvement: the clauses can actually be evaluated in parallel
-                    if prev_store.exists(&item) {
+                    if prev_store.exists(&item)? {
                         trace!(
                             "item for {:?}.{}: {:?} at {}, rederived",
                             rule_symb,
@@ -556,9 +596,9 @@ impl<'a> SessionTx<'a> {
                             epoch
                         );
                         if limiter.should_skip_next() {
-                            out_store.put_with_skip(item);
+                            out_store.put_charged(item, true, &self.usage)?;
                         } else {
-                            out_store.put(item);
+                            out_store.put_charged(item, false, &self.usage)?;
                         }
                         if should_check_limit && limiter.incr_and_should_stop() {
                             trace!("early stopping due to result count limit exceeded");
@@ -579,7 +619,7 @@ impl<'a> SessionTx<'a> {
                     for item_res in rule.relation.iter(self, Some(delta_key), stores)? {
                         let item = item_res?;
                         // improvement: the clauses can actually be evaluated in parallel
-                        if prev_store.exists(&item) {
+                        if prev_store.exists(&item)? {
                             trace!(
                                 "item for {:?}.{}: {:?} at {}, rederived",
                                 rule_symb,
@@ -596,9 +636,9 @@ impl<'a> SessionTx<'a> {
                                 epoch
                             );
                             if limiter.should_skip_next() {
-                                out_store.put_with_skip(item);
+                                out_store.put_charged(item, true, &self.usage)?;
                             } else {
-                                out_store.put(item);
+                                out_store.put_charged(item, false, &self.usage)?;
                             }
                             if should_check_limit && limiter.incr_and_should_stop() {
                                 trace!("early stopping due to result count limit exceeded");
@@ -619,7 +659,7 @@ impl<'a> SessionTx<'a> {
         stores: &BTreeMap<MagicSymbol, EpochStore>,
         poison: Poison,
     ) -> Result<MeetAggrStore> {
-        let mut out_store = MeetAggrStore::new(ruleset[0].aggr.clone())?;
+        let mut out_store = MeetAggrStore::new(ruleset[0].aggr.clone(), self.temp_store_budget)?;
         for (rule_n, rule) in ruleset.iter().enumerate() {
             let mut need_complete_run = false;
             let mut dependencies_changed = false;
@@ -637,6 +677,7 @@ impl<'a> SessionTx<'a> {
             if !dependencies_changed {
                 continue;
             }
+            self.record_iteration(rule);
 
             let mut aggr = rule.aggr.clone();
             for (aggr, args) in aggr.iter_mut().flatten() {
@@ -646,7 +687,7 @@ impl<'a> SessionTx<'a> {
             if need_complete_run {
                 debug!("complete run for rule {:?}.{}", rule_symb, rule_n);
                 for item_res in rule.relation.iter(self, None, stores)? {
-                    out_store.meet_put(item_res?)?;
+                    out_store.meet_put(item_res?, &self.usage)?;
                 }
                 poison.check()?;
             } else {
@@ -659,7 +700,7 @@ impl<'a> SessionTx<'a> {
                         delta_key, rule_symb, rule_n
                     );
                     for item_res in rule.relation.iter(self, Some(delta_key), stores)? {
-                        out_store.meet_put(item_res?)?;
+                        out_store.meet_put(item_res?, &self.usage)?;
                     }
                     poison.check()?;
                 }
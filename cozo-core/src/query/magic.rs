@@ -52,8 +52,14 @@ impl NormalFormProgram {
 }
 
 impl StratifiedNormalFormProgram {
-    pub(crate) fn magic_sets_rewrite(self, tx: &SessionTx<'_>) -> Result<StratifiedMagicProgram> {
+    /// The entry and the rules in `roots` are not rewritten, as all their rows are needed
+    pub(crate) fn magic_sets_rewrite(
+        self,
+        tx: &SessionTx<'_>,
+        roots: &[Symbol],
+    ) -> Result<StratifiedMagicProgram> {
         let mut exempt_rules = BTreeSet::from([Symbol::new(PROG_ENTRY, SourceSpan(0, 0))]);
+        exempt_rules.extend(roots.iter().cloned());
         let mut collected = vec![];
         for prog in self.0 {
             prog.exempt_aggr_rules_for_magic_sets(&mut exempt_rules);
@@ -188,6 +194,10 @@ fn magic_rewrite_ruleset(
                     seen_bindings.extend(s.all_bindings().cloned());
                     collected_atoms.push(MagicAtom::LshSearch(s));
                 }
+                MagicAtom::RtreeSearch(s) => {
+                    seen_bindings.extend(s.all_bindings().cloned());
+                    collected_atoms.push(MagicAtom::RtreeSearch(s));
+                }
                 MagicAtom::Rule(r_app) => {
                     if r_app.name.has_bound_adornment() {
                         // we are guaranteed to have a magic rule application
@@ -555,6 +565,14 @@ impl NormalFormAtom {
                 }
                 MagicAtom::LshSearch(s.clone())
             }
+            NormalFormAtom::RtreeSearch(s) => {
+                for arg in s.all_bindings() {
+                    if !seen_bindings.contains(arg) {
+                        seen_bindings.insert(arg.clone());
+                    }
+                }
+                MagicAtom::RtreeSearch(s.clone())
+            }
 
             NormalFormAtom::Predicate(p) => {
                 // predicate cannot introduce new bindings
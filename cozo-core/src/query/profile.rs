@@ -0,0 +1,151 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Runtime statistics collected by `::explain analyze`.
+//! Operators and rules are identified by their addresses in the compiled program,
+//! which stays in place during evaluation.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use miette::Result;
+
+use crate::data::tuple::TupleIter;
+use crate::query::compile::{CompiledProgram, CompiledRule, CompiledRuleSet};
+use crate::query::ra::RelAlgebra;
+
+#[derive(Default)]
+pub(crate) struct OpStats {
+    iterated: AtomicBool,
+    rows: AtomicU64,
+    seeks: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl OpStats {
+    /// Rows produced, if the operator was iterated on its own
+    /// rather than looked up into by a join
+    pub(crate) fn rows(&self) -> Option<u64> {
+        if self.iterated.load(Ordering::Relaxed) {
+            Some(self.rows.load(Ordering::Relaxed))
+        } else {
+            None
+        }
+    }
+    /// Number of lookups and scans issued against the storage
+    pub(crate) fn seeks(&self) -> u64 {
+        self.seeks.load(Ordering::Relaxed)
+    }
+    /// Time spent producing rows, including the time spent in the inputs
+    pub(crate) fn millis(&self) -> Option<f64> {
+        if self.iterated.load(Ordering::Relaxed) {
+            Some(self.nanos.load(Ordering::Relaxed) as f64 / 1_000_000.)
+        } else {
+            None
+        }
+    }
+    pub(crate) fn record_seek(&self) {
+        self.seeks.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Times the creation of the iterator of the operator, and counts and times
+    /// the rows it yields afterwards
+    pub(crate) fn profile_iter<'a>(
+        &'a self,
+        make_iter: impl FnOnce() -> Result<TupleIter<'a>>,
+    ) -> Result<TupleIter<'a>> {
+        self.iterated.store(true, Ordering::Relaxed);
+        let timer = Timer::start();
+        let inner = make_iter();
+        timer.stop(&self.nanos);
+        let mut inner = inner?;
+        Ok(Box::new(std::iter::from_fn(move || {
+            let timer = Timer::start();
+            let ret = inner.next();
+            timer.stop(&self.nanos);
+            if let Some(Ok(_)) = &ret {
+                self.rows.fetch_add(1, Ordering::Relaxed);
+            }
+            ret
+        })))
+    }
+}
+
+struct Timer {
+    #[cfg(not(target_arch = "wasm32"))]
+    started: Instant,
+}
+
+impl Timer {
+    fn start() -> Self {
+        Self {
+            #[cfg(not(target_arch = "wasm32"))]
+            started: Instant::now(),
+        }
+    }
+    #[allow(unused_variables)]
+    fn stop(self, acc: &AtomicU64) {
+        #[cfg(not(target_arch = "wasm32"))]
+        acc.fetch_add(self.started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct QueryProfile {
+    ops: BTreeMap<usize, OpStats>,
+    iterations: BTreeMap<usize, AtomicU64>,
+}
+
+fn addr<T>(v: &T) -> usize {
+    v as *const T as usize
+}
+
+impl QueryProfile {
+    pub(crate) fn new(strata: &[CompiledProgram]) -> Self {
+        let mut ret = Self::default();
+        for prog in strata {
+            for rule_set in prog.values() {
+                if let CompiledRuleSet::Rules(rules) = rule_set {
+                    for rule in rules {
+                        ret.iterations.insert(addr(rule), Default::default());
+                        ret.register(&rule.relation);
+                    }
+                }
+            }
+        }
+        ret
+    }
+    fn register(&mut self, rel: &RelAlgebra) {
+        self.ops.insert(addr(rel), Default::default());
+        for child in rel.children() {
+            self.register(child);
+        }
+    }
+    pub(crate) fn op(&self, rel: &RelAlgebra) -> Option<&OpStats> {
+        self.ops.get(&addr(rel))
+    }
+    pub(crate) fn record_iteration(&self, rule: &CompiledRule) {
+        if let Some(n) = self.iterations.get(&addr(rule)) {
+            n.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    /// Number of semi-naive iterations in which the rule was evaluated
+    pub(crate) fn iterations(&self, rule: &CompiledRule) -> Option<u64> {
+        self.iterations
+            .get(&addr(rule))
+            .map(|n| n.load(Ordering::Relaxed))
+    }
+    /// Sum of the rows produced by the inputs of the operator that were iterated
+    pub(crate) fn rows_in(&self, rel: &RelAlgebra) -> Option<u64> {
+        rel.children()
+            .into_iter()
+            .filter_map(|child| self.op(child)?.rows())
+            .reduce(|a, b| a + b)
+    }
+}
@@ -6,7 +6,7 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::{Debug, Formatter, Write};
 use std::iter;
 
@@ -18,14 +18,17 @@ use smartstring::SmartString;
 use thiserror::Error;
 
 use crate::data::expr::{compute_bounds, eval_bytecode, eval_bytecode_pred, Bytecode, Expr};
+use crate::data::functions::{val2bbox, val2geometry};
 use crate::data::program::{FtsSearch, HnswSearch, MagicSymbol};
 use crate::data::relation::{ColType, NullableColType};
 use crate::data::symb::Symbol;
 use crate::data::tuple::{Tuple, TupleIter};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::parse::SourceSpan;
+use crate::runtime::db::HeldRows;
 use crate::runtime::minhash_lsh::LshSearch;
 use crate::runtime::relation::RelationHandle;
+use crate::runtime::rtree::RtreeSearch;
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 use crate::utils::swap_option_result;
@@ -43,6 +46,7 @@ pub(crate) enum RelAlgebra {
     HnswSearch(HnswSearchRA),
     FtsSearch(FtsSearchRA),
     LshSearch(LshSearchRA),
+    RtreeSearch(RtreeSearchRA),
 }
 
 impl RelAlgebra {
@@ -60,6 +64,7 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(i) => i.hnsw_search.span,
             RelAlgebra::FtsSearch(i) => i.fts_search.span,
             RelAlgebra::LshSearch(i) => i.lsh_search.span,
+            RelAlgebra::RtreeSearch(i) => i.rtree_search.span,
         }
     }
 }
@@ -299,6 +304,11 @@ impl Debug for RelAlgebra {
                 .field(&bindings)
                 .field(&s.lsh_search.idx_handle.name)
                 .finish(),
+            RelAlgebra::RtreeSearch(s) => f
+                .debug_tuple("RtreeSearch")
+                .field(&bindings)
+                .field(&s.rtree_search.idx_handle.name)
+                .finish(),
             RelAlgebra::StoredWithValidity(r) => f
                 .debug_tuple("StoredWithValidity")
                 .field(&bindings)
@@ -374,6 +384,9 @@ impl RelAlgebra {
             RelAlgebra::LshSearch(s) => {
                 s.fill_binding_indices_and_compile()?;
             }
+            RelAlgebra::RtreeSearch(s) => {
+                s.fill_binding_indices_and_compile()?;
+            }
             RelAlgebra::StoredWithValidity(v) => {
                 v.fill_binding_indices_and_compile()?;
             }
@@ -437,6 +450,7 @@ impl RelAlgebra {
                 filters: vec![],
                 filters_bytecodes: vec![],
                 span,
+                descending: false,
             })),
             Some(vld) => {
                 if storage.metadata.keys.last().unwrap().typing
@@ -472,7 +486,8 @@ impl RelAlgebra {
             | RelAlgebra::Unification(_)
             | RelAlgebra::HnswSearch(_)
             | RelAlgebra::FtsSearch(_)
-            | RelAlgebra::LshSearch(_)) => {
+            | RelAlgebra::LshSearch(_)
+            | RelAlgebra::RtreeSearch(_)) => {
                 let span = filter.span();
                 RelAlgebra::Filter(FilteredRA {
                     parent: Box::new(s),
@@ -520,6 +535,7 @@ impl RelAlgebra {
                 mut filters,
                 filters_bytecodes,
                 span,
+                descending,
             }) => {
                 filters.push(filter);
                 RelAlgebra::Stored(StoredRA {
@@ -528,6 +544,7 @@ impl RelAlgebra {
                     filters,
                     filters_bytecodes,
                     span,
+                    descending,
                 })
             }
             RelAlgebra::StoredWithValidity(StoredWithValidityRA {
@@ -563,8 +580,8 @@ impl RelAlgebra {
                     mut right,
                     joiner,
                     to_eliminate,
+                    materialize_right,
                     span,
-                    ..
                 } = *inner;
                 for filter in filters {
                     let f_bindings = filter.bindings()?;
@@ -581,6 +598,7 @@ impl RelAlgebra {
                     right,
                     joiner,
                     to_eliminate,
+                    materialize_right,
                     span,
                 }));
                 if !remaining.is_empty() {
@@ -634,6 +652,7 @@ impl RelAlgebra {
             parent: Box::new(self),
             fts_search,
             filter_bytecode: None,
+            extractor_bytecode: None,
             own_bindings,
         }))
     }
@@ -649,6 +668,18 @@ impl RelAlgebra {
             own_bindings,
         }))
     }
+    pub(crate) fn rtree_search(
+        self,
+        rtree_search: RtreeSearch,
+        own_bindings: Vec<Symbol>,
+    ) -> Result<Self> {
+        Ok(Self::RtreeSearch(RtreeSearchRA {
+            parent: Box::new(self),
+            rtree_search,
+            filter_bytecode: None,
+            own_bindings,
+        }))
+    }
     pub(crate) fn join(
         self,
         right: RelAlgebra,
@@ -664,9 +695,17 @@ impl RelAlgebra {
                 right_keys,
             },
             to_eliminate: Default::default(),
+            materialize_right: false,
             span,
         }))
     }
+    /// Make the join at the top scan its right side once, instead of looking up into it
+    /// for every row on the left
+    pub(crate) fn materialize_right(&mut self) {
+        if let RelAlgebra::Join(inner) = self {
+            inner.materialize_right = true;
+        }
+    }
     pub(crate) fn neg_join(
         self,
         right: RelAlgebra,
@@ -890,6 +929,9 @@ pub(crate) struct StoredRA {
     pub(crate) filters: Vec<Expr>,
     pub(crate) filters_bytecodes: Vec<(Vec<Bytecode>, SourceSpan)>,
     pub(crate) span: SourceSpan,
+    /// Scan the relation in descending key order, set when it produces the rows
+    /// of a query sorted in that order
+    pub(crate) descending: bool,
 }
 
 #[derive(Debug)]
@@ -943,7 +985,7 @@ impl LshSearchRA {
         let filter_code = self.filter_bytecode.clone();
         let mut stack = vec![];
         let perms = config.manifest.get_hash_perms();
-        let tokenizer = tx.tokenizers.get(
+        let tokenizer = tx.get_tokenizer(
             &config.idx_handle.name,
             &config.manifest.tokenizer,
             &config.manifest.filters,
@@ -973,11 +1015,95 @@ impl LshSearchRA {
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct RtreeSearchRA {
+    pub(crate) parent: Box<RelAlgebra>,
+    pub(crate) rtree_search: RtreeSearch,
+    pub(crate) filter_bytecode: Option<(Vec<Bytecode>, SourceSpan)>,
+    pub(crate) own_bindings: Vec<Symbol>,
+}
+
+impl RtreeSearchRA {
+    fn fill_binding_indices_and_compile(&mut self) -> Result<()> {
+        self.parent.fill_binding_indices_and_compile()?;
+        if let Some(filter) = self.rtree_search.filter.as_mut() {
+            let bindings: BTreeMap<_, _> = self
+                .own_bindings
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(a, b)| (b, a))
+                .collect();
+            filter.fill_binding_indices(&bindings)?;
+            self.filter_bytecode = Some((filter.compile()?, filter.span()));
+        }
+        Ok(())
+    }
+    fn iter<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        let bindings = self.parent.bindings_after_eliminate();
+        let bind_idx = |symb: &Option<Symbol>| {
+            symb.as_ref()
+                .map(|s| bindings.iter().position(|b| b == s).unwrap())
+        };
+        let bbox_idx = bind_idx(&self.rtree_search.bbox);
+        let query_idx = bind_idx(&self.rtree_search.query);
+        let config = self.rtree_search.clone();
+        let filter_code = self.filter_bytecode.clone();
+        let extractor = config
+            .base_handle
+            .compile_expr(&config.manifest.extractor)?;
+        let mut stack = vec![];
+
+        let it = self
+            .parent
+            .iter(tx, delta_rule, stores)?
+            .map_ok(move |tuple| -> Result<_> {
+                let bbox = match bbox_idx.map(|i| &tuple[i]) {
+                    None => None,
+                    Some(DataValue::Null) => return Ok(vec![].into_iter()),
+                    Some(v) => Some(val2bbox(v)?),
+                };
+                let query = match query_idx.map(|i| &tuple[i]) {
+                    None => None,
+                    Some(DataValue::Null) => return Ok(vec![].into_iter()),
+                    Some(v) => Some(val2geometry(v)?),
+                };
+                let res = tx.rtree_search(
+                    bbox,
+                    query.as_ref(),
+                    &config,
+                    &extractor,
+                    &mut stack,
+                    &filter_code,
+                )?;
+                Ok(res
+                    .into_iter()
+                    .map(|t| {
+                        let mut r = tuple.clone();
+                        r.extend(t);
+                        r
+                    })
+                    .collect_vec()
+                    .into_iter())
+            })
+            .map(flatten_err)
+            .flatten_ok();
+        Ok(Box::new(it))
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct FtsSearchRA {
     pub(crate) parent: Box<RelAlgebra>,
     pub(crate) fts_search: FtsSearch,
     pub(crate) filter_bytecode: Option<(Vec<Bytecode>, SourceSpan)>,
+    /// Only compiled when highlights or snippets are requested
+    pub(crate) extractor_bytecode: Option<Vec<Bytecode>>,
     pub(crate) own_bindings: Vec<Symbol>,
 }
 
@@ -996,6 +1122,13 @@ impl FtsSearchRA {
             filter.fill_binding_indices(&bindings)?;
             self.filter_bytecode = Some((filter.compile()?, filter.span()));
         }
+        if self.fts_search.needs_highlights() {
+            let extractor = self
+                .fts_search
+                .base_handle
+                .compile_expr(&self.fts_search.manifest.extractor)?;
+            self.extractor_bytecode = Some(extractor);
+        }
         Ok(())
     }
     fn iter<'a>(
@@ -1014,9 +1147,10 @@ impl FtsSearchRA {
         }
         let config = self.fts_search.clone();
         let filter_code = self.filter_bytecode.clone();
+        let extractor_code = self.extractor_bytecode.clone();
         let mut stack = vec![];
         let mut idf_cache = Default::default();
-        let tokenizer = tx.tokenizers.get(
+        let tokenizer = tx.get_tokenizer(
             &config.idx_handle.name,
             &config.manifest.tokenizer,
             &config.manifest.filters,
@@ -1049,6 +1183,7 @@ impl FtsSearchRA {
                     &q,
                     &config,
                     &filter_code,
+                    &extractor_code,
                     &tokenizer,
                     &mut stack,
                     &mut idf_cache,
@@ -1526,7 +1661,11 @@ impl StoredRA {
     }
 
     fn iter<'a>(&'a self, tx: &'a SessionTx<'_>) -> Result<TupleIter<'a>> {
-        let it = self.storage.scan_all(tx);
+        let it: TupleIter<'a> = if self.descending {
+            Box::new(self.storage.scan_all_rev(tx))
+        } else {
+            Box::new(self.storage.scan_all(tx))
+        };
         Ok(if self.filters.is_empty() {
             Box::new(it)
         } else {
@@ -1535,6 +1674,19 @@ impl StoredRA {
     }
 }
 
+/// When profiling, counts a storage seek into `right` for every row of `left`,
+/// as the rows of `left` are looked up one by one
+fn profile_lookups<'a>(
+    tx: &'a SessionTx<'_>,
+    right: &RelAlgebra,
+    left: TupleIter<'a>,
+) -> TupleIter<'a> {
+    match tx.profile.as_ref().and_then(|p| p.op(right)) {
+        None => left,
+        Some(stats) => Box::new(left.inspect(move |_| stats.record_seek())),
+    }
+}
+
 fn join_is_prefix(right_join_indices: &[usize]) -> bool {
     let mut indices = right_join_indices.to_vec();
     indices.sort();
@@ -1579,9 +1731,9 @@ impl TempStoreRA {
             Some(name) => *name == self.storage_key,
         };
         let it = if scan_epoch {
-            Left(storage.delta_all_iter().map(|t| Ok(t.into_tuple())))
+            Left(storage.delta_all_iter().map_ok(|t| t.into_tuple()))
         } else {
-            Right(storage.all_iter().map(|t| Ok(t.into_tuple())))
+            Right(storage.all_iter().map_ok(|t| t.into_tuple()))
         };
         Ok(if self.filters.is_empty() {
             Box::new(it)
@@ -1617,6 +1769,7 @@ impl TempStoreRA {
                             .collect_vec();
 
                         'outer: for found in storage.prefix_iter(&prefix) {
+                            let found = found?;
                             for (left_idx, right_idx) in
                                 left_join_indices.iter().zip(right_join_indices.iter())
                             {
@@ -1649,6 +1802,7 @@ impl TempStoreRA {
         } else {
             let mut right_join_vals = BTreeSet::new();
             for tuple in storage.all_iter() {
+                let tuple = tuple?;
                 let to_join: Box<[DataValue]> = right_join_indices
                     .iter()
                     .map(|i| tuple.get(*i).clone())
@@ -1736,9 +1890,10 @@ impl TempStoreRA {
                         };
                         return Left(
                             it.map(move |res_found| -> Result<Option<Tuple>> {
+                                let res_found = res_found?;
                                 if self.filters.is_empty() {
                                     let mut ret = tuple.clone();
-                                    ret.extend(res_found.into_iter().cloned());
+                                    ret.extend(res_found.into_tuple());
                                     Ok(Some(ret))
                                 } else {
                                     let found = res_found.into_tuple();
@@ -1766,9 +1921,10 @@ impl TempStoreRA {
 
                 Right(
                     it.map(move |res_found| -> Result<Option<Tuple>> {
+                        let res_found = res_found?;
                         if self.filters.is_empty() {
                             let mut ret = tuple.clone();
-                            ret.extend(res_found.into_iter().cloned());
+                            ret.extend(res_found.into_tuple());
                             Ok(Some(ret))
                         } else {
                             let found = res_found.into_tuple();
@@ -1859,6 +2015,7 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(_) => Ok(()),
             RelAlgebra::FtsSearch(_) => Ok(()),
             RelAlgebra::LshSearch(_) => Ok(()),
+            RelAlgebra::RtreeSearch(_) => Ok(()),
         }
     }
 
@@ -1876,6 +2033,7 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(_) => None,
             RelAlgebra::FtsSearch(_) => None,
             RelAlgebra::LshSearch(_) => None,
+            RelAlgebra::RtreeSearch(_) => None,
         }
     }
 
@@ -1920,6 +2078,79 @@ impl RelAlgebra {
                 bindings.extend_from_slice(&s.own_bindings);
                 bindings
             }
+            RelAlgebra::RtreeSearch(s) => {
+                let mut bindings = s.parent.bindings_after_eliminate();
+                bindings.extend_from_slice(&s.own_bindings);
+                bindings
+            }
+        }
+    }
+    /// The operators whose rows are the inputs of this one
+    pub(crate) fn children(&self) -> Vec<&RelAlgebra> {
+        match self {
+            RelAlgebra::Fixed(_)
+            | RelAlgebra::TempStore(_)
+            | RelAlgebra::Stored(_)
+            | RelAlgebra::StoredWithValidity(_) => vec![],
+            RelAlgebra::Join(j) => vec![&j.left, &j.right],
+            RelAlgebra::NegJoin(j) => vec![&j.left, &j.right],
+            RelAlgebra::Reorder(r) => vec![&r.relation],
+            RelAlgebra::Filter(r) => vec![&r.parent],
+            RelAlgebra::Unification(r) => vec![&r.parent],
+            RelAlgebra::HnswSearch(r) => vec![&r.parent],
+            RelAlgebra::FtsSearch(r) => vec![&r.parent],
+            RelAlgebra::LshSearch(r) => vec![&r.parent],
+            RelAlgebra::RtreeSearch(r) => vec![&r.parent],
+        }
+    }
+    /// Whether the rows produced are known to be distinct without collecting them into a store
+    pub(crate) fn yields_distinct_rows(&self) -> bool {
+        self.distinct_key().is_some()
+    }
+    /// Bindings whose values determine the rest of the row, if rows are known to be distinct.
+    /// Generated variables can be dropped freely, as they are copies of other variables
+    /// or constants.
+    fn distinct_key(&self) -> Option<Vec<Symbol>> {
+        let key = match self {
+            RelAlgebra::Fixed(f) => {
+                if f.data.len() <= 1 {
+                    vec![]
+                } else {
+                    return None;
+                }
+            }
+            RelAlgebra::TempStore(r) => r.bindings.clone(),
+            RelAlgebra::Stored(r) => r.bindings.get(..r.storage.metadata.keys.len())?.to_vec(),
+            RelAlgebra::StoredWithValidity(r) => {
+                r.bindings.get(..r.storage.metadata.keys.len())?.to_vec()
+            }
+            RelAlgebra::Join(j) => {
+                let mut key = j.left.distinct_key()?;
+                key.extend(j.right.distinct_key()?);
+                key
+            }
+            RelAlgebra::NegJoin(j) => j.left.distinct_key()?,
+            RelAlgebra::Reorder(r) => r.relation.distinct_key()?,
+            RelAlgebra::Filter(r) => r.parent.distinct_key()?,
+            RelAlgebra::Unification(u) => {
+                if u.is_multi {
+                    return None;
+                }
+                u.parent.distinct_key()?
+            }
+            RelAlgebra::HnswSearch(_)
+            | RelAlgebra::FtsSearch(_)
+            | RelAlgebra::LshSearch(_)
+            | RelAlgebra::RtreeSearch(_) => return None,
+        };
+        let kept = self.bindings_after_eliminate();
+        if key
+            .iter()
+            .all(|s| s.name.starts_with('*') || kept.contains(s))
+        {
+            Some(key)
+        } else {
+            None
         }
     }
     pub(crate) fn iter<'a>(
@@ -1927,6 +2158,25 @@ impl RelAlgebra {
         tx: &'a SessionTx<'_>,
         delta_rule: Option<&MagicSymbol>,
         stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        match tx.profile.as_ref().and_then(|p| p.op(self)) {
+            None => self.iter_unprofiled(tx, delta_rule, stores),
+            Some(stats) => {
+                if matches!(
+                    self,
+                    RelAlgebra::Stored(_) | RelAlgebra::StoredWithValidity(_)
+                ) {
+                    stats.record_seek();
+                }
+                stats.profile_iter(|| self.iter_unprofiled(tx, delta_rule, stores))
+            }
+        }
+    }
+    fn iter_unprofiled<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
     ) -> Result<TupleIter<'a>> {
         match self {
             RelAlgebra::Fixed(f) => Ok(Box::new(f.data.iter().map(|t| Ok(t.clone())))),
@@ -1941,6 +2191,7 @@ impl RelAlgebra {
             RelAlgebra::HnswSearch(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::FtsSearch(r) => r.iter(tx, delta_rule, stores),
             RelAlgebra::LshSearch(r) => r.iter(tx, delta_rule, stores),
+            RelAlgebra::RtreeSearch(r) => r.iter(tx, delta_rule, stores),
         }
     }
 }
@@ -2036,12 +2287,14 @@ impl NegJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                v.neg_join(
-                    tx,
-                    self.left.iter(tx, delta_rule, stores)?,
-                    join_indices,
-                    eliminate_indices,
-                )
+                let mut left_iter = self.left.iter(tx, delta_rule, stores)?;
+                if join_is_prefix(&join_indices.1) {
+                    left_iter = profile_lookups(tx, &self.right, left_iter);
+                } else if let Some(stats) = tx.profile.as_ref().and_then(|p| p.op(&self.right)) {
+                    // the relation is scanned once in full
+                    stats.record_seek();
+                }
+                v.neg_join(tx, left_iter, join_indices, eliminate_indices)
             }
             _ => {
                 unreachable!()
@@ -2056,6 +2309,9 @@ pub(crate) struct InnerJoin {
     pub(crate) right: RelAlgebra,
     pub(crate) joiner: Joiner,
     pub(crate) to_eliminate: BTreeSet<Symbol>,
+    /// Set when statistics show that scanning the stored relation on the right
+    /// is cheaper than a lookup for every row on the left
+    pub(crate) materialize_right: bool,
     pub(crate) span: SourceSpan,
 }
 
@@ -2103,7 +2359,7 @@ impl InnerJoin {
                 if join_is_prefix(&join_indices.1) {
                     "mem_prefix_join"
                 } else {
-                    "mem_mat_join"
+                    "mem_hash_join"
                 }
             }
             RelAlgebra::Stored(_) => {
@@ -2114,15 +2370,18 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if self.materialize_right {
+                    "stored_mat_join"
+                } else if join_is_prefix(&join_indices.1) {
                     "stored_prefix_join"
                 } else {
-                    "stored_mat_join"
+                    "stored_hash_join"
                 }
             }
             RelAlgebra::HnswSearch(_) => "hnsw_search_join",
             RelAlgebra::FtsSearch(_) => "fts_search_join",
             RelAlgebra::LshSearch(_) => "lsh_search_join",
+            RelAlgebra::RtreeSearch(_) => "rtree_search_join",
             RelAlgebra::StoredWithValidity(_) => {
                 let join_indices = self
                     .joiner
@@ -2131,10 +2390,12 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if self.materialize_right {
+                    "stored_mat_join"
+                } else if join_is_prefix(&join_indices.1) {
                     "stored_prefix_join"
                 } else {
-                    "stored_mat_join"
+                    "stored_hash_join"
                 }
             }
             RelAlgebra::Join(_) | RelAlgebra::Filter(_) | RelAlgebra::Unification(_) => {
@@ -2188,7 +2449,7 @@ impl InnerJoin {
                         stores,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, join_indices, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::Stored(r) => {
@@ -2199,17 +2460,28 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if r.descending {
+                    // only set when the left side is the unit, so the join is the scan itself
+                    let it = r.iter(tx)?;
+                    Ok(if eliminate_indices.is_empty() {
+                        it
+                    } else {
+                        Box::new(it.map_ok(move |t| eliminate_from_tuple(t, &eliminate_indices)))
+                    })
+                } else if self.materialize_right {
+                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                } else if join_is_prefix(&join_indices.1) {
                     let left_len = self.left.bindings_after_eliminate().len();
+                    let left_iter = self.left.iter(tx, delta_rule, stores)?;
                     r.prefix_join(
                         tx,
-                        self.left.iter(tx, delta_rule, stores)?,
+                        profile_lookups(tx, &self.right, left_iter),
                         join_indices,
                         eliminate_indices,
                         left_len,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, join_indices, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::StoredWithValidity(r) => {
@@ -2220,15 +2492,18 @@ impl InnerJoin {
                         &self.right.bindings_after_eliminate(),
                     )
                     .unwrap();
-                if join_is_prefix(&join_indices.1) {
+                if self.materialize_right {
+                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                } else if join_is_prefix(&join_indices.1) {
+                    let left_iter = self.left.iter(tx, delta_rule, stores)?;
                     r.prefix_join(
                         tx,
-                        self.left.iter(tx, delta_rule, stores)?,
+                        profile_lookups(tx, &self.right, left_iter),
                         join_indices,
                         eliminate_indices,
                     )
                 } else {
-                    self.materialized_join(tx, eliminate_indices, delta_rule, stores)
+                    self.hash_join(tx, join_indices, eliminate_indices, delta_rule, stores)
                 }
             }
             RelAlgebra::Join(_)
@@ -2236,7 +2511,8 @@ impl InnerJoin {
             | RelAlgebra::Unification(_)
             | RelAlgebra::HnswSearch(_)
             | RelAlgebra::FtsSearch(_)
-            | RelAlgebra::LshSearch(_) => {
+            | RelAlgebra::LshSearch(_)
+            | RelAlgebra::RtreeSearch(_) => {
                 self.materialized_join(tx, eliminate_indices, delta_rule, stores)
             }
             RelAlgebra::Reorder(_) => {
@@ -2247,6 +2523,105 @@ impl InnerJoin {
             }
         }
     }
+    /// Joins on columns that are not a key prefix of the right side. Both sides
+    /// are read in turn until one of them ends, and the hash table is built from
+    /// that smaller side. The rows of the right side must be distinct.
+    fn hash_join<'a>(
+        &'a self,
+        tx: &'a SessionTx<'_>,
+        (left_join_indices, right_join_indices): (Vec<usize>, Vec<usize>),
+        eliminate_indices: BTreeSet<usize>,
+        delta_rule: Option<&MagicSymbol>,
+        stores: &'a BTreeMap<MagicSymbol, EpochStore>,
+    ) -> Result<TupleIter<'a>> {
+        debug!("using hash join");
+        let mut left_iter = self.left.iter(tx, delta_rule, stores)?;
+        let mut right_iter = self.right.iter(tx, delta_rule, stores)?;
+        let mut left_rows = vec![];
+        let mut right_rows = vec![];
+        // the buffered rows are charged to the query until the join is done
+        let mut held = tx.usage.holder();
+        let build_left = loop {
+            tx.poison.check()?;
+            match left_iter.next() {
+                None => break true,
+                Some(tuple) => {
+                    let tuple = tuple?;
+                    held.hold(&tuple)?;
+                    left_rows.push(tuple)
+                }
+            }
+            match right_iter.next() {
+                None => break false,
+                Some(tuple) => {
+                    let tuple = tuple?;
+                    held.hold(&tuple)?;
+                    right_rows.push(tuple)
+                }
+            }
+        };
+        let (build_rows, build_indices, probe_rows, probe_iter, probe_indices) = if build_left {
+            (
+                left_rows,
+                left_join_indices,
+                right_rows,
+                right_iter,
+                right_join_indices,
+            )
+        } else {
+            (
+                right_rows,
+                right_join_indices,
+                left_rows,
+                left_iter,
+                left_join_indices,
+            )
+        };
+        if build_rows.is_empty() {
+            return Ok(Box::new(iter::empty()));
+        }
+
+        #[allow(clippy::mutable_key_type)]
+        let mut rows: HashMap<Vec<DataValue>, Vec<Tuple>> = HashMap::new();
+        for (i, tuple) in build_rows.into_iter().enumerate() {
+            if i % 1024 == 0 {
+                tx.poison.check()?;
+            }
+            let key = build_indices
+                .iter()
+                .map(|i| tuple[*i].clone())
+                .collect_vec();
+            rows.entry(key).or_default().push(tuple);
+        }
+        let table = HashJoinTable { rows, _held: held };
+
+        let it = probe_rows
+            .into_iter()
+            .map(Ok)
+            .chain(probe_iter)
+            .map_ok(move |probe| {
+                let key = probe_indices
+                    .iter()
+                    .map(|i| probe[*i].clone())
+                    .collect_vec();
+                table
+                    .matches(&key)
+                    .iter()
+                    .map(|found| {
+                        let (left, right) = if build_left {
+                            (found, &probe)
+                        } else {
+                            (&probe, found)
+                        };
+                        let mut ret = left.clone();
+                        ret.extend_from_slice(right);
+                        eliminate_from_tuple(ret, &eliminate_indices)
+                    })
+                    .collect_vec()
+            })
+            .flatten_ok();
+        Ok(Box::new(it))
+    }
     fn materialized_join<'a>(
         &'a self,
         tx: &'a SessionTx<'_>,
@@ -2282,6 +2657,7 @@ impl InnerJoin {
             .sorted_by_key(|(_, b)| **b)
             .map(|(a, _)| a)
             .collect_vec();
+        let mut held = tx.usage.holder();
         let cached_data = {
             let mut cache = BTreeSet::new();
             for item in self.right.iter(tx, delta_rule, stores)? {
@@ -2291,7 +2667,10 @@ impl InnerJoin {
                             .iter()
                             .map(|i| tuple[*i].clone())
                             .collect_vec();
-                        cache.insert(stored_tuple);
+                        if !cache.contains(&stored_tuple) {
+                            held.hold(&stored_tuple)?;
+                            cache.insert(stored_tuple);
+                        }
                     }
                     Err(e) => return Err(e),
                 }
@@ -2311,11 +2690,25 @@ impl InnerJoin {
             right_invert_indices,
             right_idx,
             prefix,
+            _held: held,
         };
         Ok(Box::new(it))
     }
 }
 
+/// The build side of a hash join, indexed by the join key
+struct HashJoinTable {
+    rows: HashMap<Vec<DataValue>, Vec<Tuple>>,
+    /// Charges the query for the rows while the join is running
+    _held: HeldRows,
+}
+
+impl HashJoinTable {
+    fn matches(&self, key: &[DataValue]) -> &[Tuple] {
+        self.rows.get(key).map(|v| v.as_slice()).unwrap_or_default()
+    }
+}
+
 struct CachedMaterializedIterator<'a> {
     materialized: Vec<Tuple>,
     eliminate_indices: BTreeSet<usize>,
@@ -2325,6 +2718,8 @@ struct CachedMaterializedIterator<'a> {
     prefix: Tuple,
     left: TupleIter<'a>,
     left_cache: Tuple,
+    /// Charges the query for the materialized rows while the join is running
+    _held: HeldRows,
 }
 
 impl<'a> CachedMaterializedIterator<'a> {
@@ -2424,4 +2819,31 @@ mod tests {
             vec![vec![DataValue::from(1)], vec![DataValue::from(2)]]
         )
     }
+
+    #[test]
+    fn test_hash_join() {
+        let db = new_cozo_mem().unwrap();
+        let rules = r#"
+        r[a, b] <- [[1, 'x'], [2, 'y'], [3, 'x']]
+        s[c, b] := c in int_range(100), b = if(c % 2 == 0, 'x', 'y')
+        "#;
+        // the hash table is built from the left side first, then from the right side
+        for body in ["r[a, b], s[c, b]", "s[c, b], r[a, b]"] {
+            let script = format!("{rules} ?[a, c] := {body}");
+            let res = db.run_script(&script, Default::default()).unwrap().rows;
+            assert_eq!(res.len(), 150);
+            assert!(res.contains(&vec![DataValue::from(2), DataValue::from(99)]));
+            assert!(!res.contains(&vec![DataValue::from(2), DataValue::from(98)]));
+
+            let explain = db
+                .run_script(&format!("::explain {{ {script} }}"), Default::default())
+                .unwrap()
+                .into_json();
+            assert!(explain["rows"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|row| row[4] == "mem_hash_join"));
+        }
+    }
 }
@@ -9,11 +9,15 @@
 use std::collections::BTreeSet;
 use std::mem;
 
+use itertools::Itertools;
 use miette::{bail, Diagnostic, Result};
 use thiserror::Error;
 
-use crate::data::program::{NormalFormAtom, NormalFormInlineRule};
+use crate::data::program::{NormalFormAtom, NormalFormInlineRule, NormalFormRelationApplyAtom};
+use crate::data::symb::Symbol;
 use crate::parse::SourceSpan;
+use crate::runtime::stats::{RelationStats, SEEK_COST};
+use crate::runtime::transact::SessionTx;
 
 #[derive(Diagnostic, Debug, Error)]
 #[error("Encountered unsafe negation, or empty rule definition")]
@@ -31,7 +35,7 @@ pub(crate) struct UnsafeNegation(#[label] pub(crate) SourceSpan);
 pub(crate) struct UnboundVariable(#[label] pub(crate) SourceSpan);
 
 impl NormalFormInlineRule {
-    pub(crate) fn convert_to_well_ordered_rule(self) -> Result<Self> {
+    pub(crate) fn convert_to_well_ordered_rule(self, tx: &SessionTx<'_>) -> Result<Self> {
         let mut seen_variables = BTreeSet::default();
         let mut round_1_collected = vec![];
         let mut pending = vec![];
@@ -96,9 +100,19 @@ impl NormalFormInlineRule {
                         pending.push(NormalFormAtom::LshSearch(s));
                     }
                 }
+                NormalFormAtom::RtreeSearch(s) => {
+                    if s.input_bindings().all(|b| seen_variables.contains(b)) {
+                        seen_variables.extend(s.all_bindings().cloned());
+                        round_1_collected.push(NormalFormAtom::RtreeSearch(s));
+                    } else {
+                        pending.push(NormalFormAtom::RtreeSearch(s));
+                    }
+                }
             }
         }
 
+        let round_1_collected = order_joins_by_cost(round_1_collected, tx);
+
         let mut collected = vec![];
         seen_variables.clear();
         let mut last_pending = vec![];
@@ -136,6 +150,10 @@ impl NormalFormInlineRule {
                     seen_variables.extend(s.all_bindings().cloned());
                     collected.push(NormalFormAtom::LshSearch(s));
                 }
+                NormalFormAtom::RtreeSearch(s) => {
+                    seen_variables.extend(s.all_bindings().cloned());
+                    collected.push(NormalFormAtom::RtreeSearch(s));
+                }
             }
             for atom in last_pending.iter() {
                 match atom {
@@ -178,6 +196,14 @@ impl NormalFormInlineRule {
                             pending.push(NormalFormAtom::LshSearch(s.clone()));
                         }
                     }
+                    NormalFormAtom::RtreeSearch(s) => {
+                        if s.input_bindings().all(|b| seen_variables.contains(b)) {
+                            seen_variables.extend(s.all_bindings().cloned());
+                            collected.push(NormalFormAtom::RtreeSearch(s.clone()));
+                        } else {
+                            pending.push(NormalFormAtom::RtreeSearch(s.clone()));
+                        }
+                    }
                     NormalFormAtom::Predicate(p) => {
                         if p.bindings()?.is_subset(&seen_variables) {
                             collected.push(NormalFormAtom::Predicate(p.clone()));
@@ -229,6 +255,9 @@ impl NormalFormInlineRule {
                     NormalFormAtom::LshSearch(s) => {
                         bail!(UnboundVariable(s.span))
                     }
+                    NormalFormAtom::RtreeSearch(s) => {
+                        bail!(UnboundVariable(s.span))
+                    }
                 }
             }
         }
@@ -240,3 +269,110 @@ impl NormalFormInlineRule {
         })
     }
 }
+
+/// Reorders each run of consecutive stored relations in the body, using the statistics
+/// collected by `::analyze` to put the cheapest joins first. Constant unifications
+/// within a run are moved to its start. Runs containing relations never analyzed
+/// keep the order in which they are written.
+fn order_joins_by_cost(atoms: Vec<NormalFormAtom>, tx: &SessionTx<'_>) -> Vec<NormalFormAtom> {
+    let mut ret = Vec::with_capacity(atoms.len());
+    let mut bound = BTreeSet::new();
+    let mut run = vec![];
+    for atom in atoms {
+        match &atom {
+            NormalFormAtom::Relation(_) => {
+                run.push(atom);
+                continue;
+            }
+            NormalFormAtom::Unification(u) if u.is_const() => {
+                run.push(atom);
+                continue;
+            }
+            NormalFormAtom::Unification(u) => {
+                bound.insert(u.binding.clone());
+            }
+            NormalFormAtom::Rule(r) => bound.extend(r.args.iter().cloned()),
+            NormalFormAtom::HnswSearch(s) => bound.extend(s.all_bindings().cloned()),
+            NormalFormAtom::FtsSearch(s) => bound.extend(s.all_bindings().cloned()),
+            NormalFormAtom::LshSearch(s) => bound.extend(s.all_bindings().cloned()),
+            NormalFormAtom::RtreeSearch(s) => bound.extend(s.all_bindings().cloned()),
+            NormalFormAtom::NegatedRule(_)
+            | NormalFormAtom::NegatedRelation(_)
+            | NormalFormAtom::Predicate(_) => {}
+        }
+        order_run(mem::take(&mut run), &mut bound, &mut ret, tx);
+        ret.push(atom);
+    }
+    order_run(run, &mut bound, &mut ret, tx);
+    ret
+}
+
+fn order_run(
+    run: Vec<NormalFormAtom>,
+    bound: &mut BTreeSet<Symbol>,
+    ret: &mut Vec<NormalFormAtom>,
+    tx: &SessionTx<'_>,
+) {
+    let stats = run
+        .iter()
+        .filter_map(|atom| match atom {
+            // errors are reported when the rule is compiled
+            NormalFormAtom::Relation(r) => Some(
+                tx.get_relation(&r.name, false)
+                    .ok()
+                    .and_then(|handle| handle.stats),
+            ),
+            _ => None,
+        })
+        .collect_vec();
+    if stats.len() < 2 || stats.iter().any(|s| s.is_none()) {
+        for atom in run {
+            match &atom {
+                NormalFormAtom::Relation(r) => bound.extend(r.args.iter().cloned()),
+                NormalFormAtom::Unification(u) => {
+                    bound.insert(u.binding.clone());
+                }
+                _ => unreachable!(),
+            }
+            ret.push(atom);
+        }
+        return;
+    }
+
+    let mut stats = stats.into_iter().flatten();
+    let mut relations: Vec<(NormalFormRelationApplyAtom, RelationStats)> = vec![];
+    for atom in run {
+        match atom {
+            NormalFormAtom::Relation(r) => relations.push((r, stats.next().unwrap())),
+            atom => {
+                if let NormalFormAtom::Unification(u) = &atom {
+                    bound.insert(u.binding.clone());
+                }
+                ret.push(atom);
+            }
+        }
+    }
+    // greedily join the relation that is cheapest to add to the rows so far
+    let mut est_rows = 1.;
+    while !relations.is_empty() {
+        let mut best: Option<(usize, f64, f64)> = None;
+        for (i, (r, stats)) in relations.iter().enumerate() {
+            let out_rows =
+                est_rows * stats.rows_per_probe(r.args.iter().map(|a| bound.contains(a)));
+            let is_lookup = matches!(r.args.first(), Some(a) if bound.contains(a));
+            let cost = if is_lookup {
+                (est_rows * SEEK_COST).min(stats.rows as f64) + out_rows
+            } else {
+                stats.rows as f64 + out_rows
+            };
+            if !matches!(best, Some((_, best_cost, _)) if best_cost <= cost) {
+                best = Some((i, cost, out_rows));
+            }
+        }
+        let (i, _, out_rows) = best.unwrap();
+        est_rows = out_rows;
+        let (r, _) = relations.remove(i);
+        bound.extend(r.args.iter().cloned());
+        ret.push(NormalFormAtom::Relation(r));
+    }
+}
@@ -7,23 +7,29 @@
  */
 
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BinaryHeap};
 
 use itertools::Itertools;
 use miette::Result;
 
-use crate::data::program::SortDir;
-use crate::data::symb::Symbol;
+use crate::data::program::{MagicSymbol, SortDir};
+use crate::data::symb::{Symbol, PROG_ENTRY};
 use crate::data::tuple::Tuple;
+use crate::parse::SourceSpan;
+use crate::query::compile::{CompiledProgram, CompiledRuleSet};
+use crate::query::ra::{RelAlgebra, StoredRA};
 use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
 
 impl<'a> SessionTx<'a> {
+    /// Sorts the results. If `keep` is given, only that many rows from the start of the
+    /// sorted results are returned, and only that many rows are held while sorting.
     pub(crate) fn sort_and_collect(
         &mut self,
         original: EpochStore,
         sorters: &[(Symbol, SortDir)],
         head: &[Symbol],
+        keep: Option<usize>,
     ) -> Result<Vec<Tuple>> {
         let head_indices: BTreeMap<_, _> = head.iter().enumerate().map(|(i, k)| (k, i)).collect();
         let idx_sorters = sorters
@@ -31,22 +37,156 @@ impl<'a> SessionTx<'a> {
             .map(|(k, dir)| (head_indices[k], *dir))
             .collect_vec();
 
-        let mut all_data: Vec<_> = original.all_iter().map(|v| v.into_tuple()).collect_vec();
-        all_data.sort_by(|a, b| {
-            for (idx, dir) in &idx_sorters {
-                match a[*idx].cmp(&b[*idx]) {
-                    Ordering::Equal => {}
-                    o => {
-                        return match dir {
-                            SortDir::Asc => o,
-                            SortDir::Dsc => o.reverse(),
-                        }
-                    }
+        if let Some(keep) = keep {
+            return top_k(original, &idx_sorters, keep);
+        }
+
+        let mut all_data: Vec<_> = original
+            .all_iter()
+            .map_ok(|v| v.into_tuple())
+            .try_collect()?;
+        all_data.sort_by(|a, b| compare_rows(&idx_sorters, a, b));
+
+        Ok(all_data)
+    }
+}
+
+fn compare_rows(idx_sorters: &[(usize, SortDir)], a: &Tuple, b: &Tuple) -> Ordering {
+    for (idx, dir) in idx_sorters {
+        match a[*idx].cmp(&b[*idx]) {
+            Ordering::Equal => {}
+            o => {
+                return match dir {
+                    SortDir::Asc => o,
+                    SortDir::Dsc => o.reverse(),
                 }
             }
-            Ordering::Equal
+        }
+    }
+    Ordering::Equal
+}
+
+/// A row in the heap of [top_k]. Rows with equal sort keys are ordered as in the store,
+/// so that the rows kept are those a full stable sort would put first.
+struct HeapRow<'a> {
+    tuple: Tuple,
+    idx_sorters: &'a [(usize, SortDir)],
+}
+
+impl Ord for HeapRow<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_rows(self.idx_sorters, &self.tuple, &other.tuple)
+            .then_with(|| self.tuple.cmp(&other.tuple))
+    }
+}
+
+impl PartialOrd for HeapRow<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapRow<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapRow<'_> {}
+
+/// Returns the first `k` rows in sorted order, holding at most `k` rows at any time.
+fn top_k(original: EpochStore, idx_sorters: &[(usize, SortDir)], k: usize) -> Result<Vec<Tuple>> {
+    if k == 0 {
+        return Ok(vec![]);
+    }
+    // a max-heap, so that the row to be evicted is at the top
+    let mut heap = BinaryHeap::with_capacity(k);
+    for tuple in original.all_iter() {
+        let tuple = tuple?;
+        if heap.len() == k {
+            // rows come in store order, so a row sorting equal to the last kept one goes after it
+            let last: &HeapRow<'_> = heap.peek().unwrap();
+            let ord = idx_sorters
+                .iter()
+                .map(|(idx, dir)| {
+                    let o = tuple.get(*idx).cmp(&last.tuple[*idx]);
+                    match dir {
+                        SortDir::Asc => o,
+                        SortDir::Dsc => o.reverse(),
+                    }
+                })
+                .find(|o| *o != Ordering::Equal)
+                .unwrap_or(Ordering::Equal);
+            if ord != Ordering::Less {
+                continue;
+            }
+            heap.pop();
+        }
+        heap.push(HeapRow {
+            tuple: tuple.into_tuple(),
+            idx_sorters,
         });
+    }
+    Ok(heap
+        .into_sorted_vec()
+        .into_iter()
+        .map(|row| row.tuple)
+        .collect_vec())
+}
 
-        Ok(all_data)
+/// If the entry rule does nothing but scan a stored relation, and the sorters are a prefix
+/// of its keys in a single direction, makes the scan produce rows in sorted order and
+/// returns true. The evaluation can then stop as soon as enough rows for `:offset` and
+/// `:limit` are found. Among rows with equal sort keys, those found first by the scan are kept.
+pub(crate) fn sort_by_scan(
+    compiled: &mut [CompiledProgram],
+    sorters: &[(Symbol, SortDir)],
+) -> bool {
+    let dir = match sorters.first() {
+        None => return false,
+        Some((_, dir)) => *dir,
+    };
+    if sorters.iter().any(|(_, d)| *d != dir) {
+        return false;
+    }
+    let entry_symbol = MagicSymbol::Muggle {
+        inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
+    };
+    let rule = match compiled
+        .last_mut()
+        .and_then(|prog| prog.get_mut(&entry_symbol))
+    {
+        Some(CompiledRuleSet::Rules(rules)) if rules.len() == 1 => &mut rules[0],
+        _ => return false,
+    };
+    if !rule.contained_rules.is_empty() || rule.aggr.iter().any(|a| a.is_some()) {
+        return false;
+    }
+    let stored = match scanned_relation(&mut rule.relation) {
+        Some(s) => s,
+        None => return false,
+    };
+    if sorters.len() > stored.storage.metadata.keys.len()
+        || sorters
+            .iter()
+            .zip(stored.bindings.iter())
+            .any(|((symb, _), binding)| symb != binding)
+    {
+        return false;
+    }
+    stored.descending = dir == SortDir::Dsc;
+    true
+}
+
+/// The stored relation whose scan alone produces the rows of `ra`, in the same order
+fn scanned_relation(ra: &mut RelAlgebra) -> Option<&mut StoredRA> {
+    match ra {
+        RelAlgebra::Stored(s) => Some(s),
+        RelAlgebra::Reorder(r) => scanned_relation(&mut r.relation),
+        RelAlgebra::Filter(f) => scanned_relation(&mut f.parent),
+        RelAlgebra::Join(j) if j.left.is_unit() && j.joiner.left_keys.is_empty() => {
+            scanned_relation(&mut j.right)
+        }
+        _ => None,
     }
 }
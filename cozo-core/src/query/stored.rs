@@ -15,23 +15,32 @@ use pest::Parser;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
-use crate::data::expr::{Bytecode, Expr};
-use crate::data::program::{FixedRuleApply, InputInlineRulesOrFixed, InputProgram, RelationOp};
-use crate::data::relation::{ColumnDef, NullableColType, StoredRelationMetadata};
-use crate::data::symb::Symbol;
+use crate::data::expr::{eval_bytecode_pred, Bytecode, Expr};
+use crate::data::program::{
+    FixedRuleApply, InputAtom, InputInlineRule, InputInlineRulesOrFixed, InputProgram,
+    InputRelationApplyAtom, InputRuleApplyAtom, RelationOp,
+};
+use crate::data::relation::{
+    ColumnDef, ForeignKey, ForeignKeyAction, NullableColType, StoredRelationMetadata,
+};
+use crate::data::symb::{Symbol, PROG_ENTRY};
 use crate::data::tuple::{Tuple, ENCODED_KEY_MIN_LEN};
 use crate::data::value::{DataValue, ValidityTs};
 use crate::fixed_rule::utilities::constant::Constant;
 use crate::fixed_rule::FixedRuleHandle;
 use crate::fts::tokenizer::TextAnalyzer;
 use crate::parse::expr::build_expr;
-use crate::parse::{parse_script, CozoScriptParser, Rule};
+use crate::parse::{CozoScriptParser, Rule};
 use crate::runtime::callback::{CallbackCollector, CallbackOp};
 use crate::runtime::minhash_lsh::HashPermutations;
 use crate::runtime::relation::{
-    extend_tuple_from_v, AccessLevel, InputRelationHandle, InsufficientAccessLevel, RelationHandle,
+    extend_tuple_from_v, AccessLevel, InputRelationHandle, InsufficientAccessLevel,
+    ReadByMaterializedViews, RelationHandle, UsedAsDictionary,
 };
 use crate::runtime::transact::SessionTx;
+use crate::runtime::view::{
+    delta_view_atom, materialized_view_rules, AFFECTED_RULE, ALL_RULE, DELTA_RULE,
+};
 use crate::storage::Storage;
 use crate::{Db, NamedRows, SourceSpan, StoreTx};
 
@@ -41,10 +50,12 @@ use crate::{Db, NamedRows, SourceSpan, StoreTx};
 struct RelationArityMismatch(String, usize, usize);
 
 impl<'a> SessionTx<'a> {
+    /// Applies the mutation to the relation. With `returning`, the changed rows are also
+    /// returned, in the format of [returned_row].
     pub(crate) fn execute_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         op: RelationOp,
         meta: &InputRelationHandle,
         headers: &[Symbol],
@@ -52,7 +63,8 @@ impl<'a> SessionTx<'a> {
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
         propagate_triggers: bool,
-    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        returning: bool,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<NamedRows>)> {
         let mut to_clear = vec![];
         let mut replaced_old_triggers = None;
         if op == RelationOp::Replace {
@@ -78,17 +90,27 @@ impl<'a> SessionTx<'a> {
                         old_handle.access_level
                     ));
                 }
+                if !old_handle.materialized_views.is_empty() {
+                    bail!(ReadByMaterializedViews(
+                        old_handle.name.to_string(),
+                        old_handle
+                            .materialized_views
+                            .iter()
+                            .map(|v| v.to_string())
+                            .collect_vec()
+                    ))
+                }
+                let dependents = self.dictionary_dependents(&old_handle)?;
+                if !dependents.is_empty() {
+                    bail!(UsedAsDictionary(old_handle.name.to_string(), dependents))
+                }
                 if old_handle.has_triggers() {
                     replaced_old_triggers = Some((old_handle.put_triggers, old_handle.rm_triggers))
                 }
                 for trigger in &old_handle.replace_triggers {
-                    let program = parse_script(
-                        trigger,
-                        &Default::default(),
-                        &db.fixed_rules.read().unwrap(),
-                        cur_vld,
-                    )?
-                    .get_single_program()?;
+                    let program = db
+                        .parse_payload(trigger, &Default::default(), None, cur_vld)?
+                        .get_single_program()?;
 
                     let (_, cleanups) = db
                         .run_query(
@@ -119,6 +141,9 @@ impl<'a> SessionTx<'a> {
         } else {
             self.get_relation(&meta.name, false)?
         };
+        relation_store
+            .metadata
+            .resolve_custom_functions(&db.custom_functions.read().unwrap())?;
         if let Some((old_put, old_retract)) = replaced_old_triggers {
             relation_store.put_triggers = old_put;
             relation_store.rm_triggers = old_retract;
@@ -131,6 +156,8 @@ impl<'a> SessionTx<'a> {
             ..
         } = meta;
 
+        let mut returned = vec![];
+        let returned_ref = if returning { Some(&mut returned) } else { None };
         match op {
             RelationOp::Rm => self.remove_from_relation(
                 db,
@@ -145,6 +172,7 @@ impl<'a> SessionTx<'a> {
                 metadata,
                 key_bindings,
                 *span,
+                returned_ref,
             )?,
             RelationOp::Ensure => self.ensure_in_relation(
                 res_iter,
@@ -177,6 +205,7 @@ impl<'a> SessionTx<'a> {
                 metadata,
                 key_bindings,
                 *span,
+                returned_ref,
             )?,
             RelationOp::Create | RelationOp::Replace | RelationOp::Put => self.put_into_relation(
                 db,
@@ -192,16 +221,38 @@ impl<'a> SessionTx<'a> {
                 key_bindings,
                 dep_bindings,
                 *span,
+                returned_ref,
             )?,
         };
 
-        Ok(to_clear)
+        if !relation_store.dictionary_of.is_empty()
+            && matches!(op, RelationOp::Put | RelationOp::Rm | RelationOp::Update)
+        {
+            self.rebuild_dictionary_dependents(&relation_store)?;
+        }
+
+        let returned = if returning {
+            let mut headers = vec!["_kind".to_string()];
+            headers.extend(
+                relation_store
+                    .metadata
+                    .keys
+                    .iter()
+                    .chain(relation_store.metadata.non_keys.iter())
+                    .map(|col| col.name.to_string()),
+            );
+            headers.push("_old".to_string());
+            Some(NamedRows::new(headers, returned))
+        } else {
+            None
+        };
+        Ok((to_clear, returned))
     }
 
     fn put_into_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
@@ -213,6 +264,7 @@ impl<'a> SessionTx<'a> {
         key_bindings: &[Symbol],
         dep_bindings: &[Symbol],
         span: SourceSpan,
+        mut returned: Option<&mut Vec<Tuple>>,
     ) -> Result<()> {
         let is_callback_target = callback_targets.contains(&relation_store.name);
 
@@ -233,11 +285,13 @@ impl<'a> SessionTx<'a> {
 
         let need_to_collect = !relation_store.is_temp
             && (is_callback_target
-                || (propagate_triggers && !relation_store.put_triggers.is_empty()));
+                || (propagate_triggers && !relation_store.put_triggers.is_empty())
+                || !relation_store.materialized_views.is_empty());
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
         let has_lsh_indices = !relation_store.lsh_indices.is_empty();
+        let has_rtree_indices = !relation_store.rtree_indices.is_empty();
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
 
@@ -261,12 +315,18 @@ impl<'a> SessionTx<'a> {
         let hnsw_filters = Self::make_hnsw_filters(relation_store)?;
         let fts_lsh_processors = self.make_fts_lsh_processors(relation_store)?;
         let lsh_perms = self.make_lsh_hash_perms(relation_store);
+        let rtree_extractors = Self::make_rtree_extractors(relation_store)?;
+        let checks = Self::make_check_constraints(relation_store)?;
+        let fk_targets = self.make_foreign_key_targets(relation_store)?;
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
                 .try_collect()?;
+            Self::ensure_check_constraints(relation_store, &checks, &mut stack, &extracted)?;
+            self.ensure_foreign_keys(relation_store, &fk_targets, &extracted)?;
 
             let key = relation_store.encode_key_for_store(&extracted, span)?;
             let val = relation_store.encode_val_for_store(&extracted, span)?;
@@ -276,28 +336,37 @@ impl<'a> SessionTx<'a> {
                 || has_hnsw_indices
                 || has_fts_indices
                 || has_lsh_indices
+                || has_rtree_indices
+                || returned.is_some()
             {
-                if let Some(existing) = self.store_tx.get(&key, false)? {
+                let existing = if relation_store.is_temp {
+                    self.temp_store_tx.get(&key, false)?
+                } else {
+                    self.store_tx.get(&key, false)?
+                };
+                if let Some(existing) = existing {
                     let mut tup = extracted[0..relation_store.metadata.keys.len()].to_vec();
                     extend_tuple_from_v(&mut tup, &existing);
                     if has_indices && extracted != tup {
                         self.update_in_index(relation_store, &extracted, &tup)?;
-                        self.del_in_fts(relation_store, &mut stack, &fts_lsh_processors, &tup)?;
                         self.del_in_lsh(relation_store, &tup)?;
                     }
+                    // the old document must leave the corpus statistics before the new one enters
+                    self.del_in_fts(relation_store, &mut stack, &fts_lsh_processors, &tup)?;
+                    self.del_in_rtree(relation_store, &mut stack, &rtree_extractors, &tup)?;
+                    if let Some(returned) = &mut returned {
+                        returned.push(returned_row(UPDATED_KIND, &extracted, Some(&tup)));
+                    }
 
                     if need_to_collect {
                         old_tuples.push(DataValue::List(tup));
                     }
-                } else if has_indices {
-                    for (idx_rel, extractor) in relation_store.indices.values() {
-                        let idx_tup_new = extractor
-                            .iter()
-                            .map(|i| extracted[*i].clone())
-                            .collect_vec();
-                        let encoded_new =
-                            idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
-                        self.store_tx.put(&encoded_new, &[])?;
+                } else {
+                    if has_indices {
+                        self.put_in_index(relation_store, &extracted)?;
+                    }
+                    if let Some(returned) = &mut returned {
+                        returned.push(returned_row(INSERTED_KIND, &extracted, None));
                     }
                 }
 
@@ -310,6 +379,7 @@ impl<'a> SessionTx<'a> {
                     &extracted,
                     &lsh_perms,
                 )?;
+                self.put_in_rtree(relation_store, &mut stack, &rtree_extractors, &extracted)?;
 
                 if need_to_collect {
                     new_tuples.push(DataValue::List(extracted));
@@ -347,9 +417,11 @@ impl<'a> SessionTx<'a> {
         processors: &BTreeMap<SmartString<LazyCompact>, (Arc<TextAnalyzer>, Vec<Bytecode>)>,
         new_kv: &[DataValue],
     ) -> Result<()> {
-        for (k, (idx_handle, _)) in rel_handle.fts_indices.iter() {
+        for (k, (idx_handle, manifest)) in rel_handle.fts_indices.iter() {
             let (tokenizer, extractor) = processors.get(k).unwrap();
-            self.put_fts_index_item(new_kv, extractor, stack, tokenizer, rel_handle, idx_handle)?;
+            self.put_fts_index_item(
+                new_kv, extractor, stack, tokenizer, rel_handle, idx_handle, manifest,
+            )?;
         }
         Ok(())
     }
@@ -361,9 +433,11 @@ impl<'a> SessionTx<'a> {
         processors: &BTreeMap<SmartString<LazyCompact>, (Arc<TextAnalyzer>, Vec<Bytecode>)>,
         old_kv: &[DataValue],
     ) -> Result<()> {
-        for (k, (idx_handle, _)) in rel_handle.fts_indices.iter() {
+        for (k, (idx_handle, manifest)) in rel_handle.fts_indices.iter() {
             let (tokenizer, extractor) = processors.get(k).unwrap();
-            self.del_fts_index_item(old_kv, extractor, stack, tokenizer, rel_handle, idx_handle)?;
+            self.del_fts_index_item(
+                old_kv, extractor, stack, tokenizer, rel_handle, idx_handle, manifest,
+            )?;
         }
         Ok(())
     }
@@ -400,6 +474,47 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    fn put_in_rtree(
+        &mut self,
+        rel_handle: &RelationHandle,
+        stack: &mut Vec<DataValue>,
+        extractors: &BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>,
+        new_kv: &[DataValue],
+    ) -> Result<()> {
+        for (k, (idx_handle, manifest)) in rel_handle.rtree_indices.iter() {
+            let extractor = extractors.get(k).unwrap();
+            self.put_rtree_index_item(new_kv, extractor, stack, rel_handle, idx_handle, manifest)?;
+        }
+        Ok(())
+    }
+
+    fn del_in_rtree(
+        &mut self,
+        rel_handle: &RelationHandle,
+        stack: &mut Vec<DataValue>,
+        extractors: &BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>,
+        old_kv: &[DataValue],
+    ) -> Result<()> {
+        for (k, (idx_handle, _)) in rel_handle.rtree_indices.iter() {
+            let extractor = extractors.get(k).unwrap();
+            self.del_rtree_index_item(old_kv, extractor, stack, rel_handle, idx_handle)?;
+        }
+        Ok(())
+    }
+
+    fn make_rtree_extractors(
+        relation_store: &RelationHandle,
+    ) -> Result<BTreeMap<SmartString<LazyCompact>, Vec<Bytecode>>> {
+        let mut extractors = BTreeMap::new();
+        for (name, (_, manifest)) in relation_store.rtree_indices.iter() {
+            extractors.insert(
+                name.clone(),
+                relation_store.compile_expr(&manifest.extractor)?,
+            );
+        }
+        Ok(extractors)
+    }
+
     fn update_in_hnsw(
         &mut self,
         relation_store: &RelationHandle,
@@ -437,38 +552,17 @@ impl<'a> SessionTx<'a> {
         relation_store: &RelationHandle,
     ) -> Result<BTreeMap<SmartString<LazyCompact>, (Arc<TextAnalyzer>, Vec<Bytecode>)>> {
         let mut processors = BTreeMap::new();
-        for (name, (_, manifest)) in relation_store.fts_indices.iter() {
-            let tokenizer = self.tokenizers.get(
-                &relation_store.name,
-                &manifest.tokenizer,
-                &manifest.filters,
-            )?;
-
-            let parsed = CozoScriptParser::parse(Rule::expr, &manifest.extractor)
-                .into_diagnostic()?
-                .next()
-                .unwrap();
-            let mut code_expr = build_expr(parsed, &Default::default())?;
-            let binding_map = relation_store.raw_binding_map();
-            code_expr.fill_binding_indices(&binding_map)?;
-            let extractor = code_expr.compile()?;
+        for (name, (idx_handle, manifest)) in relation_store.fts_indices.iter() {
+            let tokenizer =
+                self.get_tokenizer(&idx_handle.name, &manifest.tokenizer, &manifest.filters)?;
+            let extractor = relation_store.compile_expr(&manifest.extractor)?;
             processors.insert(name.clone(), (tokenizer, extractor));
         }
-        for (name, (_, _, manifest)) in relation_store.lsh_indices.iter() {
-            let tokenizer = self.tokenizers.get(
-                &relation_store.name,
-                &manifest.tokenizer,
-                &manifest.filters,
-            )?;
+        for (name, (idx_handle, _, manifest)) in relation_store.lsh_indices.iter() {
+            let tokenizer =
+                self.get_tokenizer(&idx_handle.name, &manifest.tokenizer, &manifest.filters)?;
 
-            let parsed = CozoScriptParser::parse(Rule::expr, &manifest.extractor)
-                .into_diagnostic()?
-                .next()
-                .unwrap();
-            let mut code_expr = build_expr(parsed, &Default::default())?;
-            let binding_map = relation_store.raw_binding_map();
-            code_expr.fill_binding_indices(&binding_map)?;
-            let extractor = code_expr.compile()?;
+            let extractor = relation_store.compile_expr(&manifest.extractor)?;
             processors.insert(name.clone(), (tokenizer, extractor));
         }
         Ok(processors)
@@ -493,10 +587,245 @@ impl<'a> SessionTx<'a> {
         Ok(hnsw_filters)
     }
 
+    pub(crate) fn make_check_constraints(
+        relation_store: &RelationHandle,
+    ) -> Result<Vec<(SmartString<LazyCompact>, Vec<Bytecode>)>> {
+        let mut checks = vec![];
+        let binding_map = relation_store.raw_binding_map();
+        for col in relation_store
+            .metadata
+            .keys
+            .iter()
+            .chain(relation_store.metadata.non_keys.iter())
+        {
+            if let Some(check) = &col.check {
+                let mut check = check.clone();
+                check.fill_binding_indices(&binding_map)?;
+                checks.push((col.name.clone(), check.compile()?));
+            }
+        }
+        Ok(checks)
+    }
+
+    pub(crate) fn ensure_check_constraints(
+        relation_store: &RelationHandle,
+        checks: &[(SmartString<LazyCompact>, Vec<Bytecode>)],
+        stack: &mut Vec<DataValue>,
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (col_name, check) in checks {
+            if !eval_bytecode_pred(check, row, stack, Default::default())? {
+                bail!(CheckConstraintViolation {
+                    relation: relation_store.name.to_string(),
+                    column: col_name.to_string(),
+                    row: row.to_vec(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn make_foreign_key_targets(
+        &self,
+        relation_store: &RelationHandle,
+    ) -> Result<Vec<(usize, RelationHandle, ForeignKey)>> {
+        let mut targets = vec![];
+        let binding_map = relation_store.raw_binding_map();
+        for fk in relation_store.metadata.foreign_keys.iter() {
+            let col_idx = binding_map[&Symbol::new(fk.column.clone(), Default::default())];
+            let target = if fk.ref_relation == relation_store.name {
+                relation_store.clone()
+            } else {
+                self.get_relation(&fk.ref_relation, false)?
+            };
+            targets.push((col_idx, target, fk.clone()));
+        }
+        Ok(targets)
+    }
+
+    pub(crate) fn ensure_foreign_keys(
+        &self,
+        relation_store: &RelationHandle,
+        targets: &[(usize, RelationHandle, ForeignKey)],
+        row: &[DataValue],
+    ) -> Result<()> {
+        for (col_idx, target, fk) in targets {
+            let val = &row[*col_idx];
+            if *val == DataValue::Null {
+                continue;
+            }
+            // a row may reference itself
+            if target.name == relation_store.name && row[0] == *val {
+                continue;
+            }
+            let key = target.encode_key_for_store(std::slice::from_ref(val), Default::default())?;
+            if !self.store_tx.exists(&key, false)? {
+                bail!(ForeignKeyViolation {
+                    relation: relation_store.name.to_string(),
+                    column: fk.column.to_string(),
+                    target: format!("{}.{}", fk.ref_relation, fk.ref_column),
+                    row: row.to_vec(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the `on delete` actions of all foreign keys referencing the removed keys
+    pub(crate) fn apply_referential_actions<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        propagate_triggers: bool,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        relation_store: &RelationHandle,
+        removed_keys: &[DataValue],
+        span: SourceSpan,
+    ) -> Result<()> {
+        for referrer_name in relation_store.referenced_by.iter() {
+            let mut referrer = self.get_relation(referrer_name, false)?;
+            referrer
+                .metadata
+                .resolve_custom_functions(&db.custom_functions.read().unwrap())?;
+            let n_keys = referrer.metadata.keys.len();
+            let key_bindings = referrer
+                .metadata
+                .keys
+                .iter()
+                .map(|col| Symbol::new(col.name.clone(), Default::default()))
+                .collect_vec();
+            for fk in referrer.metadata.foreign_keys.clone() {
+                if fk.ref_relation != relation_store.name {
+                    continue;
+                }
+                let col_idx =
+                    referrer.raw_binding_map()[&Symbol::new(fk.column.clone(), Default::default())];
+                let referencing = self.find_referencing_keys(&referrer, col_idx, removed_keys)?;
+                if referencing.is_empty() {
+                    continue;
+                }
+                match fk.on_delete {
+                    ForeignKeyAction::Restrict => {
+                        bail!(ForeignKeyRestricted {
+                            relation: relation_store.name.to_string(),
+                            referrer: referrer.name.to_string(),
+                            column: fk.column.to_string(),
+                            key: referencing[0].clone(),
+                        })
+                    }
+                    ForeignKeyAction::Cascade => {
+                        let metadata = referrer.metadata.clone();
+                        self.remove_from_relation(
+                            db,
+                            referencing.into_iter().map(Ok),
+                            &key_bindings,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                            propagate_triggers,
+                            to_clear,
+                            &mut referrer,
+                            &metadata,
+                            &key_bindings,
+                            span,
+                            None,
+                        )?;
+                    }
+                    ForeignKeyAction::SetNull => {
+                        let col = referrer.metadata.non_keys[col_idx - n_keys].clone();
+                        let mut bindings = key_bindings.clone();
+                        bindings.push(Symbol::new(col.name.clone(), Default::default()));
+                        let mut metadata = StoredRelationMetadata {
+                            keys: referrer.metadata.keys.clone(),
+                            non_keys: vec![],
+                            foreign_keys: vec![],
+                        };
+                        metadata.keys.push(col);
+                        let rows = referencing.into_iter().map(|mut key| {
+                            key.push(DataValue::Null);
+                            Ok(key)
+                        });
+                        self.update_in_relation(
+                            db,
+                            rows,
+                            &bindings,
+                            cur_vld,
+                            callback_targets,
+                            callback_collector,
+                            propagate_triggers,
+                            to_clear,
+                            &mut referrer,
+                            &metadata,
+                            &bindings,
+                            span,
+                            None,
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys of the rows of `referrer` whose column at `col_idx` takes one of `values`.
+    /// Uses the primary key or a plain index when the column comes first in either,
+    /// otherwise scans the whole relation.
+    fn find_referencing_keys(
+        &self,
+        referrer: &RelationHandle,
+        col_idx: usize,
+        values: &[DataValue],
+    ) -> Result<Vec<Tuple>> {
+        let n_keys = referrer.metadata.keys.len();
+        let mut ret = vec![];
+        if col_idx == 0 {
+            for val in values {
+                for tuple in referrer.scan_prefix(self, &vec![val.clone()]) {
+                    ret.push(tuple?[..n_keys].to_vec());
+                }
+            }
+        } else if let Some((idx_rel, extractor)) =
+            referrer.indices.values().find(|(_, ex)| ex[0] == col_idx)
+        {
+            for val in values {
+                for tuple in idx_rel.scan_prefix(self, &vec![val.clone()]) {
+                    let tuple = tuple?;
+                    ret.push(
+                        (0..n_keys)
+                            .map(|i| tuple[extractor.iter().position(|e| *e == i).unwrap()].clone())
+                            .collect_vec(),
+                    );
+                }
+            }
+        } else {
+            let col = referrer
+                .metadata
+                .keys
+                .iter()
+                .chain(referrer.metadata.non_keys.iter())
+                .nth(col_idx)
+                .unwrap();
+            let mut values = values
+                .iter()
+                .map(|v| col.typing.coerce_for_lookup(v).unwrap_or_else(|| v.clone()))
+                .collect_vec();
+            values.sort();
+            for tuple in referrer.scan_all(self) {
+                let tuple = tuple?;
+                if values.binary_search(&tuple[col_idx]).is_ok() {
+                    ret.push(tuple[..n_keys].to_vec());
+                }
+            }
+        }
+        Ok(ret)
+    }
+
     fn update_in_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
@@ -507,6 +836,7 @@ impl<'a> SessionTx<'a> {
         metadata: &StoredRelationMetadata,
         key_bindings: &[Symbol],
         span: SourceSpan,
+        mut returned: Option<&mut Vec<Tuple>>,
     ) -> Result<()> {
         let is_callback_target = callback_targets.contains(&relation_store.name);
 
@@ -527,11 +857,13 @@ impl<'a> SessionTx<'a> {
 
         let need_to_collect = !relation_store.is_temp
             && (is_callback_target
-                || (propagate_triggers && !relation_store.put_triggers.is_empty()));
+                || (propagate_triggers && !relation_store.put_triggers.is_empty())
+                || !relation_store.materialized_views.is_empty());
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
         let has_lsh_indices = !relation_store.lsh_indices.is_empty();
+        let has_rtree_indices = !relation_store.rtree_indices.is_empty();
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
 
@@ -546,8 +878,12 @@ impl<'a> SessionTx<'a> {
         let hnsw_filters = Self::make_hnsw_filters(relation_store)?;
         let fts_lsh_processors = self.make_fts_lsh_processors(relation_store)?;
         let lsh_perms = self.make_lsh_hash_perms(relation_store);
+        let rtree_extractors = Self::make_rtree_extractors(relation_store)?;
+        let checks = Self::make_check_constraints(relation_store)?;
+        let fk_targets = self.make_foreign_key_targets(relation_store)?;
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let mut new_kv: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
@@ -584,16 +920,23 @@ impl<'a> SessionTx<'a> {
                     }
                 }
             }
+            Self::ensure_check_constraints(relation_store, &checks, &mut stack, &new_kv)?;
+            self.ensure_foreign_keys(relation_store, &fk_targets, &new_kv)?;
             let new_val = relation_store.encode_val_for_store(&new_kv, span)?;
+            if let Some(returned) = &mut returned {
+                returned.push(returned_row(UPDATED_KIND, &new_kv, Some(&old_kv)));
+            }
 
             if need_to_collect
                 || has_indices
                 || has_hnsw_indices
                 || has_fts_indices
                 || has_lsh_indices
+                || has_rtree_indices
             {
                 self.del_in_fts(relation_store, &mut stack, &fts_lsh_processors, &old_kv)?;
                 self.del_in_lsh(relation_store, &old_kv)?;
+                self.del_in_rtree(relation_store, &mut stack, &rtree_extractors, &old_kv)?;
                 self.update_in_index(relation_store, &new_kv, &old_kv)?;
 
                 if need_to_collect {
@@ -609,6 +952,7 @@ impl<'a> SessionTx<'a> {
                     &new_kv,
                     &lsh_perms,
                 )?;
+                self.put_in_rtree(relation_store, &mut stack, &rtree_extractors, &new_kv)?;
 
                 if need_to_collect {
                     new_tuples.push(DataValue::List(new_kv));
@@ -668,13 +1012,9 @@ impl<'a> SessionTx<'a> {
         let kv_bindings = bindings;
         if propagate_triggers {
             for trigger in &relation_store.put_triggers {
-                let mut program = parse_script(
-                    trigger,
-                    &Default::default(),
-                    &db.fixed_rules.read().unwrap(),
-                    cur_vld,
-                )?
-                .get_single_program()?;
+                let mut program = db
+                    .parse_payload(trigger, &Default::default(), None, cur_vld)?
+                    .get_single_program()?;
 
                 make_const_rule(
                     &mut program,
@@ -709,6 +1049,24 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        if !relation_store.materialized_views.is_empty() {
+            let changed = new_tuples
+                .iter()
+                .chain(old_tuples.iter())
+                .cloned()
+                .collect_vec();
+            self.maintain_materialized_views(
+                db,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                propagate_triggers,
+                to_clear,
+                relation_store,
+                changed,
+            )?;
+        }
+
         if is_callback_target {
             let target_collector = callback_collector
                 .entry(relation_store.name.clone())
@@ -744,13 +1102,326 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    /// Recomputes all rows of a materialized view, returning the data to clean up
+    pub(crate) fn refresh_materialized_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        name: &str,
+        cur_vld: ValidityTs,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let view = self.get_view(name)?;
+        let prog = parse_view_script(db, &view.script, cur_vld)?;
+        let rules = materialized_view_rules(&prog)?;
+        let mut to_clear = vec![];
+        self.write_materialized_view(
+            db,
+            name,
+            rules,
+            None,
+            cur_vld,
+            &Default::default(),
+            &mut Default::default(),
+            true,
+            &mut to_clear,
+        )?;
+        Ok(to_clear)
+    }
+
+    /// Brings the materialized views reading `relation_store` up to date,
+    /// where `changed` holds the full rows both before and after the mutation.
+    /// Only the rows of the views derivable from the changed rows are recomputed.
+    fn maintain_materialized_views<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        propagate_triggers: bool,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+        relation_store: &RelationHandle,
+        changed: Vec<DataValue>,
+    ) -> Result<()> {
+        let source_cols = relation_store
+            .metadata
+            .keys
+            .iter()
+            .chain(relation_store.metadata.non_keys.iter())
+            .map(|col| col.name.clone())
+            .collect_vec();
+        let kv_bindings = source_cols
+            .iter()
+            .map(|col| Symbol::new(col.clone(), Default::default()))
+            .collect_vec();
+        let kv_args = kv_bindings
+            .iter()
+            .map(|var| Expr::Binding {
+                var: var.clone(),
+                tuple_pos: None,
+            })
+            .collect_vec();
+
+        for view_name in relation_store.materialized_views.iter() {
+            let view = self.get_view(view_name)?;
+            let prog = parse_view_script(db, &view.script, cur_vld)?;
+            let rules = materialized_view_rules(&prog)?;
+            let has_keys = rules[0].aggr.iter().any(|aggr| aggr.is_none());
+
+            // without grouping columns the view has a single row, recomputed as a whole
+            let affected = if has_keys {
+                // semi-naive style delta rules, one for each application of the source
+                let mut affected_rules = vec![];
+                for rule in rules {
+                    let head = rule
+                        .head
+                        .iter()
+                        .zip(rule.aggr.iter())
+                        .filter(|(_, aggr)| aggr.is_none())
+                        .map(|(symb, _)| symb.clone())
+                        .collect_vec();
+                    let mut n_applications = 0;
+                    for atom in rule.body.iter() {
+                        delta_view_atom(
+                            atom,
+                            &relation_store.name,
+                            &source_cols,
+                            usize::MAX,
+                            &mut n_applications,
+                        );
+                    }
+                    for target in 0..n_applications {
+                        let mut counter = 0;
+                        let body = rule
+                            .body
+                            .iter()
+                            .map(|atom| {
+                                delta_view_atom(
+                                    atom,
+                                    &relation_store.name,
+                                    &source_cols,
+                                    target,
+                                    &mut counter,
+                                )
+                            })
+                            .collect_vec();
+                        affected_rules.push(InputInlineRule {
+                            aggr: vec![None; head.len()],
+                            head: head.clone(),
+                            body,
+                            span: rule.span,
+                        });
+                    }
+                }
+
+                let mut affected_prog = InputProgram {
+                    prog: Default::default(),
+                    out_opts: Default::default(),
+                    disable_magic_rewrite: false,
+                };
+                affected_prog.prog.insert(
+                    Symbol::new(PROG_ENTRY, Default::default()),
+                    InputInlineRulesOrFixed::Rules {
+                        rules: affected_rules,
+                    },
+                );
+                make_const_rule(
+                    &mut affected_prog,
+                    DELTA_RULE,
+                    kv_bindings.clone(),
+                    changed.clone(),
+                );
+                let all_rule = |atom| InputInlineRule {
+                    head: kv_bindings.clone(),
+                    aggr: vec![None; kv_bindings.len()],
+                    body: vec![atom],
+                    span: Default::default(),
+                };
+                affected_prog.prog.insert(
+                    Symbol::new(ALL_RULE, Default::default()),
+                    InputInlineRulesOrFixed::Rules {
+                        rules: vec![
+                            all_rule(InputAtom::Relation {
+                                inner: InputRelationApplyAtom {
+                                    name: Symbol::new(
+                                        relation_store.name.clone(),
+                                        Default::default(),
+                                    ),
+                                    args: kv_args.clone(),
+                                    valid_at: None,
+                                    span: Default::default(),
+                                },
+                            }),
+                            all_rule(InputAtom::Rule {
+                                inner: InputRuleApplyAtom {
+                                    name: Symbol::new(DELTA_RULE, Default::default()),
+                                    args: kv_args.clone(),
+                                    span: Default::default(),
+                                },
+                            }),
+                        ],
+                    },
+                );
+
+                let (res, cleanups) = db.run_query(
+                    self,
+                    affected_prog,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    false,
+                )?;
+                to_clear.extend(cleanups);
+                if res.rows.is_empty() {
+                    continue;
+                }
+                Some(res.rows)
+            } else {
+                None
+            };
+
+            self.write_materialized_view(
+                db,
+                view_name,
+                rules,
+                affected,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                propagate_triggers,
+                to_clear,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Recomputes the rows of a materialized view with the given keys, or all rows if `None`
+    fn write_materialized_view<'s, S: Storage<'s>>(
+        &mut self,
+        db: &Db<S>,
+        name: &str,
+        rules: &[InputInlineRule],
+        affected: Option<Vec<Tuple>>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        propagate_triggers: bool,
+        to_clear: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let mut view_rel = self.get_relation(name, true)?;
+        // the relation is only read-only for users
+        view_rel.access_level = AccessLevel::Protected;
+        let metadata = view_rel.metadata.clone();
+        let key_bindings = metadata
+            .keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+        let dep_bindings = metadata
+            .non_keys
+            .iter()
+            .map(|col| Symbol::new(col.name.clone(), Default::default()))
+            .collect_vec();
+
+        let mut rules = rules.to_vec();
+        let mut prog = InputProgram {
+            prog: Default::default(),
+            out_opts: Default::default(),
+            disable_magic_rewrite: false,
+        };
+        let to_remove = match affected {
+            Some(keys) => {
+                for rule in rules.iter_mut() {
+                    let args = rule
+                        .head
+                        .iter()
+                        .zip(rule.aggr.iter())
+                        .filter(|(_, aggr)| aggr.is_none())
+                        .map(|(symb, _)| Expr::Binding {
+                            var: symb.clone(),
+                            tuple_pos: None,
+                        })
+                        .collect_vec();
+                    rule.body.insert(
+                        0,
+                        InputAtom::Rule {
+                            inner: InputRuleApplyAtom {
+                                name: Symbol::new(AFFECTED_RULE, rule.span),
+                                args,
+                                span: rule.span,
+                            },
+                        },
+                    );
+                }
+                make_const_rule(
+                    &mut prog,
+                    AFFECTED_RULE,
+                    key_bindings.clone(),
+                    keys.iter().cloned().map(DataValue::List).collect_vec(),
+                );
+                keys
+            }
+            None => view_rel
+                .scan_all(self)
+                .map_ok(|tuple| tuple[..key_bindings.len()].to_vec())
+                .try_collect()?,
+        };
+        let headers = rules.last().unwrap().head.clone();
+        prog.prog.insert(
+            Symbol::new(PROG_ENTRY, Default::default()),
+            InputInlineRulesOrFixed::Rules { rules },
+        );
+
+        let (res, cleanups) = db.run_query(
+            self,
+            prog,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            false,
+        )?;
+        to_clear.extend(cleanups);
+
+        if !to_remove.is_empty() {
+            self.remove_from_relation(
+                db,
+                to_remove.into_iter().map(Ok),
+                &key_bindings,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                propagate_triggers,
+                to_clear,
+                &mut view_rel,
+                &metadata,
+                &key_bindings,
+                Default::default(),
+                None,
+            )?;
+        }
+        self.put_into_relation(
+            db,
+            res.rows.into_iter().map(Ok),
+            &headers,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            propagate_triggers,
+            to_clear,
+            &mut view_rel,
+            &metadata,
+            &key_bindings,
+            &dep_bindings,
+            Default::default(),
+            None,
+        )
+    }
+
     fn update_in_index(
         &mut self,
         relation_store: &RelationHandle,
         new_kv: &[DataValue],
         old_kv: &[DataValue],
     ) -> Result<()> {
-        for (idx_rel, idx_extractor) in relation_store.indices.values() {
+        for (idx_name, (idx_rel, idx_extractor)) in relation_store.indices.iter() {
             let idx_tup_old = idx_extractor
                 .iter()
                 .map(|i| old_kv[*i].clone())
@@ -762,15 +1433,74 @@ impl<'a> SessionTx<'a> {
                 .iter()
                 .map(|i| new_kv[*i].clone())
                 .collect_vec();
+            self.ensure_unique_in_index(relation_store, idx_name, &idx_tup_new)?;
             let encoded_new = idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
             self.store_tx.put(&encoded_new, &[])?;
         }
         Ok(())
     }
 
+    fn put_in_index(
+        &mut self,
+        relation_store: &RelationHandle,
+        new_kv: &[DataValue],
+    ) -> Result<()> {
+        for (idx_name, (idx_rel, idx_extractor)) in relation_store.indices.iter() {
+            let idx_tup_new = idx_extractor
+                .iter()
+                .map(|i| new_kv[*i].clone())
+                .collect_vec();
+            self.ensure_unique_in_index(relation_store, idx_name, &idx_tup_new)?;
+            let encoded_new = idx_rel.encode_key_for_store(&idx_tup_new, Default::default())?;
+            self.store_tx.put(&encoded_new, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Checks that no other row shares the unique columns of `idx_tup`. Must be called
+    /// after the old entry of the row being written has been removed from the index.
+    /// Nulls never conflict with each other.
+    pub(crate) fn ensure_unique_in_index(
+        &self,
+        relation_store: &RelationHandle,
+        idx_name: &str,
+        idx_tup: &[DataValue],
+    ) -> Result<()> {
+        let n_cols = match relation_store.unique_indices.get(idx_name) {
+            None => return Ok(()),
+            Some(n) => *n,
+        };
+        let prefix = idx_tup[..n_cols].to_vec();
+        if prefix.contains(&DataValue::Null) {
+            return Ok(());
+        }
+        let (idx_rel, idx_extractor) = &relation_store.indices[idx_name];
+        for existing in idx_rel.scan_prefix(self, &prefix) {
+            let existing = existing?;
+            if existing != idx_tup {
+                let key_of = |t: &[DataValue]| {
+                    (0..relation_store.metadata.keys.len())
+                        .map(|i| {
+                            let pos = idx_extractor.iter().position(|e| *e == i).unwrap();
+                            t[pos].clone()
+                        })
+                        .collect_vec()
+                };
+                bail!(UniqueIndexViolation {
+                    relation: relation_store.name.to_string(),
+                    index: idx_name.to_string(),
+                    key: key_of(idx_tup),
+                    existing_key: key_of(&existing),
+                    values: prefix,
+                })
+            }
+        }
+        Ok(())
+    }
+
     fn ensure_not_in_relation(
         &mut self,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         relation_store: &mut RelationHandle,
@@ -794,6 +1524,7 @@ impl<'a> SessionTx<'a> {
         )?;
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
@@ -817,7 +1548,7 @@ impl<'a> SessionTx<'a> {
 
     fn ensure_in_relation(
         &mut self,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         relation_store: &mut RelationHandle,
@@ -849,6 +1580,7 @@ impl<'a> SessionTx<'a> {
         key_extractors.extend(val_extractors);
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
@@ -887,7 +1619,7 @@ impl<'a> SessionTx<'a> {
     fn remove_from_relation<'s, S: Storage<'s>>(
         &mut self,
         db: &Db<S>,
-        res_iter: impl Iterator<Item = Tuple>,
+        res_iter: impl Iterator<Item = Result<Tuple>>,
         headers: &[Symbol],
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
@@ -898,6 +1630,7 @@ impl<'a> SessionTx<'a> {
         metadata: &StoredRelationMetadata,
         key_bindings: &[Symbol],
         span: SourceSpan,
+        mut returned: Option<&mut Vec<Tuple>>,
     ) -> Result<()> {
         let is_callback_target = callback_targets.contains(&relation_store.name);
 
@@ -917,27 +1650,49 @@ impl<'a> SessionTx<'a> {
 
         let need_to_collect = !relation_store.is_temp
             && (is_callback_target
-                || (propagate_triggers && !relation_store.rm_triggers.is_empty()));
+                || (propagate_triggers && !relation_store.rm_triggers.is_empty())
+                || !relation_store.materialized_views.is_empty());
         let has_indices = !relation_store.indices.is_empty();
         let has_hnsw_indices = !relation_store.hnsw_indices.is_empty();
         let has_fts_indices = !relation_store.fts_indices.is_empty();
+        let has_rtree_indices = !relation_store.rtree_indices.is_empty();
+        let has_referrers = !relation_store.referenced_by.is_empty();
         let fts_processors = self.make_fts_lsh_processors(relation_store)?;
+        let rtree_extractors = Self::make_rtree_extractors(relation_store)?;
         let mut new_tuples: Vec<DataValue> = vec![];
         let mut old_tuples: Vec<DataValue> = vec![];
+        let mut removed_keys = vec![];
         let mut stack = vec![];
 
         for tuple in res_iter {
+            let tuple = tuple?;
             let extracted: Vec<DataValue> = key_extractors
                 .iter()
                 .map(|ex| ex.extract_data(&tuple, cur_vld))
                 .try_collect()?;
             let key = relation_store.encode_key_for_store(&extracted, span)?;
-            if need_to_collect || has_indices || has_hnsw_indices || has_fts_indices {
-                if let Some(existing) = self.store_tx.get(&key, false)? {
+            if need_to_collect
+                || has_indices
+                || has_hnsw_indices
+                || has_fts_indices
+                || has_rtree_indices
+                || has_referrers
+                || returned.is_some()
+            {
+                let existing = if relation_store.is_temp {
+                    self.temp_store_tx.get(&key, false)?
+                } else {
+                    self.store_tx.get(&key, false)?
+                };
+                if let Some(existing) = existing {
+                    if has_referrers {
+                        removed_keys.push(extracted[0].clone());
+                    }
                     let mut tup = extracted.clone();
                     extend_tuple_from_v(&mut tup, &existing);
                     self.del_in_fts(relation_store, &mut stack, &fts_processors, &tup)?;
                     self.del_in_lsh(relation_store, &tup)?;
+                    self.del_in_rtree(relation_store, &mut stack, &rtree_extractors, &tup)?;
                     if has_indices {
                         for (idx_rel, extractor) in relation_store.indices.values() {
                             let idx_tup = extractor.iter().map(|i| tup[*i].clone()).collect_vec();
@@ -951,6 +1706,9 @@ impl<'a> SessionTx<'a> {
                             self.hnsw_remove(relation_store, idx_handle, &extracted)?;
                         }
                     }
+                    if let Some(returned) = &mut returned {
+                        returned.push(returned_row(DELETED_KIND, &tup, None));
+                    }
                     if need_to_collect {
                         old_tuples.push(DataValue::List(tup));
                     }
@@ -966,6 +1724,20 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        if !removed_keys.is_empty() {
+            self.apply_referential_actions(
+                db,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                propagate_triggers,
+                to_clear,
+                relation_store,
+                &removed_keys,
+                span,
+            )?;
+        }
+
         // triggers and callbacks
         if need_to_collect && !new_tuples.is_empty() {
             let k_bindings = relation_store
@@ -986,13 +1758,9 @@ impl<'a> SessionTx<'a> {
 
             if propagate_triggers {
                 for trigger in &relation_store.rm_triggers {
-                    let mut program = parse_script(
-                        trigger,
-                        &Default::default(),
-                        &db.fixed_rules.read().unwrap(),
-                        cur_vld,
-                    )?
-                    .get_single_program()?;
+                    let mut program = db
+                        .parse_payload(trigger, &Default::default(), None, cur_vld)?
+                        .get_single_program()?;
 
                     make_const_rule(&mut program, "_new", k_bindings.clone(), new_tuples.clone());
 
@@ -1023,6 +1791,19 @@ impl<'a> SessionTx<'a> {
                 }
             }
 
+            if !relation_store.materialized_views.is_empty() {
+                self.maintain_materialized_views(
+                    db,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    propagate_triggers,
+                    to_clear,
+                    relation_store,
+                    old_tuples.clone(),
+                )?;
+            }
+
             if is_callback_target {
                 let target_collector = callback_collector
                     .entry(relation_store.name.clone())
@@ -1062,6 +1843,23 @@ impl<'a> SessionTx<'a> {
     }
 }
 
+const INSERTED_KIND: &str = "inserted";
+const UPDATED_KIND: &str = "updated";
+const DELETED_KIND: &str = "deleted";
+
+/// A row returned by `:returning`: the kind of change, the row after the change (the removed
+/// row for deletions), and the row replaced by an update, or null for insertions and deletions
+fn returned_row(kind: &str, row: &[DataValue], old: Option<&[DataValue]>) -> Tuple {
+    let mut ret = Vec::with_capacity(row.len() + 2);
+    ret.push(DataValue::from(kind));
+    ret.extend_from_slice(row);
+    ret.push(match old {
+        None => DataValue::Null,
+        Some(old) => DataValue::List(old.to_vec()),
+    });
+    ret
+}
+
 #[derive(Debug, Error, Diagnostic)]
 #[error("Assertion failure for {key:?} of {relation}: {notice}")]
 struct TransactAssertionFailure {
@@ -1070,6 +1868,48 @@ struct TransactAssertionFailure {
     notice: String,
 }
 
+#[derive(Debug, Error, Diagnostic)]
+#[error("Check constraint of column {column} of {relation} violated by row {row:?}")]
+#[diagnostic(code(eval::check_constraint_violation))]
+struct CheckConstraintViolation {
+    relation: String,
+    column: String,
+    row: Vec<DataValue>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error(
+    "Foreign key {column} of {relation} violated: row {row:?} references a missing row of {target}"
+)]
+#[diagnostic(code(eval::foreign_key_violation))]
+struct ForeignKeyViolation {
+    relation: String,
+    column: String,
+    target: String,
+    row: Vec<DataValue>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot remove rows of {relation}: still referenced by row with key {key:?} of {referrer} via column {column}")]
+#[diagnostic(code(eval::foreign_key_restricted))]
+struct ForeignKeyRestricted {
+    relation: String,
+    referrer: String,
+    column: String,
+    key: Vec<DataValue>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Unique index {index} of {relation} violated: key {key:?} conflicts with existing key {existing_key:?} on values {values:?}")]
+#[diagnostic(code(eval::unique_index_violation))]
+struct UniqueIndexViolation {
+    relation: String,
+    index: String,
+    key: Vec<DataValue>,
+    existing_key: Vec<DataValue>,
+    values: Vec<DataValue>,
+}
+
 enum DataExtractor {
     DefaultExtractor(Expr, NullableColType),
     IndexExtractor(usize, NullableColType),
@@ -1141,6 +1981,15 @@ fn make_extractor(
     }
 }
 
+fn parse_view_script<'s, S: Storage<'s>>(
+    db: &Db<S>,
+    script: &str,
+    cur_vld: ValidityTs,
+) -> Result<InputProgram> {
+    db.parse_payload(script, &Default::default(), None, cur_vld)?
+        .get_single_program()
+}
+
 fn make_const_rule(
     program: &mut InputProgram,
     rule_name: &str,
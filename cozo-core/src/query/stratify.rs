@@ -32,7 +32,8 @@ impl NormalFormAtom {
             | NormalFormAtom::Unification(_)
             | NormalFormAtom::HnswSearch(_)
             | NormalFormAtom::FtsSearch(_)
-            | NormalFormAtom::LshSearch(_) => Default::default(),
+            | NormalFormAtom::LshSearch(_)
+            | NormalFormAtom::RtreeSearch(_) => Default::default(),
             NormalFormAtom::Rule(r) => BTreeMap::from([(&r.name, false)]),
             NormalFormAtom::NegatedRule(r) => BTreeMap::from([(&r.name, true)]),
         }
@@ -221,9 +222,12 @@ fn make_scc_reduced_graph(
 }
 
 impl NormalFormProgram {
-    /// returns the stratified program and the store lifetimes of the intermediate relations
+    /// returns the stratified program and the store lifetimes of the intermediate relations.
+    /// The rules in `roots` are kept along with those needed by the entry, and their
+    /// stores live until the end of the evaluation.
     pub(crate) fn into_stratified_program(
         self,
+        roots: &[Symbol],
     ) -> Result<(StratifiedNormalFormProgram, BTreeMap<MagicSymbol, usize>)> {
         // prerequisite: the program is already in disjunctive normal form
         // 0. build a graph of the program
@@ -232,10 +236,14 @@ impl NormalFormProgram {
         let graph = reduce_to_graph(&stratified_graph);
 
         // 1. find reachable clauses starting from the query
-        let reachable: BTreeSet<_> = reachable_components(&graph, &prog_entry)
-            .into_iter()
-            .map(|k| (*k).clone())
-            .collect();
+        let mut reachable = BTreeSet::new();
+        for root in roots.iter().chain([prog_entry]) {
+            reachable.extend(
+                reachable_components(&graph, &root)
+                    .into_iter()
+                    .map(|k| (*k).clone()),
+            );
+        }
         // 2. prune the graph of unreachable clauses
         let stratified_graph: StratifiedGraph<_> = stratified_graph
             .into_iter()
@@ -270,7 +278,17 @@ impl NormalFormProgram {
             })
             .collect_vec();
 
-        let mut store_lifetimes = BTreeMap::new();
+        let mut store_lifetimes: BTreeMap<_, _> = roots
+            .iter()
+            .map(|root| {
+                (
+                    MagicSymbol::Muggle {
+                        inner: root.clone(),
+                    },
+                    usize::MAX,
+                )
+            })
+            .collect();
         for (fr, tos) in &stratified_graph {
             if let Some(fr_idx) = invert_indices.get(fr) {
                 if let Some(fr_stratum) = invert_sort_result.get(fr_idx) {
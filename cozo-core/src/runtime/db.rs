@@ -13,7 +13,7 @@ use std::fmt::{Debug, Formatter};
 use std::iter;
 use std::path::Path;
 #[allow(unused_imports)]
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 #[allow(unused_imports)]
 use std::thread;
@@ -32,29 +32,40 @@ use serde_json::json;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::aggr::{parse_aggr, CustomAggregation};
+use crate::data::expr::{get_op, CustomFunction};
 use crate::data::functions::current_validity;
 use crate::data::json::JsonValue;
-use crate::data::program::{InputProgram, QueryAssertion, RelationOp};
+use crate::data::program::{
+    InputProgram, MagicSymbol, QueryAssertion, QueryOutOptions, RelationOp, RuleMutation,
+};
 use crate::data::relation::ColumnDef;
+use crate::data::symb::Symbol;
 use crate::data::tuple::{Tuple, TupleT};
 use crate::data::value::{DataValue, ValidityTs, LARGEST_UTF_CHAR};
 use crate::fixed_rule::DEFAULT_FIXED_RULES;
 use crate::fts::TokenizerCache;
 use crate::parse::sys::SysOp;
-use crate::parse::{parse_script, CozoScript, SourceSpan};
+use crate::parse::{parse_script, CozoScript, ParseEnv, PreparedParams, SourceSpan};
 use crate::query::compile::{CompiledProgram, CompiledRule, CompiledRuleSet};
+use crate::query::profile::QueryProfile;
 use crate::query::ra::{
     FilteredRA, FtsSearchRA, HnswSearchRA, InnerJoin, LshSearchRA, NegJoin, RelAlgebra, ReorderRA,
-    StoredRA, StoredWithValidityRA, TempStoreRA, UnificationRA,
+    RtreeSearchRA, StoredRA, StoredWithValidityRA, TempStoreRA, UnificationRA,
 };
+use crate::query::sort::sort_by_scan;
 #[allow(unused_imports)]
 use crate::runtime::callback::{
     CallbackCollector, CallbackDeclaration, CallbackOp, EventCallbackRegistry,
 };
 use crate::runtime::relation::{
-    extend_tuple_from_v, AccessLevel, InsufficientAccessLevel, RelationHandle, RelationId,
+    extend_tuple_from_v, AccessLevel, InputRelationHandle, InsufficientAccessLevel,
+    ReadByMaterializedViews, RelationHandle, RelationId,
 };
+use crate::runtime::spill::tuple_size;
+use crate::runtime::temp_store::EpochStore;
 use crate::runtime::transact::SessionTx;
+use crate::runtime::view::materialized_view_sources;
 use crate::storage::temp::TempStorage;
 use crate::storage::{Storage, StoreTx};
 use crate::{decode_tuple_from_kv, FixedRule};
@@ -62,6 +73,7 @@ use crate::{decode_tuple_from_kv, FixedRule};
 pub(crate) struct RunningQueryHandle {
     pub(crate) started_at: f64,
     pub(crate) poison: Poison,
+    pub(crate) usage: QueryUsage,
 }
 
 pub(crate) struct RunningQueryCleanup {
@@ -78,6 +90,16 @@ impl Drop for RunningQueryCleanup {
     }
 }
 
+/// A query compiled to the point where it can be evaluated
+pub(crate) struct CompiledQuery {
+    pub(crate) entry_head_or_default: Vec<Symbol>,
+    pub(crate) out_opts: QueryOutOptions,
+    pub(crate) store_lifetimes: BTreeMap<MagicSymbol, usize>,
+    pub(crate) compiled: Vec<CompiledProgram>,
+    /// Set when the entry rule produces its rows in the order of the sorters
+    pub(crate) sorted_by_scan: bool,
+}
+
 #[derive(serde_derive::Serialize, serde_derive::Deserialize)]
 pub struct DbManifest {
     pub storage_version: u64,
@@ -92,12 +114,17 @@ pub struct Db<S> {
     pub(crate) queries_count: Arc<AtomicU64>,
     pub(crate) running_queries: Arc<Mutex<BTreeMap<u64, RunningQueryHandle>>>,
     pub(crate) fixed_rules: Arc<ShardedLock<BTreeMap<String, Arc<Box<dyn FixedRule>>>>>,
+    pub(crate) custom_functions: Arc<ShardedLock<BTreeMap<String, Arc<CustomFunction>>>>,
+    pub(crate) custom_aggregations: Arc<ShardedLock<BTreeMap<String, Arc<dyn CustomAggregation>>>>,
     pub(crate) tokenizers: Arc<TokenizerCache>,
     #[cfg(not(target_arch = "wasm32"))]
     callback_count: Arc<AtomicU32>,
     #[cfg(not(target_arch = "wasm32"))]
     pub(crate) event_callbacks: Arc<ShardedLock<EventCallbackRegistry>>,
     relation_locks: Arc<ShardedLock<BTreeMap<SmartString<LazyCompact>, Arc<ShardedLock<()>>>>>,
+    /// Memory budget in bytes of each store holding rule results, `usize::MAX` for none
+    temp_store_budget: Arc<AtomicUsize>,
+    default_query_limits: Arc<ShardedLock<QueryLimits>>,
 }
 
 impl<S> Debug for Db<S> {
@@ -241,6 +268,8 @@ impl<'s, S: Storage<'s>> Db<S> {
             queries_count: Default::default(),
             running_queries: Default::default(),
             fixed_rules: Arc::new(ShardedLock::new(DEFAULT_FIXED_RULES.clone())),
+            custom_functions: Default::default(),
+            custom_aggregations: Default::default(),
             tokenizers: Arc::new(Default::default()),
             #[cfg(not(target_arch = "wasm32"))]
             callback_count: Default::default(),
@@ -248,6 +277,8 @@ impl<'s, S: Storage<'s>> Db<S> {
             #[cfg(not(target_arch = "wasm32"))]
             event_callbacks: Default::default(),
             relation_locks: Default::default(),
+            temp_store_budget: Arc::new(AtomicUsize::new(usize::MAX)),
+            default_query_limits: Default::default(),
         };
         Ok(ret)
     }
@@ -312,18 +343,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                     break;
                 }
                 TransactionPayload::Query((script, params)) => {
-                    let p =
-                        match parse_script(&script, &params, &self.fixed_rules.read().unwrap(), ts)
-                        {
-                            Ok(p) => p,
-                            Err(err) => {
-                                if results.send(Err(err)).is_err() {
-                                    break;
-                                } else {
-                                    continue;
-                                }
+                    let parsed = self.parse_payload(&script, &params, None, ts);
+                    let p = match parsed {
+                        Ok(p) => p,
+                        Err(err) => {
+                            if results.send(Err(err)).is_err() {
+                                break;
+                            } else {
+                                continue;
                             }
-                        };
+                        }
+                    };
 
                     let p = match p.get_single_program() {
                         Ok(p) => p,
@@ -335,7 +365,16 @@ impl<'s, S: Storage<'s>> Db<S> {
                             }
                         }
                     };
-                    if let Some(write_lock_name) = p.needs_write_lock() {
+                    let mut write_lock_names = BTreeSet::new();
+                    p.needs_write_locks(&mut write_lock_names);
+                    if let Err(err) = tx.add_dependent_relations(&mut write_lock_names) {
+                        if results.send(Err(err)).is_err() {
+                            break;
+                        } else {
+                            continue;
+                        }
+                    }
+                    for write_lock_name in write_lock_names {
                         match write_locks.entry(write_lock_name) {
                             Entry::Vacant(e) => {
                                 let lock = self
@@ -437,7 +476,11 @@ impl<'s, S: Storage<'s>> Db<S> {
         #[diagnostic(code(import::bad_data))]
         struct BadDataForRelation(String, JsonValue);
 
-        let rel_names = data.keys().map(SmartString::from).collect_vec();
+        let mut rel_names = data
+            .keys()
+            .map(|k| SmartString::from(k.strip_prefix('-').unwrap_or(k)))
+            .collect();
+        self.add_dependent_relations(&mut rel_names)?;
         let locks = self.obtain_relation_locks(rel_names.iter());
         let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
 
@@ -460,7 +503,10 @@ impl<'s, S: Storage<'s>> Db<S> {
             if relation.contains(':') {
                 bail!(ImportIntoIndex(relation.to_string()))
             }
-            let handle = tx.get_relation(relation, false)?;
+            let mut handle = tx.get_relation(relation, false)?;
+            handle
+                .metadata
+                .resolve_custom_functions(&self.custom_functions.read().unwrap())?;
             let has_indices = !handle.indices.is_empty();
 
             if handle.access_level < AccessLevel::Protected {
@@ -470,6 +516,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                     handle.access_level
                 ));
             }
+            // imported rows bypass the maintenance of the views
+            if !handle.materialized_views.is_empty() {
+                bail!(ReadByMaterializedViews(
+                    handle.name.to_string(),
+                    handle
+                        .materialized_views
+                        .iter()
+                        .map(|v| v.to_string())
+                        .collect_vec()
+                ))
+            }
 
             let header2idx: BTreeMap<_, _> = in_data
                 .headers
@@ -513,6 +570,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                     })
                     .try_collect()?
             };
+            let (checks, fk_targets) = if is_delete {
+                (vec![], vec![])
+            } else {
+                (
+                    SessionTx::make_check_constraints(&handle)?,
+                    tx.make_foreign_key_targets(&handle)?,
+                )
+            };
+            let has_referrers = is_delete && !handle.referenced_by.is_empty();
+            let mut removed_keys = vec![];
+            let mut stack = vec![];
 
             for row in in_data.rows {
                 let keys: Vec<_> = key_indices
@@ -525,11 +593,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                     })
                     .try_collect()?;
                 let k_store = handle.encode_key_for_store(&keys, Default::default())?;
-                if has_indices {
+                if has_indices || has_referrers {
                     if let Some(existing) = tx.store_tx.get(&k_store, false)? {
+                        if has_referrers {
+                            removed_keys.push(keys[0].clone());
+                        }
                         let mut old = keys.clone();
                         extend_tuple_from_v(&mut old, &existing);
-                        if is_delete || old != row {
+                        if has_indices && (is_delete || old != row) {
                             for (idx_rel, extractor) in handle.indices.values() {
                                 let idx_tup =
                                     extractor.iter().map(|i| old[*i].clone()).collect_vec();
@@ -552,13 +623,16 @@ impl<'s, S: Storage<'s>> Db<S> {
                             col.typing.coerce(v.clone(), cur_vld)
                         })
                         .try_collect()?;
-                    let v_store = handle.encode_val_only_for_store(&vals, Default::default())?;
+                    let mut kv = keys;
+                    kv.extend(vals);
+                    SessionTx::ensure_check_constraints(&handle, &checks, &mut stack, &kv)?;
+                    tx.ensure_foreign_keys(&handle, &fk_targets, &kv)?;
+                    let v_store = handle.encode_val_for_store(&kv, Default::default())?;
                     tx.store_tx.put(&k_store, &v_store)?;
                     if has_indices {
-                        let mut kv = keys;
-                        kv.extend(vals);
-                        for (idx_rel, extractor) in handle.indices.values() {
+                        for (idx_name, (idx_rel, extractor)) in handle.indices.iter() {
                             let idx_tup = extractor.iter().map(|i| kv[*i].clone()).collect_vec();
+                            tx.ensure_unique_in_index(&handle, idx_name, &idx_tup)?;
                             let encoded =
                                 idx_rel.encode_key_for_store(&idx_tup, Default::default())?;
                             tx.store_tx.put(&encoded, &[])?;
@@ -566,6 +640,26 @@ impl<'s, S: Storage<'s>> Db<S> {
                     }
                 }
             }
+            if !removed_keys.is_empty() {
+                let mut cleanups = vec![];
+                tx.apply_referential_actions(
+                    self,
+                    cur_vld,
+                    &Default::default(),
+                    &mut Default::default(),
+                    false,
+                    &mut cleanups,
+                    &handle,
+                    &removed_keys,
+                    Default::default(),
+                )?;
+                for (lower, upper) in cleanups {
+                    tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+                }
+            }
+            if !handle.dictionary_of.is_empty() {
+                tx.rebuild_dictionary_dependents(&handle)?;
+            }
         }
         tx.commit_tx()?;
         Ok(())
@@ -714,6 +808,84 @@ impl<'s, S: Storage<'s>> Db<S> {
         Ok(self.fixed_rules.write().unwrap().remove(name).is_some())
     }
 
+    /// Register a custom scalar function taking exactly `arity` arguments,
+    /// usable in expressions of subsequently parsed scripts.
+    /// Names of built-in functions cannot be registered.
+    pub fn register_function<F>(&self, name: String, arity: usize, func: F) -> Result<()>
+    where
+        F: Fn(&[DataValue]) -> Result<DataValue> + Send + Sync + 'static,
+    {
+        if get_op(&name).is_some() || name == "cond" || name == "if" {
+            bail!(
+                "Cannot register function {}: it is a builtin function",
+                name
+            );
+        }
+        match self.custom_functions.write().unwrap().entry(name) {
+            Entry::Vacant(ent) => {
+                let name = ent.key().clone();
+                ent.insert(Arc::new(CustomFunction {
+                    name,
+                    arity,
+                    inner: Box::new(func),
+                }));
+                Ok(())
+            }
+            Entry::Occupied(ent) => {
+                bail!(
+                    "A function with the name {} is already registered",
+                    ent.key()
+                )
+            }
+        }
+    }
+
+    /// Unregister a custom scalar function.
+    pub fn unregister_function(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .custom_functions
+            .write()
+            .unwrap()
+            .remove(name)
+            .is_some())
+    }
+
+    /// Register a custom aggregation, usable in rule heads of subsequently parsed scripts.
+    /// Names of built-in aggregations cannot be registered.
+    pub fn register_aggregation<A>(&self, name: String, aggr_impl: A) -> Result<()>
+    where
+        A: CustomAggregation + 'static,
+    {
+        if parse_aggr(&name).is_some() {
+            bail!(
+                "Cannot register aggregation {}: it is a builtin aggregation",
+                name
+            );
+        }
+        match self.custom_aggregations.write().unwrap().entry(name) {
+            Entry::Vacant(ent) => {
+                ent.insert(Arc::new(aggr_impl));
+                Ok(())
+            }
+            Entry::Occupied(ent) => {
+                bail!(
+                    "An aggregation with the name {} is already registered",
+                    ent.key()
+                )
+            }
+        }
+    }
+
+    /// Unregister a custom aggregation.
+    pub fn unregister_aggregation(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .custom_aggregations
+            .write()
+            .unwrap()
+            .remove(name)
+            .is_some())
+    }
+
     /// Register callback channel to receive changes when the requested relation are successfully committed.
     /// The returned ID can be used to unregister the callback channel.
     #[cfg(not(target_arch = "wasm32"))]
@@ -759,6 +931,17 @@ impl<'s, S: Storage<'s>> Db<S> {
         ret.is_some()
     }
 
+    /// Adds the relations that writing to any of the stored relations also writes to
+    pub(crate) fn add_dependent_relations(
+        &'s self,
+        rels: &mut BTreeSet<SmartString<LazyCompact>>,
+    ) -> Result<()> {
+        if rels.is_empty() {
+            return Ok(());
+        }
+        self.transact()?.add_dependent_relations(rels)
+    }
+
     pub(crate) fn obtain_relation_locks<'a, T: Iterator<Item = &'a SmartString<LazyCompact>>>(
         &'s self,
         rels: T,
@@ -800,6 +983,27 @@ impl<'s, S: Storage<'s>> Db<S> {
         tx.commit_tx()?;
         Ok(())
     }
+    /// Set the number of bytes each store holding the results of a rule may occupy in memory
+    /// while a query is evaluated. Stores growing past the budget are written to sorted runs
+    /// in temporary files, which are read back as needed, so that queries producing large
+    /// intermediate results can complete with bounded memory at the cost of speed.
+    /// `None`, the default, keeps all results in memory.
+    pub fn set_temp_store_memory_budget(&self, bytes: Option<usize>) {
+        self.temp_store_budget
+            .store(bytes.unwrap_or(usize::MAX), Ordering::Release);
+    }
+    /// Set the limits applying to queries that do not specify their own limits
+    /// with the `:max_rows` and `:max_memory` options. A query exceeding a limit is aborted.
+    /// By default queries are not limited.
+    pub fn set_default_query_limits(&self, limits: QueryLimits) {
+        *self.default_query_limits.write().unwrap() = limits;
+    }
+    fn temp_store_budget(&self) -> Option<usize> {
+        match self.temp_store_budget.load(Ordering::Acquire) {
+            usize::MAX => None,
+            bytes => Some(bytes),
+        }
+    }
     pub(crate) fn transact(&'s self) -> Result<SessionTx<'_>> {
         let ret = SessionTx {
             store_tx: Box::new(self.db.transact(false)?),
@@ -807,6 +1011,10 @@ impl<'s, S: Storage<'s>> Db<S> {
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
             tokenizers: self.tokenizers.clone(),
+            profile: None,
+            poison: Default::default(),
+            usage: Default::default(),
+            temp_store_budget: self.temp_store_budget(),
         };
         Ok(ret)
     }
@@ -817,6 +1025,10 @@ impl<'s, S: Storage<'s>> Db<S> {
             relation_store_id: self.relation_store_id.clone(),
             temp_store_id: Default::default(),
             tokenizers: self.tokenizers.clone(),
+            profile: None,
+            poison: Default::default(),
+            usage: Default::default(),
+            temp_store_budget: self.temp_store_budget(),
         };
         Ok(ret)
     }
@@ -832,8 +1044,11 @@ impl<'s, S: Storage<'s>> Db<S> {
     ) -> Result<NamedRows> {
         #[allow(unused_variables)]
         let sleep_opt = p.out_opts.sleep;
-        let (q_res, q_cleanups) =
-            self.run_query(tx, p, cur_vld, callback_targets, callback_collector, true)?;
+        // the query charges its own usage, which is charged to that of the program
+        let program_usage = tx.usage.clone();
+        let res = self.run_query(tx, p, cur_vld, callback_targets, callback_collector, true);
+        tx.usage = program_usage;
+        let (q_res, q_cleanups) = res?;
         cleanups.extend(q_cleanups);
         #[cfg(not(target_arch = "wasm32"))]
         if let Some(secs) = sleep_opt {
@@ -848,13 +1063,36 @@ impl<'s, S: Storage<'s>> Db<S> {
         param_pool: &BTreeMap<String, DataValue>,
         cur_vld: ValidityTs,
     ) -> Result<NamedRows> {
-        match parse_script(
-            payload,
-            param_pool,
-            &self.fixed_rules.read().unwrap(),
-            cur_vld,
-        )? {
-            CozoScript::Single(p) => self.execute_single(cur_vld, p),
+        let parsed = self.parse_payload(payload, param_pool, None, cur_vld)?;
+        self.execute_script(parsed, cur_vld)
+    }
+
+    /// Parses a script with the functions, aggregations and fixed rules registered on the database
+    pub(crate) fn parse_payload(
+        &self,
+        payload: &str,
+        param_pool: &BTreeMap<String, DataValue>,
+        prepared: Option<&PreparedParams>,
+        cur_vld: ValidityTs,
+    ) -> Result<CozoScript> {
+        let functions = self.custom_functions.read().unwrap();
+        let aggregations = self.custom_aggregations.read().unwrap();
+        let env = ParseEnv {
+            params: param_pool,
+            functions: &functions,
+            aggregations: &aggregations,
+            prepared,
+        };
+        parse_script(payload, &env, &self.fixed_rules.read().unwrap(), cur_vld)
+    }
+
+    pub(crate) fn execute_script(
+        &'s self,
+        parsed: CozoScript,
+        cur_vld: ValidityTs,
+    ) -> Result<NamedRows> {
+        match parsed {
+            CozoScript::Single(p) => self.execute_single(cur_vld, *p),
             CozoScript::Imperative(ps) => self.execute_imperative(cur_vld, &ps),
             CozoScript::Sys(op) => self.run_sys_op(op),
         }
@@ -862,14 +1100,12 @@ impl<'s, S: Storage<'s>> Db<S> {
 
     fn execute_single(&'s self, cur_vld: ValidityTs, p: InputProgram) -> Result<NamedRows, Report> {
         let mut callback_collector = BTreeMap::new();
-        let write_lock_names = p.needs_write_lock();
-        let is_write = write_lock_names.is_some();
+        let mut write_lock_names = BTreeSet::new();
+        p.needs_write_locks(&mut write_lock_names);
+        self.add_dependent_relations(&mut write_lock_names)?;
+        let is_write = !write_lock_names.is_empty();
         let write_lock = self.obtain_relation_locks(write_lock_names.iter());
-        let _write_lock_guards = if is_write {
-            Some(write_lock[0].read().unwrap())
-        } else {
-            None
-        };
+        let _write_lock_guards = write_lock.iter().map(|l| l.read().unwrap()).collect_vec();
         let callback_targets = if is_write {
             self.current_callback_targets()
         } else {
@@ -906,7 +1142,12 @@ impl<'s, S: Storage<'s>> Db<S> {
 
         Ok(res)
     }
-    fn explain_compiled(&self, strata: &[CompiledProgram]) -> Result<NamedRows> {
+    /// With a profile, the runtime statistics collected are reported alongside the plan
+    fn explain_compiled(
+        &self,
+        strata: &[CompiledProgram],
+        profile: Option<&QueryProfile>,
+    ) -> Result<NamedRows> {
         let mut ret: Vec<JsonValue> = vec![];
         const STRATUM: &str = "stratum";
         const ATOM_IDX: &str = "atom_idx";
@@ -917,8 +1158,13 @@ impl<'s, S: Storage<'s>> Db<S> {
         const OUT_BINDINGS: &str = "out_relation";
         const JOINS_ON: &str = "joins_on";
         const FILTERS: &str = "filters/expr";
+        const ROWS_IN: &str = "rows_in";
+        const ROWS_OUT: &str = "rows_out";
+        const SEEKS: &str = "seeks";
+        const TIME_MS: &str = "time_ms";
+        const ITERATIONS: &str = "iterations";
 
-        let headers = vec![
+        let mut headers = vec![
             STRATUM.to_string(),
             RULE_IDX.to_string(),
             RULE_NAME.to_string(),
@@ -929,13 +1175,17 @@ impl<'s, S: Storage<'s>> Db<S> {
             FILTERS.to_string(),
             OUT_BINDINGS.to_string(),
         ];
+        if profile.is_some() {
+            headers.extend([ROWS_IN, ROWS_OUT, SEEKS, TIME_MS, ITERATIONS].map(|h| h.to_string()));
+        }
 
         for (stratum, p) in strata.iter().enumerate() {
             let mut clause_idx = -1;
             for (rule_name, v) in p {
                 match v {
                     CompiledRuleSet::Rules(rules) => {
-                        for CompiledRule { aggr, relation, .. } in rules.iter() {
+                        for rule in rules.iter() {
+                            let CompiledRule { aggr, relation, .. } = rule;
                             clause_idx += 1;
                             let mut ret_for_relation = vec![];
                             let mut rel_stack = vec![relation];
@@ -951,14 +1201,20 @@ impl<'s, S: Storage<'s>> Db<S> {
                                 }
                             }
 
-                            ret_for_relation.push(json!({
+                            let mut out_row = json!({
                                 STRATUM: stratum,
                                 ATOM_IDX: idx,
                                 OP: atom_type,
                                 RULE_IDX: clause_idx,
                                 RULE_NAME: rule_name.to_string(),
                                 OUT_BINDINGS: relation.bindings_after_eliminate().into_iter().map(|v| v.to_string()).collect_vec()
-                            }));
+                            });
+                            if let Some(profile) = profile {
+                                out_row[ROWS_OUT] =
+                                    json!(profile.op(relation).and_then(|s| s.rows()));
+                                out_row[ITERATIONS] = json!(profile.iterations(rule));
+                            }
+                            ret_for_relation.push(out_row);
                             idx += 1;
 
                             while let Some(rel) = rel_stack.pop() {
@@ -980,9 +1236,16 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         json!(filters.iter().map(|f| f.to_string()).collect_vec()),
                                     ),
                                     RelAlgebra::Stored(StoredRA {
-                                        storage, filters, ..
+                                        storage,
+                                        filters,
+                                        descending,
+                                        ..
                                     }) => (
-                                        "load_stored",
+                                        if *descending {
+                                            "load_stored_desc"
+                                        } else {
+                                            "load_stored"
+                                        },
                                         json!(format!(":{}", storage.name)),
                                         json!(null),
                                         json!(filters.iter().map(|f| f.to_string()).collect_vec()),
@@ -1058,39 +1321,78 @@ impl<'s, S: Storage<'s>> Db<S> {
                                         )
                                     }
                                     RelAlgebra::HnswSearch(HnswSearchRA {
-                                        hnsw_search, ..
-                                    }) => (
-                                        "hnsw_index",
-                                        json!(format!(":{}", hnsw_search.query.name)),
-                                        json!(hnsw_search.query.name),
-                                        json!(hnsw_search
-                                            .filter
-                                            .iter()
-                                            .map(|f| f.to_string())
-                                            .collect_vec()),
-                                    ),
-                                    RelAlgebra::FtsSearch(FtsSearchRA { fts_search, .. }) => (
-                                        "fts_index",
-                                        json!(format!(":{}", fts_search.query.name)),
-                                        json!(fts_search.query.name),
-                                        json!(fts_search
-                                            .filter
-                                            .iter()
-                                            .map(|f| f.to_string())
-                                            .collect_vec()),
-                                    ),
-                                    RelAlgebra::LshSearch(LshSearchRA { lsh_search, .. }) => (
-                                        "lsh_index",
-                                        json!(format!(":{}", lsh_search.query.name)),
-                                        json!(lsh_search.query.name),
-                                        json!(lsh_search
-                                            .filter
-                                            .iter()
-                                            .map(|f| f.to_string())
-                                            .collect_vec()),
-                                    ),
+                                        parent,
+                                        hnsw_search,
+                                        ..
+                                    }) => {
+                                        rel_stack.push(parent);
+                                        (
+                                            "hnsw_index",
+                                            json!(format!(":{}", hnsw_search.query.name)),
+                                            json!(hnsw_search.query.name),
+                                            json!(hnsw_search
+                                                .filter
+                                                .iter()
+                                                .map(|f| f.to_string())
+                                                .collect_vec()),
+                                        )
+                                    }
+                                    RelAlgebra::FtsSearch(FtsSearchRA {
+                                        parent,
+                                        fts_search,
+                                        ..
+                                    }) => {
+                                        rel_stack.push(parent);
+                                        (
+                                            "fts_index",
+                                            json!(format!(":{}", fts_search.query.name)),
+                                            json!(fts_search.query.name),
+                                            json!(fts_search
+                                                .filter
+                                                .iter()
+                                                .map(|f| f.to_string())
+                                                .collect_vec()),
+                                        )
+                                    }
+                                    RelAlgebra::LshSearch(LshSearchRA {
+                                        parent,
+                                        lsh_search,
+                                        ..
+                                    }) => {
+                                        rel_stack.push(parent);
+                                        (
+                                            "lsh_index",
+                                            json!(format!(":{}", lsh_search.query.name)),
+                                            json!(lsh_search.query.name),
+                                            json!(lsh_search
+                                                .filter
+                                                .iter()
+                                                .map(|f| f.to_string())
+                                                .collect_vec()),
+                                        )
+                                    }
+                                    RelAlgebra::RtreeSearch(RtreeSearchRA {
+                                        parent,
+                                        rtree_search,
+                                        ..
+                                    }) => {
+                                        rel_stack.push(parent);
+                                        (
+                                            "rtree_index",
+                                            json!(format!(":{}", rtree_search.idx_handle.name)),
+                                            json!(rtree_search
+                                                .input_bindings()
+                                                .map(|b| b.name.to_string())
+                                                .collect_vec()),
+                                            json!(rtree_search
+                                                .filter
+                                                .iter()
+                                                .map(|f| f.to_string())
+                                                .collect_vec()),
+                                        )
+                                    }
                                 };
-                                ret_for_relation.push(json!({
+                                let mut row = json!({
                                     STRATUM: stratum,
                                     ATOM_IDX: idx,
                                     OP: atom_type,
@@ -1100,7 +1402,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                                     OUT_BINDINGS: rel.bindings_after_eliminate().into_iter().map(|v| v.to_string()).collect_vec(),
                                     JOINS_ON: joins_on,
                                     FILTERS: filters,
-                                }));
+                                });
+                                if let Some(stats) = profile.and_then(|p| p.op(rel)) {
+                                    row[ROWS_IN] = json!(profile.unwrap().rows_in(rel));
+                                    row[ROWS_OUT] = json!(stats.rows());
+                                    row[SEEKS] = json!(stats.seeks());
+                                    row[TIME_MS] = json!(stats.millis());
+                                }
+                                ret_for_relation.push(row);
                                 idx += 1;
                             }
                             ret_for_relation.reverse();
@@ -1132,14 +1441,33 @@ impl<'s, S: Storage<'s>> Db<S> {
     }
     fn run_sys_op(&'s self, op: SysOp) -> Result<NamedRows> {
         match op {
-            SysOp::Explain(prog) => {
+            SysOp::Explain(mut prog) => {
+                let mut tx = self.transact()?;
+                self.inline_views(&tx, &mut prog, current_validity())?;
+                let query = self.compile_query(&mut tx, *prog)?;
+                tx.commit_tx()?;
+                self.explain_compiled(&query.compiled, None)
+            }
+            SysOp::ExplainAnalyze(mut prog) => {
+                let cur_vld = current_validity();
                 let mut tx = self.transact()?;
-                let (normalized_program, _) = prog.into_normalized_program(&tx)?;
-                let (stratified_program, _) = normalized_program.into_stratified_program()?;
-                let program = stratified_program.magic_sets_rewrite(&tx)?;
-                let compiled = tx.stratified_magic_compile(program)?;
+                self.inline_views(&tx, &mut prog, cur_vld)?;
+                let mut query = self.compile_query(&mut tx, *prog)?;
+                // the query is run for real, but its results are never written to storage
+                query.out_opts.store_relation = None;
+                query.out_opts.rule_mutations.clear();
+                let profile = Arc::new(QueryProfile::new(&query.compiled));
+                tx.profile = Some(profile.clone());
+                self.run_compiled_query(
+                    &mut tx,
+                    &query,
+                    cur_vld,
+                    &Default::default(),
+                    &mut Default::default(),
+                    true,
+                )?;
                 tx.commit_tx()?;
-                self.explain_compiled(&compiled)
+                self.explain_compiled(&query.compiled, Some(&profile))
             }
             SysOp::Compact => {
                 self.compact_relation()?;
@@ -1160,9 +1488,11 @@ impl<'s, S: Storage<'s>> Db<S> {
                 ))
             }
             SysOp::RemoveRelation(rel_names) => {
-                let rel_name_strs = rel_names.iter().map(|n| &n.name);
-                let locks = self.obtain_relation_locks(rel_name_strs);
-                let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
+                // the handles of the targets of foreign keys are also written
+                let mut lock_names = rel_names.iter().map(|n| n.name.clone()).collect();
+                self.add_dependent_relations(&mut lock_names)?;
+                let locks = self.obtain_relation_locks(lock_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
                 let mut bounds = vec![];
                 let mut tx = self.transact_write()?;
                 for rs in rel_names {
@@ -1180,6 +1510,39 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::AnalyzeRelations(rel_names) => {
+                let locks = self.obtain_relation_locks(rel_names.iter().map(|n| &n.name));
+                let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
+                let mut tx = self.transact_write()?;
+                let mut rows = vec![];
+                for rs in rel_names {
+                    let handle = tx.analyze_relation(&rs)?;
+                    let stats = handle.stats.as_ref().unwrap();
+                    let cols = handle
+                        .metadata
+                        .keys
+                        .iter()
+                        .chain(handle.metadata.non_keys.iter());
+                    for (col, distinct) in cols.zip(stats.distinct.iter()) {
+                        rows.push(vec![
+                            DataValue::from(&handle.name as &str),
+                            DataValue::from(&col.name as &str),
+                            DataValue::from(stats.rows as i64),
+                            DataValue::from(*distinct as i64),
+                        ]);
+                    }
+                }
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![
+                        "relation".to_string(),
+                        "column".to_string(),
+                        "rows".to_string(),
+                        "distinct".to_string(),
+                    ],
+                    rows,
+                ))
+            }
             SysOp::DescribeRelation(rel_name, description) => {
                 let mut tx = self.transact_write()?;
                 tx.describe_relation(&rel_name, description)?;
@@ -1188,14 +1551,14 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
-            SysOp::CreateIndex(rel_name, idx_name, cols) => {
+            SysOp::CreateIndex(rel_name, idx_name, cols, unique) => {
                 let lock = self
                     .obtain_relation_locks(iter::once(&rel_name.name))
                     .pop()
                     .unwrap();
                 let _guard = lock.write().unwrap();
                 let mut tx = self.transact_write()?;
-                tx.create_index(&rel_name, &idx_name, cols)?;
+                tx.create_index(&rel_name, &idx_name, cols, unique)?;
                 tx.commit_tx()?;
                 Ok(NamedRows::new(
                     vec![STATUS_STR.to_string()],
@@ -1217,11 +1580,16 @@ impl<'s, S: Storage<'s>> Db<S> {
                 ))
             }
             SysOp::CreateFtsIndex(config) => {
-                let lock = self
-                    .obtain_relation_locks(iter::once(&config.base_relation))
-                    .pop()
-                    .unwrap();
-                let _guard = lock.write().unwrap();
+                // the relations holding the dictionaries are registered with the index
+                let rel_names: BTreeSet<_> = config
+                    .filters
+                    .iter()
+                    .filter_map(|f| f.dictionary_relation())
+                    .map(SmartString::from)
+                    .chain(iter::once(config.base_relation.clone()))
+                    .collect();
+                let locks = self.obtain_relation_locks(rel_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
                 let mut tx = self.transact_write()?;
                 tx.create_fts_index(config)?;
                 tx.commit_tx()?;
@@ -1231,13 +1599,32 @@ impl<'s, S: Storage<'s>> Db<S> {
                 ))
             }
             SysOp::CreateMinHashLshIndex(config) => {
+                // the relations holding the dictionaries are registered with the index
+                let rel_names: BTreeSet<_> = config
+                    .filters
+                    .iter()
+                    .filter_map(|f| f.dictionary_relation())
+                    .map(SmartString::from)
+                    .chain(iter::once(config.base_relation.clone()))
+                    .collect();
+                let locks = self.obtain_relation_locks(rel_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                let mut tx = self.transact_write()?;
+                tx.create_minhash_lsh_index(config)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::CreateRtreeIndex(config) => {
                 let lock = self
                     .obtain_relation_locks(iter::once(&config.base_relation))
                     .pop()
                     .unwrap();
                 let _guard = lock.write().unwrap();
                 let mut tx = self.transact_write()?;
-                tx.create_minhash_lsh_index(config)?;
+                tx.create_rtree_index(config)?;
                 tx.commit_tx()?;
                 Ok(NamedRows::new(
                     vec![STATUS_STR.to_string()],
@@ -1245,11 +1632,11 @@ impl<'s, S: Storage<'s>> Db<S> {
                 ))
             }
             SysOp::RemoveIndex(rel_name, idx_name) => {
-                let lock = self
-                    .obtain_relation_locks(iter::once(&rel_name.name))
-                    .pop()
-                    .unwrap();
-                let _guard = lock.read().unwrap();
+                // the index is unregistered from the relations holding its dictionaries
+                let mut lock_names = BTreeSet::from([rel_name.name.clone()]);
+                lock_names.extend(self.transact()?.index_dictionaries(&rel_name, &idx_name)?);
+                let locks = self.obtain_relation_locks(lock_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
                 let mut tx = self.transact_write()?;
                 let bounds = tx.remove_index(&rel_name, &idx_name)?;
                 for (lower, upper) in bounds {
@@ -1261,12 +1648,36 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::AlterRelation(rel_name, ops) => {
+                // the handles of the referrers are also written
+                let mut lock_names = BTreeSet::from([rel_name.name.clone()]);
+                self.add_dependent_relations(&mut lock_names)?;
+                let locks = self.obtain_relation_locks(lock_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                let mut tx = self.transact_write()?;
+                tx.alter_relation(
+                    &rel_name,
+                    ops,
+                    &self.custom_functions.read().unwrap(),
+                    current_validity(),
+                )?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
             SysOp::ListColumns(rs) => self.list_columns(&rs),
             SysOp::ListIndices(rs) => self.list_indices(&rs),
             SysOp::RenameRelation(rename_pairs) => {
-                let rel_names = rename_pairs.iter().flat_map(|(f, t)| [&f.name, &t.name]);
-                let locks = self.obtain_relation_locks(rel_names);
-                let _guards = locks.iter().map(|l| l.read().unwrap()).collect_vec();
+                // the handles of the targets and referrers of foreign keys are also written
+                let mut lock_names = rename_pairs
+                    .iter()
+                    .flat_map(|(f, t)| [f.name.clone(), t.name.clone()])
+                    .collect();
+                self.add_dependent_relations(&mut lock_names)?;
+                let locks = self.obtain_relation_locks(lock_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
                 let mut tx = self.transact_write()?;
                 for (old, new) in rename_pairs {
                     tx.rename_relation(old, new)?;
@@ -1277,6 +1688,60 @@ impl<'s, S: Storage<'s>> Db<S> {
                     vec![vec![DataValue::from(OK_STR)]],
                 ))
             }
+            SysOp::CreateView(name, script, columns) => {
+                let lock = self
+                    .obtain_relation_locks(iter::once(&name.name))
+                    .pop()
+                    .unwrap();
+                let _guard = lock.write().unwrap();
+                let mut tx = self.transact_write()?;
+                tx.create_view(&name, script, columns)?;
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::CreateMaterializedView(name, script, prog) => {
+                // the view is registered with its sources
+                let mut lock_names = materialized_view_sources(&prog)?;
+                lock_names.insert(name.name.clone());
+                let locks = self.obtain_relation_locks(lock_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                let mut tx = self.transact_write()?;
+                tx.create_materialized_view(&name, script, &prog)?;
+                let cleanups = tx.refresh_materialized_view(self, &name, current_validity())?;
+                for (lower, upper) in cleanups {
+                    tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+                }
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
+            SysOp::DropView(name) => {
+                // the view is unregistered from its sources
+                let mut lock_names = BTreeSet::from([name.name.clone()]);
+                {
+                    let tx = self.transact()?;
+                    if tx.view_exists(&name)? {
+                        lock_names.extend(tx.get_view(&name)?.sources);
+                    }
+                }
+                let locks = self.obtain_relation_locks(lock_names.iter());
+                let _guards = locks.iter().map(|l| l.write().unwrap()).collect_vec();
+                let mut tx = self.transact_write()?;
+                let bounds = tx.destroy_view(&name)?;
+                for (lower, upper) in bounds {
+                    tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+                }
+                tx.commit_tx()?;
+                Ok(NamedRows::new(
+                    vec![STATUS_STR.to_string()],
+                    vec![vec![DataValue::from(OK_STR)]],
+                ))
+            }
             SysOp::ListRunning => self.list_running(),
             SysOp::KillRunning(id) => {
                 let queries = self.running_queries.lock().unwrap();
@@ -1341,78 +1806,86 @@ impl<'s, S: Storage<'s>> Db<S> {
     }
     /// This is the entry to query evaluation
     pub(crate) fn run_query(
+        &self,
+        tx: &mut SessionTx<'_>,
+        mut input_program: InputProgram,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        top_level: bool,
+    ) -> Result<(NamedRows, Vec<(Vec<u8>, Vec<u8>)>)> {
+        self.inline_views(tx, &mut input_program, cur_vld)?;
+        let query = self.compile_query(tx, input_program)?;
+        self.run_compiled_query(
+            tx,
+            &query,
+            cur_vld,
+            callback_targets,
+            callback_collector,
+            top_level,
+        )
+    }
+    /// Query compilation, for a program whose views are already inlined.
+    /// The result can be evaluated repeatedly as long as the relations it reads are unchanged.
+    pub(crate) fn compile_query(
         &self,
         tx: &mut SessionTx<'_>,
         input_program: InputProgram,
+    ) -> Result<CompiledQuery> {
+        let entry_head_or_default = input_program.get_entry_out_head_or_default()?;
+        let (normalized_program, out_opts) = input_program.into_normalized_program(tx)?;
+        let roots = out_opts
+            .rule_mutations
+            .iter()
+            .map(|m| m.source.clone())
+            .collect_vec();
+        let (stratified_program, store_lifetimes) =
+            normalized_program.into_stratified_program(&roots)?;
+        let program = stratified_program.magic_sets_rewrite(tx, &roots)?;
+        let mut compiled = tx.stratified_magic_compile(program)?;
+        let sorted_by_scan =
+            out_opts.limit.is_some() && sort_by_scan(&mut compiled, &out_opts.sorters);
+        Ok(CompiledQuery {
+            entry_head_or_default,
+            out_opts,
+            store_lifetimes,
+            compiled,
+            sorted_by_scan,
+        })
+    }
+    /// Query evaluation, including the mutation specified by the query
+    pub(crate) fn run_compiled_query(
+        &self,
+        tx: &mut SessionTx<'_>,
+        query: &CompiledQuery,
         cur_vld: ValidityTs,
         callback_targets: &BTreeSet<SmartString<LazyCompact>>,
         callback_collector: &mut CallbackCollector,
         top_level: bool,
     ) -> Result<(NamedRows, Vec<(Vec<u8>, Vec<u8>)>)> {
+        let CompiledQuery {
+            entry_head_or_default,
+            out_opts,
+            store_lifetimes,
+            compiled,
+            sorted_by_scan,
+        } = query;
+
         // cleanups contain stored relations that should be deleted at the end of query
         let mut clean_ups = vec![];
 
         // Some checks in case the query specifies mutation
-        if let Some((meta, op)) = &input_program.out_opts.store_relation {
-            if *op == RelationOp::Create {
-                #[derive(Debug, Error, Diagnostic)]
-                #[error("Stored relation {0} conflicts with an existing one")]
-                #[diagnostic(code(eval::stored_relation_conflict))]
-                struct StoreRelationConflict(String);
-
-                ensure!(
-                    !tx.relation_exists(&meta.name)?,
-                    StoreRelationConflict(meta.name.to_string())
-                )
-            } else if *op != RelationOp::Replace {
-                #[derive(Debug, Error, Diagnostic)]
-                #[error("Stored relation {0} not found")]
-                #[diagnostic(code(eval::stored_relation_not_found))]
-                struct StoreRelationNotFoundError(String);
-
-                let existing = tx.get_relation(&meta.name, false)?;
-
-                ensure!(
-                    tx.relation_exists(&meta.name)?,
-                    StoreRelationNotFoundError(meta.name.to_string())
-                );
-
-                existing
-                    .ensure_compatible(meta, *op == RelationOp::Rm || *op == RelationOp::Update)?;
-            }
-        };
-
-        // query compilation
-        let entry_head_or_default = input_program.get_entry_out_head_or_default()?;
-        let (normalized_program, out_opts) = input_program.into_normalized_program(tx)?;
-        let (stratified_program, store_lifetimes) = normalized_program.into_stratified_program()?;
-        let program = stratified_program.magic_sets_rewrite(tx)?;
-        let compiled = tx.stratified_magic_compile(program)?;
-
-        // poison is used to terminate queries early
-        let poison = Poison::default();
-        if let Some(secs) = out_opts.timeout {
-            poison.set_timeout(secs)?;
+        if let Some((meta, op)) = &out_opts.store_relation {
+            ensure_mutation_target(tx, meta, *op)?;
+        }
+        for mutation in &out_opts.rule_mutations {
+            ensure_mutation_target(tx, &mutation.handle, mutation.op)?;
         }
-        // give the query an ID and store it so that it can be queried and cancelled
-        let id = self.queries_count.fetch_add(1, Ordering::AcqRel);
-
-        // time the query
-        let since_the_epoch = seconds_since_the_epoch()?;
-
-        let handle = RunningQueryHandle {
-            started_at: since_the_epoch,
-            poison: poison.clone(),
-        };
-        self.running_queries.lock().unwrap().insert(id, handle);
 
-        // RAII cleanups of running query handle
-        let _guard = RunningQueryCleanup {
-            id,
-            running_queries: self.running_queries.clone(),
-        };
+        let (poison, usage, _guard) = self.register_running_query(out_opts, &tx.usage)?;
 
-        let total_num_to_take = if out_opts.sorters.is_empty() {
+        // rows produced in sorted order can be cut short just like unsorted ones
+        let total_num_to_take = if out_opts.sorters.is_empty() || *sorted_by_scan {
             out_opts.num_to_take()
         } else {
             None
@@ -1425,9 +1898,11 @@ impl<'s, S: Storage<'s>> Db<S> {
         };
 
         // the real evaluation
-        let (result_store, early_return) = tx.stratified_magic_evaluate(
-            &compiled,
-            store_lifetimes,
+        tx.poison = poison.clone();
+        tx.usage = usage;
+        let (result_store, other_stores, early_return) = tx.stratified_magic_evaluate(
+            compiled,
+            store_lifetimes.clone(),
             total_num_to_take,
             num_to_skip,
             poison,
@@ -1438,6 +1913,7 @@ impl<'s, S: Storage<'s>> Db<S> {
             match assertion {
                 QueryAssertion::AssertNone(span) => {
                     if let Some(tuple) = result_store.all_iter().next() {
+                        let tuple = tuple?;
                         #[derive(Debug, Error, Diagnostic)]
                         #[error(
                             "The query is asserted to return no result, but a tuple {0:?} is found"
@@ -1459,10 +1935,14 @@ impl<'s, S: Storage<'s>> Db<S> {
             }
         }
 
-        if !out_opts.sorters.is_empty() {
+        let ret = if !out_opts.sorters.is_empty() {
             // sort outputs if required
-            let sorted_result =
-                tx.sort_and_collect(result_store, &out_opts.sorters, &entry_head_or_default)?;
+            let sorted_result = tx.sort_and_collect(
+                result_store,
+                &out_opts.sorters,
+                entry_head_or_default,
+                out_opts.num_to_take(),
+            )?;
             let sorted_iter = if let Some(offset) = out_opts.offset {
                 Left(sorted_result.into_iter().skip(offset))
             } else {
@@ -1474,45 +1954,44 @@ impl<'s, S: Storage<'s>> Db<S> {
                 Right(sorted_iter)
             };
             if let Some((meta, relation_op)) = &out_opts.store_relation {
-                let to_clear = tx
+                let (to_clear, returned) = tx
                     .execute_relation(
                         self,
-                        sorted_iter,
+                        sorted_iter.map(Ok),
                         *relation_op,
                         meta,
-                        &entry_head_or_default,
+                        entry_head_or_default,
                         cur_vld,
                         callback_targets,
                         callback_collector,
                         top_level,
+                        out_opts.returning,
                     )
                     .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
                 clean_ups.extend(to_clear);
-                Ok((
+                returned.unwrap_or_else(|| {
                     NamedRows::new(
                         vec![STATUS_STR.to_string()],
                         vec![vec![DataValue::from(OK_STR)]],
-                    ),
-                    clean_ups,
-                ))
+                    )
+                })
             } else {
                 // not sorting outputs
                 let rows: Vec<Tuple> = sorted_iter.collect_vec();
-                Ok((
-                    NamedRows::new(
-                        entry_head_or_default
-                            .iter()
-                            .map(|s| s.to_string())
-                            .collect_vec(),
-                        rows,
-                    ),
-                    clean_ups,
-                ))
+                NamedRows::new(
+                    entry_head_or_default
+                        .iter()
+                        .map(|s| s.to_string())
+                        .collect_vec(),
+                    rows,
+                )
             }
         } else {
             let scan = if early_return {
                 Right(Left(
-                    result_store.early_returned_iter().map(|t| t.into_tuple()),
+                    result_store
+                        .early_returned_iter()
+                        .map_ok(|t| t.into_tuple()),
                 ))
             } else if out_opts.limit.is_some() || out_opts.offset.is_some() {
                 let limit = out_opts.limit.unwrap_or(usize::MAX);
@@ -1522,49 +2001,132 @@ impl<'s, S: Storage<'s>> Db<S> {
                         .all_iter()
                         .skip(offset)
                         .take(limit)
-                        .map(|t| t.into_tuple()),
+                        .map_ok(|t| t.into_tuple()),
                 ))
             } else {
-                Left(result_store.all_iter().map(|t| t.into_tuple()))
+                Left(result_store.all_iter().map_ok(|t| t.into_tuple()))
             };
 
             if let Some((meta, relation_op)) = &out_opts.store_relation {
-                let to_clear = tx
+                let (to_clear, returned) = tx
                     .execute_relation(
                         self,
                         scan,
                         *relation_op,
                         meta,
-                        &entry_head_or_default,
+                        entry_head_or_default,
                         cur_vld,
                         callback_targets,
                         callback_collector,
                         top_level,
+                        out_opts.returning,
                     )
                     .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
                 clean_ups.extend(to_clear);
-                Ok((
+                returned.unwrap_or_else(|| {
                     NamedRows::new(
                         vec![STATUS_STR.to_string()],
                         vec![vec![DataValue::from(OK_STR)]],
-                    ),
-                    clean_ups,
-                ))
+                    )
+                })
             } else {
-                let rows: Vec<Tuple> = scan.collect_vec();
-
-                Ok((
-                    NamedRows::new(
-                        entry_head_or_default
-                            .iter()
-                            .map(|s| s.to_string())
-                            .collect_vec(),
-                        rows,
-                    ),
-                    clean_ups,
-                ))
+                let rows: Vec<Tuple> = scan.try_collect()?;
+                NamedRows::new(
+                    entry_head_or_default
+                        .iter()
+                        .map(|s| s.to_string())
+                        .collect_vec(),
+                    rows,
+                )
             }
+        };
+
+        if !out_opts.rule_mutations.is_empty() {
+            let to_clear = self.execute_rule_mutations(
+                tx,
+                &out_opts.rule_mutations,
+                other_stores,
+                cur_vld,
+                callback_targets,
+                callback_collector,
+                top_level,
+            )?;
+            clean_ups.extend(to_clear);
+        }
+        Ok((ret, clean_ups))
+    }
+    /// Runs the mutations fed by named rules in the order they are given,
+    /// taking the rows from the stores of the rules
+    fn execute_rule_mutations(
+        &self,
+        tx: &mut SessionTx<'_>,
+        mutations: &[RuleMutation],
+        stores: BTreeMap<MagicSymbol, EpochStore>,
+        cur_vld: ValidityTs,
+        callback_targets: &BTreeSet<SmartString<LazyCompact>>,
+        callback_collector: &mut CallbackCollector,
+        top_level: bool,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut clean_ups = vec![];
+        for mutation in mutations {
+            let store = &stores[&MagicSymbol::Muggle {
+                inner: mutation.source.clone(),
+            }];
+            let meta = &mutation.handle;
+            let (to_clear, _) = tx
+                .execute_relation(
+                    self,
+                    store.all_iter().map_ok(|t| t.into_tuple()),
+                    mutation.op,
+                    meta,
+                    &mutation.headers,
+                    cur_vld,
+                    callback_targets,
+                    callback_collector,
+                    top_level,
+                    false,
+                )
+                .wrap_err_with(|| format!("when executing against relation '{}'", meta.name))?;
+            clean_ups.extend(to_clear);
+        }
+        Ok(clean_ups)
+    }
+    /// Registers a query so that it can be listed and cancelled. Its usage is also
+    /// charged to `parent`. The returned guard removes the registration when dropped.
+    pub(crate) fn register_running_query(
+        &self,
+        out_opts: &QueryOutOptions,
+        parent: &QueryUsage,
+    ) -> Result<(Poison, QueryUsage, RunningQueryCleanup)> {
+        // poison is used to terminate queries early
+        let poison = Poison::default();
+        if let Some(secs) = out_opts.timeout {
+            poison.set_timeout(secs)?;
         }
+        let defaults = *self.default_query_limits.read().unwrap();
+        let usage = parent.child(QueryLimits {
+            max_rows: out_opts.max_rows.or(defaults.max_rows),
+            max_memory: out_opts.max_memory.or(defaults.max_memory),
+        });
+        // give the query an ID and store it so that it can be queried and cancelled
+        let id = self.queries_count.fetch_add(1, Ordering::AcqRel);
+
+        // time the query
+        let since_the_epoch = seconds_since_the_epoch()?;
+
+        let handle = RunningQueryHandle {
+            started_at: since_the_epoch,
+            poison: poison.clone(),
+            usage: usage.clone(),
+        };
+        self.running_queries.lock().unwrap().insert(id, handle);
+
+        // RAII cleanups of running query handle
+        let guard = RunningQueryCleanup {
+            id,
+            running_queries: self.running_queries.clone(),
+        };
+        Ok((poison, usage, guard))
     }
     pub(crate) fn list_running(&self) -> Result<NamedRows> {
         let rows = self
@@ -1576,11 +2138,18 @@ impl<'s, S: Storage<'s>> Db<S> {
                 vec![
                     DataValue::from(*k as i64),
                     DataValue::from(format!("{:?}", v.started_at)),
+                    DataValue::from(v.usage.rows() as i64),
+                    DataValue::from(v.usage.memory() as i64),
                 ]
             })
             .collect_vec();
         Ok(NamedRows::new(
-            vec!["id".to_string(), "started_at".to_string()],
+            vec![
+                "id".to_string(),
+                "started_at".to_string(),
+                "rows".to_string(),
+                "memory".to_string(),
+            ],
             rows,
         ))
     }
@@ -1593,7 +2162,7 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(name),
                 json!("normal"),
                 json!([rel.name]),
-                json!({ "indices": cols }),
+                json!({ "indices": cols, "unique": handle.unique_indices.contains_key(name) }),
             ]);
         }
         for (name, (rel, manifest)) in &handle.hnsw_indices {
@@ -1645,6 +2214,17 @@ impl<'s, S: Storage<'s>> Db<S> {
                 }),
             ]);
         }
+        for (name, (rel, manifest)) in &handle.rtree_indices {
+            rows.push(vec![
+                json!(name),
+                json!("rtree"),
+                json!([rel.name]),
+                json!({
+                    "extractor": manifest.extractor,
+                    "max_entries": manifest.max_entries,
+                }),
+            ]);
+        }
         tx.commit_tx()?;
         let rows = rows
             .into_iter()
@@ -1701,6 +2281,20 @@ impl<'s, S: Storage<'s>> Db<S> {
             rows,
         ))
     }
+    pub(crate) fn inline_views(
+        &self,
+        tx: &SessionTx<'_>,
+        prog: &mut InputProgram,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        prog.inline_views(tx, &|script| {
+            let parsed = self.parse_payload(script, &Default::default(), None, cur_vld)?;
+            match parsed {
+                CozoScript::Single(p) => Ok(*p),
+                _ => bail!("Stored view does not contain a query"),
+            }
+        })
+    }
     fn list_relations(&'s self) -> Result<NamedRows> {
         let lower = vec![DataValue::from("")].encode_as_key(RelationId::SYSTEM);
         let upper =
@@ -1734,6 +2328,24 @@ impl<'s, S: Storage<'s>> Db<S> {
                 json!(meta.description),
             ]);
         }
+        for view in self.transact()?.list_views()? {
+            // materialized views are listed as their stored relations
+            if view.materialized {
+                continue;
+            }
+            let arity = view.columns.len();
+            rows.push(vec![
+                json!(view.name),
+                json!(arity),
+                json!("view"),
+                json!(0),
+                json!(arity),
+                json!(0),
+                json!(0),
+                json!(0),
+                json!(""),
+            ]);
+        }
         let rows = rows
             .into_iter()
             .map(|row| row.into_iter().map(DataValue::from).collect_vec())
@@ -1789,6 +2401,164 @@ impl Poison {
     }
 }
 
+/// Limits on the resources a query may use. Limits not given by the query with the
+/// `:max_rows` and `:max_memory` options are taken from [Db::set_default_query_limits].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// Maximum number of rows the query may hold at any time, in the stores for the results
+    /// of rules and in the tables built for joins
+    pub max_rows: Option<usize>,
+    /// Maximum estimated number of bytes the rows held in memory by the query may occupy
+    pub max_memory: Option<usize>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Query exceeded its limit {0} = {1}")]
+#[diagnostic(code(eval::query_limit_exceeded))]
+#[diagnostic(help(
+    "The limit is set by the `:{0}` option of the query or by the defaults of the database"
+))]
+pub(crate) struct QueryLimitExceeded(&'static str, usize);
+
+/// Rows and memory held by a running query, checked against its limits
+#[derive(Clone, Default)]
+pub(crate) struct QueryUsage(Arc<QueryUsageInner>);
+
+#[derive(Default)]
+struct QueryUsageInner {
+    limits: QueryLimits,
+    rows: AtomicUsize,
+    memory: AtomicUsize,
+    /// The usage of the query or program this query runs within, also charged for this one
+    parent: Option<QueryUsage>,
+}
+
+impl Drop for QueryUsageInner {
+    fn drop(&mut self) {
+        if let Some(parent) = &self.parent {
+            parent.release(*self.rows.get_mut(), *self.memory.get_mut());
+        }
+    }
+}
+
+impl QueryUsage {
+    /// Creates the usage of a query run within this one. What the query holds is also
+    /// charged to this one, until the returned usage is dropped.
+    pub(crate) fn child(&self, limits: QueryLimits) -> Self {
+        Self(Arc::new(QueryUsageInner {
+            limits,
+            rows: Default::default(),
+            memory: Default::default(),
+            parent: Some(self.clone()),
+        }))
+    }
+    /// Records that the query holds more rows and memory, failing if a limit is exceeded.
+    pub(crate) fn charge(&self, rows: usize, bytes: usize) -> Result<()> {
+        let inner = &self.0;
+        let total_rows = inner.rows.fetch_add(rows, Ordering::Relaxed) + rows;
+        let total_bytes = inner.memory.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let parent_res = match &inner.parent {
+            Some(parent) => parent.charge(rows, bytes),
+            None => Ok(()),
+        };
+        if let Some(max) = inner.limits.max_rows {
+            ensure!(total_rows <= max, QueryLimitExceeded("max_rows", max));
+        }
+        if let Some(max) = inner.limits.max_memory {
+            ensure!(total_bytes <= max, QueryLimitExceeded("max_memory", max));
+        }
+        parent_res
+    }
+    /// Records that the query no longer holds the rows and memory.
+    pub(crate) fn release(&self, rows: usize, bytes: usize) {
+        let inner = &self.0;
+        if let Some(parent) = &inner.parent {
+            parent.release(rows, bytes);
+        }
+        let _ = inner
+            .rows
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(rows))
+            });
+        let _ = inner
+            .memory
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(bytes))
+            });
+    }
+    /// Creates a holder for rows buffered by an operator, released when it is dropped.
+    pub(crate) fn holder(&self) -> HeldRows {
+        HeldRows {
+            usage: self.clone(),
+            rows: 0,
+            bytes: 0,
+        }
+    }
+    pub(crate) fn rows(&self) -> usize {
+        self.0.rows.load(Ordering::Relaxed)
+    }
+    pub(crate) fn memory(&self) -> usize {
+        self.0.memory.load(Ordering::Relaxed)
+    }
+}
+
+/// Rows buffered by an operator, charged to the query as long as the holder lives
+pub(crate) struct HeldRows {
+    usage: QueryUsage,
+    rows: usize,
+    bytes: usize,
+}
+
+impl HeldRows {
+    pub(crate) fn hold(&mut self, tuple: &[DataValue]) -> Result<()> {
+        let bytes = tuple_size(tuple);
+        self.rows += 1;
+        self.bytes += bytes;
+        self.usage.charge(1, bytes)
+    }
+}
+
+impl Drop for HeldRows {
+    fn drop(&mut self) {
+        self.usage.release(self.rows, self.bytes);
+    }
+}
+
+/// Checks that the relation to be mutated exists, or does not exist for `:create`,
+/// and that the mutation is compatible with its schema
+fn ensure_mutation_target(
+    tx: &SessionTx<'_>,
+    meta: &InputRelationHandle,
+    op: RelationOp,
+) -> Result<()> {
+    if op == RelationOp::Create {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Stored relation {0} conflicts with an existing one")]
+        #[diagnostic(code(eval::stored_relation_conflict))]
+        struct StoreRelationConflict(String);
+
+        ensure!(
+            !tx.relation_exists(&meta.name)?,
+            StoreRelationConflict(meta.name.to_string())
+        )
+    } else if op != RelationOp::Replace {
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Stored relation {0} not found")]
+        #[diagnostic(code(eval::stored_relation_not_found))]
+        struct StoreRelationNotFoundError(String);
+
+        let existing = tx.get_relation(&meta.name, false)?;
+
+        ensure!(
+            tx.relation_exists(&meta.name)?,
+            StoreRelationNotFoundError(meta.name.to_string())
+        );
+
+        existing.ensure_compatible(meta, op == RelationOp::Rm || op == RelationOp::Update)?;
+    }
+    Ok(())
+}
+
 pub(crate) fn seconds_since_the_epoch() -> Result<f64> {
     #[cfg(not(target_arch = "wasm32"))]
     let now = SystemTime::now();
@@ -232,6 +232,7 @@ impl<'s, S: Storage<'s>> Db<S> {
         for p in ps {
             p.needs_write_locks(&mut write_lock_names);
         }
+        self.add_dependent_relations(&mut write_lock_names)?;
         let is_write = !write_lock_names.is_empty();
         let write_lock = self.obtain_relation_locks(write_lock_names.iter());
         let _write_lock_guards = write_lock.iter().map(|l| l.read().unwrap()).collect_vec();
@@ -257,6 +258,8 @@ impl<'s, S: Storage<'s>> Db<S> {
             let q_handle = RunningQueryHandle {
                 started_at: since_the_epoch,
                 poison: poison.clone(),
+                // the statements run as queries within the program, charging it as well
+                usage: tx.usage.clone(),
             };
             self.running_queries.lock().unwrap().insert(qid, q_handle);
             let _guard = RunningQueryCleanup {
@@ -8,11 +8,17 @@
 
 pub(crate) mod callback;
 pub(crate) mod db;
+pub(crate) mod hnsw;
 pub(crate) mod imperative;
+pub(crate) mod minhash_lsh;
+pub(crate) mod prepared;
 pub(crate) mod relation;
+pub(crate) mod rtree;
+pub(crate) mod spill;
+pub(crate) mod stats;
+pub(crate) mod stream;
 pub(crate) mod temp_store;
-pub(crate) mod transact;
-pub(crate) mod hnsw;
-pub(crate) mod minhash_lsh;
 #[cfg(test)]
 mod tests;
+pub(crate) mod transact;
+pub(crate) mod view;
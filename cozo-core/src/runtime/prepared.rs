@@ -0,0 +1,254 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+use itertools::Itertools;
+use miette::{Diagnostic, Result};
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::ParamCell;
+use crate::data::functions::current_validity;
+use crate::data::tuple::TupleT;
+use crate::data::value::{DataValue, ValidityTs};
+use crate::parse::PreparedParams;
+use crate::runtime::db::{CompiledQuery, Db, NamedRows};
+use crate::runtime::relation::RelationId;
+use crate::runtime::transact::SessionTx;
+use crate::runtime::view::view_key;
+use crate::storage::Storage;
+
+/// A query script that is parsed and compiled once, and then executed
+/// many times with different parameters. Obtained by [Db::prepare].
+///
+/// The compiled plan is rebuilt automatically when a stored relation or view that
+/// the query reads from or writes to is created, removed or altered, including
+/// changes to its indices. It is also rebuilt when a parameter used where a constant
+/// is required, for example in `:limit` or in a constant rule, takes a different value,
+/// and on every execution if the script uses `'NOW'` for time travel.
+///
+/// Executions of the same prepared query take turns.
+pub struct PreparedQuery<S> {
+    db: Db<S>,
+    script: String,
+    plan: Mutex<Option<PreparedPlan>>,
+}
+
+struct PreparedPlan {
+    query: CompiledQuery,
+    /// The placeholders of all parameters used in the script
+    params: BTreeMap<String, ParamCell>,
+    /// Parameters that were compiled into the plan as constants, with their values
+    bound: BTreeMap<String, Option<DataValue>>,
+    uses_now: bool,
+    write_lock_names: BTreeSet<SmartString<LazyCompact>>,
+    /// Catalog entries of the stored relations and views used by the query,
+    /// as they were when the plan was compiled
+    deps: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Required parameter {0} not found")]
+#[diagnostic(code(eval::param_not_found))]
+struct ParamNotFoundError(String);
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Parse and compile a query script for repeated execution with
+    /// different parameters, see [PreparedQuery].
+    ///
+    /// Only scripts consisting of a single query can be prepared.
+    pub fn prepare(&'s self, script: &str) -> Result<PreparedQuery<S>> {
+        // compile eagerly to report errors early, unless the plan depends on parameter values
+        let plan = match compile_prepared(self, script, &Default::default(), current_validity()) {
+            Ok(plan) => Some(plan),
+            Err((err, needs_params)) => {
+                if !needs_params {
+                    return Err(err);
+                }
+                None
+            }
+        };
+        Ok(PreparedQuery {
+            db: self.clone(),
+            script: script.to_string(),
+            plan: Mutex::new(plan),
+        })
+    }
+}
+
+impl<'s, S: Storage<'s>> PreparedQuery<S> {
+    /// The script of the query
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+    /// Execute the query with the given parameters
+    pub fn execute(&'s self, params: BTreeMap<String, DataValue>) -> Result<NamedRows> {
+        let cur_vld = current_validity();
+        let mut plan = self.plan.lock().unwrap();
+        loop {
+            let stale = match &*plan {
+                None => true,
+                Some(p) => p.uses_now || p.bound.iter().any(|(k, v)| params.get(k) != v.as_ref()),
+            };
+            if stale {
+                *plan = Some(
+                    compile_prepared(&self.db, &self.script, &params, cur_vld)
+                        .map_err(|(err, _)| err)?,
+                );
+            }
+            let p = plan.as_ref().unwrap();
+            for (name, cell) in &p.params {
+                let val = params
+                    .get(name)
+                    .ok_or_else(|| ParamNotFoundError(name.to_string()))?;
+                cell.set(Some(val.clone()));
+            }
+            match self.execute_plan(p, cur_vld)? {
+                Some(res) => return Ok(res),
+                // the relations used have changed since compilation
+                None => *plan = None,
+            }
+        }
+    }
+    /// Returns `None` without running anything if the plan is out of date
+    fn execute_plan(
+        &'s self,
+        plan: &PreparedPlan,
+        cur_vld: ValidityTs,
+    ) -> Result<Option<NamedRows>> {
+        let db = &self.db;
+        let mut callback_collector = BTreeMap::new();
+        let is_write = !plan.write_lock_names.is_empty();
+        let write_lock = db.obtain_relation_locks(plan.write_lock_names.iter());
+        let _write_lock_guards = write_lock.iter().map(|l| l.read().unwrap()).collect_vec();
+        let callback_targets = if is_write {
+            db.current_callback_targets()
+        } else {
+            Default::default()
+        };
+        let res;
+        {
+            let mut tx = if is_write {
+                db.transact_write()?
+            } else {
+                db.transact()?
+            };
+            for (key, val) in &plan.deps {
+                if tx.store_tx.get(key, false)? != *val {
+                    return Ok(None);
+                }
+            }
+
+            let (q_res, cleanups) = db.run_compiled_query(
+                &mut tx,
+                &plan.query,
+                cur_vld,
+                &callback_targets,
+                &mut callback_collector,
+                true,
+            )?;
+            res = q_res;
+            #[cfg(not(target_arch = "wasm32"))]
+            if let Some(secs) = plan.query.out_opts.sleep {
+                thread::sleep(Duration::from_micros((secs * 1000000.) as u64));
+            }
+
+            for (lower, upper) in cleanups {
+                tx.store_tx.del_range_from_persisted(&lower, &upper)?;
+            }
+
+            tx.commit_tx()?;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if !callback_collector.is_empty() {
+            db.send_callbacks(callback_collector)
+        }
+
+        Ok(Some(res))
+    }
+}
+
+/// On failure, also tells whether compilation needed values of parameters not given
+fn compile_prepared<'s, S: Storage<'s>>(
+    db: &'s Db<S>,
+    script: &str,
+    params: &BTreeMap<String, DataValue>,
+    cur_vld: ValidityTs,
+) -> Result<PreparedPlan, (miette::Error, bool)> {
+    let prepared = PreparedParams::default();
+    do_compile_prepared(db, script, params, &prepared, cur_vld).map_err(|err| {
+        let needs_params = prepared
+            .cells()
+            .values()
+            .any(|cell| cell.take_bound() && cell.get().is_none());
+        (err, needs_params)
+    })
+}
+fn do_compile_prepared<'s, S: Storage<'s>>(
+    db: &'s Db<S>,
+    script: &str,
+    params: &BTreeMap<String, DataValue>,
+    prepared: &PreparedParams,
+    cur_vld: ValidityTs,
+) -> Result<PreparedPlan> {
+    let parsed = db.parse_payload(script, params, Some(prepared), cur_vld)?;
+    let mut program = parsed.get_single_program()?;
+    let mut write_lock_names = BTreeSet::new();
+    program.needs_write_locks(&mut write_lock_names);
+    db.add_dependent_relations(&mut write_lock_names)?;
+
+    let mut tx = db.transact()?;
+    let mut names = program.referenced_names();
+    db.inline_views(&tx, &mut program, cur_vld)?;
+    names.extend(program.referenced_names());
+    let deps = catalog_entries(&tx, names)?;
+    let query = db.compile_query(&mut tx, program)?;
+
+    let params = prepared.cells();
+    let bound = params
+        .iter()
+        .filter(|(_, cell)| cell.take_bound())
+        .map(|(name, cell)| (name.clone(), cell.get()))
+        .collect();
+    Ok(PreparedPlan {
+        query,
+        params,
+        bound,
+        uses_now: prepared.uses_now(),
+        write_lock_names,
+        deps,
+    })
+}
+
+/// The catalog entries for the given names both as stored relations and as views,
+/// so that creating a relation or view of a previously unknown name is also noticed
+fn catalog_entries(
+    tx: &SessionTx<'_>,
+    names: BTreeSet<SmartString<LazyCompact>>,
+) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>> {
+    let mut ret = vec![];
+    for name in names {
+        if name.starts_with('_') {
+            continue;
+        }
+        for key in [
+            vec![DataValue::from(&name as &str)].encode_as_key(RelationId::SYSTEM),
+            view_key(&name),
+        ] {
+            let val = tx.store_tx.get(&key, false)?;
+            ret.push((key, val));
+        }
+    }
+    Ok(ret)
+}
@@ -6,31 +6,41 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
 use itertools::Itertools;
 use log::error;
-use miette::{bail, ensure, Diagnostic, IntoDiagnostic, Result};
+use miette::{bail, ensure, Diagnostic, IntoDiagnostic, Result, WrapErr};
 use pest::Parser;
+use rand::{thread_rng, RngCore};
 use rmp_serde::Serializer;
 use serde::Serialize;
 use smartstring::{LazyCompact, SmartString};
 use thiserror::Error;
 
+use crate::data::expr::{Bytecode, CustomFunction, Expr};
 use crate::data::memcmp::MemCmpEncoder;
-use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::relation::{
+    ColType, ColumnDef, ForeignKey, NullableColType, StoredRelationMetadata,
+};
 use crate::data::symb::Symbol;
 use crate::data::tuple::{decode_tuple_from_key, Tuple, TupleT, ENCODED_KEY_MIN_LEN};
 use crate::data::value::{DataValue, ValidityTs};
-use crate::fts::FtsIndexManifest;
+use crate::fts::{FtsIndexManifest, TokenizerConfig};
 use crate::parse::expr::build_expr;
-use crate::parse::sys::{FtsIndexConfig, HnswIndexConfig, MinHashLshConfig};
+use crate::parse::sys::{
+    AlterRelationOp, FtsIndexConfig, HnswIndexConfig, MinHashLshConfig, RtreeIndexConfig,
+};
 use crate::parse::{CozoScriptParser, Rule, SourceSpan};
 use crate::query::compile::IndexPositionUse;
 use crate::runtime::hnsw::HnswIndexManifest;
 use crate::runtime::minhash_lsh::{HashPermutations, LshParams, MinHashLshIndexManifest, Weights};
+use crate::runtime::rtree::RtreeIndexManifest;
+use crate::runtime::stats::RelationStats;
 use crate::runtime::transact::SessionTx;
 use crate::{NamedRows, StoreTx};
 use crate::utils::TempCollector;
@@ -90,20 +100,56 @@ pub(crate) struct RelationHandle {
         (RelationHandle, RelationHandle, MinHashLshIndexManifest),
     >,
     pub(crate) description: SmartString<LazyCompact>,
+    /// Unique indices, mapped to the number of leading index columns that must be unique
+    #[serde(default)]
+    pub(crate) unique_indices: BTreeMap<SmartString<LazyCompact>, usize>,
+    /// Relations having foreign keys referencing this relation
+    #[serde(default)]
+    pub(crate) referenced_by: BTreeSet<SmartString<LazyCompact>>,
+    /// Materialized views reading this relation, kept up to date on every mutation
+    #[serde(default)]
+    pub(crate) materialized_views: BTreeSet<SmartString<LazyCompact>>,
+    /// Statistics collected by the last `::analyze`, used for ordering joins
+    #[serde(default)]
+    pub(crate) stats: Option<RelationStats>,
+    /// R-tree indices over geometries extracted from the rows
+    #[serde(default)]
+    pub(crate) rtree_indices:
+        BTreeMap<SmartString<LazyCompact>, (RelationHandle, RtreeIndexManifest)>,
+    /// Text indices, as pairs of base relation and index name, having token filters
+    /// that read their dictionaries from this relation, rebuilt on every mutation
+    #[serde(default)]
+    pub(crate) dictionary_of: BTreeSet<(SmartString<LazyCompact>, SmartString<LazyCompact>)>,
+    /// Drawn anew whenever the relation holding dictionaries is mutated,
+    /// so that the analyzers built from the old dictionaries are not reused
+    #[serde(default)]
+    pub(crate) dictionary_version: u64,
 }
 
 impl RelationHandle {
+    /// The token filters of the text index of the name, empty if there is no such index
+    pub(crate) fn text_index_filters(&self, index_name: &str) -> &[TokenizerConfig] {
+        if let Some((_, manifest)) = self.fts_indices.get(index_name) {
+            &manifest.filters
+        } else if let Some((_, _, manifest)) = self.lsh_indices.get(index_name) {
+            &manifest.filters
+        } else {
+            &[]
+        }
+    }
     pub(crate) fn has_index(&self, index_name: &str) -> bool {
         self.indices.contains_key(index_name)
             || self.hnsw_indices.contains_key(index_name)
             || self.fts_indices.contains_key(index_name)
             || self.lsh_indices.contains_key(index_name)
+            || self.rtree_indices.contains_key(index_name)
     }
     pub(crate) fn has_no_index(&self) -> bool {
         self.indices.is_empty()
             && self.hnsw_indices.is_empty()
             && self.fts_indices.is_empty()
             && self.lsh_indices.is_empty()
+            && self.rtree_indices.is_empty()
     }
 }
 
@@ -150,6 +196,16 @@ struct StoredRelArityMismatch {
 }
 
 impl RelationHandle {
+    /// Compiles an expression over the columns of the relation, such as the extractor of an index
+    pub(crate) fn compile_expr(&self, code: &str) -> Result<Vec<Bytecode>> {
+        let parsed = CozoScriptParser::parse(Rule::expr, code)
+            .into_diagnostic()?
+            .next()
+            .unwrap();
+        let mut code_expr = build_expr(parsed, &Default::default())?;
+        code_expr.fill_binding_indices(&self.raw_binding_map())?;
+        code_expr.compile()
+    }
     pub(crate) fn raw_binding_map(&self) -> BTreeMap<Symbol, usize> {
         let mut ret = BTreeMap::new();
         for (i, col) in self.metadata.keys.iter().enumerate() {
@@ -166,6 +222,17 @@ impl RelationHandle {
     pub(crate) fn has_triggers(&self) -> bool {
         !self.put_triggers.is_empty() || !self.rm_triggers.is_empty()
     }
+    /// The key with the numbers converted to the declared types of the key columns, so that
+    /// lookups match stored keys that are numerically equal, as comparisons do
+    pub(crate) fn lookup_key<'k>(&self, key: &'k [DataValue]) -> Cow<'k, [DataValue]> {
+        let mut ret = Cow::Borrowed(key);
+        for (i, col) in self.metadata.keys.iter().enumerate().take(key.len()) {
+            if let Some(coerced) = col.typing.coerce_for_lookup(&key[i]) {
+                ret.to_mut()[i] = coerced;
+            }
+        }
+        ret
+    }
     fn encode_key_prefix(&self, len: usize) -> Vec<u8> {
         let mut ret = Vec::with_capacity(4 + 4 * len + 10 * len);
         let prefix_bytes = self.id.0.to_be_bytes();
@@ -260,7 +327,7 @@ impl RelationHandle {
             }
         );
         let mut ret = self.encode_key_prefix(len);
-        for val in &tuple[0..len] {
+        for val in self.lookup_key(&tuple[0..len]).iter() {
             ret.encode_datavalue(val);
         }
         Ok(ret)
@@ -367,6 +434,19 @@ impl RelationHandle {
         }
     }
 
+    pub(crate) fn scan_all_rev<'a>(
+        &self,
+        tx: &'a SessionTx<'_>,
+    ) -> impl Iterator<Item = Result<Tuple>> + 'a {
+        let lower = Tuple::default().encode_as_key(self.id);
+        let upper = Tuple::default().encode_as_key(self.id.next());
+        if self.is_temp {
+            tx.temp_store_tx.range_scan_tuple_rev(&lower, &upper)
+        } else {
+            tx.store_tx.range_scan_tuple_rev(&lower, &upper)
+        }
+    }
+
     pub(crate) fn skip_scan_all<'a>(
         &self,
         tx: &'a SessionTx<'_>,
@@ -383,7 +463,7 @@ impl RelationHandle {
     }
 
     pub(crate) fn get(&self, tx: &SessionTx<'_>, key: &[DataValue]) -> Result<Option<Tuple>> {
-        let key_data = key.encode_as_key(self.id);
+        let key_data = self.lookup_key(key).encode_as_key(self.id);
         if self.is_temp {
             Ok(tx
                 .temp_store_tx
@@ -402,7 +482,7 @@ impl RelationHandle {
         tx: &SessionTx<'_>,
         key: &[DataValue],
     ) -> Result<Option<Tuple>> {
-        let key_data = key.encode_as_key(self.id);
+        let key_data = self.lookup_key(key).encode_as_key(self.id);
         if self.is_temp {
             Ok(tx
                 .temp_store_tx
@@ -417,7 +497,7 @@ impl RelationHandle {
     }
 
     pub(crate) fn exists(&self, tx: &SessionTx<'_>, key: &[DataValue]) -> Result<bool> {
-        let key_data = key.encode_as_key(self.id);
+        let key_data = self.lookup_key(key).encode_as_key(self.id);
         if self.is_temp {
             tx.temp_store_tx.exists(&key_data, false)
         } else {
@@ -430,8 +510,8 @@ impl RelationHandle {
         tx: &'a SessionTx<'_>,
         prefix: &Tuple,
     ) -> impl Iterator<Item = Result<Tuple>> + 'a {
-        let mut lower = prefix.clone();
-        lower.truncate(self.metadata.keys.len());
+        let len = prefix.len().min(self.metadata.keys.len());
+        let lower = self.lookup_key(&prefix[..len]).into_owned();
         let mut upper = lower.clone();
         upper.push(DataValue::Bot);
         let prefix_encoded = lower.encode_as_key(self.id);
@@ -451,8 +531,8 @@ impl RelationHandle {
         prefix: &Tuple,
         valid_at: ValidityTs,
     ) -> impl Iterator<Item = Result<Tuple>> + 'a {
-        let mut lower = prefix.clone();
-        lower.truncate(self.metadata.keys.len());
+        let len = prefix.len().min(self.metadata.keys.len());
+        let lower = self.lookup_key(&prefix[..len]).into_owned();
         let mut upper = lower.clone();
         upper.push(DataValue::Bot);
         let prefix_encoded = lower.encode_as_key(self.id);
@@ -475,8 +555,10 @@ impl RelationHandle {
     ) -> impl Iterator<Item = Result<Tuple>> + 'a {
         let mut lower_t = prefix.to_vec();
         lower_t.extend_from_slice(lower);
+        let lower_t = self.lookup_key(&lower_t);
         let mut upper_t = prefix.to_vec();
         upper_t.extend_from_slice(upper);
+        let mut upper_t = self.lookup_key(&upper_t).into_owned();
         upper_t.push(DataValue::Bot);
         let lower_encoded = lower_t.encode_as_key(self.id);
         let upper_encoded = upper_t.encode_as_key(self.id);
@@ -497,8 +579,10 @@ impl RelationHandle {
     ) -> impl Iterator<Item = Result<Tuple>> + 'a {
         let mut lower_t = prefix.clone();
         lower_t.extend_from_slice(lower);
+        let lower_t = self.lookup_key(&lower_t);
         let mut upper_t = prefix.clone();
         upper_t.extend_from_slice(upper);
+        let mut upper_t = self.lookup_key(&upper_t).into_owned();
         upper_t.push(DataValue::Bot);
         let lower_encoded = lower_t.encode_as_key(self.id);
         let upper_encoded = upper_t.encode_as_key(self.id);
@@ -540,6 +624,18 @@ pub(crate) struct IndexAlreadyExists(String, String);
 #[diagnostic(code(eval::rel_name_conflict))]
 struct RelNameConflictError(String);
 
+#[derive(Debug, Diagnostic, Error)]
+#[error("Relation {0} is read by the materialized views {1:?}")]
+#[diagnostic(code(eval::rel_read_by_materialized_views))]
+#[diagnostic(help("Drop the materialized views first"))]
+pub(crate) struct ReadByMaterializedViews(pub(crate) String, pub(crate) Vec<String>);
+
+#[derive(Debug, Diagnostic, Error)]
+#[error("Relation {0} holds the dictionary of token filters of the indices {1:?}")]
+#[diagnostic(code(eval::rel_used_as_dictionary))]
+#[diagnostic(help("Drop the indices first"))]
+pub(crate) struct UsedAsDictionary(pub(crate) String, pub(crate) Vec<String>);
+
 impl<'a> SessionTx<'a> {
     pub(crate) fn relation_exists(&self, name: &str) -> Result<bool> {
         let key = DataValue::from(name);
@@ -596,17 +692,20 @@ impl<'a> SessionTx<'a> {
             if self.store_tx.exists(&encoded, true)? {
                 bail!(RelNameConflictError(input_meta.name.to_string()))
             };
-        } else if self.temp_store_tx.exists(&encoded, true)? {
+        } else if self.temp_store_tx.exists(&encoded, true)?
+            || self.view_exists(&input_meta.name)?
+        {
             bail!(RelNameConflictError(input_meta.name.to_string()))
         }
 
-        let metadata = input_meta.metadata.clone();
+        let mut metadata = input_meta.metadata.clone();
+        metadata.unbind_custom_functions();
         let last_id = if is_temp {
             self.temp_store_id.fetch_add(1, Ordering::Relaxed) as u64
         } else {
             self.relation_store_id.fetch_add(1, Ordering::SeqCst)
         };
-        let meta = RelationHandle {
+        let mut meta = RelationHandle {
             name: input_meta.name.name,
             id: RelationId::new(last_id + 1),
             metadata,
@@ -620,8 +719,35 @@ impl<'a> SessionTx<'a> {
             fts_indices: Default::default(),
             lsh_indices: Default::default(),
             description: Default::default(),
+            unique_indices: Default::default(),
+            referenced_by: Default::default(),
+            materialized_views: Default::default(),
+            stats: None,
+            rtree_indices: Default::default(),
+            dictionary_of: Default::default(),
+            dictionary_version: 0,
         };
 
+        for fk in meta.metadata.foreign_keys.iter() {
+            if is_temp {
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("temp relation {0} cannot have foreign keys")]
+                #[diagnostic(code(tx::fk_in_temp_rel))]
+                struct ForeignKeyInTempRelation(String);
+
+                bail!(ForeignKeyInTempRelation(meta.name.to_string()))
+            }
+            if fk.ref_relation == meta.name {
+                check_foreign_key_target(&meta, fk)?;
+                meta.referenced_by.insert(meta.name.clone());
+            } else {
+                let mut target = self.get_relation(&fk.ref_relation, true)?;
+                check_foreign_key_target(&target, fk)?;
+                target.referenced_by.insert(meta.name.clone());
+                self.put_relation_handle(&target)?;
+            }
+        }
+
         let name_key = vec![DataValue::Str(meta.name.clone())].encode_as_key(RelationId::SYSTEM);
         let mut meta_val = vec![];
         meta.serialize(&mut Serializer::new(&mut meta_val).with_struct_map())
@@ -703,6 +829,44 @@ impl<'a> SessionTx<'a> {
                 store.access_level
             ))
         }
+        let referrers = store
+            .referenced_by
+            .iter()
+            .filter(|r| **r != store.name)
+            .collect_vec();
+        if !referrers.is_empty() {
+            #[derive(Debug, Error, Diagnostic)]
+            #[error("cannot remove relation {0} since it is referenced by {1:?}")]
+            #[diagnostic(code(tx::remove_referenced_rel))]
+            #[diagnostic(help("Remove the referencing relations first"))]
+            struct RemoveReferencedRelation(String, Vec<String>);
+
+            bail!(RemoveReferencedRelation(
+                name.to_string(),
+                referrers.iter().map(|r| r.to_string()).collect_vec()
+            ))
+        }
+        if !store.materialized_views.is_empty() {
+            bail!(ReadByMaterializedViews(
+                name.to_string(),
+                store
+                    .materialized_views
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect_vec()
+            ))
+        }
+        let dependents = self.dictionary_dependents(&store)?;
+        if !dependents.is_empty() {
+            bail!(UsedAsDictionary(name.to_string(), dependents))
+        }
+        for fk in store.metadata.foreign_keys.iter() {
+            if fk.ref_relation != store.name {
+                let mut target = self.get_relation(&fk.ref_relation, true)?;
+                target.referenced_by.remove(&store.name);
+                self.put_relation_handle(&target)?;
+            }
+        }
 
         for k in store.indices.keys() {
             let more_to_clean = self.destroy_relation(&format!("{name}:{k}"))?;
@@ -714,6 +878,11 @@ impl<'a> SessionTx<'a> {
             to_clean.extend(more_to_clean);
         }
 
+        for k in store.rtree_indices.keys() {
+            let more_to_clean = self.destroy_relation(&format!("{name}:{k}"))?;
+            to_clean.extend(more_to_clean);
+        }
+
         let key = DataValue::from(name);
         let encoded = vec![key].encode_as_key(RelationId::SYSTEM);
         if is_temp {
@@ -760,6 +929,7 @@ impl<'a> SessionTx<'a> {
                 nullable: false,
             },
             default_gen: None,
+            check: None,
         }];
 
         let mut idx_keys = vec![ColumnDef {
@@ -769,12 +939,14 @@ impl<'a> SessionTx<'a> {
                 nullable: false,
             },
             default_gen: None,
+            check: None,
         }];
         for k in rel_handle.metadata.keys.iter() {
             idx_keys.push(ColumnDef {
                 name: format!("src_{}", k.name).into(),
                 typing: k.typing.clone(),
                 default_gen: None,
+                check: None,
             });
         }
         let idx_vals = vec![];
@@ -818,21 +990,38 @@ impl<'a> SessionTx<'a> {
             perms: perms.as_bytes().to_vec(),
         };
 
-        // populate index
-        let tokenizer =
-            self.tokenizers
-                .get(&idx_handle.name, &manifest.tokenizer, &manifest.filters)?;
-        let parsed = CozoScriptParser::parse(Rule::expr, &manifest.extractor)
-            .into_diagnostic()?
-            .next()
+        self.register_dictionaries(&rel_handle.name, &manifest.index_name, &manifest.filters)?;
+        self.populate_lsh_index(&rel_handle, &idx_handle, &inv_idx_handle, &manifest)?;
+
+        rel_handle.lsh_indices.insert(
+            manifest.index_name.clone(),
+            (idx_handle, inv_idx_handle, manifest),
+        );
+
+        // update relation metadata
+        let new_encoded =
+            vec![DataValue::from(&rel_handle.name as &str)].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        rel_handle
+            .serialize(&mut Serializer::new(&mut meta_val))
             .unwrap();
-        let mut code_expr = build_expr(parsed, &Default::default())?;
-        let binding_map = rel_handle.raw_binding_map();
-        code_expr.fill_binding_indices(&binding_map)?;
-        let extractor = code_expr.compile()?;
+        self.store_tx.put(&new_encoded, &meta_val)?;
 
-        let mut stack = vec![];
+        Ok(())
+    }
 
+    fn populate_lsh_index(
+        &mut self,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+        inv_idx_handle: &RelationHandle,
+        manifest: &MinHashLshIndexManifest,
+    ) -> Result<()> {
+        let tokenizer =
+            self.get_tokenizer(&idx_handle.name, &manifest.tokenizer, &manifest.filters)?;
+        let extractor = rel_handle.compile_expr(&manifest.extractor)?;
+
+        let mut stack = vec![];
 
         let hash_perms = manifest.get_hash_perms();
         let mut existing = TempCollector::default();
@@ -846,18 +1035,99 @@ impl<'a> SessionTx<'a> {
                 &extractor,
                 &mut stack,
                 &tokenizer,
+                rel_handle,
+                idx_handle,
+                inv_idx_handle,
+                manifest,
+                &hash_perms,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn create_rtree_index(&mut self, config: RtreeIndexConfig) -> Result<()> {
+        let mut rel_handle = self.get_relation(&config.base_relation, true)?;
+
+        if rel_handle.has_index(&config.index_name) {
+            bail!(IndexAlreadyExists(
+                config.index_name.to_string(),
+                config.index_name.to_string()
+            ));
+        }
+
+        // each row is a node of the tree, the entries being lists of
+        // `[min_x, min_y, max_x, max_y, child]`
+        let idx_keys = vec![ColumnDef {
+            name: SmartString::from("node"),
+            typing: NullableColType {
+                coltype: ColType::Int,
+                nullable: false,
+            },
+            default_gen: None,
+            check: None,
+        }];
+        let idx_vals = vec![
+            ColumnDef {
+                name: SmartString::from("level"),
+                typing: NullableColType {
+                    coltype: ColType::Int,
+                    nullable: false,
+                },
+                default_gen: None,
+                check: None,
+            },
+            ColumnDef {
+                name: SmartString::from("entries"),
+                typing: NullableColType {
+                    coltype: ColType::List {
+                        eltype: Box::new(NullableColType {
+                            coltype: ColType::Any,
+                            nullable: false,
+                        }),
+                        len: None,
+                    },
+                    nullable: false,
+                },
+                default_gen: None,
+                check: None,
+            },
+        ];
+
+        let idx_handle = self.write_idx_relation(
+            &config.base_relation,
+            &config.index_name,
+            idx_keys,
+            idx_vals,
+        )?;
+
+        let manifest = RtreeIndexManifest {
+            base_relation: config.base_relation,
+            index_name: config.index_name,
+            extractor: config.extractor,
+            max_entries: config.max_entries,
+        };
+
+        // populate index
+        let extractor = rel_handle.compile_expr(&manifest.extractor)?;
+        let mut stack = vec![];
+        let mut existing = TempCollector::default();
+        for tuple in rel_handle.scan_all(self) {
+            existing.push(tuple?);
+        }
+        for tuple in existing.into_iter() {
+            self.put_rtree_index_item(
+                &tuple,
+                &extractor,
+                &mut stack,
                 &rel_handle,
                 &idx_handle,
-                &inv_idx_handle,
                 &manifest,
-                &hash_perms,
             )?;
         }
 
-        rel_handle.lsh_indices.insert(
-            manifest.index_name.clone(),
-            (idx_handle, inv_idx_handle, manifest),
-        );
+        rel_handle
+            .rtree_indices
+            .insert(manifest.index_name.clone(), (idx_handle, manifest));
 
         // update relation metadata
         let new_encoded =
@@ -891,6 +1161,7 @@ impl<'a> SessionTx<'a> {
                 nullable: false,
             },
             default_gen: None,
+            check: None,
         }];
 
         for k in rel_handle.metadata.keys.iter() {
@@ -898,6 +1169,7 @@ impl<'a> SessionTx<'a> {
                 name: format!("src_{}", k.name).into(),
                 typing: k.typing.clone(),
                 default_gen: None,
+                check: None,
             });
         }
 
@@ -917,16 +1189,19 @@ impl<'a> SessionTx<'a> {
                 name: SmartString::from("offset_from"),
                 typing: col_type.clone(),
                 default_gen: None,
+                check: None,
             },
             ColumnDef {
                 name: SmartString::from("offset_to"),
                 typing: col_type.clone(),
                 default_gen: None,
+                check: None,
             },
             ColumnDef {
                 name: SmartString::from("position"),
                 typing: col_type,
                 default_gen: None,
+                check: None,
             },
             ColumnDef {
                 name: SmartString::from("total_length"),
@@ -935,6 +1210,7 @@ impl<'a> SessionTx<'a> {
                     nullable: false,
                 },
                 default_gen: None,
+                check: None,
             },
         ];
 
@@ -945,6 +1221,26 @@ impl<'a> SessionTx<'a> {
             non_idx_keys,
         )?;
 
+        // a single row holding the corpus statistics
+        let stats_vals = ["n_docs", "total_length"]
+            .into_iter()
+            .map(|name| ColumnDef {
+                name: SmartString::from(name),
+                typing: NullableColType {
+                    coltype: ColType::Int,
+                    nullable: false,
+                },
+                default_gen: None,
+                check: None,
+            })
+            .collect_vec();
+        let stats_handle = self.write_idx_relation(
+            &config.base_relation,
+            &format!("{}:stats", config.index_name),
+            vec![],
+            stats_vals,
+        )?;
+
         // add index to relation
         let manifest = FtsIndexManifest {
             base_relation: config.base_relation,
@@ -952,21 +1248,37 @@ impl<'a> SessionTx<'a> {
             extractor: config.extractor,
             tokenizer: config.tokenizer,
             filters: config.filters,
+            stats_handle: Some(Box::new(stats_handle)),
         };
 
-        // populate index
-        let tokenizer =
-            self.tokenizers
-                .get(&idx_handle.name, &manifest.tokenizer, &manifest.filters)?;
+        self.register_dictionaries(&rel_handle.name, &manifest.index_name, &manifest.filters)?;
+        self.populate_fts_index(&rel_handle, &idx_handle, &manifest)?;
 
-        let parsed = CozoScriptParser::parse(Rule::expr, &manifest.extractor)
-            .into_diagnostic()?
-            .next()
+        rel_handle
+            .fts_indices
+            .insert(manifest.index_name.clone(), (idx_handle, manifest));
+
+        // update relation metadata
+        let new_encoded =
+            vec![DataValue::from(&rel_handle.name as &str)].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        rel_handle
+            .serialize(&mut Serializer::new(&mut meta_val))
             .unwrap();
-        let mut code_expr = build_expr(parsed, &Default::default())?;
-        let binding_map = rel_handle.raw_binding_map();
-        code_expr.fill_binding_indices(&binding_map)?;
-        let extractor = code_expr.compile()?;
+        self.store_tx.put(&new_encoded, &meta_val)?;
+
+        Ok(())
+    }
+
+    fn populate_fts_index(
+        &mut self,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+        manifest: &FtsIndexManifest,
+    ) -> Result<()> {
+        let tokenizer =
+            self.get_tokenizer(&idx_handle.name, &manifest.tokenizer, &manifest.filters)?;
+        let extractor = rel_handle.compile_expr(&manifest.extractor)?;
 
         let mut stack = vec![];
 
@@ -978,37 +1290,191 @@ impl<'a> SessionTx<'a> {
             let key_part = &tuple[..rel_handle.metadata.keys.len()];
             if rel_handle.exists(self, key_part)? {
                 self.del_fts_index_item(
-                    &tuple,
-                    &extractor,
-                    &mut stack,
-                    &tokenizer,
-                    &rel_handle,
-                    &idx_handle,
+                    &tuple, &extractor, &mut stack, &tokenizer, rel_handle, idx_handle, manifest,
                 )?;
             }
             self.put_fts_index_item(
-                &tuple,
-                &extractor,
-                &mut stack,
-                &tokenizer,
-                &rel_handle,
-                &idx_handle,
+                &tuple, &extractor, &mut stack, &tokenizer, rel_handle, idx_handle, manifest,
             )?;
         }
+        Ok(())
+    }
 
-        rel_handle
-            .fts_indices
-            .insert(manifest.index_name.clone(), (idx_handle, manifest));
+    /// Records the indices in the relations holding the dictionaries of their token filters
+    fn register_dictionaries(
+        &mut self,
+        base_relation: &SmartString<LazyCompact>,
+        index_name: &SmartString<LazyCompact>,
+        filters: &[TokenizerConfig],
+    ) -> Result<()> {
+        for filter in filters {
+            if let Some(dict_name) = filter.dictionary_relation() {
+                ensure!(
+                    dict_name != base_relation.as_str(),
+                    "relation {} cannot hold the dictionary for its own index {}",
+                    dict_name,
+                    index_name
+                );
+                let mut dict_handle = self.get_relation(dict_name, true)?;
+                ensure!(
+                    !dict_handle.is_temp,
+                    "temp relation {} cannot hold the dictionary for index {}",
+                    dict_name,
+                    index_name
+                );
+                dict_handle
+                    .dictionary_of
+                    .insert((base_relation.clone(), index_name.clone()));
+                // an earlier index of the same name may have read an earlier relation of the same name
+                dict_handle.dictionary_version = thread_rng().next_u64();
+                self.put_relation_handle(&dict_handle)?;
+            }
+        }
+        Ok(())
+    }
 
-        // update relation metadata
-        let new_encoded =
-            vec![DataValue::from(&rel_handle.name as &str)].encode_as_key(RelationId::SYSTEM);
-        let mut meta_val = vec![];
-        rel_handle
-            .serialize(&mut Serializer::new(&mut meta_val))
-            .unwrap();
-        self.store_tx.put(&new_encoded, &meta_val)?;
+    /// Removes the index from the relations holding the dictionaries of its token filters
+    fn unregister_dictionaries(
+        &mut self,
+        base_relation: &SmartString<LazyCompact>,
+        index_name: &SmartString<LazyCompact>,
+        filters: &[TokenizerConfig],
+    ) -> Result<()> {
+        for filter in filters {
+            if let Some(dict_name) = filter.dictionary_relation() {
+                if !self.relation_exists(dict_name)? {
+                    continue;
+                }
+                let mut dict_handle = self.get_relation(dict_name, true)?;
+                if dict_handle
+                    .dictionary_of
+                    .remove(&(base_relation.clone(), index_name.clone()))
+                {
+                    self.put_relation_handle(&dict_handle)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The relations holding the dictionaries of the token filters of the index, if it exists
+    pub(crate) fn index_dictionaries(
+        &self,
+        rel_name: &Symbol,
+        idx_name: &Symbol,
+    ) -> Result<Vec<SmartString<LazyCompact>>> {
+        if !self.relation_exists(rel_name)? {
+            return Ok(vec![]);
+        }
+        let rel = self.get_relation(rel_name, false)?;
+        Ok(rel
+            .text_index_filters(idx_name)
+            .iter()
+            .filter_map(|f| f.dictionary_relation())
+            .map(SmartString::from)
+            .collect())
+    }
+
+    /// Whether the index of the relation still reads its dictionary from the relation
+    fn reads_dictionary(
+        &self,
+        base: &str,
+        idx: &str,
+        dict_handle: &RelationHandle,
+    ) -> Result<bool> {
+        if !self.relation_exists(base)? {
+            return Ok(false);
+        }
+        let base_handle = self.get_relation(base, false)?;
+        Ok(base_handle
+            .text_index_filters(idx)
+            .iter()
+            .any(|f| f.dictionary_relation() == Some(&dict_handle.name)))
+    }
 
+    /// The text indices still reading their dictionaries from the relation
+    pub(crate) fn dictionary_dependents(
+        &self,
+        dict_handle: &RelationHandle,
+    ) -> Result<Vec<String>> {
+        let mut ret = vec![];
+        for (base, idx) in dict_handle.dictionary_of.iter() {
+            if self.reads_dictionary(base, idx, dict_handle)? {
+                ret.push(format!("{base}:{idx}"));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Adds the relations that writing to any of the relations also writes to:
+    /// the targets and referrers of their foreign keys, and the base relations of
+    /// the text indices reading their dictionaries from them
+    pub(crate) fn add_dependent_relations(
+        &self,
+        rels: &mut BTreeSet<SmartString<LazyCompact>>,
+    ) -> Result<()> {
+        let mut dependents = vec![];
+        for rel in rels.iter() {
+            if !self.relation_exists(rel)? {
+                continue;
+            }
+            let handle = self.get_relation(rel, false)?;
+            dependents.extend(
+                handle
+                    .metadata
+                    .foreign_keys
+                    .into_iter()
+                    .map(|fk| fk.ref_relation),
+            );
+            dependents.extend(handle.referenced_by);
+            dependents.extend(handle.dictionary_of.into_iter().map(|(base, _)| base));
+        }
+        rels.extend(dependents);
+        Ok(())
+    }
+
+    /// Rebuilds the text indices reading their dictionaries from the relation,
+    /// after the relation is mutated
+    pub(crate) fn rebuild_dictionary_dependents(
+        &mut self,
+        dict_handle: &RelationHandle,
+    ) -> Result<()> {
+        let mut dict_handle = self.get_relation(&dict_handle.name, true)?;
+        dict_handle.dictionary_version = thread_rng().next_u64();
+        self.put_relation_handle(&dict_handle)?;
+        for (base, idx) in dict_handle.dictionary_of.iter() {
+            // indices dropped since registration are skipped
+            if !self.reads_dictionary(base, idx, &dict_handle)? {
+                continue;
+            }
+            let base_handle = self.get_relation(base, true)?;
+            if let Some((idx_handle, manifest)) = base_handle.fts_indices.get(idx) {
+                self.clear_relation_rows(idx_handle)?;
+                if let Some(stats_handle) = &manifest.stats_handle {
+                    self.clear_relation_rows(stats_handle)?;
+                }
+                self.populate_fts_index(&base_handle, idx_handle, manifest)?;
+            } else if let Some((idx_handle, inv_idx_handle, manifest)) =
+                base_handle.lsh_indices.get(idx)
+            {
+                self.clear_relation_rows(idx_handle)?;
+                self.clear_relation_rows(inv_idx_handle)?;
+                self.populate_lsh_index(&base_handle, idx_handle, inv_idx_handle, manifest)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn clear_relation_rows(&mut self, handle: &RelationHandle) -> Result<()> {
+        let lower_bound = Tuple::default().encode_as_key(handle.id);
+        let upper_bound = Tuple::default().encode_as_key(handle.id.next());
+        let mut keys = vec![];
+        for item in self.store_tx.range_scan(&lower_bound, &upper_bound) {
+            keys.push(item?.0);
+        }
+        for key in keys {
+            self.store_tx.del(&key)?;
+        }
         Ok(())
     }
 
@@ -1074,6 +1540,7 @@ impl<'a> SessionTx<'a> {
                 nullable: false,
             },
             default_gen: None,
+            check: None,
         }];
         // for self-loops, fr and to are identical
         for prefix in ["fr", "to"] {
@@ -1089,6 +1556,7 @@ impl<'a> SessionTx<'a> {
                     nullable: false,
                 },
                 default_gen: None,
+                check: None,
             });
             idx_keys.push(ColumnDef {
                 name: SmartString::from(format!("{}__sub_idx", prefix)),
@@ -1097,6 +1565,7 @@ impl<'a> SessionTx<'a> {
                     nullable: false,
                 },
                 default_gen: None,
+                check: None,
             });
         }
 
@@ -1110,6 +1579,7 @@ impl<'a> SessionTx<'a> {
                     nullable: false,
                 },
                 default_gen: None,
+                check: None,
             },
             // For self-loops, stores a hash of the neighbours, for conflict detection
             ColumnDef {
@@ -1119,6 +1589,7 @@ impl<'a> SessionTx<'a> {
                     nullable: true,
                 },
                 default_gen: None,
+                check: None,
             },
             ColumnDef {
                 name: SmartString::from("ignore_link"),
@@ -1127,6 +1598,7 @@ impl<'a> SessionTx<'a> {
                     nullable: false,
                 },
                 default_gen: None,
+                check: None,
             },
         ];
         // create index relation
@@ -1225,6 +1697,7 @@ impl<'a> SessionTx<'a> {
             metadata: StoredRelationMetadata {
                 keys: idx_keys,
                 non_keys: non_idx_keys,
+                foreign_keys: vec![],
             },
             key_bindings,
             dep_bindings,
@@ -1239,6 +1712,7 @@ impl<'a> SessionTx<'a> {
         rel_name: &Symbol,
         idx_name: &Symbol,
         cols: Vec<Symbol>,
+        unique: bool,
     ) -> Result<()> {
         // Get relation handle
         let mut rel_handle = self.get_relation(rel_name, true)?;
@@ -1278,6 +1752,8 @@ impl<'a> SessionTx<'a> {
             ));
         }
 
+        let n_unique_cols = col_defs.len();
+
         'outer: for key in rel_handle.metadata.keys.iter() {
             for col in cols.iter() {
                 if col.name == key.name {
@@ -1294,6 +1770,7 @@ impl<'a> SessionTx<'a> {
         let idx_meta = StoredRelationMetadata {
             keys: col_defs,
             non_keys: vec![],
+            foreign_keys: vec![],
         };
 
         // create index relation
@@ -1355,6 +1832,19 @@ impl<'a> SessionTx<'a> {
             }
         }
 
+        if unique {
+            self.ensure_index_unique(
+                &rel_handle,
+                &idx_name.name,
+                &idx_handle,
+                &extraction_indices,
+                n_unique_cols,
+            )?;
+            rel_handle
+                .unique_indices
+                .insert(idx_name.name.clone(), n_unique_cols);
+        }
+
         // add index to relation
         rel_handle
             .indices
@@ -1372,6 +1862,55 @@ impl<'a> SessionTx<'a> {
         Ok(())
     }
 
+    /// Fails if two rows of the relation share the values of the unique columns of an index
+    fn ensure_index_unique(
+        &self,
+        rel_handle: &RelationHandle,
+        idx_name: &str,
+        idx_handle: &RelationHandle,
+        extractor: &[usize],
+        n_unique_cols: usize,
+    ) -> Result<()> {
+        // entries sharing the unique columns are adjacent in the index
+        let mut prev: Option<Tuple> = None;
+        for tuple in idx_handle.scan_all(self) {
+            let tuple = tuple?;
+            let cols = &tuple[..n_unique_cols];
+            if let Some(prev) = &prev {
+                if !cols.contains(&DataValue::Null) && prev[..n_unique_cols] == *cols {
+                    #[derive(Debug, Error, Diagnostic)]
+                    #[error("unique index {0} for relation {1}: rows {2:?} and {3:?} have the same values {4:?}")]
+                    #[diagnostic(code(tx::duplicates_in_unique_idx))]
+                    pub(crate) struct DuplicatesInUniqueIndex(
+                        String,
+                        String,
+                        Vec<DataValue>,
+                        Vec<DataValue>,
+                        Vec<DataValue>,
+                    );
+
+                    let key_of = |t: &Tuple| {
+                        (0..rel_handle.metadata.keys.len())
+                            .map(|i| {
+                                let pos = extractor.iter().position(|e| *e == i).unwrap();
+                                t[pos].clone()
+                            })
+                            .collect_vec()
+                    };
+                    bail!(DuplicatesInUniqueIndex(
+                        idx_name.to_string(),
+                        rel_handle.name.to_string(),
+                        key_of(prev),
+                        key_of(&tuple),
+                        cols.to_vec()
+                    ));
+                }
+            }
+            prev = Some(tuple);
+        }
+        Ok(())
+    }
+
     pub(crate) fn remove_index(
         &mut self,
         rel_name: &Symbol,
@@ -1379,9 +1918,12 @@ impl<'a> SessionTx<'a> {
     ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
         let mut rel = self.get_relation(rel_name, true)?;
         let is_lsh = rel.lsh_indices.contains_key(&idx_name.name);
+        let filters = rel.text_index_filters(idx_name).to_vec();
+        rel.unique_indices.remove(&idx_name.name);
         if rel.indices.remove(&idx_name.name).is_none()
             && rel.hnsw_indices.remove(&idx_name.name).is_none()
             && rel.lsh_indices.remove(&idx_name.name).is_none()
+            && rel.rtree_indices.remove(&idx_name.name).is_none()
         {
             #[derive(Debug, Error, Diagnostic)]
             #[error("index {0} for relation {1} not found")]
@@ -1398,6 +1940,7 @@ impl<'a> SessionTx<'a> {
                 self.destroy_relation(&format!("{}:{}:inv", rel_name.name, idx_name.name))?,
             );
         }
+        self.unregister_dictionaries(&rel.name, &idx_name.name, &filters)?;
 
         let new_encoded =
             vec![DataValue::from(&rel_name.name as &str)].encode_as_key(RelationId::SYSTEM);
@@ -1408,6 +1951,435 @@ impl<'a> SessionTx<'a> {
         Ok(to_clean)
     }
 
+    pub(crate) fn alter_relation(
+        &mut self,
+        rel_name: &Symbol,
+        ops: Vec<AlterRelationOp>,
+        functions: &BTreeMap<String, Arc<CustomFunction>>,
+        cur_vld: ValidityTs,
+    ) -> Result<()> {
+        if rel_name.is_temp_store_name() || rel_name.name.contains(':') {
+            bail!("Cannot alter temp store or index relation {}", rel_name)
+        }
+        let mut rel_handle = self.get_relation(rel_name, true)?;
+        rel_handle.metadata.resolve_custom_functions(functions)?;
+        if rel_handle.access_level < AccessLevel::Normal {
+            bail!(InsufficientAccessLevel(
+                rel_handle.name.to_string(),
+                "altering relation".to_string(),
+                rel_handle.access_level
+            ));
+        }
+        if !rel_handle.materialized_views.is_empty() {
+            bail!(ReadByMaterializedViews(
+                rel_handle.name.to_string(),
+                rel_handle
+                    .materialized_views
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect_vec()
+            ))
+        }
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} not found in relation {1}")]
+        #[diagnostic(code(tx::alter_col_not_found))]
+        struct AlterColumnNotFound(String, String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} already exists in relation {1}")]
+        #[diagnostic(code(tx::alter_col_exists))]
+        struct AlterColumnExists(String, String);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("cannot {0} key column {1} of relation {2}")]
+        #[diagnostic(code(tx::alter_key_col))]
+        #[diagnostic(help("Only non-key columns can be added, dropped or retyped"))]
+        struct AlterKeyColumn(&'static str, String, String, #[label] SourceSpan);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("new column {0} is neither nullable nor has a default")]
+        #[diagnostic(code(tx::alter_add_no_default))]
+        struct AlterAddWithoutDefault(String);
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("column {0} of relation {1} is used by index {2}")]
+        #[diagnostic(code(tx::alter_col_used_by_index))]
+        #[diagnostic(help("Remove the index before altering the column"))]
+        struct ColumnUsedByIndex(String, String, String);
+
+        let rel_name_str = rel_handle.name.to_string();
+        let n_keys = rel_handle.metadata.keys.len();
+        let old_metadata = rel_handle.metadata.clone();
+        let mut keys = old_metadata.keys.clone();
+        // every non-key column is paired with its position in the old tuple, if it existed
+        let mut non_keys: Vec<(ColumnDef, Option<usize>)> = old_metadata
+            .non_keys
+            .iter()
+            .enumerate()
+            .map(|(i, col)| (col.clone(), Some(i + n_keys)))
+            .collect_vec();
+
+        for op in ops {
+            match op {
+                AlterRelationOp::Add(col) => {
+                    if keys.iter().any(|k| k.name == col.name)
+                        || non_keys.iter().any(|(c, _)| c.name == col.name)
+                    {
+                        bail!(AlterColumnExists(col.name.to_string(), rel_name_str))
+                    }
+                    if col.default_gen.is_none() && !col.typing.nullable {
+                        bail!(AlterAddWithoutDefault(col.name.to_string()))
+                    }
+                    non_keys.push((col, None));
+                }
+                AlterRelationOp::Drop(name) => {
+                    if keys.iter().any(|k| k.name == name.name) {
+                        bail!(AlterKeyColumn(
+                            "drop",
+                            name.name.to_string(),
+                            rel_name_str,
+                            name.span
+                        ))
+                    }
+                    let pos = non_keys
+                        .iter()
+                        .position(|(c, _)| c.name == name.name)
+                        .ok_or_else(|| {
+                            AlterColumnNotFound(
+                                name.name.to_string(),
+                                rel_name_str.clone(),
+                                name.span,
+                            )
+                        })?;
+                    non_keys.remove(pos);
+                }
+                AlterRelationOp::Rename(old, new) => {
+                    if keys.iter().any(|k| k.name == new.name)
+                        || non_keys.iter().any(|(c, _)| c.name == new.name)
+                    {
+                        bail!(AlterColumnExists(new.name.to_string(), rel_name_str))
+                    }
+                    let col = keys
+                        .iter_mut()
+                        .chain(non_keys.iter_mut().map(|(c, _)| c))
+                        .find(|c| c.name == old.name)
+                        .ok_or_else(|| {
+                            AlterColumnNotFound(
+                                old.name.to_string(),
+                                rel_name_str.clone(),
+                                old.span,
+                            )
+                        })?;
+                    col.name = new.name;
+                }
+                AlterRelationOp::Retype(name, typing) => {
+                    if keys.iter().any(|k| k.name == name.name) {
+                        bail!(AlterKeyColumn(
+                            "retype",
+                            name.name.to_string(),
+                            rel_name_str,
+                            name.span
+                        ))
+                    }
+                    let (col, _) = non_keys
+                        .iter_mut()
+                        .find(|(c, _)| c.name == name.name)
+                        .ok_or_else(|| {
+                            AlterColumnNotFound(
+                                name.name.to_string(),
+                                rel_name_str.clone(),
+                                name.span,
+                            )
+                        })?;
+                    col.typing = typing;
+                }
+            }
+        }
+
+        // how the columns of the old tuple map onto the new one
+        let mut old_to_new: Vec<Option<usize>> = (0..n_keys).map(Some).collect_vec();
+        old_to_new.resize(rel_handle.arity(), None);
+        let mut retyped = BTreeSet::new();
+        for (i, (col, src)) in non_keys.iter().enumerate() {
+            if let Some(j) = src {
+                old_to_new[*j] = Some(i + n_keys);
+                if old_metadata.non_keys[*j - n_keys].typing != col.typing {
+                    retyped.insert(*j);
+                }
+            }
+        }
+        let needs_rewrite = non_keys.len() != old_metadata.non_keys.len()
+            || non_keys
+                .iter()
+                .enumerate()
+                .any(|(i, (_, src))| *src != Some(i + n_keys))
+            || !retyped.is_empty();
+        let mut renames: BTreeMap<SmartString<LazyCompact>, SmartString<LazyCompact>> =
+            BTreeMap::new();
+        for (old_col, new_pos) in old_metadata
+            .keys
+            .iter()
+            .chain(old_metadata.non_keys.iter())
+            .zip(old_to_new.iter())
+        {
+            if let Some(new_pos) = new_pos {
+                let new_name = if *new_pos < n_keys {
+                    &keys[*new_pos].name
+                } else {
+                    &non_keys[*new_pos - n_keys].0.name
+                };
+                if *new_name != old_col.name {
+                    renames.insert(old_col.name.clone(), new_name.clone());
+                }
+            }
+        }
+        let col_name_at = |i: usize| -> String {
+            if i < n_keys {
+                old_metadata.keys[i].name.to_string()
+            } else {
+                old_metadata.non_keys[i - n_keys].name.to_string()
+            }
+        };
+
+        rel_handle.metadata = StoredRelationMetadata {
+            keys,
+            non_keys: non_keys.iter().map(|(c, _)| c.clone()).collect_vec(),
+            foreign_keys: old_metadata.foreign_keys.clone(),
+        };
+        let binding_map = rel_handle.raw_binding_map();
+
+        // foreign keys refer to columns by name, on both sides
+        for fk in rel_handle.metadata.foreign_keys.iter_mut() {
+            if let Some(new_name) = renames.get(&fk.column) {
+                fk.column = new_name.clone();
+            }
+            if fk.ref_relation == rel_handle.name {
+                if let Some(new_name) = renames.get(&fk.ref_column) {
+                    fk.ref_column = new_name.clone();
+                }
+            }
+            if !binding_map.contains_key(&Symbol::new(fk.column.clone(), Default::default())) {
+                #[derive(Debug, Error, Diagnostic)]
+                #[error("column {0} of relation {1} has a foreign key")]
+                #[diagnostic(code(tx::alter_col_with_fk))]
+                struct ColumnWithForeignKey(String, String);
+
+                bail!(ColumnWithForeignKey(fk.column.to_string(), rel_name_str))
+            }
+        }
+        if !renames.is_empty() {
+            for referrer in rel_handle.referenced_by.iter() {
+                if *referrer == rel_handle.name {
+                    continue;
+                }
+                let mut referrer = self.get_relation(referrer, true)?;
+                for fk in referrer.metadata.foreign_keys.iter_mut() {
+                    if fk.ref_relation == rel_handle.name {
+                        if let Some(new_name) = renames.get(&fk.ref_column) {
+                            fk.ref_column = new_name.clone();
+                        }
+                    }
+                }
+                self.put_relation_handle(&referrer)?;
+            }
+        }
+
+        // check constraints refer to columns by name
+        for col in rel_handle
+            .metadata
+            .keys
+            .iter_mut()
+            .chain(rel_handle.metadata.non_keys.iter_mut())
+        {
+            if let Some(check) = &mut col.check {
+                rename_bindings(check, &renames);
+                for binding in check.bindings()? {
+                    if !binding_map.contains_key(&binding) {
+                        #[derive(Debug, Error, Diagnostic)]
+                        #[error("column {0} of relation {1} is used by the check constraint of column {2}")]
+                        #[diagnostic(code(tx::alter_col_used_by_check))]
+                        struct ColumnUsedByCheck(String, String, String);
+
+                        bail!(ColumnUsedByCheck(
+                            binding.name.to_string(),
+                            rel_name_str,
+                            col.name.to_string()
+                        ))
+                    }
+                }
+            }
+        }
+
+        // plain indices: remap positions and column names, rebuild if a used column is retyped
+        let mut indices_to_rebuild = vec![];
+        let mut new_indices = BTreeMap::new();
+        for (idx_name, (mut idx_handle, extractor)) in rel_handle.indices.clone() {
+            let mut new_extractor = Vec::with_capacity(extractor.len());
+            for i in extractor.iter() {
+                match old_to_new[*i] {
+                    None => bail!(ColumnUsedByIndex(
+                        col_name_at(*i),
+                        rel_name_str,
+                        idx_name.to_string()
+                    )),
+                    Some(j) => new_extractor.push(j),
+                }
+            }
+            if extractor.iter().any(|i| retyped.contains(i)) {
+                indices_to_rebuild.push((idx_name.clone(), idx_handle.clone(), extractor.clone()));
+            }
+            for col in idx_handle.metadata.keys.iter_mut() {
+                if let Some(new_name) = renames.get(&col.name) {
+                    col.name = new_name.clone();
+                }
+            }
+            for (col, i) in idx_handle
+                .metadata
+                .keys
+                .iter_mut()
+                .zip(new_extractor.iter())
+            {
+                if *i >= n_keys {
+                    col.typing = rel_handle.metadata.non_keys[*i - n_keys].typing.clone();
+                }
+            }
+            self.put_relation_handle(&idx_handle)?;
+            new_indices.insert(idx_name, (idx_handle, new_extractor));
+        }
+        rel_handle.indices = new_indices;
+
+        // the other kinds of indices refer to columns by position or by expressions
+        let check_code = |code: &str, idx_name: &str| -> Result<String> {
+            let new_code = rename_bindings_in_code(code, &renames)?;
+            let parsed = CozoScriptParser::parse(Rule::expr, &new_code)
+                .into_diagnostic()?
+                .next()
+                .unwrap();
+            let code_expr = build_expr(parsed, &Default::default())?;
+            for binding in code_expr.bindings()? {
+                match binding_map.get(&binding) {
+                    None => bail!(ColumnUsedByIndex(
+                        binding.name.to_string(),
+                        rel_name_str.clone(),
+                        idx_name.to_string()
+                    )),
+                    Some(i) => {
+                        if *i >= n_keys {
+                            if let Some(j) = non_keys[*i - n_keys].1 {
+                                if retyped.contains(&j) {
+                                    bail!(ColumnUsedByIndex(
+                                        binding.name.to_string(),
+                                        rel_name_str.clone(),
+                                        idx_name.to_string()
+                                    ))
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(new_code)
+        };
+        for (idx_name, (_, manifest)) in rel_handle.hnsw_indices.iter_mut() {
+            let mut new_fields = Vec::with_capacity(manifest.vec_fields.len());
+            for i in manifest.vec_fields.iter() {
+                match old_to_new[*i] {
+                    Some(j) if !retyped.contains(i) => new_fields.push(j),
+                    _ => bail!(ColumnUsedByIndex(
+                        col_name_at(*i),
+                        rel_name_str.clone(),
+                        idx_name.to_string()
+                    )),
+                }
+            }
+            manifest.vec_fields = new_fields;
+            if let Some(filter) = &manifest.index_filter {
+                manifest.index_filter = Some(check_code(filter, idx_name)?);
+            }
+        }
+        for (idx_name, (_, manifest)) in rel_handle.fts_indices.iter_mut() {
+            manifest.extractor = check_code(&manifest.extractor, idx_name)?;
+        }
+        for (idx_name, (_, _, manifest)) in rel_handle.lsh_indices.iter_mut() {
+            manifest.extractor = check_code(&manifest.extractor, idx_name)?;
+        }
+        for (idx_name, (_, manifest)) in rel_handle.rtree_indices.iter_mut() {
+            manifest.extractor = check_code(&manifest.extractor, idx_name)?;
+        }
+
+        if needs_rewrite {
+            let mut existing = TempCollector::default();
+            for tuple in rel_handle.scan_all(self) {
+                existing.push(tuple?);
+            }
+            for tuple in existing.into_iter() {
+                let mut new_tuple = tuple[..n_keys].to_vec();
+                for (col, src) in non_keys.iter() {
+                    let val = match src {
+                        Some(j) => tuple[*j].clone(),
+                        None => match &col.default_gen {
+                            Some(expr) => expr.clone().eval_to_const()?,
+                            None => DataValue::Null,
+                        },
+                    };
+                    let val = col
+                        .typing
+                        .coerce(val, cur_vld)
+                        .wrap_err_with(|| format!("when altering tuple {tuple:?}"))?;
+                    new_tuple.push(val);
+                }
+                for (_, idx_handle, extractor) in indices_to_rebuild.iter() {
+                    let idx_tup_old = extractor.iter().map(|i| tuple[*i].clone()).collect_vec();
+                    let encoded_old =
+                        idx_handle.encode_key_for_store(&idx_tup_old, Default::default())?;
+                    self.store_tx.del(&encoded_old)?;
+                    let idx_tup_new = extractor
+                        .iter()
+                        .map(|i| new_tuple[old_to_new[*i].unwrap()].clone())
+                        .collect_vec();
+                    let encoded_new =
+                        idx_handle.encode_key_for_store(&idx_tup_new, Default::default())?;
+                    self.store_tx.put(&encoded_new, &[])?;
+                }
+                let key = rel_handle.encode_key_for_store(&new_tuple, Default::default())?;
+                let val = rel_handle.encode_val_for_store(&new_tuple, Default::default())?;
+                self.store_tx.put(&key, &val)?;
+            }
+            // retyping can make distinct values equal, such as `1` and `1.0` as `Int`
+            for (idx_name, idx_handle, extractor) in indices_to_rebuild.iter() {
+                if let Some(n_unique_cols) = rel_handle.unique_indices.get(idx_name) {
+                    let extractor = extractor
+                        .iter()
+                        .map(|i| old_to_new[*i].unwrap())
+                        .collect_vec();
+                    self.ensure_index_unique(
+                        &rel_handle,
+                        idx_name,
+                        idx_handle,
+                        &extractor,
+                        *n_unique_cols,
+                    )?;
+                }
+            }
+        }
+
+        // the statistics are kept by column position
+        rel_handle.stats = None;
+        rel_handle.metadata.unbind_custom_functions();
+        self.put_relation_handle(&rel_handle)
+    }
+
+    pub(crate) fn put_relation_handle(&mut self, handle: &RelationHandle) -> Result<()> {
+        let encoded = vec![DataValue::from(&handle.name as &str)].encode_as_key(RelationId::SYSTEM);
+        let mut meta_val = vec![];
+        handle
+            .serialize(&mut Serializer::new(&mut meta_val))
+            .unwrap();
+        self.store_tx.put(&encoded, &meta_val)?;
+        Ok(())
+    }
+
     pub(crate) fn rename_relation(&mut self, old: Symbol, new: Symbol) -> Result<()> {
         if old.name.starts_with('_') || new.name.starts_with('_') {
             bail!("Bad name given");
@@ -1415,7 +2387,7 @@ impl<'a> SessionTx<'a> {
         let new_key = DataValue::Str(new.name.clone());
         let new_encoded = vec![new_key].encode_as_key(RelationId::SYSTEM);
 
-        if self.store_tx.exists(&new_encoded, true)? {
+        if self.store_tx.exists(&new_encoded, true)? || self.view_exists(&new.name)? {
             bail!(RelNameConflictError(new.name.to_string()))
         };
 
@@ -1430,6 +2402,47 @@ impl<'a> SessionTx<'a> {
                 rel.access_level
             ));
         }
+        if !rel.materialized_views.is_empty() {
+            bail!(ReadByMaterializedViews(
+                rel.name.to_string(),
+                rel.materialized_views
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect_vec()
+            ))
+        }
+        let dependents = self.dictionary_dependents(&rel)?;
+        if !dependents.is_empty() {
+            bail!(UsedAsDictionary(rel.name.to_string(), dependents))
+        }
+
+        // foreign keys refer to relations by name, on both sides
+        for fk in rel.metadata.foreign_keys.iter_mut() {
+            if fk.ref_relation == old.name {
+                fk.ref_relation = new.name.clone();
+            } else {
+                let mut target = self.get_relation(&fk.ref_relation, true)?;
+                target.referenced_by.remove(&old.name);
+                target.referenced_by.insert(new.name.clone());
+                self.put_relation_handle(&target)?;
+            }
+        }
+        if rel.referenced_by.remove(&old.name) {
+            rel.referenced_by.insert(new.name.clone());
+        }
+        for referrer in rel.referenced_by.iter() {
+            if *referrer == new.name {
+                continue;
+            }
+            let mut referrer = self.get_relation(referrer, true)?;
+            for fk in referrer.metadata.foreign_keys.iter_mut() {
+                if fk.ref_relation == old.name {
+                    fk.ref_relation = new.name.clone();
+                }
+            }
+            self.put_relation_handle(&referrer)?;
+        }
+
         rel.name = new.name;
 
         let mut meta_val = vec![];
@@ -1462,6 +2475,69 @@ impl<'a> SessionTx<'a> {
     }
 }
 
+fn check_foreign_key_target(target: &RelationHandle, fk: &ForeignKey) -> Result<()> {
+    #[derive(Debug, Error, Diagnostic)]
+    #[error("foreign key {0} must reference the only key column of {1}, found {2}")]
+    #[diagnostic(code(tx::bad_fk_target))]
+    struct BadForeignKeyTarget(String, String, String);
+
+    if target.is_temp
+        || target.name.contains(':')
+        || target.metadata.keys.len() != 1
+        || target.metadata.keys[0].name != fk.ref_column
+    {
+        bail!(BadForeignKeyTarget(
+            fk.column.to_string(),
+            target.name.to_string(),
+            fk.ref_column.to_string()
+        ))
+    }
+    Ok(())
+}
+
+fn rename_bindings_in_code(
+    code: &str,
+    renames: &BTreeMap<SmartString<LazyCompact>, SmartString<LazyCompact>>,
+) -> Result<String> {
+    if renames.is_empty() {
+        return Ok(code.to_string());
+    }
+    let parsed = CozoScriptParser::parse(Rule::expr, code)
+        .into_diagnostic()?
+        .next()
+        .unwrap();
+    let mut code_expr = build_expr(parsed, &Default::default())?;
+    rename_bindings(&mut code_expr, renames);
+    Ok(code_expr.to_string())
+}
+
+fn rename_bindings(
+    expr: &mut Expr,
+    renames: &BTreeMap<SmartString<LazyCompact>, SmartString<LazyCompact>>,
+) {
+    match expr {
+        Expr::Binding { var, .. } => {
+            if let Some(new_name) = renames.get(&var.name) {
+                var.name = new_name.clone();
+            }
+        }
+        Expr::Const { .. } | Expr::Param { .. } => {}
+        Expr::Apply { args, .. }
+        | Expr::UnboundApply { args, .. }
+        | Expr::CustomApply { args, .. } => {
+            for arg in args.iter_mut() {
+                rename_bindings(arg, renames);
+            }
+        }
+        Expr::Cond { clauses, .. } => {
+            for (cond, val) in clauses {
+                rename_bindings(cond, renames);
+                rename_bindings(val, renames);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Error, Diagnostic)]
 #[error("Insufficient access level {2} for {1} on stored relation '{0}'")]
 #[diagnostic(code(tx::insufficient_access_level))]
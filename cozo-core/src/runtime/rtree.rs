@@ -0,0 +1,585 @@
+/*
+ * Copyright 2023, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! R-tree indices over the bounding boxes of geometries. Each node of the tree is a row of
+//! the index relation, holding its level (zero for leaves) and its entries. An entry is the
+//! bounding box of a child node, or at the leaves, of the geometry of a row of the base
+//! relation together with the keys of that row. The root is always stored under id zero.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::mem;
+
+use itertools::Itertools;
+use miette::{miette, Result};
+use rand::{thread_rng, Rng};
+use smartstring::{LazyCompact, SmartString};
+
+use crate::data::expr::{eval_bytecode, eval_bytecode_pred, Bytecode};
+use crate::data::functions::val2geometry;
+use crate::data::geo::{BBox, Geometry};
+use crate::data::tuple::Tuple;
+use crate::runtime::relation::RelationHandle;
+use crate::runtime::transact::SessionTx;
+use crate::{DataValue, Expr, SourceSpan, Symbol};
+
+const ROOT_ID: i64 = 0;
+
+#[derive(Debug, Clone, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct RtreeIndexManifest {
+    pub(crate) base_relation: SmartString<LazyCompact>,
+    pub(crate) index_name: SmartString<LazyCompact>,
+    pub(crate) extractor: String,
+    pub(crate) max_entries: usize,
+}
+
+impl RtreeIndexManifest {
+    fn min_entries(&self) -> usize {
+        (self.max_entries * 2 / 5).max(1)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct RtreeSearch {
+    pub(crate) base_handle: RelationHandle,
+    pub(crate) idx_handle: RelationHandle,
+    pub(crate) manifest: RtreeIndexManifest,
+    pub(crate) bindings: Vec<Symbol>,
+    /// Only geometries intersecting this bounding box are returned
+    pub(crate) bbox: Option<Symbol>,
+    /// Geometries are returned nearest to this geometry first
+    pub(crate) query: Option<Symbol>,
+    pub(crate) k: Option<usize>,
+    pub(crate) radius: Option<f64>,
+    pub(crate) bind_distance: Option<Symbol>,
+    pub(crate) filter: Option<Expr>,
+    pub(crate) span: SourceSpan,
+}
+
+impl RtreeSearch {
+    pub(crate) fn all_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.bindings.iter().chain(self.bind_distance.iter())
+    }
+    /// The bindings that must be bound before the search
+    pub(crate) fn input_bindings(&self) -> impl Iterator<Item = &Symbol> {
+        self.bbox.iter().chain(self.query.iter())
+    }
+}
+
+struct RtreeEntry {
+    bbox: BBox,
+    /// The id of the child node, or at the leaves, the list of keys of the indexed row
+    child: DataValue,
+}
+
+struct RtreeNode {
+    level: i64,
+    entries: Vec<RtreeEntry>,
+}
+
+impl RtreeNode {
+    fn bbox(&self) -> BBox {
+        self.entries
+            .iter()
+            .map(|e| e.bbox)
+            .reduce(|a, b| a.union(&b))
+            .unwrap()
+    }
+    fn from_tuple(tuple: Tuple) -> Self {
+        let level = tuple[1].get_int().unwrap();
+        let entries = match &tuple[2] {
+            DataValue::List(l) => l
+                .iter()
+                .map(|entry| match entry {
+                    DataValue::List(fields) => RtreeEntry {
+                        bbox: BBox {
+                            min_x: fields[0].get_float().unwrap(),
+                            min_y: fields[1].get_float().unwrap(),
+                            max_x: fields[2].get_float().unwrap(),
+                            max_y: fields[3].get_float().unwrap(),
+                        },
+                        child: fields[4].clone(),
+                    },
+                    _ => unreachable!(),
+                })
+                .collect_vec(),
+            _ => unreachable!(),
+        };
+        Self { level, entries }
+    }
+    fn to_tuple(&self, id: i64) -> Tuple {
+        let entries = self
+            .entries
+            .iter()
+            .map(|e| {
+                let mut fields = e
+                    .bbox
+                    .to_vec()
+                    .into_iter()
+                    .map(DataValue::from)
+                    .collect_vec();
+                fields.push(e.child.clone());
+                DataValue::List(fields)
+            })
+            .collect_vec();
+        vec![
+            DataValue::from(id),
+            DataValue::from(self.level),
+            DataValue::List(entries),
+        ]
+    }
+}
+
+fn extract_geometry(
+    extractor: &[Bytecode],
+    tuple: &[DataValue],
+    stack: &mut Vec<DataValue>,
+) -> Result<Option<Geometry>> {
+    match eval_bytecode(extractor, tuple, stack)? {
+        DataValue::Null => Ok(None),
+        v => Ok(Some(val2geometry(&v)?)),
+    }
+}
+
+/// Compares the growth of the first box needed to include the second, by area first, then
+/// by margin, so that boxes of points on a line are also told apart
+fn enlargement(bbox: &BBox, added: &BBox) -> (f64, f64) {
+    let union = bbox.union(added);
+    (union.area() - bbox.area(), union.margin() - bbox.margin())
+}
+
+fn cmp_cost(a: (f64, f64), b: (f64, f64)) -> Ordering {
+    a.0.total_cmp(&b.0).then_with(|| a.1.total_cmp(&b.1))
+}
+
+fn choose_subtree(node: &RtreeNode, bbox: &BBox) -> usize {
+    node.entries
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            cmp_cost(enlargement(&a.bbox, bbox), enlargement(&b.bbox, bbox))
+                .then_with(|| a.bbox.area().total_cmp(&b.bbox.area()))
+        })
+        .unwrap()
+        .0
+}
+
+/// Quadratic split: starts the two groups with the pair of entries that would waste the most
+/// space together, then adds the entry with the strongest preference for a group, until
+/// one group must take all the remaining entries to get `min_entries` of them.
+fn split_entries(
+    mut entries: Vec<RtreeEntry>,
+    min_entries: usize,
+) -> (Vec<RtreeEntry>, Vec<RtreeEntry>) {
+    let mut seeds = (0, 1);
+    let mut worst = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for i in 0..entries.len() {
+        for j in i + 1..entries.len() {
+            let (a, b) = (&entries[i].bbox, &entries[j].bbox);
+            let union = a.union(b);
+            let waste = (
+                union.area() - a.area() - b.area(),
+                union.margin() - a.margin() - b.margin(),
+            );
+            if cmp_cost(waste, worst) == Ordering::Greater {
+                worst = waste;
+                seeds = (i, j);
+            }
+        }
+    }
+    let second = entries.swap_remove(seeds.1);
+    let first = entries.swap_remove(seeds.0);
+    let mut bbox_a = first.bbox;
+    let mut bbox_b = second.bbox;
+    let mut group_a = vec![first];
+    let mut group_b = vec![second];
+    while !entries.is_empty() {
+        if group_a.len() + entries.len() <= min_entries {
+            group_a.append(&mut entries);
+            break;
+        }
+        if group_b.len() + entries.len() <= min_entries {
+            group_b.append(&mut entries);
+            break;
+        }
+        let (idx, to_a) = entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| {
+                let cost_a = enlargement(&bbox_a, &e.bbox);
+                let cost_b = enlargement(&bbox_b, &e.bbox);
+                let preference = ((cost_a.0 - cost_b.0).abs(), (cost_a.1 - cost_b.1).abs());
+                let to_a = match cmp_cost(cost_a, cost_b) {
+                    Ordering::Less => true,
+                    Ordering::Greater => false,
+                    Ordering::Equal => group_a.len() <= group_b.len(),
+                };
+                (i, to_a, preference)
+            })
+            .max_by(|a, b| cmp_cost(a.2, b.2))
+            .map(|(i, to_a, _)| (i, to_a))
+            .unwrap();
+        let entry = entries.swap_remove(idx);
+        if to_a {
+            bbox_a = bbox_a.union(&entry.bbox);
+            group_a.push(entry);
+        } else {
+            bbox_b = bbox_b.union(&entry.bbox);
+            group_b.push(entry);
+        }
+    }
+    (group_a, group_b)
+}
+
+enum Candidate {
+    Node(i64),
+    Item(Vec<DataValue>),
+    Found(Tuple),
+}
+
+/// A candidate in the queue of the nearest neighbour search, ordered so that the nearest one,
+/// and among equally near ones the first queued, is at the top
+struct Queued {
+    distance: f64,
+    seq: usize,
+    candidate: Candidate,
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance
+            .total_cmp(&self.distance)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl<'a> SessionTx<'a> {
+    fn rtree_load(&self, idx_handle: &RelationHandle, id: i64) -> Result<RtreeNode> {
+        Ok(match idx_handle.get(self, &[DataValue::from(id)])? {
+            Some(tuple) => RtreeNode::from_tuple(tuple),
+            // only the root of an empty tree is missing
+            None => RtreeNode {
+                level: 0,
+                entries: vec![],
+            },
+        })
+    }
+    fn rtree_save(&mut self, idx_handle: &RelationHandle, id: i64, node: &RtreeNode) -> Result<()> {
+        let tuple = node.to_tuple(id);
+        let key = idx_handle.encode_key_for_store(&tuple, Default::default())?;
+        let val = idx_handle.encode_val_for_store(&tuple, Default::default())?;
+        self.store_tx.put(&key, &val)
+    }
+    fn rtree_remove_node(&mut self, idx_handle: &RelationHandle, id: i64) -> Result<()> {
+        let key = idx_handle.encode_key_for_store(&[DataValue::from(id)], Default::default())?;
+        self.store_tx.del(&key)
+    }
+    fn rtree_new_id(&self, idx_handle: &RelationHandle) -> Result<i64> {
+        loop {
+            let id: i64 = thread_rng().gen();
+            if id != ROOT_ID && !idx_handle.exists(self, &[DataValue::from(id)])? {
+                return Ok(id);
+            }
+        }
+    }
+    pub(crate) fn put_rtree_index_item(
+        &mut self,
+        tuple: &[DataValue],
+        extractor: &[Bytecode],
+        stack: &mut Vec<DataValue>,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+        manifest: &RtreeIndexManifest,
+    ) -> Result<()> {
+        let geometry = match extract_geometry(extractor, tuple, stack)? {
+            None => return Ok(()),
+            Some(g) => g,
+        };
+        let entry = RtreeEntry {
+            bbox: geometry.bbox(),
+            child: DataValue::List(tuple[..rel_handle.metadata.keys.len()].to_vec()),
+        };
+        self.rtree_insert(idx_handle, manifest, entry)
+    }
+    pub(crate) fn del_rtree_index_item(
+        &mut self,
+        tuple: &[DataValue],
+        extractor: &[Bytecode],
+        stack: &mut Vec<DataValue>,
+        rel_handle: &RelationHandle,
+        idx_handle: &RelationHandle,
+    ) -> Result<()> {
+        let geometry = match extract_geometry(extractor, tuple, stack)? {
+            None => return Ok(()),
+            Some(g) => g,
+        };
+        let key = DataValue::List(tuple[..rel_handle.metadata.keys.len()].to_vec());
+        self.rtree_remove(idx_handle, &geometry.bbox(), &key)
+    }
+    fn rtree_insert(
+        &mut self,
+        idx_handle: &RelationHandle,
+        manifest: &RtreeIndexManifest,
+        entry: RtreeEntry,
+    ) -> Result<()> {
+        // the nodes passed on the way down, with the positions of the entries followed
+        let mut path = vec![];
+        let mut id = ROOT_ID;
+        let mut node = self.rtree_load(idx_handle, id)?;
+        while node.level > 0 {
+            let i = choose_subtree(&node, &entry.bbox);
+            let child_id = node.entries[i].child.get_int().unwrap();
+            path.push((id, node, i));
+            id = child_id;
+            node = self.rtree_load(idx_handle, id)?;
+        }
+        node.entries.push(entry);
+        loop {
+            let mut split_off = None;
+            if node.entries.len() > manifest.max_entries {
+                let (group_a, group_b) =
+                    split_entries(mem::take(&mut node.entries), manifest.min_entries());
+                let sibling = RtreeNode {
+                    level: node.level,
+                    entries: group_b,
+                };
+                let sibling_id = self.rtree_new_id(idx_handle)?;
+                self.rtree_save(idx_handle, sibling_id, &sibling)?;
+                if id == ROOT_ID {
+                    // the root keeps its id, so its entries move down to a new node
+                    let moved = RtreeNode {
+                        level: node.level,
+                        entries: group_a,
+                    };
+                    let moved_id = self.rtree_new_id(idx_handle)?;
+                    self.rtree_save(idx_handle, moved_id, &moved)?;
+                    let root = RtreeNode {
+                        level: node.level + 1,
+                        entries: vec![
+                            RtreeEntry {
+                                bbox: moved.bbox(),
+                                child: DataValue::from(moved_id),
+                            },
+                            RtreeEntry {
+                                bbox: sibling.bbox(),
+                                child: DataValue::from(sibling_id),
+                            },
+                        ],
+                    };
+                    return self.rtree_save(idx_handle, ROOT_ID, &root);
+                }
+                node.entries = group_a;
+                split_off = Some(RtreeEntry {
+                    bbox: sibling.bbox(),
+                    child: DataValue::from(sibling_id),
+                });
+            }
+            self.rtree_save(idx_handle, id, &node)?;
+            match path.pop() {
+                None => return Ok(()),
+                Some((parent_id, mut parent, i)) => {
+                    parent.entries[i].bbox = node.bbox();
+                    parent.entries.extend(split_off);
+                    id = parent_id;
+                    node = parent;
+                }
+            }
+        }
+    }
+    /// Finds the leaf entry for the item, recording the ids of the nodes from the root and the
+    /// positions of the entries followed in them
+    fn rtree_find(
+        &self,
+        idx_handle: &RelationHandle,
+        id: i64,
+        node: &RtreeNode,
+        bbox: &BBox,
+        key: &DataValue,
+        path: &mut Vec<(i64, usize)>,
+    ) -> Result<bool> {
+        for (i, entry) in node.entries.iter().enumerate() {
+            if !entry.bbox.contains(bbox) {
+                continue;
+            }
+            path.push((id, i));
+            if node.level == 0 {
+                if entry.child == *key {
+                    return Ok(true);
+                }
+            } else {
+                let child_id = entry.child.get_int().unwrap();
+                let child = self.rtree_load(idx_handle, child_id)?;
+                if self.rtree_find(idx_handle, child_id, &child, bbox, key, path)? {
+                    return Ok(true);
+                }
+            }
+            path.pop();
+        }
+        Ok(false)
+    }
+    /// Removes the item. Nodes left with few entries are kept as they are instead of having
+    /// their entries inserted again, and nodes left empty are removed.
+    fn rtree_remove(
+        &mut self,
+        idx_handle: &RelationHandle,
+        bbox: &BBox,
+        key: &DataValue,
+    ) -> Result<()> {
+        let root = self.rtree_load(idx_handle, ROOT_ID)?;
+        let mut path = vec![];
+        if !self.rtree_find(idx_handle, ROOT_ID, &root, bbox, key, &mut path)? {
+            return Ok(());
+        }
+        let (mut id, i) = path.pop().unwrap();
+        let mut node = self.rtree_load(idx_handle, id)?;
+        node.entries.remove(i);
+        while let Some((parent_id, i)) = path.pop() {
+            let mut parent = self.rtree_load(idx_handle, parent_id)?;
+            if node.entries.is_empty() {
+                self.rtree_remove_node(idx_handle, id)?;
+                parent.entries.remove(i);
+            } else {
+                self.rtree_save(idx_handle, id, &node)?;
+                parent.entries[i].bbox = node.bbox();
+            }
+            id = parent_id;
+            node = parent;
+        }
+        // a root with a single child is replaced by that child
+        while node.level > 0 && node.entries.len() == 1 {
+            let child_id = node.entries[0].child.get_int().unwrap();
+            node = self.rtree_load(idx_handle, child_id)?;
+            self.rtree_remove_node(idx_handle, child_id)?;
+        }
+        if node.entries.is_empty() {
+            node.level = 0;
+        }
+        self.rtree_save(idx_handle, ROOT_ID, &node)
+    }
+    /// Returns the rows whose geometries intersect `bbox`, if given, nearest to `query` first
+    /// if given. The distance is appended to the rows if it is to be bound.
+    pub(crate) fn rtree_search(
+        &self,
+        bbox: Option<BBox>,
+        query: Option<&Geometry>,
+        config: &RtreeSearch,
+        extractor: &[Bytecode],
+        stack: &mut Vec<DataValue>,
+        filter_code: &Option<(Vec<Bytecode>, SourceSpan)>,
+    ) -> Result<Vec<Tuple>> {
+        let bbox_geometry = bbox.map(|b| b.to_geometry());
+        let query_bbox = query.map(|q| q.bbox());
+        let mut ret = vec![];
+        let mut seq = 0;
+        let mut queue = BinaryHeap::new();
+        queue.push(Queued {
+            distance: 0.,
+            seq,
+            candidate: Candidate::Node(ROOT_ID),
+        });
+        while let Some(Queued {
+            distance,
+            candidate,
+            ..
+        }) = queue.pop()
+        {
+            if let Some(r) = config.radius {
+                if distance > r {
+                    break;
+                }
+            }
+            match candidate {
+                Candidate::Found(tuple) => {
+                    ret.push(tuple);
+                    if let Some(k) = config.k {
+                        if ret.len() >= k {
+                            break;
+                        }
+                    }
+                }
+                Candidate::Node(id) => {
+                    let node = self.rtree_load(&config.idx_handle, id)?;
+                    for entry in node.entries {
+                        if let Some(b) = &bbox {
+                            if !entry.bbox.intersects(b) {
+                                continue;
+                            }
+                        }
+                        let distance = match &query_bbox {
+                            Some(q) => q.distance(&entry.bbox),
+                            None => 0.,
+                        };
+                        let candidate = if node.level == 0 {
+                            match entry.child {
+                                DataValue::List(key) => Candidate::Item(key),
+                                _ => unreachable!(),
+                            }
+                        } else {
+                            Candidate::Node(entry.child.get_int().unwrap())
+                        };
+                        seq += 1;
+                        queue.push(Queued {
+                            distance,
+                            seq,
+                            candidate,
+                        });
+                    }
+                }
+                Candidate::Item(key) => {
+                    let mut tuple = config
+                        .base_handle
+                        .get(self, &key)?
+                        .ok_or_else(|| miette!("Tuple not found in base R-tree relation"))?;
+                    let geometry = match extract_geometry(extractor, &tuple, stack)? {
+                        None => continue,
+                        Some(g) => g,
+                    };
+                    if let Some(b) = &bbox_geometry {
+                        if !geometry.intersects(b) {
+                            continue;
+                        }
+                    }
+                    let distance = match query {
+                        Some(q) => geometry.distance(q),
+                        None => 0.,
+                    };
+                    if config.bind_distance.is_some() {
+                        tuple.push(DataValue::from(distance));
+                    }
+                    if let Some((filter_code, span)) = filter_code {
+                        if !eval_bytecode_pred(filter_code, &tuple, stack, *span)? {
+                            continue;
+                        }
+                    }
+                    seq += 1;
+                    queue.push(Queued {
+                        distance,
+                        seq,
+                        candidate: Candidate::Found(tuple),
+                    });
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,213 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Sorted runs of temp store entries written to disk when a store grows past its memory budget.
+//! Runs are written in blocks, and the first key of each block is kept in memory so that
+//! point lookups and range scans only read the blocks they need.
+
+use std::borrow::{Borrow, Cow};
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::Mutex;
+
+use either::{Left, Right};
+use itertools::Itertools;
+use miette::{miette, IntoDiagnostic, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::data::tuple::Tuple;
+use crate::data::value::{DataValue, Vector};
+
+/// Number of entries in each block of a run
+const BLOCK_LEN: usize = 256;
+
+/// Estimated number of bytes the tuple occupies in memory when stored in a temp store
+pub(crate) fn tuple_size(tuple: &[DataValue]) -> usize {
+    // the vector itself and its share of the tree node
+    4 * mem::size_of::<usize>() + tuple.iter().map(value_size).sum::<usize>()
+}
+
+fn value_size(val: &DataValue) -> usize {
+    mem::size_of::<DataValue>()
+        + match val {
+            DataValue::Str(s) => s.len(),
+            DataValue::Bytes(b) => b.len(),
+            DataValue::List(l) => l.iter().map(value_size).sum(),
+            DataValue::Set(s) => s.iter().map(value_size).sum(),
+            DataValue::Vec(Vector::F32(v)) => v.len() * mem::size_of::<f32>(),
+            DataValue::Vec(Vector::F64(v)) => v.len() * mem::size_of::<f64>(),
+            _ => 0,
+        }
+}
+
+/// Entries sorted by key, stored in an anonymous temporary file that is deleted when dropped
+/// ([crate::utils::TempCollector] is not used, as it can only be read back sequentially once)
+pub(crate) struct SpilledRun<V> {
+    file: Mutex<File>,
+    /// The first key, the offset and the length in bytes of each block
+    blocks: Vec<(Tuple, u64, usize)>,
+    /// Number of entries
+    len: usize,
+    _value: PhantomData<V>,
+}
+
+impl<V> Debug for SpilledRun<V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SpilledRun<{} blocks>", self.blocks.len())
+    }
+}
+
+impl<V: Serialize + DeserializeOwned> SpilledRun<V> {
+    /// Writes the entries, which must be sorted by key without duplicates
+    pub(crate) fn write<K: Borrow<Tuple>, W: Borrow<V>>(
+        entries: impl Iterator<Item = Result<(K, W)>>,
+    ) -> Result<Self> {
+        let file = tempfile::tempfile().into_diagnostic()?;
+        let mut blocks = vec![];
+        let mut len = 0;
+        {
+            let mut writer = BufWriter::new(&file);
+            let mut offset = 0;
+            for chunk in &entries.chunks(BLOCK_LEN) {
+                let block: Vec<_> = chunk.try_collect()?;
+                let refs = block
+                    .iter()
+                    .map(|(k, v)| (k.borrow(), v.borrow()))
+                    .collect_vec();
+                let bytes = rmp_serde::to_vec(&refs).into_diagnostic()?;
+                writer.write_all(&bytes).into_diagnostic()?;
+                blocks.push((refs[0].0.clone(), offset, bytes.len()));
+                len += refs.len();
+                offset += bytes.len() as u64;
+            }
+            writer.flush().into_diagnostic()?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            blocks,
+            len,
+            _value: PhantomData,
+        })
+    }
+    fn read_block(&self, idx: usize) -> Result<Vec<(Tuple, V)>> {
+        let (_, offset, len) = &self.blocks[idx];
+        let mut buf = vec![0; *len];
+        {
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|_| miette!("spilled run is poisoned"))?;
+            file.seek(SeekFrom::Start(*offset)).into_diagnostic()?;
+            file.read_exact(&mut buf).into_diagnostic()?;
+        }
+        rmp_serde::from_slice(&buf).into_diagnostic()
+    }
+    /// The entries of the block, or the error reading it
+    fn block_entries(&self, idx: usize) -> impl Iterator<Item = Result<(Tuple, V)>> {
+        match self.read_block(idx) {
+            Ok(block) => Left(block.into_iter().map(Ok)),
+            Err(err) => Right(iter::once(Err(err))),
+        }
+    }
+    pub(crate) fn num_entries(&self) -> usize {
+        self.len
+    }
+    pub(crate) fn get(&self, key: &[DataValue]) -> Result<Option<V>> {
+        let idx = self
+            .blocks
+            .partition_point(|(first, _, _)| first.as_slice() <= key);
+        if idx == 0 {
+            return Ok(None);
+        }
+        let mut block = self.read_block(idx - 1)?;
+        Ok(block
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|found| block.swap_remove(found).1))
+    }
+    pub(crate) fn range(
+        &self,
+        lower: Tuple,
+        upper: Tuple,
+        upper_inclusive: bool,
+    ) -> impl Iterator<Item = Result<(Tuple, V)>> + '_ {
+        let start = self
+            .blocks
+            .partition_point(|(first, _, _)| *first < lower)
+            .saturating_sub(1);
+        let block_upper = upper.clone();
+        (start..self.blocks.len())
+            .take_while(move |idx| {
+                let first = &self.blocks[*idx].0;
+                *first < block_upper || (upper_inclusive && *first == block_upper)
+            })
+            .flat_map(move |idx| self.block_entries(idx))
+            .skip_while(move |entry| matches!(entry, Ok((k, _)) if *k < lower))
+            .take_while(move |entry| match entry {
+                Ok((k, _)) => *k < upper || (upper_inclusive && *k == upper),
+                Err(_) => true,
+            })
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Result<(Tuple, V)>> + '_ {
+        (0..self.blocks.len()).flat_map(move |idx| self.block_entries(idx))
+    }
+}
+
+type MergedEntry<'a, V> = Result<(Cow<'a, Tuple>, Cow<'a, V>)>;
+
+/// Merges iterators over entries sorted by key. For entries with the same key,
+/// the one from the iterator that comes first wins, and the others are dropped.
+/// Iteration stops after the first error.
+pub(crate) struct MergedRuns<'a, V: Clone> {
+    iters: Vec<Box<dyn Iterator<Item = MergedEntry<'a, V>> + 'a>>,
+    heads: Vec<Option<MergedEntry<'a, V>>>,
+}
+
+impl<'a, V: Clone + 'a> MergedRuns<'a, V> {
+    pub(crate) fn new(mut iters: Vec<Box<dyn Iterator<Item = MergedEntry<'a, V>> + 'a>>) -> Self {
+        let heads = iters.iter_mut().map(|it| it.next()).collect();
+        Self { iters, heads }
+    }
+    /// Advances the iterator at `idx` past the entries with the given key
+    fn skip_key(&mut self, idx: usize, key: &Tuple) {
+        while matches!(&self.heads[idx], Some(Ok((k, _))) if **k == *key) {
+            self.heads[idx] = self.iters[idx].next();
+        }
+    }
+}
+
+impl<'a, V: Clone + 'a> Iterator for MergedRuns<'a, V> {
+    type Item = MergedEntry<'a, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(err_idx) = self.heads.iter().position(|h| matches!(h, Some(Err(_)))) {
+            let err = self.heads[err_idx].take();
+            self.heads.clear();
+            return err;
+        }
+        let idx = (0..self.heads.len())
+            .filter_map(|i| match &self.heads[i] {
+                Some(Ok((k, _))) => Some((i, k)),
+                _ => None,
+            })
+            .min_by(|(_, a), (_, b)| a.cmp(b))?
+            .0;
+        let ret = mem::replace(&mut self.heads[idx], self.iters[idx].next())?;
+        if let Ok((key, _)) = &ret {
+            for i in 0..self.heads.len() {
+                self.skip_key(i, key);
+            }
+        }
+        Some(ret)
+    }
+}
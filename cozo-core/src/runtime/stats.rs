@@ -0,0 +1,110 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Statistics of stored relations collected by `::analyze`, and the cost model
+//! used for ordering joins with them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+
+use miette::{bail, Result};
+
+use crate::data::value::DataValue;
+use crate::runtime::relation::{AccessLevel, InsufficientAccessLevel, RelationHandle};
+use crate::runtime::transact::SessionTx;
+
+/// Number of hashes kept for estimating the number of distinct values of a column
+const SKETCH_SIZE: usize = 1024;
+
+/// The cost of a single lookup into a stored relation, in units of rows scanned
+pub(crate) const SEEK_COST: f64 = 8.;
+
+#[derive(Clone, Debug, Eq, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct RelationStats {
+    /// Number of rows when the relation was analyzed
+    pub(crate) rows: u64,
+    /// Estimated number of distinct values of each column, keys first
+    pub(crate) distinct: Vec<u64>,
+}
+
+impl RelationStats {
+    /// Expected number of rows matching a single combination of values
+    /// for the columns marked as bound
+    pub(crate) fn rows_per_probe(&self, bound: impl Iterator<Item = bool>) -> f64 {
+        let mut ret = self.rows as f64;
+        for (distinct, is_bound) in self.distinct.iter().zip(bound) {
+            if is_bound {
+                ret /= (*distinct).max(1) as f64;
+            }
+        }
+        ret.max(1.)
+    }
+}
+
+/// K-minimum-values sketch: the number of distinct values is estimated from the
+/// spread of the smallest hashes seen
+#[derive(Default)]
+struct DistinctSketch {
+    smallest: BTreeSet<u64>,
+}
+
+impl DistinctSketch {
+    fn insert(&mut self, val: &DataValue) {
+        let mut hasher = DefaultHasher::new();
+        val.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.smallest.len() < SKETCH_SIZE {
+            self.smallest.insert(hash);
+        } else if hash < *self.smallest.last().unwrap() && self.smallest.insert(hash) {
+            self.smallest.pop_last();
+        }
+    }
+    fn estimate(&self) -> u64 {
+        if self.smallest.len() < SKETCH_SIZE {
+            return self.smallest.len() as u64;
+        }
+        let kth = *self.smallest.last().unwrap() as f64 / u64::MAX as f64;
+        ((SKETCH_SIZE - 1) as f64 / kth) as u64
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    /// Scan the relation and store its statistics in its metadata
+    pub(crate) fn analyze_relation(&mut self, name: &str) -> Result<RelationHandle> {
+        let mut handle = self.get_relation(name, true)?;
+        if handle.is_temp {
+            bail!("Cannot analyze temp relation '{}'", name);
+        }
+        if handle.access_level < AccessLevel::ReadOnly {
+            bail!(InsufficientAccessLevel(
+                handle.name.to_string(),
+                "analyzing".to_string(),
+                handle.access_level
+            ));
+        }
+        let mut sketches = (0..handle.arity())
+            .map(|_| DistinctSketch::default())
+            .collect::<Vec<_>>();
+        let mut rows = 0;
+        for tuple in handle.scan_all(self) {
+            let tuple = tuple?;
+            rows += 1;
+            for (sketch, val) in sketches.iter_mut().zip(tuple.iter()) {
+                sketch.insert(val);
+            }
+        }
+        handle.stats = Some(RelationStats {
+            rows,
+            distinct: sketches.iter().map(|s| s.estimate()).collect(),
+        });
+
+        self.put_relation_handle(&handle)?;
+        Ok(handle)
+    }
+}
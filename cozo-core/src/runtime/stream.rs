@@ -0,0 +1,188 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Running scripts with the results sent in chunks as they are produced.
+
+use std::collections::{BTreeMap, HashSet};
+use std::mem;
+
+use crossbeam::channel::Sender;
+use itertools::Itertools;
+use miette::Result;
+
+use crate::data::functions::current_validity;
+use crate::data::program::{InputProgram, MagicSymbol};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::value::{DataValue, ValidityTs};
+use crate::parse::{CozoScript, SourceSpan};
+use crate::query::compile::{AggrKind, CompiledRuleSet};
+use crate::runtime::db::CompiledQuery;
+use crate::{Db, NamedRows, Storage};
+
+impl<'s, S: Storage<'s>> Db<S> {
+    /// Run the CozoScript passed in, sending the results to `results` in chunks of
+    /// at most `chunk_size` rows. Every chunk carries the headers, and at least one
+    /// chunk is sent. An error is sent in place of a chunk and ends the results.
+    /// Dropping the receiving end stops the evaluation.
+    ///
+    /// A single query that neither sorts, asserts nor mutates has its rows sent as they are
+    /// produced by the entry rule, without collecting them first. The order of the rows
+    /// then differs from what [`Db::run_script`] returns. Other scripts are run to
+    /// completion before their results are sent.
+    pub fn run_script_streaming(
+        &'s self,
+        payload: &str,
+        params: BTreeMap<String, DataValue>,
+        chunk_size: usize,
+        results: Sender<Result<NamedRows>>,
+    ) {
+        let cur_vld = current_validity();
+        let chunk_size = chunk_size.max(1);
+        let res = self
+            .parse_payload(payload, &params, None, cur_vld)
+            .and_then(|parsed| match parsed {
+                CozoScript::Single(p)
+                    if p.out_opts.sorters.is_empty()
+                        && p.out_opts.assertion.is_none()
+                        && p.out_opts.store_relation.is_none()
+                        && p.out_opts.rule_mutations.is_empty() =>
+                {
+                    self.stream_single(*p, cur_vld, chunk_size, &results)
+                }
+                parsed => {
+                    let res = self.execute_script(parsed, cur_vld)?;
+                    send_in_chunks(res, chunk_size, &results);
+                    Ok(())
+                }
+            });
+        if let Err(err) = res {
+            let _ = results.send(Err(err));
+        }
+    }
+
+    fn stream_single(
+        &'s self,
+        mut p: InputProgram,
+        cur_vld: ValidityTs,
+        chunk_size: usize,
+        results: &Sender<Result<NamedRows>>,
+    ) -> Result<()> {
+        let mut tx = self.transact()?;
+        self.inline_views(&tx, &mut p, cur_vld)?;
+        let mut query = self.compile_query(&mut tx, p)?;
+        let entry_symbol = MagicSymbol::Muggle {
+            inner: Symbol::new(PROG_ENTRY, SourceSpan(0, 0)),
+        };
+
+        // the entry rule can be iterated directly only if it is evaluated in a single pass
+        let streamable = match query.compiled.last().and_then(|p| p.get(&entry_symbol)) {
+            Some(rule_set @ CompiledRuleSet::Rules(rules)) => {
+                matches!(rule_set.aggr_kind(), AggrKind::None)
+                    && rules
+                        .iter()
+                        .all(|r| !r.contained_rules.contains_key(&entry_symbol))
+            }
+            _ => false,
+        };
+        if !streamable {
+            let (res, _) = self.run_compiled_query(
+                &mut tx,
+                &query,
+                cur_vld,
+                &Default::default(),
+                &mut Default::default(),
+                true,
+            )?;
+            tx.commit_tx()?;
+            send_in_chunks(res, chunk_size, results);
+            return Ok(());
+        }
+        let rules = match query.compiled.last_mut().unwrap().remove(&entry_symbol) {
+            Some(CompiledRuleSet::Rules(rules)) => rules,
+            _ => unreachable!(),
+        };
+        let CompiledQuery {
+            entry_head_or_default,
+            out_opts,
+            store_lifetimes,
+            compiled,
+            ..
+        } = query;
+        let headers = entry_head_or_default
+            .iter()
+            .map(|s| s.to_string())
+            .collect_vec();
+
+        let (poison, usage, _guard) = self.register_running_query(&out_opts, &tx.usage)?;
+        tx.poison = poison.clone();
+        tx.usage = usage;
+        let (stores, _) =
+            tx.evaluate_strata(&compiled, store_lifetimes, None, None, poison.clone())?;
+
+        // rows not known to be distinct are deduplicated as the store would have done
+        let is_distinct = rules.len() == 1 && rules[0].relation.yields_distinct_rows();
+        #[allow(clippy::mutable_key_type)]
+        let mut seen = HashSet::new();
+        let mut held = tx.usage.holder();
+        let mut num_to_skip = out_opts.offset.unwrap_or(0);
+        let mut num_to_take = out_opts.limit.unwrap_or(usize::MAX);
+        let mut chunk = vec![];
+        let mut sent = false;
+        let iters = rules
+            .iter()
+            .map(|rule| rule.relation.iter(&tx, None, &stores))
+            .collect::<Result<Vec<_>>>()?;
+        for row in iters.into_iter().flatten() {
+            if num_to_take == 0 {
+                break;
+            }
+            let row = row?;
+            poison.check()?;
+            if !is_distinct {
+                if !seen.insert(row.clone()) {
+                    continue;
+                }
+                held.hold(&row)?;
+            }
+            if num_to_skip > 0 {
+                num_to_skip -= 1;
+                continue;
+            }
+            num_to_take -= 1;
+            chunk.push(row);
+            if chunk.len() == chunk_size {
+                let rows = mem::take(&mut chunk);
+                if results
+                    .send(Ok(NamedRows::new(headers.clone(), rows)))
+                    .is_err()
+                {
+                    return Ok(());
+                }
+                sent = true;
+            }
+        }
+        if !chunk.is_empty() || !sent {
+            let _ = results.send(Ok(NamedRows::new(headers, chunk)));
+        }
+        Ok(())
+    }
+}
+
+fn send_in_chunks(res: NamedRows, chunk_size: usize, results: &Sender<Result<NamedRows>>) {
+    let NamedRows { headers, rows, .. } = res;
+    if rows.is_empty() {
+        let _ = results.send(Ok(NamedRows::new(headers, rows)));
+        return;
+    }
+    for chunk in &rows.into_iter().chunks(chunk_size) {
+        let chunk = NamedRows::new(headers.clone(), chunk.collect_vec());
+        if results.send(Ok(chunk)).is_err() {
+            break;
+        }
+    }
+}
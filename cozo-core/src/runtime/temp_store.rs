@@ -6,6 +6,7 @@
  * You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
@@ -20,23 +21,48 @@ use miette::Result;
 use crate::data::aggr::Aggregation;
 use crate::data::tuple::Tuple;
 use crate::data::value::DataValue;
+use crate::runtime::db::QueryUsage;
+use crate::runtime::spill::{tuple_size, MergedRuns, SpilledRun};
+
+/// Number of runs a store may spill before they are merged into one
+const MAX_SPILLED_RUNS: usize = 8;
 
 /// A store holding temp data during evaluation of queries.
 /// The public interface is used in custom implementations of algorithms/utilities.
 #[derive(Default, Debug)]
 pub struct RegularTempStore {
     inner: BTreeMap<Tuple, bool>,
+    /// Runs written to disk, oldest first. Entries in memory and in newer runs
+    /// take precedence over entries with the same key in older runs.
+    spilled: Vec<SpilledRun<bool>>,
+    mem_size: usize,
+    /// Bytes the store may hold in memory before spilling to disk
+    memory_budget: Option<usize>,
 }
 
 const EMPTY_TUPLE_REF: &Tuple = &vec![];
 
 impl RegularTempStore {
+    pub(crate) fn with_budget(memory_budget: Option<usize>) -> Self {
+        Self {
+            memory_budget,
+            ..Default::default()
+        }
+    }
     pub(crate) fn wrap(self) -> TempStore {
         TempStore::Normal(self)
     }
     /// Tests if a key already exists in the store.
-    pub fn exists(&self, key: &Tuple) -> bool {
-        self.inner.contains_key(key)
+    pub fn exists(&self, key: &Tuple) -> Result<bool> {
+        if self.inner.contains_key(key) {
+            return Ok(true);
+        }
+        for run in &self.spilled {
+            if run.get(key)?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
     fn range_iter(
@@ -44,47 +70,159 @@ impl RegularTempStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         let lower_bound = Included(lower.to_vec());
         let upper_bound = if upper_inclusive {
             Included(upper.to_vec())
         } else {
             Excluded(upper.to_vec())
         };
-        self.inner
-            .range((lower_bound, upper_bound))
-            .map(|(t, skip)| TupleInIter(t, EMPTY_TUPLE_REF, *skip))
+        let in_mem = self.inner.range((lower_bound, upper_bound));
+        if self.spilled.is_empty() {
+            return Left(in_mem.map(|(t, skip)| {
+                Ok(TupleInIter(
+                    Cow::Borrowed(t),
+                    Cow::Borrowed(EMPTY_TUPLE_REF),
+                    *skip,
+                ))
+            }));
+        }
+        let mut iters: Vec<Box<dyn Iterator<Item = _>>> = vec![Box::new(
+            in_mem.map(|(k, v)| Ok((Cow::Borrowed(k), Cow::Borrowed(v)))),
+        )];
+        for run in self.spilled.iter().rev() {
+            iters.push(Box::new(
+                run.range(lower.to_vec(), upper.to_vec(), upper_inclusive)
+                    .map_ok(|(k, v)| (Cow::Owned(k), Cow::Owned(v))),
+            ));
+        }
+        Right(
+            MergedRuns::new(iters)
+                .map_ok(|(t, skip)| TupleInIter(t, Cow::Borrowed(EMPTY_TUPLE_REF), *skip)),
+        )
     }
     /// Add a tuple to the store
     pub fn put(&mut self, tuple: Tuple) {
-        self.inner.insert(tuple, false);
+        self.insert(tuple, false);
+    }
+    /// Add a tuple to the store, charging the query for the rows and memory added.
+    /// The store is spilled to disk if it grows past its memory budget.
+    pub(crate) fn put_charged(
+        &mut self,
+        tuple: Tuple,
+        skip: bool,
+        usage: &QueryUsage,
+    ) -> Result<()> {
+        let (rows, bytes) = self.size();
+        self.insert(tuple, skip);
+        let (new_rows, new_bytes) = self.size();
+        usage.charge(new_rows - rows, new_bytes - bytes)?;
+        usage.release(0, self.spill_over_budget()?);
+        Ok(())
     }
-    pub(crate) fn put_with_skip(&mut self, tuple: Tuple) {
-        self.inner.insert(tuple, true);
+    fn insert(&mut self, tuple: Tuple, skip: bool) {
+        match self.inner.entry(tuple) {
+            Entry::Vacant(ent) => {
+                self.mem_size += tuple_size(ent.key());
+                ent.insert(skip);
+            }
+            Entry::Occupied(mut ent) => {
+                ent.insert(skip);
+            }
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty() && self.spilled.is_empty()
+    }
+    /// The number of rows, and the number of bytes held in memory
+    pub(crate) fn size(&self) -> (usize, usize) {
+        let spilled_rows: usize = self.spilled.iter().map(|run| run.num_entries()).sum();
+        (self.inner.len() + spilled_rows, self.mem_size)
+    }
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.spilled.clear();
+        self.mem_size = 0;
+    }
+    /// Moves the entries held in memory to a new run on disk
+    fn spill(&mut self) -> Result<()> {
+        if self.inner.is_empty() {
+            return Ok(());
+        }
+        self.spilled
+            .push(SpilledRun::write(self.inner.iter().map(Ok))?);
+        self.inner.clear();
+        self.mem_size = 0;
+        if self.spilled.len() > MAX_SPILLED_RUNS {
+            let merged = MergedRuns::new(
+                self.spilled
+                    .iter()
+                    .rev()
+                    .map(|run| -> Box<dyn Iterator<Item = _>> {
+                        Box::new(run.iter().map_ok(|(k, v)| (Cow::Owned(k), Cow::Owned(v))))
+                    })
+                    .collect(),
+            );
+            self.spilled = vec![SpilledRun::write(merged)?];
+        }
+        Ok(())
+    }
+    /// Spills the store if it holds more than its memory budget,
+    /// returning the number of bytes moved out of memory
+    fn spill_over_budget(&mut self) -> Result<usize> {
+        match self.memory_budget {
+            Some(budget) if self.mem_size > budget => {
+                let bytes = self.mem_size;
+                self.spill()?;
+                Ok(bytes)
+            }
+            _ => Ok(0),
+        }
     }
     // returns true if prev is guaranteed to be the same as self after this function call,
     // false if we are not sure.
-    pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> bool {
-        prev.inner.clear();
-        if new.inner.is_empty() {
-            return false;
+    pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> Result<bool> {
+        prev.clear();
+        if new.is_empty() {
+            return Ok(false);
         }
-        if self.inner.is_empty() {
+        if self.is_empty() {
             mem::swap(&mut new, self);
-            return true;
+            return Ok(true);
+        }
+        if new.spilled.is_empty() {
+            for (k, v) in mem::take(&mut new.inner) {
+                self.merge_entry(prev, k, v)?;
+            }
+        } else {
+            for entry in new.range_iter(&vec![], &vec![DataValue::Bot], true) {
+                let TupleInIter(k, _, v) = entry?;
+                self.merge_entry(prev, k.into_owned(), v)?;
+            }
         }
-        for (k, v) in new.inner {
+        Ok(false)
+    }
+    fn merge_entry(&mut self, prev: &mut Self, k: Tuple, v: bool) -> Result<()> {
+        if self.spilled.is_empty() {
             match self.inner.entry(k) {
                 Entry::Vacant(ent) => {
-                    prev.inner.insert(ent.key().clone(), v);
+                    self.mem_size += tuple_size(ent.key());
+                    prev.insert(ent.key().clone(), v);
                     ent.insert(v);
                 }
                 Entry::Occupied(mut ent) => {
                     ent.insert(v);
                 }
             }
+        } else {
+            if !self.exists(&k)? {
+                prev.insert(k.clone(), v);
+            }
+            self.insert(k, v);
         }
-        false
+        self.spill_over_budget()?;
+        prev.spill_over_budget()?;
+        Ok(())
     }
 }
 
@@ -93,20 +231,82 @@ pub(crate) struct MeetAggrStore {
     inner: BTreeMap<Tuple, Tuple>,
     aggregations: Vec<(Aggregation, Vec<DataValue>)>,
     grouping_len: usize,
+    /// Runs written to disk, oldest first, as for [RegularTempStore]
+    spilled: Vec<SpilledRun<Tuple>>,
+    mem_size: usize,
+    /// Bytes the store may hold in memory before spilling to disk
+    memory_budget: Option<usize>,
 }
 
 impl MeetAggrStore {
     pub(crate) fn wrap(self) -> TempStore {
         TempStore::MeetAggr(self)
     }
-    pub(crate) fn exists(&self, key: &Tuple) -> bool {
+    pub(crate) fn exists(&self, key: &Tuple) -> Result<bool> {
         let truncated = &key[0..self.grouping_len];
-        self.inner.contains_key(truncated)
+        Ok(self.inner.contains_key(truncated) || self.get_spilled(truncated)?.is_some())
     }
     pub(crate) fn is_empty(&self) -> bool {
-        self.inner.is_empty()
+        self.inner.is_empty() && self.spilled.is_empty()
+    }
+    /// The number of rows, and the number of bytes held in memory
+    fn size(&self) -> (usize, usize) {
+        let spilled_rows: usize = self.spilled.iter().map(|run| run.num_entries()).sum();
+        (self.inner.len() + spilled_rows, self.mem_size)
+    }
+    /// The latest aggregated values for the group among the spilled runs
+    fn get_spilled(&self, key: &[DataValue]) -> Result<Option<Tuple>> {
+        for run in self.spilled.iter().rev() {
+            if let Some(found) = run.get(key)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+    fn clear(&mut self) {
+        self.inner.clear();
+        self.spilled.clear();
+        self.mem_size = 0;
+    }
+    /// Moves the entries held in memory to a new run on disk
+    fn spill(&mut self) -> Result<()> {
+        if self.inner.is_empty() {
+            return Ok(());
+        }
+        self.spilled
+            .push(SpilledRun::write(self.inner.iter().map(Ok))?);
+        self.inner.clear();
+        self.mem_size = 0;
+        if self.spilled.len() > MAX_SPILLED_RUNS {
+            let merged = MergedRuns::new(
+                self.spilled
+                    .iter()
+                    .rev()
+                    .map(|run| -> Box<dyn Iterator<Item = _>> {
+                        Box::new(run.iter().map_ok(|(k, v)| (Cow::Owned(k), Cow::Owned(v))))
+                    })
+                    .collect(),
+            );
+            self.spilled = vec![SpilledRun::write(merged)?];
+        }
+        Ok(())
     }
-    pub(crate) fn new(aggrs: Vec<Option<(Aggregation, Vec<DataValue>)>>) -> Result<Self> {
+    /// Spills the store if it holds more than its memory budget,
+    /// returning the number of bytes moved out of memory
+    fn spill_over_budget(&mut self) -> Result<usize> {
+        match self.memory_budget {
+            Some(budget) if self.mem_size > budget => {
+                let bytes = self.mem_size;
+                self.spill()?;
+                Ok(bytes)
+            }
+            _ => Ok(0),
+        }
+    }
+    pub(crate) fn new(
+        aggrs: Vec<Option<(Aggregation, Vec<DataValue>)>>,
+        memory_budget: Option<usize>,
+    ) -> Result<Self> {
         let total_key_len = aggrs.len();
         let mut aggregations = aggrs.into_iter().flatten().collect_vec();
         for (aggr, args) in aggregations.iter_mut() {
@@ -117,12 +317,29 @@ impl MeetAggrStore {
             inner: Default::default(),
             aggregations,
             grouping_len,
+            spilled: vec![],
+            mem_size: 0,
+            memory_budget,
         })
     }
     // also need to check if value exists beforehand! use the idempotency!
     // need to think this through more carefully.
-    pub(crate) fn meet_put(&mut self, tuple: Tuple) -> Result<bool> {
+    pub(crate) fn meet_put(&mut self, tuple: Tuple, usage: &QueryUsage) -> Result<bool> {
+        let (rows, bytes) = self.size();
+        let changed = self.meet_put_inner(tuple)?;
+        let (new_rows, new_bytes) = self.size();
+        usage.charge(new_rows - rows, new_bytes - bytes)?;
+        usage.release(0, self.spill_over_budget()?);
+        Ok(changed)
+    }
+    fn meet_put_inner(&mut self, tuple: Tuple) -> Result<bool> {
         let (key_part, val_part) = tuple.split_at(self.grouping_len);
+        if !self.inner.contains_key(key_part) {
+            if let Some(spilled) = self.get_spilled(key_part)? {
+                self.mem_size += tuple_size(key_part) + tuple_size(&spilled);
+                self.inner.insert(key_part.to_vec(), spilled);
+            }
+        }
         match self.inner.get_mut(key_part) {
             Some(prev_aggr) => {
                 let mut changed = false;
@@ -133,6 +350,7 @@ impl MeetAggrStore {
                 Ok(changed)
             }
             None => {
+                self.mem_size += tuple_size(key_part) + tuple_size(val_part);
                 self.inner.insert(key_part.to_vec(), val_part.to_vec());
                 Ok(true)
             }
@@ -143,7 +361,7 @@ impl MeetAggrStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         let lower_key = if lower.len() > self.grouping_len {
             lower[0..self.grouping_len].to_vec()
         } else {
@@ -156,60 +374,96 @@ impl MeetAggrStore {
         };
         let lower = lower.to_vec();
         let upper = upper.to_vec();
-        self.inner
-            .range(lower_key..=upper_key)
-            .filter_map(move |(k, v)| {
-                let ret = TupleInIter(k, v, false);
-                if ret.partial_cmp(&lower as &[DataValue]) == Some(Ordering::Less) {
-                    None
-                } else {
-                    match ret.partial_cmp(&upper as &[DataValue]).unwrap() {
-                        Ordering::Less => Some(ret),
-                        Ordering::Equal => {
-                            if upper_inclusive {
-                                Some(ret)
-                            } else {
-                                None
-                            }
+        let in_mem = self.inner.range(lower_key.clone()..=upper_key.clone());
+        let entries = if self.spilled.is_empty() {
+            Left(in_mem.map(|(k, v)| Ok((Cow::Borrowed(k), Cow::Borrowed(v)))))
+        } else {
+            let mut iters: Vec<Box<dyn Iterator<Item = _>>> = vec![Box::new(
+                in_mem.map(|(k, v)| Ok((Cow::Borrowed(k), Cow::Borrowed(v)))),
+            )];
+            for run in self.spilled.iter().rev() {
+                iters.push(Box::new(
+                    run.range(lower_key.clone(), upper_key.clone(), true)
+                        .map_ok(|(k, v)| (Cow::Owned(k), Cow::Owned(v))),
+                ));
+            }
+            Right(MergedRuns::new(iters))
+        };
+        entries.filter_map_ok(move |(k, v)| {
+            let ret = TupleInIter(k, v, false);
+            if ret.partial_cmp(&lower as &[DataValue]) == Some(Ordering::Less) {
+                None
+            } else {
+                match ret.partial_cmp(&upper as &[DataValue]).unwrap() {
+                    Ordering::Less => Some(ret),
+                    Ordering::Equal => {
+                        if upper_inclusive {
+                            Some(ret)
+                        } else {
+                            None
                         }
-                        Ordering::Greater => None,
                     }
+                    Ordering::Greater => None,
                 }
-            })
+            }
+        })
     }
     /// returns true if prev is guaranteed to be the same as self after this function call,
     /// false if we are not sure.
     pub(crate) fn merge_in(&mut self, prev: &mut Self, mut new: Self) -> Result<bool> {
-        prev.inner.clear();
-        if new.inner.is_empty() {
+        prev.clear();
+        if new.is_empty() {
             return Ok(false);
         }
-        if self.inner.is_empty() {
+        if self.is_empty() {
             mem::swap(self, &mut new);
             return Ok(true);
         }
-        for (k, v) in new.inner {
-            match self.inner.entry(k) {
-                Entry::Vacant(ent) => {
-                    prev.inner.insert(ent.key().clone(), v.clone());
-                    ent.insert(v);
-                }
-                Entry::Occupied(mut ent) => {
-                    let mut changed = false;
-                    {
-                        let target = ent.get_mut();
-                        for (i, (aggr_op, _)) in self.aggregations.iter().enumerate() {
-                            let op = aggr_op.meet_op.as_ref().unwrap();
-                            changed |= op.update(&mut target[i], &v[i])?;
-                        }
-                    }
-                    if changed {
-                        prev.inner.insert(ent.key().clone(), ent.get().clone());
+        if new.spilled.is_empty() {
+            for (k, v) in mem::take(&mut new.inner) {
+                self.merge_entry(prev, k, v)?;
+            }
+        } else {
+            for entry in new.range_iter(&vec![], &vec![DataValue::Bot], true) {
+                let TupleInIter(k, v, _) = entry?;
+                self.merge_entry(prev, k.into_owned(), v.into_owned())?;
+            }
+        }
+        Ok(false)
+    }
+    fn merge_entry(&mut self, prev: &mut Self, k: Tuple, v: Tuple) -> Result<()> {
+        if !self.spilled.is_empty() && !self.inner.contains_key(&k) {
+            if let Some(spilled) = self.get_spilled(&k)? {
+                self.mem_size += tuple_size(&k) + tuple_size(&spilled);
+                self.inner.insert(k.clone(), spilled);
+            }
+        }
+        match self.inner.entry(k) {
+            Entry::Vacant(ent) => {
+                let size = tuple_size(ent.key()) + tuple_size(&v);
+                self.mem_size += size;
+                prev.mem_size += size;
+                prev.inner.insert(ent.key().clone(), v.clone());
+                ent.insert(v);
+            }
+            Entry::Occupied(mut ent) => {
+                let mut changed = false;
+                {
+                    let target = ent.get_mut();
+                    for (i, (aggr_op, _)) in self.aggregations.iter().enumerate() {
+                        let op = aggr_op.meet_op.as_ref().unwrap();
+                        changed |= op.update(&mut target[i], &v[i])?;
                     }
                 }
+                if changed {
+                    prev.mem_size += tuple_size(ent.key()) + tuple_size(ent.get());
+                    prev.inner.insert(ent.key().clone(), ent.get().clone());
+                }
             }
         }
-        Ok(false)
+        self.spill_over_budget()?;
+        prev.spill_over_budget()?;
+        Ok(())
     }
 }
 
@@ -220,7 +474,7 @@ pub(crate) enum TempStore {
 }
 
 impl TempStore {
-    fn exists(&self, key: &Tuple) -> bool {
+    fn exists(&self, key: &Tuple) -> Result<bool> {
         match self {
             TempStore::Normal(n) => n.exists(key),
             TempStore::MeetAggr(m) => m.exists(key),
@@ -231,7 +485,7 @@ impl TempStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         match self {
             TempStore::Normal(n) => Left(n.range_iter(lower, upper, upper_inclusive)),
             TempStore::MeetAggr(m) => Right(m.range_iter(lower, upper, upper_inclusive)),
@@ -239,8 +493,27 @@ impl TempStore {
     }
     fn is_empty(&self) -> bool {
         match self {
-            TempStore::Normal(n) => n.inner.is_empty(),
-            TempStore::MeetAggr(m) => m.inner.is_empty(),
+            TempStore::Normal(n) => n.is_empty(),
+            TempStore::MeetAggr(m) => m.is_empty(),
+        }
+    }
+    fn mem_size(&self) -> usize {
+        match self {
+            TempStore::Normal(n) => n.mem_size,
+            TempStore::MeetAggr(m) => m.mem_size,
+        }
+    }
+    /// The number of rows, and the number of bytes held in memory
+    pub(crate) fn size(&self) -> (usize, usize) {
+        match self {
+            TempStore::Normal(n) => n.size(),
+            TempStore::MeetAggr(m) => m.size(),
+        }
+    }
+    fn spill(&mut self) -> Result<()> {
+        match self {
+            TempStore::Normal(n) => n.spill(),
+            TempStore::MeetAggr(m) => m.spill(),
         }
     }
 }
@@ -251,39 +524,64 @@ pub(crate) struct EpochStore {
     delta: TempStore,
     use_total_for_delta: bool,
     pub(crate) arity: usize,
+    /// Bytes the store may hold in memory before spilling to disk
+    memory_budget: Option<usize>,
 }
 
 impl EpochStore {
-    pub(crate) fn exists(&self, key: &Tuple) -> bool {
+    pub(crate) fn exists(&self, key: &Tuple) -> Result<bool> {
         self.total.exists(key)
     }
-    pub(crate) fn new_normal(arity: usize) -> Self {
+    pub(crate) fn new_normal(arity: usize, memory_budget: Option<usize>) -> Self {
         Self {
-            total: TempStore::Normal(RegularTempStore::default()),
-            delta: TempStore::Normal(RegularTempStore::default()),
+            total: TempStore::Normal(RegularTempStore::with_budget(memory_budget)),
+            delta: TempStore::Normal(RegularTempStore::with_budget(memory_budget)),
             use_total_for_delta: true,
             arity,
+            memory_budget,
         }
     }
-    pub(crate) fn new_meet(aggrs: &[Option<(Aggregation, Vec<DataValue>)>]) -> Result<Self> {
+    pub(crate) fn new_meet(
+        aggrs: &[Option<(Aggregation, Vec<DataValue>)>],
+        memory_budget: Option<usize>,
+    ) -> Result<Self> {
         Ok(Self {
-            total: TempStore::MeetAggr(MeetAggrStore::new(aggrs.to_vec())?),
-            delta: TempStore::MeetAggr(MeetAggrStore::new(aggrs.to_vec())?),
+            total: TempStore::MeetAggr(MeetAggrStore::new(aggrs.to_vec(), memory_budget)?),
+            delta: TempStore::MeetAggr(MeetAggrStore::new(aggrs.to_vec(), memory_budget)?),
             use_total_for_delta: true,
             arity: aggrs.len(),
+            memory_budget,
         })
     }
-    pub(crate) fn merge_in(&mut self, new: TempStore) -> Result<()> {
+    /// The number of rows, and the number of bytes held in memory
+    pub(crate) fn size(&self) -> (usize, usize) {
+        let (total_rows, total_bytes) = self.total.size();
+        let (delta_rows, delta_bytes) = self.delta.size();
+        (total_rows + delta_rows, total_bytes + delta_bytes)
+    }
+    /// Merges the results of an epoch. The query is expected to have been charged
+    /// for the size of `new`, and is charged for the growth of the store instead.
+    pub(crate) fn merge_in(&mut self, new: TempStore, usage: &QueryUsage) -> Result<()> {
+        let (old_rows, old_bytes) = self.size();
+        let (new_rows, new_bytes) = new.size();
         match (&mut self.total, &mut self.delta, new) {
             (TempStore::Normal(total), TempStore::Normal(prev), TempStore::Normal(new)) => {
-                self.use_total_for_delta = total.merge_in(prev, new);
+                self.use_total_for_delta = total.merge_in(prev, new)?;
             }
             (TempStore::MeetAggr(total), TempStore::MeetAggr(prev), TempStore::MeetAggr(new)) => {
                 self.use_total_for_delta = total.merge_in(prev, new)?;
             }
             _ => unreachable!(),
         }
-        Ok(())
+        if let Some(budget) = self.memory_budget {
+            if self.total.mem_size() + self.delta.mem_size() > budget {
+                self.total.spill()?;
+                self.delta.spill()?;
+            }
+        }
+        usage.release(old_rows + new_rows, old_bytes + new_bytes);
+        let (rows, bytes) = self.size();
+        usage.charge(rows, bytes)
     }
     pub(crate) fn has_delta(&self) -> bool {
         if self.use_total_for_delta {
@@ -297,7 +595,7 @@ impl EpochStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         self.total.range_iter(lower, upper, upper_inclusive)
     }
     pub(crate) fn delta_range_iter(
@@ -305,14 +603,17 @@ impl EpochStore {
         lower: &Tuple,
         upper: &Tuple,
         upper_inclusive: bool,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         if self.use_total_for_delta {
             self.total.range_iter(lower, upper, upper_inclusive)
         } else {
             self.delta.range_iter(lower, upper, upper_inclusive)
         }
     }
-    pub(crate) fn prefix_iter(&self, prefix: &Tuple) -> impl Iterator<Item = TupleInIter<'_>> {
+    pub(crate) fn prefix_iter(
+        &self,
+        prefix: &Tuple,
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         let mut upper = prefix.to_vec();
         upper.push(DataValue::Bot);
         self.range_iter(prefix, &upper, true)
@@ -320,27 +621,28 @@ impl EpochStore {
     pub(crate) fn delta_prefix_iter(
         &self,
         prefix: &Tuple,
-    ) -> impl Iterator<Item = TupleInIter<'_>> {
+    ) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         let mut upper = prefix.to_vec();
         upper.push(DataValue::Bot);
         self.delta_range_iter(prefix, &upper, true)
     }
-    pub(crate) fn all_iter(&self) -> impl Iterator<Item = TupleInIter<'_>> {
+    pub(crate) fn all_iter(&self) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         self.prefix_iter(&vec![])
     }
-    pub(crate) fn delta_all_iter(&self) -> impl Iterator<Item = TupleInIter<'_>> {
+    pub(crate) fn delta_all_iter(&self) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
         self.delta_prefix_iter(&vec![])
     }
-    pub(crate) fn early_returned_iter(&self) -> impl Iterator<Item = TupleInIter<'_>> {
-        self.all_iter().filter(|t| !t.should_skip())
+    pub(crate) fn early_returned_iter(&self) -> impl Iterator<Item = Result<TupleInIter<'_>>> {
+        self.all_iter()
+            .filter(|t| !matches!(t, Ok(t) if t.should_skip()))
     }
 }
 
-#[derive(Copy, Clone)]
-pub(crate) struct TupleInIter<'a>(&'a Tuple, &'a Tuple, bool);
+#[derive(Clone)]
+pub(crate) struct TupleInIter<'a>(Cow<'a, Tuple>, Cow<'a, Tuple>, bool);
 
 impl<'a> TupleInIter<'a> {
-    pub(crate) fn get(self, idx: usize) -> &'a DataValue {
+    pub(crate) fn get(&self, idx: usize) -> &DataValue {
         self.0
             .get(idx)
             .unwrap_or_else(|| self.1.get(idx - self.0.len()).unwrap())
@@ -348,31 +650,21 @@ impl<'a> TupleInIter<'a> {
     fn should_skip(&self) -> bool {
         self.2
     }
-    pub(crate) fn into_tuple(self) -> Tuple {
-        self.into_iter().cloned().collect_vec()
+    fn iter(&self) -> impl Iterator<Item = &DataValue> {
+        self.0.iter().chain(self.1.iter())
     }
-}
-
-impl<'a> IntoIterator for TupleInIter<'a> {
-    type Item = &'a DataValue;
-    type IntoIter = TupleInIterIterator<'a>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        TupleInIterIterator {
-            inner: self,
-            idx: 0,
+    pub(crate) fn into_tuple(self) -> Tuple {
+        if self.1.is_empty() {
+            self.0.into_owned()
+        } else {
+            self.iter().cloned().collect_vec()
         }
     }
 }
 
-pub(crate) struct TupleInIterIterator<'a> {
-    inner: TupleInIter<'a>,
-    idx: usize,
-}
-
 impl PartialEq for TupleInIter<'_> {
     fn eq(&self, other: &Self) -> bool {
-        self.into_iter().eq(other.into_iter())
+        self.iter().eq(other.iter())
     }
 }
 
@@ -380,7 +672,7 @@ impl Eq for TupleInIter<'_> {}
 
 impl Ord for TupleInIter<'_> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.into_iter().cmp(other.into_iter())
+        self.iter().cmp(other.iter())
     }
 }
 
@@ -392,28 +684,12 @@ impl PartialOrd for TupleInIter<'_> {
 
 impl PartialEq<[DataValue]> for TupleInIter<'_> {
     fn eq(&self, other: &'_ [DataValue]) -> bool {
-        self.into_iter().eq(other.iter())
+        self.iter().eq(other.iter())
     }
 }
 
 impl PartialOrd<[DataValue]> for TupleInIter<'_> {
     fn partial_cmp(&self, other: &'_ [DataValue]) -> Option<Ordering> {
-        self.into_iter().partial_cmp(other.iter())
-    }
-}
-
-impl<'a> Iterator for TupleInIterIterator<'a> {
-    type Item = &'a DataValue;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let ret = match self.inner.0.get(self.idx) {
-            Some(d) => d,
-            None => match self.inner.1.get(self.idx - self.inner.0.len()) {
-                None => return None,
-                Some(d) => d,
-            },
-        };
-        self.idx += 1;
-        Some(ret)
+        self.iter().partial_cmp(other.iter())
     }
 }
@@ -8,10 +8,12 @@
  */
 
 use std::collections::BTreeMap;
+use std::iter;
 use std::time::Duration;
 
 use itertools::Itertools;
 use log::debug;
+use miette::{miette, Result};
 use serde_json::json;
 use smartstring::{LazyCompact, SmartString};
 
@@ -22,8 +24,11 @@ use crate::fixed_rule::FixedRulePayload;
 use crate::fts::{TokenizerCache, TokenizerConfig};
 use crate::parse::SourceSpan;
 use crate::runtime::callback::CallbackOp;
-use crate::runtime::db::Poison;
-use crate::{new_cozo_mem, DbInstance, FixedRule, RegularTempStore};
+use crate::runtime::db::{Poison, QueryUsage};
+use crate::{
+    new_cozo_mem, CustomAggregation, DbInstance, FixedRule, MeetAggrObj, NamedRows, NormalAggrObj,
+    QueryLimits, RegularTempStore,
+};
 
 #[test]
 fn test_limit_offset() {
@@ -613,69 +618,1755 @@ fn test_index() {
         .unwrap();
 }
 
+#[test]
+fn test_check_constraints() {
+    let db = new_cozo_mem().unwrap();
+    assert!(db
+        .run_script(
+            ":create items {id: Int => price: Float check price > no_such_col}",
+            Default::default(),
+        )
+        .is_err());
+    assert!(db
+        .run_script(
+            r"?[id, price, start, end] <- [[1, -1.0, 0, 1]]
+              :create items {id: Int => price: Float check price > 0, start: Int, end: Int check end >= start}",
+            Default::default(),
+        )
+        .is_err());
+    db.run_script(
+        r"?[id, price, start, end] <- [[1, 1.0, 0, 1]]
+          :create items {id: Int => price: Float check price > 0, start: Int, end: Int check end >= start}",
+        Default::default(),
+    )
+    .unwrap();
+
+    let err = db
+        .run_script(
+            r"?[id, price, start, end] <- [[2, 1.0, 5, 1]] :put items {id => price, start, end}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(err.root_cause().to_string().contains("column end"));
+    assert!(db
+        .run_script(
+            r"?[id, price] <- [[1, 0.0]] :update items {id => price}",
+            Default::default(),
+        )
+        .is_err());
+    db.run_script(
+        r"?[id, end] <- [[1, 10]] :update items {id => end}",
+        Default::default(),
+    )
+    .unwrap();
+
+    // constraints follow renamed columns
+    assert!(db
+        .run_script("::alter items {drop start}", Default::default())
+        .is_err());
+    db.run_script("::alter items {rename start -> begin}", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script(
+            r"?[id, price, begin, end] <- [[3, 1.0, 5, 1]] :put items {id => price, begin, end}",
+            Default::default(),
+        )
+        .is_err());
+    let res = db
+        .run_script(
+            "?[id, begin, end] := *items{id, begin, end}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, 0, 10]]));
+
+    // imported rows are checked too
+    let headers = vec![
+        "id".to_string(),
+        "price".to_string(),
+        "begin".to_string(),
+        "end".to_string(),
+    ];
+    let bad_row = vec![
+        DataValue::from(4),
+        DataValue::from(1.0),
+        DataValue::from(5),
+        DataValue::from(1),
+    ];
+    assert!(db
+        .import_relations(BTreeMap::from([(
+            "items".to_string(),
+            NamedRows::new(headers, vec![bad_row])
+        )]))
+        .is_err());
+}
+
+#[test]
+fn test_foreign_keys() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(
+        ":create node {id: Int => label: String}",
+        Default::default(),
+    )
+    .unwrap();
+    assert!(db
+        .run_script(
+            ":create bad {id: Int => n: Int references node.label}",
+            Default::default(),
+        )
+        .is_err());
+    assert!(db
+        .run_script(
+            ":create bad {id: Int => n: Int references node.id on delete set null}",
+            Default::default(),
+        )
+        .is_err());
+    db.run_script(
+        r"
+        {:create edge {src: Int references node.id on delete cascade, dst: Int references node.id}}
+        {:create tag {id: Int => node: Int? references node.id on delete set null}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[id, label] <- [[1, 'a'], [2, 'b'], [3, 'c']] :put node {id => label}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[src, dst] <- [[1, 2], [2, 3], [3, 1]] :put edge {src, dst}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[id, node] <- [[10, 1], [20, 2]] :put tag {id => node}",
+        Default::default(),
+    )
+    .unwrap();
+
+    let err = db
+        .run_script(
+            r"?[src, dst] <- [[1, 4]] :put edge {src, dst}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(err.root_cause().to_string().contains("edge"));
+    assert!(db
+        .run_script(
+            r"?[id, node] <- [[10, 4]] :update tag {id => node}",
+            Default::default()
+        )
+        .is_err());
+
+    // removing node 1 cascades on edge.src, but edge 3 -> 1 restricts via edge.dst
+    assert!(db
+        .run_script(r"?[id] <- [[1]] :rm node {id}", Default::default())
+        .is_err());
+    db.run_script(
+        r"?[src, dst] <- [[3, 1]] :rm edge {src, dst}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(r"?[id] <- [[1]] :rm node {id}", Default::default())
+        .unwrap();
+    let res = db
+        .run_script("?[src, dst] := *edge{src, dst}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2, 3]]));
+    let res = db
+        .run_script("?[id, node] := *tag{id, node}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[10, null], [20, 2]]));
+
+    assert!(db.run_script("::remove node", Default::default()).is_err());
+    db.run_script("::rename node -> vertex", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script(
+            r"?[src, dst] <- [[3, 5]] :put edge {src, dst}",
+            Default::default(),
+        )
+        .is_err());
+    db.run_script(
+        r"?[id, label] <- [[5, 'e']] :put vertex {id => label}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[src, dst] <- [[3, 5]] :put edge {src, dst}",
+        Default::default(),
+    )
+    .unwrap();
+
+    // checks see the writes of the enclosing multi-transaction
+    let tx = db.multi_transaction(true);
+    tx.run_script(
+        r"?[id, label] <- [[6, 'f']] :put vertex {id => label}",
+        Default::default(),
+    )
+    .unwrap();
+    tx.run_script(
+        r"?[src, dst] <- [[6, 5]] :put edge {src, dst}",
+        Default::default(),
+    )
+    .unwrap();
+    tx.run_script(r"?[id] <- [[6]] :rm vertex {id}", Default::default())
+        .unwrap();
+    assert!(tx
+        .run_script(r"?[id] <- [[5]] :rm vertex {id}", Default::default())
+        .is_err());
+    tx.commit().unwrap();
+    let res = db
+        .run_script("?[src, dst] := *edge{src, dst}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2, 3], [3, 5]]));
+
+    // imports are held to the same rules
+    let import = |rel: &str, headers: &[&str], row: Vec<DataValue>| {
+        db.import_relations(BTreeMap::from([(
+            rel.to_string(),
+            NamedRows::new(headers.iter().map(|h| h.to_string()).collect(), vec![row]),
+        )]))
+    };
+    assert!(import(
+        "edge",
+        &["src", "dst"],
+        vec![DataValue::from(3), DataValue::from(9)]
+    )
+    .is_err());
+    assert!(import("-vertex", &["id"], vec![DataValue::from(3)]).is_err());
+    import("-vertex", &["id"], vec![DataValue::from(2)]).unwrap();
+    let res = db
+        .run_script("?[src, dst] := *edge{src, dst}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[3, 5]]));
+    let res = db
+        .run_script("?[id, node] := *tag{id, node}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[10, null], [20, null]]));
+
+    db.run_script("::remove edge, tag", Default::default())
+        .unwrap();
+    db.run_script("::remove vertex", Default::default())
+        .unwrap();
+}
+
+#[test]
+fn test_unique_index() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create users {id: Int => email: String?, name: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[id, email, name] <- [[1,'a@x','a'],[2,'b@x','b'],[3,null,'c']] :put users {id => email, name}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::index create users:name_idx {name}", Default::default())
+        .unwrap();
+    db.run_script(
+        "::index create unique users:email {email}",
+        Default::default(),
+    )
+    .unwrap();
+
+    // conflicts with an existing row
+    let err = db
+        .run_script(
+            r"?[id, email, name] <- [[4,'a@x','d']] :put users {id => email, name}",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert!(err.root_cause().to_string().contains("[1]"));
+    // conflicts within the same write
+    assert!(db
+        .run_script(
+            r"?[id, email, name] <- [[4,'d@x','d'],[5,'d@x','e']] :put users {id => email, name}",
+            Default::default(),
+        )
+        .is_err());
+    assert!(db
+        .run_script(
+            r"?[id, email] <- [[2,'a@x']] :update users {id => email}",
+            Default::default(),
+        )
+        .is_err());
+
+    // rewriting the same row and multiple nulls are fine
+    db.run_script(
+        r"?[id, email, name] <- [[1,'a@x','aa'],[4,null,'d']] :put users {id => email, name}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(r"?[id] <- [[1]] :rm users {id}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"?[id, email] <- [[2,'a@x']] :update users {id => email}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            "?[id] := *users:email{email: 'a@x', id}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2]]));
+
+    assert!(db
+        .run_script(
+            r"?[id, email, name] <- [[5,'c@x','b']] :put users {id => email, name}",
+            Default::default(),
+        )
+        .is_ok());
+    assert!(db
+        .run_script(
+            "::index create unique users:name {name}",
+            Default::default(),
+        )
+        .is_err());
+
+    // retyping may make distinct values equal
+    db.run_script(
+        r"
+        {:create scores {id: Int => score: Any}}
+        {?[id, score] <- [[1, 1], [2, 1.0]] :put scores {id => score}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "::index create unique scores:by_score {score}",
+        Default::default(),
+    )
+    .unwrap();
+    assert!(db
+        .run_script("::alter scores {retype score: Int}", Default::default())
+        .is_err());
+    let res = db
+        .run_script("?[id, score] := *scores{id, score}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, 1], [2, 1.0]]));
+}
+
+#[test]
+fn test_alter_relation() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create friends {fr: Int, to: Int => data: Any, extra: Any}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[fr, to, data, extra] <- [[1,2,3,'a'],[4,5,6,'b']] :put friends {fr, to => data, extra}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("::index create friends:rev {to, data}", Default::default())
+        .unwrap();
+
+    assert!(db
+        .run_script("::alter friends {drop data}", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("::alter friends {drop fr}", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("::alter friends {add weight: Float}", Default::default())
+        .is_err());
+
+    db.run_script(
+        "::alter friends {add weight: Float default 1.5, drop extra, rename data -> payload}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            "?[fr, to, payload, weight] := *friends{fr, to, payload, weight}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([[1, 2, 3, 1.5], [4, 5, 6, 1.5]])
+    );
+    let res = db
+        .run_script(
+            "?[fr, payload] := *friends:rev{to: 5, fr, payload}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[4, 6]]));
+
+    assert!(db
+        .run_script("::alter friends {retype weight: Int}", Default::default())
+        .is_err());
+    db.run_script("::alter friends {retype payload: Int}", Default::default())
+        .unwrap();
+
+    db.run_script(
+        r"?[fr, to, payload, weight] <- [[7,8,9,2]] :put friends {fr, to => payload, weight}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("?[fr] := *friends:rev{to: 8, fr}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[7]]));
+}
+
 #[test]
 fn test_json_objects() {
     let db = new_cozo_mem().unwrap();
-    db.run_script("?[a] := a = {'a': 1}", Default::default())
+    db.run_script("?[a] := a = {'a': 1}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"?[a] := a = {
+            'a': 1
+        }",
+        Default::default(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_custom_rules() {
+    let db = new_cozo_mem().unwrap();
+    struct Custom;
+
+    impl FixedRule for Custom {
+        fn arity(
+            &self,
+            _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
+            _rule_head: &[Symbol],
+            _span: SourceSpan,
+        ) -> miette::Result<usize> {
+            Ok(1)
+        }
+
+        fn run(
+            &self,
+            payload: FixedRulePayload<'_, '_>,
+            out: &'_ mut RegularTempStore,
+            _poison: Poison,
+        ) -> miette::Result<()> {
+            let rel = payload.get_input(0)?;
+            let mult = payload.integer_option("mult", Some(2))?;
+            for maybe_row in rel.iter()? {
+                let row = maybe_row?;
+                let mut sum = 0;
+                for col in row {
+                    let d = col.get_int().unwrap_or(0);
+                    sum += d;
+                }
+                sum *= mult;
+                out.put(vec![DataValue::from(sum)])
+            }
+            Ok(())
+        }
+    }
+
+    db.register_fixed_rule("SumCols".to_string(), Custom)
+        .unwrap();
+    let res = db
+        .run_script(
+            r#"
+        rel[] <- [[1,2,3,4],[5,6,7,8]]
+        ?[x] <~ SumCols(rel[], mult: 100)
+    "#,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1000], [2600]]));
+}
+
+#[test]
+fn test_register_function() {
+    let db = new_cozo_mem().unwrap();
+    db.register_function("twice".to_string(), 1, |args| {
+        let n = args[0]
+            .get_int()
+            .ok_or_else(|| miette!("twice requires an integer"))?;
+        Ok(DataValue::from(n * 2))
+    })
+    .unwrap();
+    assert!(db
+        .register_function("add".to_string(), 2, |_| Ok(DataValue::Null))
+        .is_err());
+    assert!(db
+        .register_function("twice".to_string(), 1, |_| Ok(DataValue::Null))
+        .is_err());
+
+    let res = db
+        .run_script(
+            "?[x, y] := x in [1, 2, 3], y = twice(x + 1), twice(y) > 8",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2, 6], [3, 8]]));
+
+    let err = db
+        .run_script("?[x] := x = twice(1, 2)", Default::default())
+        .unwrap_err();
+    assert!(err.to_string().contains("Wrong number of arguments"));
+    assert!(db
+        .run_script("?[x] := x = twice('a')", Default::default())
+        .is_err());
+
+    // column defaults and checks keep the function by name
+    db.run_script(
+        r"?[k, v] <- [[1, 2]]
+          :create doubled {k: Int => v: Int check v == twice(k), w: Int default twice(21)}",
+        Default::default(),
+    )
+    .unwrap();
+    assert!(db
+        .run_script(
+            "?[k, v] <- [[2, 5]] :put doubled {k => v}",
+            Default::default()
+        )
+        .is_err());
+    db.run_script(
+        "?[k, v] <- [[2, 4]] :put doubled {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("?[k, v, w] := *doubled{k, v, w}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1, 2, 42], [2, 4, 42]]));
+
+    assert!(db.unregister_function("twice").unwrap());
+    assert!(db
+        .run_script(
+            "?[k, v] <- [[3, 6]] :put doubled {k => v}",
+            Default::default()
+        )
+        .is_err());
+    assert!(db
+        .run_script("?[x] := x = twice(1)", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_register_aggregation() {
+    struct SmallestInt;
+
+    struct SmallestIntState(Option<i64>);
+
+    impl NormalAggrObj for SmallestIntState {
+        fn set(&mut self, value: &DataValue) -> Result<()> {
+            let v = value.get_int().ok_or_else(|| miette!("not an integer"))?;
+            self.0 = Some(self.0.map_or(v, |cur| cur.min(v)));
+            Ok(())
+        }
+
+        fn get(&self) -> Result<DataValue> {
+            Ok(self.0.map_or(DataValue::Null, DataValue::from))
+        }
+    }
+
+    struct SmallestIntMeet;
+
+    impl MeetAggrObj for SmallestIntMeet {
+        fn init_val(&self) -> DataValue {
+            DataValue::Null
+        }
+
+        fn update(&self, left: &mut DataValue, right: &DataValue) -> Result<bool> {
+            if *left == DataValue::Null || right < left {
+                *left = right.clone();
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        }
+    }
+
+    impl CustomAggregation for SmallestInt {
+        fn is_meet(&self) -> bool {
+            true
+        }
+
+        fn normal_init(&self, _args: &[DataValue]) -> Result<Box<dyn NormalAggrObj>> {
+            Ok(Box::new(SmallestIntState(None)))
+        }
+
+        fn meet_init(&self, _args: &[DataValue]) -> Result<Box<dyn MeetAggrObj>> {
+            Ok(Box::new(SmallestIntMeet))
+        }
+    }
+
+    struct CountOdd;
+
+    struct CountOddState(i64);
+
+    impl NormalAggrObj for CountOddState {
+        fn set(&mut self, value: &DataValue) -> Result<()> {
+            if value.get_int().unwrap_or(0) % 2 != 0 {
+                self.0 += 1;
+            }
+            Ok(())
+        }
+
+        fn get(&self) -> Result<DataValue> {
+            Ok(DataValue::from(self.0))
+        }
+    }
+
+    impl CustomAggregation for CountOdd {
+        fn normal_init(&self, _args: &[DataValue]) -> Result<Box<dyn NormalAggrObj>> {
+            Ok(Box::new(CountOddState(0)))
+        }
+    }
+
+    let db = new_cozo_mem().unwrap();
+    db.register_aggregation("smallest_int".to_string(), SmallestInt)
+        .unwrap();
+    db.register_aggregation("count_odd".to_string(), CountOdd)
+        .unwrap();
+    assert!(db
+        .register_aggregation("count".to_string(), CountOdd)
+        .is_err());
+
+    let res = db
+        .run_script(
+            r#"
+        edge[] <- [[1, 2, 1], [2, 3, 1], [1, 3, 5], [3, 4, 1]]
+        dist[n, smallest_int(d)] := n = 1, d = 0
+        dist[n, smallest_int(d)] := dist[m, d0], edge[m, n, w], d = d0 + w
+        ?[n, d] := dist[n, d]
+    "#,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([[1, 0], [2, 1], [3, 2], [4, 3]])
+    );
+
+    let res = db
+        .run_script(
+            "?[count_odd(x), smallest_int(x)] := x in [3, 4, 5, 6, 7]",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[3, 3]]));
+
+    assert!(db
+        .run_script(
+            r#"
+        r[count_odd(x)] := x in [1, 2]
+        r[count_odd(x)] := r[x]
+        ?[x] := r[x]
+    "#,
+            Default::default(),
+        )
+        .is_err());
+
+    assert!(db.unregister_aggregation("count_odd").unwrap());
+    assert!(db
+        .run_script("?[count_odd(x)] := x in [1]", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_views() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r#"
+        ?[a, b] <- [[1, 2], [2, 3], [3, 4]]
+        :create friend {a: Int, b: Int}
+    "#,
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r#"
+        ::view create reach {
+            r[a, b] := *friend[a, b]
+            r[a, b] := r[a, c], *friend[c, b]
+            ?[from, to] := r[from, to]
+        }
+    "#,
+        Default::default(),
+    )
+    .unwrap();
+
+    for q in [
+        "?[to] := reach[1, to]",
+        "?[to] := *reach[1, to]",
+        "?[to] := *reach{from: 1, to}",
+        "r[x] := x = 100\n?[to] := reach[1, to], not r[to]",
+    ] {
+        let res = db.run_script(q, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], json!([[2], [3], [4]]), "{}", q);
+    }
+
+    db.run_script(
+        "::view create reach_from_one { ?[to] := reach[1, to], to > 2 }",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script("?[x] := *reach_from_one[x]", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[3], [4]]));
+
+    let res = db.run_script("::relations", Default::default()).unwrap();
+    let views = res
+        .rows
+        .iter()
+        .filter(|row| row[2] == DataValue::from("view"))
+        .map(|row| (row[0].clone(), row[1].clone()))
+        .collect_vec();
+    assert_eq!(
+        views,
+        vec![
+            (DataValue::from("reach"), DataValue::from(2)),
+            (DataValue::from("reach_from_one"), DataValue::from(1))
+        ]
+    );
+
+    assert!(db
+        .run_script(
+            "::view create bad { ?[a] := a = 1 :limit 1 }",
+            Default::default()
+        )
+        .is_err());
+    assert!(db
+        .run_script("::view create friend { ?[a] := a = 1 }", Default::default())
+        .is_err());
+    assert!(db
+        .run_script(":create reach {a}", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("::rename friend -> reach", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("?[to] := *reach{from: 1, too: to}", Default::default())
+        .is_err());
+
+    db.run_script("::view drop reach_from_one", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script("?[x] := *reach_from_one[x]", Default::default())
+        .is_err());
+    assert!(db
+        .run_script("::view drop reach_from_one", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_materialized_views() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r#"
+        {:create product {name: String}}
+        {:create sale {id: Int => product: String, region: String, amount: Int}}
+        {?[name] <- [['apple'], ['pear'], ['plum']] :put product {name}}
+        {
+            ?[id, product, region, amount] <- [[1, 'apple', 'north', 10], [2, 'pear', 'north', 20],
+                                               [3, 'apple', 'south', 5]]
+            :put sale {id => product, region, amount}
+        }
+    "#,
+        Default::default(),
+    )
+    .unwrap();
+
+    let views = [
+        (
+            "region_total",
+            "?[region, sum(amount), count(id)] := *sale{id, region, amount}",
+        ),
+        (
+            "unsold",
+            "?[name] := *product{name}, not *sale{product: name}",
+        ),
+        (
+            "pairs",
+            "?[a, b] := *sale{id: i, product: a, region: r}, *sale{id: j, product: b, region: r}, i < j",
+        ),
+        ("n_sales", "?[count(id)] := *sale{id}"),
+        (
+            "big_regions",
+            "?[region] := *region_total{region, amount}, amount > 25",
+        ),
+    ];
+    for (name, script) in views {
+        db.run_script(
+            &format!("::view materialize {name} {{ {script} }}"),
+            Default::default(),
+        )
+        .unwrap();
+    }
+    let check = || {
+        for (name, script) in views {
+            let stored = db
+                .run_script(
+                    &format!("?[a, b, c] := *{name}[a, b, c]"),
+                    Default::default(),
+                )
+                .or_else(|_| {
+                    db.run_script(&format!("?[a, b] := *{name}[a, b]"), Default::default())
+                })
+                .or_else(|_| db.run_script(&format!("?[a] := *{name}[a]"), Default::default()))
+                .unwrap();
+            let computed = db.run_script(script, Default::default()).unwrap();
+            assert_eq!(stored.rows, computed.rows, "{}", name);
+        }
+    };
+    check();
+    let res = db
+        .run_script("?[r, t] := *region_total[r, t, _]", Default::default())
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["north", 30.0], ["south", 5.0]])
+    );
+
+    for mutation in [
+        "?[id, product, region, amount] <- [[4, 'plum', 'south', 40]] :put sale {id => product, region, amount}",
+        "?[id, product, region, amount] <- [[1, 'apple', 'south', 10]] :put sale {id => product, region, amount}",
+        "?[id, amount] <- [[2, 1]] :update sale {id => amount}",
+        "?[id] <- [[4]] :rm sale {id}",
+        "?[id] <- [[2], [3]] :rm sale {id}",
+        "?[name] <- [['fig']] :put product {name}",
+    ] {
+        db.run_script(mutation, Default::default()).unwrap();
+        check();
+    }
+    let res = db
+        .run_script("?[r, t, n] := *region_total[r, t, n]", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["south", 10.0, 1]]));
+
+    assert!(db
+        .run_script(
+            "?[region, amount, id] <- [['east', 1, 1]] :put region_total {region => amount, id}",
+            Default::default()
+        )
+        .is_err());
+    assert!(db.run_script("::remove sale", Default::default()).is_err());
+    assert!(db
+        .run_script("::alter sale {add note: String?}", Default::default())
+        .is_err());
+    let mut data = BTreeMap::new();
+    data.insert(
+        "sale".to_string(),
+        NamedRows::new(
+            vec![
+                "id".into(),
+                "product".into(),
+                "region".into(),
+                "amount".into(),
+            ],
+            vec![vec![
+                DataValue::from(5),
+                DataValue::from("fig"),
+                DataValue::from("east"),
+                DataValue::from(1),
+            ]],
+        ),
+    );
+    assert!(db.import_relations(data).is_err());
+    check();
+    assert!(db
+        .run_script(
+            "::view materialize bad { r[x] := *product{name: x}\n ?[x] := r[x] }",
+            Default::default()
+        )
+        .is_err());
+    assert!(db
+        .run_script("::view drop region_total", Default::default())
+        .is_err());
+
+    for name in ["big_regions", "region_total", "unsold", "pairs", "n_sales"] {
+        db.run_script(&format!("::view drop {name}"), Default::default())
+            .unwrap();
+    }
+    assert!(db
+        .run_script("?[r] := *region_total[r, _, _]", Default::default())
+        .is_err());
+    db.run_script("::remove sale", Default::default()).unwrap();
+}
+
+#[test]
+fn test_analyze_join_order() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r"
+        {?[k, x] := k in int_range(1000), x = k % 10 :create big {k => x}}
+        {?[k, v] <- [[1, 'a'], [2, 'b'], [3, 'c']] :create small {k => v}}
+        {?[a] := a in int_range(50) :create r1 {a}}
+        {?[a, b] := a in int_range(100), b = a * 2 :create r2 {a, b}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let plan = |script: &str| {
+        let res = db
+            .run_script(&format!("::explain {{ {script} }}"), Default::default())
+            .unwrap()
+            .into_json();
+        res["rows"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| (r[4].as_str().unwrap().to_string(), r[5].clone()))
+            .collect_vec()
+    };
+    let q1 = "?[k, v, x] := *big{k, x}, *small{k, v}";
+    let q2 = "?[a, b] := *r1{a}, *r2{a, b}";
+    let loads = |rows: &[(String, serde_json::Value)]| {
+        rows.iter()
+            .filter(|(op, _)| op == "load_stored")
+            .map(|(_, r)| r.clone())
+            .collect_vec()
+    };
+    assert_eq!(loads(&plan(q1)), vec![json!(":big"), json!(":small")]);
+    assert!(plan(q2).iter().any(|(op, _)| op == "stored_prefix_join"));
+
+    let stats = db
+        .run_script("::analyze big, small, r1, r2", Default::default())
+        .unwrap();
+    assert_eq!(stats.rows.len(), 7);
+    assert_eq!(stats.rows[0][2], DataValue::from(1000));
+    assert_eq!(stats.rows[1][3], DataValue::from(10));
+
+    // the small relation is scanned first, and the big one looked up into
+    assert_eq!(loads(&plan(q1)), vec![json!(":small"), json!(":big")]);
+    assert!(plan(q1).iter().any(|(op, _)| op == "stored_prefix_join"));
+    // a lookup for every row of r1 costs more than scanning r2 once
+    assert!(plan(q2).iter().any(|(op, _)| op == "stored_mat_join"));
+
+    let res = db.run_script(q1, Default::default()).unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([[1, "a", 1], [2, "b", 2], [3, "c", 3]])
+    );
+    let res = db.run_script(q2, Default::default()).unwrap();
+    assert_eq!(res.rows.len(), 50);
+
+    // altering the columns discards the statistics
+    for rel in ["big", "small"] {
+        db.run_script(
+            &format!("::alter {rel} {{add note: String? default null}}"),
+            Default::default(),
+        )
+        .unwrap();
+    }
+    assert_eq!(loads(&plan(q1)), vec![json!(":big"), json!(":small")]);
+}
+
+#[test]
+fn test_run_script_iter() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(
+        r"?[k, v] := k in int_range(100), v = k % 7 :create r {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+
+    // streamed directly from the stored relation
+    let iter = db
+        .run_script_iter("?[k, v] := *r{k, v}, v == 3", Default::default(), 4)
+        .unwrap();
+    assert_eq!(iter.headers, vec!["k", "v"]);
+    let rows: Vec<_> = iter.try_collect().unwrap();
+    assert_eq!(rows.len(), 14);
+    assert!(rows.iter().all(|r| r[1] == DataValue::from(3)));
+
+    // duplicates are removed even when streamed
+    let rows: Vec<_> = db
+        .run_script_iter("?[v] := *r{v}", Default::default(), 4)
+        .unwrap()
+        .try_collect()
+        .unwrap();
+    assert_eq!(rows.len(), 7);
+
+    let mut iter = db
+        .run_script_iter("?[k] := *r{k} :offset 10 :limit 25", Default::default(), 10)
+        .unwrap();
+    let chunk_sizes = iter::from_fn(|| iter.next_chunk())
+        .map(|c| c.unwrap().len())
+        .collect_vec();
+    assert_eq!(chunk_sizes, vec![10, 10, 5]);
+
+    // sorted results are collected before being sent
+    let rows: Vec<_> = db
+        .run_script_iter("?[k] := *r{k} :order -k :limit 3", Default::default(), 2)
+        .unwrap()
+        .try_collect()
+        .unwrap();
+    assert_eq!(
+        rows,
+        vec![
+            vec![DataValue::from(99)],
+            vec![DataValue::from(98)],
+            vec![DataValue::from(97)]
+        ]
+    );
+
+    let mut iter = db
+        .run_script_iter("?[k] := *r{k}, k > 1000", Default::default(), 10)
+        .unwrap();
+    assert_eq!(iter.headers, vec!["k"]);
+    assert!(iter.next_chunk().unwrap().unwrap().is_empty());
+    assert!(iter.next_chunk().is_none());
+
+    assert!(db
+        .run_script_iter("?[k] := *nonexistent{k}", Default::default(), 10)
+        .is_err());
+}
+
+#[test]
+fn test_temp_store_spilling() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(
+        r"?[fr, to, w] := fr in int_range(80), to = fr + 1, w = fr % 3 + 1
+          ?[fr, to, w] := fr in int_range(78), to = fr + 2, w = 5
+          :create edge {fr, to => w}",
+        Default::default(),
+    )
+    .unwrap();
+    let queries = [
+        r"reach[a, b] := *edge{fr: a, to: b}
+          reach[a, b] := reach[a, c], *edge{fr: c, to: b}
+          ?[a, b] := reach[a, b]",
+        r"dist[a, min(d)] := a = 0, d = 0
+          dist[b, min(d)] := dist[a, d0], *edge{fr: a, to: b, w}, d = d0 + w
+          ?[a, d] := dist[a, d]",
+        r"?[x, y] := x in int_range(50), y in int_range(40)",
+        r"?[x, y] := x in int_range(50), y in int_range(40)
+          :limit 100",
+        r"?[x, min(y)] := x in int_range(300), y in int_range(5)",
+    ];
+    for query in queries {
+        db.set_temp_store_memory_budget(None);
+        let expected = db.run_script(query, Default::default()).unwrap();
+        assert!(expected.rows.len() > 80);
+        db.set_temp_store_memory_budget(Some(1024));
+        let spilled = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(spilled.rows, expected.rows);
+    }
+    db.set_temp_store_memory_budget(None);
+}
+
+#[test]
+fn test_query_limits() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(
+        r"?[k] := k in int_range(1000) :create r {k}",
+        Default::default(),
+    )
+    .unwrap();
+    let product = r"?[a, b] := *r{k: a}, *r{k: b}, a < 100";
+
+    let err = db
+        .run_script(&format!("{product} :max_rows 10000"), Default::default())
+        .unwrap_err();
+    assert_eq!(
+        err.code().unwrap().to_string(),
+        "eval::query_limit_exceeded"
+    );
+    let err = db
+        .run_script(&format!("{product} :max_memory 100000"), Default::default())
+        .unwrap_err();
+    assert!(err.to_string().contains("max_memory"));
+    let res = db
+        .run_script(&format!("{product} :max_rows 200000"), Default::default())
+        .unwrap();
+    assert_eq!(res.rows.len(), 100000);
+
+    // defaults apply unless the query sets its own limits
+    db.set_default_query_limits(QueryLimits {
+        max_rows: Some(500),
+        max_memory: None,
+    });
+    assert!(db.run_script(product, Default::default()).is_err());
+    assert!(db
+        .run_script("?[k] := *r{k}, k < 100", Default::default())
+        .is_ok());
+    assert!(db
+        .run_script(&format!("{product} :max_rows 200000"), Default::default())
+        .is_ok());
+    db.set_default_query_limits(Default::default());
+    let res = db
+        .run_script(
+            &format!("{{{product} :max_rows 200000}} {{?[k] := *r{{k}}, k < 10}}"),
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows.len(), 10);
+
+    // a query is charged to the program it runs within, until it is done
+    let program = QueryUsage::default();
+    let query = program.child(QueryLimits {
+        max_rows: Some(2),
+        max_memory: None,
+    });
+    query.charge(2, 100).unwrap();
+    assert_eq!((program.rows(), program.memory()), (2, 100));
+    assert!(query.charge(1, 0).is_err());
+    drop(query);
+    assert_eq!((program.rows(), program.memory()), (0, 0));
+
+    let running = db.run_script("::running", Default::default()).unwrap();
+    assert_eq!(running.headers, vec!["id", "started_at", "rows", "memory"]);
+}
+
+#[test]
+fn test_sort_with_limit() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(
+        r"?[k, v] := k in int_range(1000), v = k % 7 :create r {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    let ints = |res: NamedRows| {
+        res.rows
+            .into_iter()
+            .map(|row| row.into_iter().map(|v| v.get_int().unwrap()).collect_vec())
+            .collect_vec()
+    };
+
+    // sorted by a descending scan of the relation
+    let res = db
+        .run_script(
+            "?[k, v] := *r{k, v}, v != 0 :order -k :limit 3 :offset 1",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(ints(res), vec![vec![998, 4], vec![997, 3], vec![996, 2]]);
+    let explained = db
+        .run_script(
+            "::explain { ?[k, v] := *r{k, v} :order -k :limit 3 }",
+            Default::default(),
+        )
+        .unwrap();
+    assert!(explained
+        .rows
+        .iter()
+        .any(|row| row.contains(&DataValue::from("load_stored_desc"))));
+
+    // sorted with a bounded heap, giving the same rows as a full sort
+    let full = ints(
+        db.run_script("?[v, k] := *r{k, v} :order v, -k", Default::default())
+            .unwrap(),
+    );
+    let top = ints(
+        db.run_script(
+            "?[v, k] := *r{k, v} :order v, -k :limit 5 :offset 140",
+            Default::default(),
+        )
+        .unwrap(),
+    );
+    assert_eq!(top, full[140..145].to_vec());
+    let res = db
+        .run_script("?[v] := *r{v} :order -v :limit 2", Default::default())
+        .unwrap();
+    assert_eq!(ints(res), vec![vec![6], vec![5]]);
+}
+
+#[test]
+fn test_returning() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(
+        ":create r {k: Int => v: String default 'x'}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script("?[k, v] <- [[1, 'a']] :put r {k => v}", Default::default())
+        .unwrap();
+
+    let res = db
+        .run_script(
+            "?[k, v] <- [[1, 'b'], [2, 'c']] :put r {k => v} :returning",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.headers, vec!["_kind", "k", "v", "_old"]);
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["updated", 1, "b", [1, "a"]], ["inserted", 2, "c", null]])
+    );
+
+    let res = db
+        .run_script(
+            "?[k, v] <- [[2, 'd']] :update r {k => v} :returning",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["updated", 2, "d", [2, "c"]]])
+    );
+
+    let res = db
+        .run_script(
+            "?[k] <- [[1], [3]] :rm r {k} :returning",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["deleted", 1, "b", null]]));
+
+    // temp relations return their changes too
+    let res = db
+        .run_script(
+            r"
+            {:create _t {k}}
+            {?[k] <- [[1]] :put _t {k}}
+            {?[k] <- [[1], [2]] :put _t {k} :returning}
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["updated", 1, [1]], ["inserted", 2, null]])
+    );
+
+    // without the option only the status is returned
+    let res = db
+        .run_script("?[k] <- [[4]] :put r {k}", Default::default())
+        .unwrap();
+    assert_eq!(res.headers, vec!["status"]);
+    assert!(db
+        .run_script("?[k] <- [[1]] :returning", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_multiple_mutations() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create node {id: Uuid => name: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(":create edge {fr: Uuid, to: Uuid}", Default::default())
+        .unwrap();
+    let (_id, receiver) = db.register_callback("edge", None);
+
+    // rules shared by the mutations are evaluated once
+    db.run_script(
+        r"
+        n[id, name] := name in ['a', 'b', 'c'], id = rand_uuid_v4()
+        e[fr, to] := n[fr, fr_name], fr_name == 'a', n[to, name], name != 'a'
+        ?[id, name] := n[id, name]
+        :put node {id => name}
+        :put edge {fr, to} from e
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    let edges = r"?[a, b] := *edge{fr, to}, *node{id: fr, name: a}, *node{id: to, name: b}";
+    let res = db.run_script(edges, Default::default()).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["a", "b"], ["a", "c"]]));
+    std::thread::sleep(Duration::from_secs_f64(0.01));
+    let (op, new, _) = receiver.try_recv().unwrap();
+    assert_eq!(op, CallbackOp::Put);
+    assert_eq!(new.rows.len(), 2);
+
+    // the entry need not write anything
+    let res = db
+        .run_script(
+            r"
+            gone[fr, to] := *edge{fr, to}, *node{id: to, name}, name == 'c'
+            ?[name] := *node{name}
+            :rm edge {fr, to} from gone
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows.len(), 3);
+    let res = db.run_script(edges, Default::default()).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["a", "b"]]));
+
+    for bad in [
+        "?[x] <- [[1]] :put edge {fr, to} from e",
+        "?[id, name] <- [] :put node {id => name} :put node {id => name}",
+        "e[a] <- [[1]] ?[x] <- [[1]] :create x {a} from e",
+    ] {
+        assert!(db.run_script(bad, Default::default()).is_err());
+    }
+    let err = db
+        .run_script(
+            r"
+            e[fr, to] := *edge{fr, to}
+            ?[id, name] := *node{id, name}
+            :put node {id => name}
+            :rm edge {fr, to} from e
+            :returning
+            ",
+            Default::default(),
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.code().unwrap().to_string(),
+        "parser::returning_with_rule_mutations"
+    );
+}
+
+#[test]
+fn test_timestamp_columns() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create event {at: Timestamp => took: Duration, name: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r#"
+        ?[at, took, name] <- [["2023-01-01T10:00:00Z", "1h", "a"],
+                              ["2023-01-02T10:00:00+02:00", 90, "b"],
+                              [1672740000, "1m30s", "c"]]
+        :put event {at => took, name}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            r"
+            ?[name, ends, took] := *event{at, took, name},
+                                   at >= to_timestamp('2023-01-02'),
+                                   ends = at + took
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([
+            ["b", "2023-01-02T08:01:30Z", "1m30s"],
+            ["c", "2023-01-03T10:01:30Z", "1m30s"]
+        ])
+    );
+
+    // values read back as JSON can be written again
+    let rows = db
+        .run_script(
+            "?[at, took, name] := *event{at, took, name}",
+            Default::default(),
+        )
+        .unwrap()
+        .into_json()["rows"]
+        .clone();
+    db.run_script(
+        ":create copy {at: Timestamp => took: Duration, name: String}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "?[at, took, name] <- $rows :put copy {at => took, name}",
+        BTreeMap::from([("rows".to_string(), DataValue::from(rows))]),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            "?[name] := *event{at, took, name}, *copy{at, took, name}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.rows.len(), 3);
+
+    assert!(db
+        .run_script(
+            "?[at, took, name] <- [['soon', '1h', 'd']] :put event {at => took, name}",
+            Default::default()
+        )
+        .is_err());
+}
+
+#[test]
+fn test_decimal_columns() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create invoice {id: Int => amount: Decimal}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r#"
+        ?[id, amount] <- [[1, "0.10"], [2, 0.2], [3, 5], [4, "100.005"]]
+        :put invoice {id => amount}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            "?[sum(amount), mean(amount)] := *invoice{amount}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["105.305", "26.32625"]]));
+
+    let res = db
+        .run_script(
+            "?[id, amount] := *invoice{id, amount}, amount > 0.15, amount < 100",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2, "0.2"], [3, "5"]]));
+
+    // the scale of stored values is kept, and values read back as JSON can be written again
+    let rows = db
+        .run_script("?[id, amount] := *invoice{id, amount}", Default::default())
+        .unwrap()
+        .into_json()["rows"]
+        .clone();
+    assert_eq!(rows[0], json!([1, "0.10"]));
+    db.run_script(
+        ":create copy {id: Int => amount: Decimal}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        "?[id, amount] <- $rows :put copy {id => amount}",
+        BTreeMap::from([("rows".to_string(), DataValue::from(rows.clone()))]),
+    )
+    .unwrap();
+    let copied = db
+        .run_script("?[id, amount] := *copy{id, amount}", Default::default())
+        .unwrap()
+        .into_json()["rows"]
+        .clone();
+    assert_eq!(copied, rows);
+
+    // keys match the numbers of other types equal to them, as comparisons do
+    db.run_script(
+        r#"
+        {:create price {amount: Decimal => label: String}}
+        {?[amount, label] <- [[1, "one"], ["2.50", "two and a half"], [3, "three"]]
+         :put price {amount => label}}
+        {:create order {id: Int => price: Int references price.amount on delete cascade}}
+        {?[id, price] <- [[1, 1], [2, 3]] :put order {id => price}}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    for (query, expected) in [
+        ("?[label] := *price{amount: 1, label}", json!([["one"]])),
+        (
+            "?[label] := x = 3, *price{amount: x, label}",
+            json!([["three"]]),
+        ),
+        (
+            "?[label] := *price{amount, label}, amount > 2, amount <= 3",
+            json!([["three"], ["two and a half"]]),
+        ),
+        (
+            "?[amount] := *invoice{id: to_decimal(3), amount}",
+            json!([["5"]]),
+        ),
+        (
+            "?[label] := *order{price}, *price{amount: price, label}",
+            json!([["one"], ["three"]]),
+        ),
+    ] {
+        let res = db.run_script(query, Default::default()).unwrap();
+        assert_eq!(res.into_json()["rows"], expected, "{query}");
+    }
+    assert!(db
+        .run_script(
+            "?[id, price] <- [[3, 2]] :put order {id => price}",
+            Default::default()
+        )
+        .is_err());
+    db.run_script("?[amount] <- [[1]] :rm price {amount}", Default::default())
+        .unwrap();
+    let res = db
+        .run_script("?[id] := *order{id}", Default::default())
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2]]));
+
+    // only typed key columns coerce: in rules, numbers of different types are distinct keys
+    let res = db
+        .run_script(
+            r#"
+            a[x] <- [[1]]
+            b[x] := x = to_decimal(1)
+            ?[x] := a[x], b[x]
+            "#,
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([]));
+}
+
+#[test]
+fn test_rtree_index() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        ":create place {id: Int => pos: Geometry?}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r#"
+        ?[id, pos] := x in int_range(20), y in int_range(20), id = x * 100 + y,
+                      pos = st_point(x, y)
+        extra[id, pos] <- [[5000, null], [5001, 'POLYGON ((30 30, 40 30, 40 40, 30 40, 30 30))']]
+        ?[id, pos] := extra[id, pos]
+        :put place {id => pos}
+        "#,
+        Default::default(),
+    )
+    .unwrap();
+    assert!(db
+        .run_script(
+            "::rtree create place:loc {extractor: pos, max_entries: -1}",
+            Default::default(),
+        )
+        .is_err());
+    // a small node size, so that the tree gets several levels
+    db.run_script(
+        "::rtree create place:loc {extractor: pos, max_entries: 4}",
+        Default::default(),
+    )
+    .unwrap();
+
+    let window = |bbox: &str| {
+        db.run_script(
+            &format!("?[id] := ~place:loc{{id | bbox: {bbox}}}"),
+            Default::default(),
+        )
+        .unwrap()
+        .into_json()["rows"]
+            .clone()
+    };
+    assert_eq!(
+        window("[2.5, 2.5, 4.5, 5.5]"),
+        json!([[303], [304], [305], [403], [404], [405]])
+    );
+    assert_eq!(window("[35, 35, 50, 50]"), json!([[5001]]));
+    assert_eq!(window("[20.5, 0, 29, 100]"), json!([]));
+    let all = db
+        .run_script(
+            "?[count(id)] := ~place:loc{id | bbox: [-100, -100, 100, 100]}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(all.rows[0][0], DataValue::from(401));
+
+    let res = db
+        .run_script(
+            r"
+            ?[id, r] := ~place:loc{id | query: st_point(10.2, 10.1), k: 3, bind_distance: d},
+                        r = round(d * 1000)
+            :order r
+            ",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([[1010, 224.0], [1110, 806.0], [1011, 922.0]])
+    );
+    let res = db
+        .run_script(
+            r"
+            ?[id] := ~place:loc{id | query: 'POINT (25 0)', radius: 6.5, filter: id % 2 == 0}
+            ",
+            Default::default(),
+        )
         .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1900], [1902]]));
+    assert!(db
+        .run_script(
+            "?[id] := ~place:loc{id | bbox: [0, 0, 1, 1], bind_distance: d}",
+            Default::default(),
+        )
+        .is_err());
+    // infinite bounds are rejected rather than turned into geometries
+    assert!(db
+        .run_script(
+            "?[id] := ~place:loc{id | bbox: [0, 0, to_float('INF'), 1]}",
+            Default::default(),
+        )
+        .is_err());
+    let mut params = BTreeMap::new();
+    params.insert(
+        "b".to_string(),
+        DataValue::List(vec![
+            DataValue::from(f64::NEG_INFINITY),
+            DataValue::from(f64::NEG_INFINITY),
+            DataValue::from(f64::INFINITY),
+            DataValue::from(f64::INFINITY),
+        ]),
+    );
+    assert!(db
+        .run_script("?[id] := ~place:loc{id | bbox: $b}", params)
+        .is_err());
+
+    // the index follows updates and deletions
+    db.run_script(
+        r"
+        ?[id] := *place{id}, id < 1000
+        :rm place {id}
+        ",
+        Default::default(),
+    )
+    .unwrap();
     db.run_script(
-        r"?[a] := a = {
-            'a': 1
-        }",
+        "?[id, pos] <- [[1010, 'POINT (100 100)']] :update place {id => pos}",
         Default::default(),
     )
     .unwrap();
+    let all = db
+        .run_script(
+            "?[count(id)] := ~place:loc{id | bbox: [-100, -100, 99, 99]}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(all.rows[0][0], DataValue::from(200));
+    let res = db
+        .run_script(
+            "?[id] := ~place:loc{id | query: st_point(90, 90), k: 1}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1010]]));
+
+    let res = db
+        .run_script("::indices place", Default::default())
+        .unwrap();
+    assert_eq!(res.rows[0][1], DataValue::from("rtree"));
+    db.run_script("::rtree drop place:loc", Default::default())
+        .unwrap();
+    assert!(db
+        .run_script(
+            "?[id] := ~place:loc{id | bbox: [0, 0, 1, 1]}",
+            Default::default(),
+        )
+        .is_err());
 }
 
 #[test]
-fn test_custom_rules() {
+fn test_explain_analyze() {
     let db = new_cozo_mem().unwrap();
-    struct Custom;
-
-    impl FixedRule for Custom {
-        fn arity(
-            &self,
-            _options: &BTreeMap<SmartString<LazyCompact>, Expr>,
-            _rule_head: &[Symbol],
-            _span: SourceSpan,
-        ) -> miette::Result<usize> {
-            Ok(1)
-        }
-
-        fn run(
-            &self,
-            payload: FixedRulePayload<'_, '_>,
-            out: &'_ mut RegularTempStore,
-            _poison: Poison,
-        ) -> miette::Result<()> {
-            let rel = payload.get_input(0)?;
-            let mult = payload.integer_option("mult", Some(2))?;
-            for maybe_row in rel.iter()? {
-                let row = maybe_row?;
-                let mut sum = 0;
-                for col in row {
-                    let d = col.get_int().unwrap_or(0);
-                    sum += d;
-                }
-                sum *= mult;
-                out.put(vec![DataValue::from(sum)])
-            }
-            Ok(())
-        }
-    }
-
-    db.register_fixed_rule("SumCols".to_string(), Custom)
-        .unwrap();
+    db.run_script(
+        r"?[fr, to] <- [[1, 2], [2, 3], [3, 4], [4, 5]] :create edge {fr, to}",
+        Default::default(),
+    )
+    .unwrap();
     let res = db
         .run_script(
-            r#"
-        rel[] <- [[1,2,3,4],[5,6,7,8]]
-        ?[x] <~ SumCols(rel[], mult: 100)
-    "#,
+            r"
+            ::explain analyze {
+                reach[to] := *edge{fr: 1, to}
+                reach[to] := reach[fr], *edge{fr, to}
+                ?[to] := reach[to]
+                :replace out {to}
+            }",
             Default::default(),
         )
         .unwrap();
-    assert_eq!(res.into_json()["rows"], json!([[1000], [2600]]));
+    for col in ["rows_in", "rows_out", "seeks", "time_ms", "iterations"] {
+        assert!(res.headers.iter().any(|h| h == col));
+    }
+    let res = res.into_json();
+    let rows = res["rows"].as_array().unwrap();
+    let col = |name: &str| {
+        res["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .position(|h| h == name)
+            .unwrap()
+    };
+    let out_rows = rows
+        .iter()
+        .filter(|r| r[col("op")] == "out" && r[col("rule")] == "?")
+        .collect_vec();
+    assert_eq!(out_rows[0][col("rows_out")], json!(4));
+    // the recursive clause runs once per semi-naive iteration
+    assert!(rows
+        .iter()
+        .any(|r| r[col("iterations")].as_u64().unwrap_or(0) > 1));
+    assert!(rows
+        .iter()
+        .any(|r| r[col("op")] == "load_stored" && r[col("seeks")].as_u64().unwrap() > 0));
+    // nothing is written
+    assert!(db
+        .run_script("?[to] := *out{to}", Default::default())
+        .is_err());
+}
+
+#[test]
+fn test_prepared_query() {
+    let db = new_cozo_mem().unwrap();
+    db.run_script(
+        r#"
+        {:create a {k: Int => v: Int}}
+        {?[k, v] <- [[1, 10], [2, 20], [3, 30]] :put a {k => v}}
+    "#,
+        Default::default(),
+    )
+    .unwrap();
+    let params = |pairs: &[(&str, i64)]| -> BTreeMap<String, DataValue> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), DataValue::from(*v)))
+            .collect()
+    };
+
+    let q = db.prepare("?[v] := *a{k, v}, k >= $lo :order v").unwrap();
+    let res = q.execute(params(&[("lo", 2)])).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[20], [30]]));
+    let res = q.execute(params(&[("lo", 3)])).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[30]]));
+    assert!(q.execute(Default::default()).is_err());
+
+    // changes to the relation invalidate the compiled plan
+    db.run_script("::index create a:by_v {v}", Default::default())
+        .unwrap();
+    let res = q.execute(params(&[("lo", 1)])).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[10], [20], [30]]));
+    db.run_script("::index drop a:by_v", Default::default())
+        .unwrap();
+    db.run_script("::remove a", Default::default()).unwrap();
+    db.run_script(
+        r#"
+        {:create a {k: Int, v: Int}}
+        {?[k, v] <- [[5, 50]] :put a {k, v}}
+    "#,
+        Default::default(),
+    )
+    .unwrap();
+    let res = q.execute(params(&[("lo", 1)])).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[50]]));
+    db.run_script("::remove a", Default::default()).unwrap();
+    assert!(q.execute(params(&[("lo", 1)])).is_err());
+    db.run_script(":create a {k: Int => v: Int}", Default::default())
+        .unwrap();
+
+    // writes
+    let put = db
+        .prepare("?[k, v] := k = $k, v = $k * 10 :put a {k => v}")
+        .unwrap();
+    for k in 1..=3 {
+        put.execute(params(&[("k", k)])).unwrap();
+    }
+    let res = q.execute(params(&[("lo", 2)])).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[20], [30]]));
+
+    // parameters used as constants are compiled into the plan
+    let q = db.prepare("?[x] <- [[$x], [$x + 1]] :limit $n").unwrap();
+    let res = q.execute(params(&[("x", 1), ("n", 1)])).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[1]]));
+    let res = q.execute(params(&[("x", 5), ("n", 2)])).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[5], [6]]));
+
+    assert!(db.prepare("::relations").is_err());
+    assert!(db.prepare("?[x] := *nonexistent[x]").is_err());
 }
 
 #[test]
@@ -955,6 +2646,268 @@ fn test_fts_indexing() {
     }
 }
 
+#[test]
+fn test_fts_bm25() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(r":create a {k: String => v: String}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"::fts create a:fts {extractor: v, tokenizer: Simple, filters: [Lowercase]}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[k, v] <- [
+            ['a', 'apple'],
+            ['b', 'apple banana cherry date egg'],
+            ['c', 'fig grape']
+        ] :put a {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            r"?[k, s] := ~a:fts{k | query: 'apple', k: 10, bind_score: s, score_kind: 'bm25'}
+              :order -s",
+            Default::default(),
+        )
+        .unwrap();
+    let rows = res.rows;
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0][0], DataValue::from("a"));
+    assert!(rows[0][1].get_float().unwrap() > rows[1][1].get_float().unwrap());
+
+    let stats = r"?[n, l] := *a:fts:stats{n_docs: n, total_length: l}";
+    let res = db.run_script(stats, Default::default()).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[3, 8]]));
+
+    db.run_script(
+        r"?[k, v] <- [['b', 'apple banana']] :put a {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(r"?[k] <- [['c']] :rm a {k}", Default::default())
+        .unwrap();
+    let res = db.run_script(stats, Default::default()).unwrap();
+    assert_eq!(res.into_json()["rows"], json!([[2, 3]]));
+
+    assert!(db
+        .run_script(
+            r"?[k] := ~a:fts{k | query: 'apple', k: 10, b: 2, score_kind: 'bm25'}",
+            Default::default(),
+        )
+        .is_err());
+    assert!(db
+        .run_script(
+            r"?[k] := ~a:fts{k | query: 'apple', k: 10, k1: 1.5}",
+            Default::default(),
+        )
+        .is_err());
+}
+
+#[test]
+fn test_fts_snippets() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(r":create a {k: String => v: String}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"::fts create a:fts {extractor: v, tokenizer: Simple, filters: [Lowercase]}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[k, v] <- [
+            ['a', 'The quick brown Fox jumps over the lazy dog'],
+            ['b', 'Foxes and more foxes']
+        ] :put a {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            r"?[k, h, s] := ~a:fts{k | query: 'fox', k: 10, bind_highlights: h,
+                                       bind_snippet: s, snippet_size: 3,
+                                       snippet_markers: ['[', ']']}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["a", [[16, 19]], "...quick brown [Fox]..."]])
+    );
+    let res = db
+        .run_script(
+            r"?[k, s] := ~a:fts{k | query: 'fox* NOT quick', k: 10, bind_snippet: s}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(
+        res.into_json()["rows"],
+        json!([["b", "<b>Foxes</b> and more <b>foxes</b>"]])
+    );
+    assert!(db
+        .run_script(
+            r"?[k] := ~a:fts{k | query: 'fox', k: 10, snippet_size: 3}",
+            Default::default(),
+        )
+        .is_err());
+}
+
+#[test]
+fn test_fts_fuzzy() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(r":create a {k: String => v: String}", Default::default())
+        .unwrap();
+    db.run_script(
+        r"::fts create a:fts {extractor: v, tokenizer: Simple, filters: [Lowercase]}",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"?[k, v] <- [
+            ['a', 'the quick brown fox'],
+            ['b', 'a quick brown box'],
+            ['c', 'brown bear']
+        ] :put a {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    let res = db
+        .run_script(
+            r"?[k, s] := ~a:fts{k | query: 'fox~1', k: 10, bind_score: s, score_kind: 'tf'}
+              :order -s",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["a", 1.0], ["b", 0.5]]));
+    let res = db
+        .run_script(
+            r"?[k] := ~a:fts{k | query: 'qiuck~1', k: 10}",
+            Default::default(),
+        )
+        .unwrap();
+    assert!(res.rows.is_empty());
+    let res = db
+        .run_script(
+            r"?[k] := ~a:fts{k | query: 'qiuck~2 AND bxo~2', k: 10}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([["b"]]));
+
+    // the positions of all the terms matched fuzzily are used for proximity
+    db.run_script(
+        r"?[k, v] <- [['d', 'hello world and help']] :put a {k => v}",
+        Default::default(),
+    )
+    .unwrap();
+    for query in ["NEAR/2(hello world)", "NEAR/2(helo~1 world)"] {
+        let res = db
+            .run_script(
+                &format!("?[k] := ~a:fts{{k | query: '{query}', k: 10}}"),
+                Default::default(),
+            )
+            .unwrap();
+        assert_eq!(res.into_json()["rows"], json!([["d"]]), "{}", query);
+    }
+}
+
+#[test]
+fn test_fts_dictionaries_in_relations() {
+    let db = DbInstance::new("mem", "", "").unwrap();
+    db.run_script(
+        r"
+        {?[w] <- [['the']] :create stops {w: String}}
+        {?[w, s] <- [['automobile', 'car']] :create syn {w: String => s: String}}
+        {?[k, v] <- [['a', 'The red car'], ['b', 'An automobile']] :create a {k: String => v: String}}
+        ",
+        Default::default(),
+    )
+    .unwrap();
+    db.run_script(
+        r"::fts create a:fts {
+            extractor: v,
+            tokenizer: Simple,
+            filters: [Lowercase, Stopwords('*stops'), Synonyms('*syn')]
+        }",
+        Default::default(),
+    )
+    .unwrap();
+    let search = |q: &str| {
+        let mut params = BTreeMap::new();
+        params.insert("q".to_string(), DataValue::from(q));
+        db.run_script("?[k] := ~a:fts{k | query: $q, k: 10}", params)
+            .unwrap()
+            .into_json()["rows"]
+            .clone()
+    };
+    assert_eq!(search("car"), json!([["a"], ["b"]]));
+    assert_eq!(search("the"), json!([]));
+    assert_eq!(search("red"), json!([["a"]]));
+
+    // the index is rebuilt when the dictionaries change
+    db.run_script(r"?[w] <- [['red']] :put stops {w}", Default::default())
+        .unwrap();
+    assert_eq!(search("red"), json!([]));
+    db.run_script(r"?[w] <- [['automobile']] :rm syn {w}", Default::default())
+        .unwrap();
+    assert_eq!(search("car"), json!([["a"]]));
+    assert_eq!(search("automobile"), json!([["b"]]));
+
+    // dictionaries changed by aborted transactions are not used afterwards
+    let tx = db.multi_transaction(true);
+    tx.run_script(r"?[w] <- [['car']] :put stops {w}", Default::default())
+        .unwrap();
+    let res = tx
+        .run_script(
+            "?[k] := ~a:fts{k | query: 'car', k: 10}",
+            Default::default(),
+        )
+        .unwrap();
+    assert_eq!(res.into_json()["rows"], json!([]));
+    tx.abort().unwrap();
+    assert_eq!(search("car"), json!([["a"]]));
+    db.run_script(r"?[w] <- [['car']] :put stops {w}", Default::default())
+        .unwrap();
+    assert_eq!(search("car"), json!([]));
+
+    // imports into the dictionaries also rebuild the index
+    let mut data = BTreeMap::new();
+    data.insert(
+        "-stops".to_string(),
+        NamedRows::new(vec!["w".into()], vec![vec![DataValue::from("car")]]),
+    );
+    db.import_relations(data).unwrap();
+    assert_eq!(search("car"), json!([["a"]]));
+
+    assert!(db
+        .run_script(r"::remove stops", Default::default())
+        .is_err());
+
+    // dropped indices no longer read their dictionaries, even if recreated without them
+    db.run_script(
+        r"?[w] <- [['an']] :create stops2 {w: String}",
+        Default::default(),
+    )
+    .unwrap();
+    let create_lsh = |filters: &str| {
+        db.run_script(
+            &format!(
+                "::lsh create a:l {{extractor: v, tokenizer: Simple, filters: [{filters}], \
+                 n_gram: 1, target_threshold: 0.3}}"
+            ),
+            Default::default(),
+        )
+        .unwrap();
+    };
+    create_lsh("Stopwords('*stops2')");
+    db.run_script(r"::lsh drop a:l", Default::default())
+        .unwrap();
+    create_lsh("Lowercase");
+    db.run_script(r"::remove stops2", Default::default())
+        .unwrap();
+}
+
 #[test]
 fn test_lsh_indexing() {
     let db = DbInstance::new("mem", "", "").unwrap();
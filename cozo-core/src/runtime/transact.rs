@@ -14,6 +14,8 @@ use miette::{bail, Result};
 use crate::data::tuple::TupleT;
 use crate::data::value::DataValue;
 use crate::fts::TokenizerCache;
+use crate::query::profile::QueryProfile;
+use crate::runtime::db::{Poison, QueryUsage};
 use crate::runtime::relation::RelationId;
 use crate::storage::temp::TempTx;
 use crate::storage::StoreTx;
@@ -24,6 +26,16 @@ pub struct SessionTx<'a> {
     pub(crate) relation_store_id: Arc<AtomicU64>,
     pub(crate) temp_store_id: AtomicU32,
     pub(crate) tokenizers: Arc<TokenizerCache>,
+    /// Set when runtime statistics of the query are to be collected
+    pub(crate) profile: Option<Arc<QueryProfile>>,
+    /// Poison of the query being run, checked by operators that consume
+    /// a whole input before producing any rows
+    pub(crate) poison: Poison,
+    /// Rows and memory held by the query being run, checked against its limits
+    pub(crate) usage: QueryUsage,
+    /// Memory budget in bytes of each store holding rule results,
+    /// beyond which the store is spilled to disk
+    pub(crate) temp_store_budget: Option<usize>,
 }
 
 pub const CURRENT_STORAGE_VERSION: [u8; 1] = [0x00];
@@ -0,0 +1,431 @@
+/*
+ * Copyright 2022, The Cozo Project Authors.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+ * If a copy of the MPL was not distributed with this file,
+ * You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::BTreeSet;
+
+use itertools::Itertools;
+use log::error;
+use miette::{bail, ensure, Diagnostic, Result};
+use rmp_serde::Serializer;
+use serde::Serialize;
+use smartstring::{LazyCompact, SmartString};
+use thiserror::Error;
+
+use crate::data::expr::Expr;
+use crate::data::program::{
+    InputAtom, InputInlineRule, InputInlineRulesOrFixed, InputProgram, InputRuleApplyAtom,
+    NoEntryError,
+};
+use crate::data::relation::{ColType, ColumnDef, NullableColType, StoredRelationMetadata};
+use crate::data::symb::{Symbol, PROG_ENTRY};
+use crate::data::tuple::TupleT;
+use crate::data::value::{DataValue, ValidityTs, LARGEST_UTF_CHAR};
+use crate::parse::SourceSpan;
+use crate::runtime::relation::{AccessLevel, InputRelationHandle, RelationId};
+use crate::runtime::transact::SessionTx;
+
+/// A named rule program persisted in the catalog.
+/// Queries applying the view get its rules inlined.
+#[derive(Clone, Debug, serde_derive::Serialize, serde_derive::Deserialize)]
+pub(crate) struct ViewHandle {
+    pub(crate) name: SmartString<LazyCompact>,
+    /// The rules of the view, as written by the user
+    pub(crate) script: String,
+    /// The output columns of the entry rule of the view
+    pub(crate) columns: Vec<SmartString<LazyCompact>>,
+    /// Whether the rows of the view are kept in the stored relation of the same name
+    #[serde(default)]
+    pub(crate) materialized: bool,
+    /// The stored relations read by a materialized view
+    #[serde(default)]
+    pub(crate) sources: BTreeSet<SmartString<LazyCompact>>,
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot deserialize view")]
+#[diagnostic(code(deser::view))]
+#[diagnostic(help("This could indicate a bug. Consider file a bug report."))]
+struct ViewDeserError;
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Cannot find requested view '{0}'")]
+#[diagnostic(code(query::view_not_found))]
+struct ViewNotFoundError(String);
+
+pub(crate) fn view_key(name: &str) -> Vec<u8> {
+    vec![
+        DataValue::Null,
+        DataValue::from("VIEW"),
+        DataValue::from(name),
+    ]
+    .encode_as_key(RelationId::SYSTEM)
+}
+
+impl ViewHandle {
+    pub(crate) fn decode(data: &[u8]) -> Result<Self> {
+        Ok(rmp_serde::from_slice(data).map_err(|e| {
+            error!("Cannot deserialize view from bytes: {:x?}, {:?}", data, e);
+            ViewDeserError
+        })?)
+    }
+}
+
+impl<'a> SessionTx<'a> {
+    pub(crate) fn view_exists(&self, name: &str) -> Result<bool> {
+        self.store_tx.exists(&view_key(name), false)
+    }
+    pub(crate) fn get_view(&self, name: &str) -> Result<ViewHandle> {
+        let found = self
+            .store_tx
+            .get(&view_key(name), false)?
+            .ok_or_else(|| ViewNotFoundError(name.to_string()))?;
+        ViewHandle::decode(&found)
+    }
+    fn ensure_view_name_available(&self, name: &Symbol) -> Result<()> {
+        if name.is_temp_store_name() {
+            bail!("Cannot create view {} with a temp store name", name);
+        }
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Cannot create view {0} as a relation or view with the same name already exists")]
+        #[diagnostic(code(eval::view_name_conflict))]
+        struct ViewNameConflictError(String);
+
+        if self.relation_exists(name)? || self.store_tx.exists(&view_key(name), true)? {
+            bail!(ViewNameConflictError(name.to_string()))
+        }
+        Ok(())
+    }
+    fn put_view(&mut self, handle: &ViewHandle) -> Result<()> {
+        let mut val = vec![];
+        handle
+            .serialize(&mut Serializer::new(&mut val).with_struct_map())
+            .unwrap();
+        self.store_tx.put(&view_key(&handle.name), &val)
+    }
+    pub(crate) fn create_view(
+        &mut self,
+        name: &Symbol,
+        script: String,
+        columns: Vec<Symbol>,
+    ) -> Result<()> {
+        self.ensure_view_name_available(name)?;
+        self.put_view(&ViewHandle {
+            name: name.name.clone(),
+            script,
+            columns: columns.into_iter().map(|c| c.name).collect_vec(),
+            materialized: false,
+            sources: Default::default(),
+        })
+    }
+    /// Creates the view together with the read-only stored relation holding its rows.
+    /// The relation starts out empty.
+    pub(crate) fn create_materialized_view(
+        &mut self,
+        name: &Symbol,
+        script: String,
+        prog: &InputProgram,
+    ) -> Result<()> {
+        self.ensure_view_name_available(name)?;
+
+        let rules = materialized_view_rules(prog)?;
+        let sources = materialized_view_sources(prog)?;
+
+        #[derive(Debug, Error, Diagnostic)]
+        #[error("Materialized view {0} has more than one column named {1}")]
+        #[diagnostic(code(eval::dup_col_in_materialized_view))]
+        #[diagnostic(help("Rename the columns in the head of the entry rule"))]
+        struct DuplicateColumnInView(String, String, #[label] SourceSpan);
+
+        // grouping columns become the keys, aggregated columns the non-keys
+        let entry = rules.last().unwrap();
+        let mut seen = BTreeSet::new();
+        let mut keys = vec![];
+        let mut non_keys = vec![];
+        for (symb, aggr) in entry.head.iter().zip(entry.aggr.iter()) {
+            ensure!(
+                seen.insert(&symb.name),
+                DuplicateColumnInView(name.to_string(), symb.to_string(), symb.span)
+            );
+            let col = ColumnDef {
+                name: symb.name.clone(),
+                typing: NullableColType {
+                    coltype: ColType::Any,
+                    nullable: true,
+                },
+                default_gen: None,
+                check: None,
+            };
+            if aggr.is_some() {
+                non_keys.push(col);
+            } else {
+                keys.push(col);
+            }
+        }
+        let to_symbols = |cols: &[ColumnDef]| {
+            cols.iter()
+                .map(|c| Symbol::new(c.name.clone(), name.span))
+                .collect_vec()
+        };
+        let key_bindings = to_symbols(&keys);
+        let dep_bindings = to_symbols(&non_keys);
+        let columns = keys
+            .iter()
+            .chain(non_keys.iter())
+            .map(|c| c.name.clone())
+            .collect_vec();
+
+        let mut handle = self.create_relation(InputRelationHandle {
+            name: name.clone(),
+            metadata: StoredRelationMetadata {
+                keys,
+                non_keys,
+                foreign_keys: vec![],
+            },
+            key_bindings,
+            dep_bindings,
+            span: name.span,
+        })?;
+        handle.access_level = AccessLevel::ReadOnly;
+        self.put_relation_handle(&handle)?;
+
+        for source in sources.iter() {
+            let mut source_rel = self.get_relation(source, true)?;
+            source_rel.materialized_views.insert(name.name.clone());
+            self.put_relation_handle(&source_rel)?;
+        }
+
+        self.put_view(&ViewHandle {
+            name: name.name.clone(),
+            script,
+            columns,
+            materialized: true,
+            sources,
+        })
+    }
+    /// Returns the bounds of the stored data to clean up, if the view is materialized
+    pub(crate) fn destroy_view(&mut self, name: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let key = view_key(name);
+        if !self.store_tx.exists(&key, true)? {
+            bail!(ViewNotFoundError(name.to_string()))
+        }
+        let view = self.get_view(name)?;
+        self.store_tx.del(&key)?;
+        if !view.materialized {
+            return Ok(vec![]);
+        }
+        for source in view.sources.iter() {
+            let mut source_rel = self.get_relation(source, true)?;
+            source_rel.materialized_views.remove(name);
+            self.put_relation_handle(&source_rel)?;
+        }
+        let mut handle = self.get_relation(name, true)?;
+        handle.access_level = AccessLevel::Normal;
+        self.put_relation_handle(&handle)?;
+        self.destroy_relation(name)
+    }
+    pub(crate) fn list_views(&self) -> Result<Vec<ViewHandle>> {
+        let lower = view_key("");
+        let upper = view_key(&String::from(LARGEST_UTF_CHAR));
+        let mut ret = vec![];
+        for kv_res in self.store_tx.range_scan(&lower, &upper) {
+            let (_, v_slice) = kv_res?;
+            ret.push(ViewHandle::decode(&v_slice)?);
+        }
+        Ok(ret)
+    }
+}
+
+#[derive(Debug, Error, Diagnostic)]
+#[error("Materialized views cannot contain {0}")]
+#[diagnostic(code(eval::unsupported_in_materialized_view))]
+#[diagnostic(help(
+    "Materialized views consist of non-recursive entry rules reading stored relations"
+))]
+struct UnsupportedInMaterializedView(&'static str, #[label] SourceSpan);
+
+/// The rules defining a materialized view, which are the entry rules only
+pub(crate) fn materialized_view_rules(prog: &InputProgram) -> Result<&[InputInlineRule]> {
+    for (name, rules_or_fixed) in prog.prog.iter() {
+        ensure!(
+            name.is_prog_entry(),
+            UnsupportedInMaterializedView(
+                "rules other than the entry",
+                rules_or_fixed.first_span()
+            )
+        );
+    }
+    match prog.prog.get(&Symbol::new(PROG_ENTRY, SourceSpan(0, 0))) {
+        Some(InputInlineRulesOrFixed::Rules { rules }) => Ok(rules),
+        Some(fixed) => bail!(UnsupportedInMaterializedView(
+            "fixed rules",
+            fixed.first_span()
+        )),
+        None => bail!(NoEntryError),
+    }
+}
+
+/// The stored relations read by the rules of a materialized view
+pub(crate) fn materialized_view_sources(
+    prog: &InputProgram,
+) -> Result<BTreeSet<SmartString<LazyCompact>>> {
+    let mut sources = BTreeSet::new();
+    for rule in materialized_view_rules(prog)? {
+        for atom in &rule.body {
+            collect_view_sources(atom, &mut sources)?;
+        }
+    }
+    Ok(sources)
+}
+
+fn collect_view_sources(
+    atom: &InputAtom,
+    sources: &mut BTreeSet<SmartString<LazyCompact>>,
+) -> Result<()> {
+    let mut add_source = |name: &Symbol, valid_at: &Option<ValidityTs>, span| {
+        ensure!(
+            !name.is_temp_store_name(),
+            UnsupportedInMaterializedView("temp relations", span)
+        );
+        ensure!(
+            valid_at.is_none(),
+            UnsupportedInMaterializedView("time travel", span)
+        );
+        sources.insert(name.name.clone());
+        Ok(())
+    };
+    match atom {
+        InputAtom::Relation { inner } => add_source(&inner.name, &inner.valid_at, inner.span),
+        InputAtom::NamedFieldRelation { inner } => {
+            add_source(&inner.name, &inner.valid_at, inner.span)
+        }
+        InputAtom::Rule { inner } => bail!(UnsupportedInMaterializedView(
+            "applications of rules or views",
+            inner.span
+        )),
+        InputAtom::Search { inner } => {
+            bail!(UnsupportedInMaterializedView("index searches", inner.span))
+        }
+        InputAtom::Negation { inner, span } => match inner.as_ref() {
+            InputAtom::Relation { .. } | InputAtom::NamedFieldRelation { .. } => {
+                collect_view_sources(inner, sources)
+            }
+            inner => {
+                let mut found = BTreeSet::new();
+                collect_view_sources(inner, &mut found)?;
+                ensure!(
+                    found.is_empty(),
+                    UnsupportedInMaterializedView(
+                        "negations of compound atoms reading relations",
+                        *span
+                    )
+                );
+                Ok(())
+            }
+        },
+        InputAtom::Conjunction { inner, .. } | InputAtom::Disjunction { inner, .. } => {
+            for atom in inner {
+                collect_view_sources(atom, sources)?;
+            }
+            Ok(())
+        }
+        InputAtom::Predicate { .. } | InputAtom::Unification { .. } => Ok(()),
+    }
+}
+
+/// Rewrites a rule body for computing the rows of a materialized view affected
+/// by a change to `source`: the `target`-th application of `source` reads the changed rows
+/// `delta_rule` instead, and the other applications read `all_rule`, which holds the rows
+/// both before and after the change. Other negated applications are dropped.
+/// The result is a superset of the affected rows, which are then recomputed exactly.
+/// Returns the number of applications of `source` seen so far in `counter`.
+pub(crate) fn delta_view_atom(
+    atom: &InputAtom,
+    source: &str,
+    source_cols: &[SmartString<LazyCompact>],
+    target: usize,
+    counter: &mut usize,
+) -> InputAtom {
+    let is_source = |atom: &InputAtom| match atom {
+        InputAtom::Relation { inner } => inner.name.name == source,
+        InputAtom::NamedFieldRelation { inner } => inner.name.name == source,
+        _ => false,
+    };
+    let read_rule = |atom: &InputAtom, rule_name: &str| {
+        let (args, span) = match atom {
+            InputAtom::Relation { inner } => (inner.args.clone(), inner.span),
+            InputAtom::NamedFieldRelation { inner } => (
+                source_cols
+                    .iter()
+                    .map(|col| {
+                        inner.args.get(col).cloned().unwrap_or(Expr::Binding {
+                            var: Symbol::new("_", inner.span),
+                            tuple_pos: None,
+                        })
+                    })
+                    .collect_vec(),
+                inner.span,
+            ),
+            _ => unreachable!(),
+        };
+        InputAtom::Rule {
+            inner: InputRuleApplyAtom {
+                name: Symbol::new(rule_name, span),
+                args,
+                span,
+            },
+        }
+    };
+    match atom {
+        InputAtom::Negation { inner, span } if is_source(inner) => {
+            let idx = *counter;
+            *counter += 1;
+            if idx == target {
+                read_rule(inner, DELTA_RULE)
+            } else {
+                InputAtom::Predicate {
+                    inner: Expr::Const {
+                        val: DataValue::from(true),
+                        span: *span,
+                    },
+                }
+            }
+        }
+        InputAtom::Negation { inner, span } => InputAtom::Negation {
+            inner: Box::new(delta_view_atom(inner, source, source_cols, target, counter)),
+            span: *span,
+        },
+        InputAtom::Conjunction { inner, span } => InputAtom::Conjunction {
+            inner: inner
+                .iter()
+                .map(|a| delta_view_atom(a, source, source_cols, target, counter))
+                .collect(),
+            span: *span,
+        },
+        InputAtom::Disjunction { inner, span } => InputAtom::Disjunction {
+            inner: inner
+                .iter()
+                .map(|a| delta_view_atom(a, source, source_cols, target, counter))
+                .collect(),
+            span: *span,
+        },
+        atom if is_source(atom) => {
+            let idx = *counter;
+            *counter += 1;
+            read_rule(atom, if idx == target { DELTA_RULE } else { ALL_RULE })
+        }
+        atom => atom.clone(),
+    }
+}
+
+/// The rule holding the changed rows of a source relation, during maintenance
+pub(crate) const DELTA_RULE: &str = "_delta";
+/// The rule holding the rows of a source relation both before and after a change
+pub(crate) const ALL_RULE: &str = "_all";
+/// The rule holding the keys of the rows to recompute, during maintenance
+pub(crate) const AFFECTED_RULE: &str = "_affected";
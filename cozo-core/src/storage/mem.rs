@@ -198,6 +198,27 @@ impl<'s> StoreTx<'s> for MemTx<'s> {
         }
     }
 
+    fn range_scan_tuple_rev<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        match self {
+            MemTx::Reader(rdr) => Box::new(
+                rdr.range(lower.to_vec()..upper.to_vec())
+                    .rev()
+                    .map(|(k, v)| Ok(decode_tuple_from_kv(k, v, None))),
+            ),
+            MemTx::Writer(_, _) => {
+                let rows = self.range_scan_tuple(lower, upper).collect_vec();
+                Box::new(rows.into_iter().rev())
+            }
+        }
+    }
+
     fn range_skip_scan_tuple<'a>(
         &'a self,
         lower: &[u8],
@@ -118,6 +118,22 @@ pub trait StoreTx<'s>: Sync {
         Box::new(it.map_ok(|(k, v)| decode_tuple_from_kv(&k, &v, None)))
     }
 
+    /// Scan on a range in descending order. `lower` is inclusive whereas `upper` is exclusive.
+    /// The default implementation collects the results of
+    /// [`range_scan_tuple`](Self::range_scan_tuple) and reverses them.
+    /// Implementations should override it if the storage can iterate backwards.
+    fn range_scan_tuple_rev<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        let rows = self.range_scan_tuple(lower, upper).collect_vec();
+        Box::new(rows.into_iter().rev())
+    }
+
     /// Scan on a range with a certain validity.
     ///
     /// `lower` is inclusive whereas `upper` is exclusive.
@@ -225,6 +225,28 @@ impl<'s> StoreTx<'s> for RocksDbTx {
         })
     }
 
+    fn range_scan_tuple_rev<'a>(
+        &'a self,
+        lower: &[u8],
+        upper: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Tuple>> + 'a>
+    where
+        's: 'a,
+    {
+        let mut inner = self
+            .db_tx
+            .iterator()
+            .lower_bound(lower)
+            .upper_bound(upper)
+            .start();
+        inner.seek_to_end();
+        Box::new(RocksDbRevIterator {
+            inner,
+            started: false,
+            lower_bound: lower.to_vec(),
+        })
+    }
+
     fn range_skip_scan_tuple<'a>(
         &'a self,
         lower: &[u8],
@@ -318,6 +340,41 @@ impl Iterator for RocksDbIterator {
     }
 }
 
+pub(crate) struct RocksDbRevIterator {
+    inner: DbIter,
+    started: bool,
+    lower_bound: Vec<u8>,
+}
+
+impl RocksDbRevIterator {
+    #[inline]
+    fn next_inner(&mut self) -> Result<Option<Tuple>> {
+        if self.started {
+            self.inner.prev()
+        } else {
+            self.started = true;
+        }
+        Ok(match self.inner.pair()? {
+            None => None,
+            Some((k_slice, v_slice)) => {
+                if k_slice < self.lower_bound.as_slice() {
+                    None
+                } else {
+                    Some(decode_tuple_from_kv(k_slice, v_slice, None))
+                }
+            }
+        })
+    }
+}
+
+impl Iterator for RocksDbRevIterator {
+    type Item = Result<Tuple>;
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        swap_option_result(self.next_inner())
+    }
+}
+
 pub(crate) struct RocksDbSkipIterator {
     inner: DbIter,
     upper_bound: Vec<u8>,
@@ -27,6 +27,17 @@ declare module "cozo-node" {
      */
     run(script: string, params?: Record<string, any>): Promise<any>;
 
+    /**
+     * Runs a query, receiving the results in chunks as they are produced
+     *
+     * @param script:    the query
+     * @param params:    the parameters as key-value pairs
+     * @param chunkSize: the maximum number of rows in each chunk
+     * @param onChunk:   called with each chunk, which contains the headers and the rows
+     * @return a promise that resolves after the last chunk has been received
+     */
+    runChunked(script: string, params: Record<string, any>, chunkSize: number, onChunk: (chunk: any) => void): Promise<void>;
+
     /**
      * Export several relations
      *
@@ -64,6 +64,21 @@ class CozoDb {
         })
     }
 
+    runChunked(script, params, chunkSize, onChunk) {
+        return new Promise((resolve, reject) => {
+            params = params || {};
+            native.query_db_chunked(this.db_id, script, params, chunkSize, (err, chunk) => {
+                if (err) {
+                    reject(JSON.parse(err))
+                } else if (chunk) {
+                    onChunk(chunk)
+                } else {
+                    resolve()
+                }
+            })
+        })
+    }
+
     exportRelations(relations, as_objects) {
         return new Promise((resolve, reject) => {
             native.export_relations(this.db_id, relations, (err, data) => {
@@ -153,6 +168,28 @@ class CozoDb {
     unregisterNamedRule(name) {
         return native.unregister_named_rule(this.db_id, name)
     }
+
+    registerFunction(name, arity, cb) {
+        return native.register_function(this.db_id, name, arity, async (ret_id, args) => {
+            let ret = undefined;
+            try {
+                ret = await cb(...args);
+            } catch (e) {
+                console.error(e);
+                native.respond_to_function_invocation(ret_id, true, '' + e);
+                return;
+            }
+            try {
+                native.respond_to_function_invocation(ret_id, false, ret);
+            } catch (e) {
+                console.error(e);
+            }
+        })
+    }
+
+    unregisterFunction(name) {
+        return native.unregister_function(this.db_id, name)
+    }
 }
 
 module.exports = {CozoDb: CozoDb}
@@ -191,6 +191,10 @@ fn value2js<'a>(cx: &mut impl Context<'a>, val: &DataValue) -> JsResult<'a, JsVa
             target_l.as_value(cx)
         }
         DataValue::Json(JsonData(j)) => json2js(cx, j)?,
+        d @ (DataValue::Timestamp(_)
+        | DataValue::Duration(_)
+        | DataValue::Decimal(_)
+        | DataValue::Geometry(_)) => json2js(cx, &serde_json::Value::from(d.clone()))?,
     })
 }
 
@@ -272,6 +276,7 @@ struct Handles {
     dbs: Mutex<BTreeMap<u32, DbInstance>>,
     cb_idx: AtomicU32,
     current_cbs: Mutex<BTreeMap<u32, Sender<Result<NamedRows>>>>,
+    current_fn_cbs: Mutex<BTreeMap<u32, Sender<Result<DataValue>>>>,
     nxt_tx_id: AtomicU32,
     txs: Mutex<BTreeMap<u32, Arc<MultiTransaction>>>,
 }
@@ -433,6 +438,66 @@ fn query_db(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     Ok(cx.undefined())
 }
 
+fn query_db_chunked(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let db = get_db!(cx);
+    let query = cx.argument::<JsString>(1)?.value(&mut cx);
+    let params_js = cx.argument::<JsObject>(2)?;
+    let mut params = BTreeMap::new();
+    js2params(&mut cx, params_js, &mut params)?;
+    let chunk_size = cx.argument::<JsNumber>(3)?.value(&mut cx) as usize;
+
+    let callback = Arc::new(cx.argument::<JsFunction>(4)?.root(&mut cx));
+
+    let channel = cx.channel();
+
+    thread::spawn(move || {
+        // `None` marks the end of the results
+        let send = |result: Option<Result<NamedRows>>| {
+            let cb = callback.clone();
+            let query = query.clone();
+            channel.send(move |mut cx| {
+                let callback = cb.to_inner(&mut cx);
+                let this = cx.undefined();
+                match result {
+                    Some(Ok(nr)) => {
+                        let js_vals = named_rows2js(&mut cx, &nr)?.as_value(&mut cx);
+                        let err = cx.undefined().as_value(&mut cx);
+                        callback.call(&mut cx, this, vec![err, js_vals])?;
+                    }
+                    Some(Err(err)) => {
+                        let reports = format_error_as_json(err, Some(&query)).to_string();
+                        let err = cx.string(&reports).as_value(&mut cx);
+                        callback.call(&mut cx, this, vec![err])?;
+                    }
+                    None => {
+                        let err = cx.undefined().as_value(&mut cx);
+                        let done = cx.null().as_value(&mut cx);
+                        callback.call(&mut cx, this, vec![err, done])?;
+                    }
+                }
+                Ok(())
+            });
+        };
+        match db.run_script_iter(&query, params, chunk_size) {
+            Ok(mut iter) => {
+                while let Some(chunk) = iter.next_chunk() {
+                    let failed = chunk.is_err();
+                    send(Some(
+                        chunk.map(|rows| NamedRows::new(iter.headers.clone(), rows)),
+                    ));
+                    if failed {
+                        return;
+                    }
+                }
+                send(None);
+            }
+            Err(err) => send(Some(Err(err))),
+        }
+    });
+
+    Ok(cx.undefined())
+}
+
 fn query_tx(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let tx = get_tx!(cx);
     let query = cx.argument::<JsString>(1)?.value(&mut cx);
@@ -768,11 +833,98 @@ fn unregister_named_rule(mut cx: FunctionContext) -> JsResult<JsBoolean> {
     Ok(cx.boolean(removed))
 }
 
+fn register_function(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let db = get_db!(cx);
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    let arity = cx.argument::<JsNumber>(2)?.value(&mut cx) as usize;
+    let callback = Arc::new(cx.argument::<JsFunction>(3)?.root(&mut cx));
+    let channel = cx.channel();
+    let func = move |args: &[DataValue]| -> Result<DataValue> {
+        let (sender, receiver) = crossbeam::channel::bounded(1);
+        let id = HANDLES.cb_idx.fetch_add(1, Ordering::AcqRel);
+        {
+            HANDLES.current_fn_cbs.lock().unwrap().insert(id, sender);
+        }
+        let args = args.to_vec();
+        let cb = callback.clone();
+        channel.send(move |mut cx| {
+            let callback = cb.to_inner(&mut cx);
+            let args_js = cx.empty_array();
+            for (i, arg) in args.iter().enumerate() {
+                let arg_js = value2js(&mut cx, arg)?;
+                args_js.set(&mut cx, i as u32, arg_js)?;
+            }
+            let args_js = args_js.as_value(&mut cx);
+            let this = cx.undefined();
+            let ret_id = cx.number(id).as_value(&mut cx);
+            callback.call(&mut cx, this, vec![ret_id, args_js])?;
+            Ok(())
+        });
+        receiver
+            .recv()
+            .map_err(|_| miette!("Javascript function did not respond"))?
+    };
+    if let Err(err) = db.register_function(name, arity, func) {
+        let msg = cx.string(err.to_string());
+        return cx.throw(msg);
+    }
+    Ok(cx.undefined())
+}
+
+fn respond_to_function_invocation(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let ret_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
+    let sender = {
+        match HANDLES.current_fn_cbs.lock().unwrap().remove(&ret_id) {
+            None => {
+                let msg = cx.string("function invocation sender should only be used once");
+                return cx.throw(msg);
+            }
+            Some(s) => s,
+        }
+    };
+
+    let send_err = |err| {
+        let _ = sender.send(Err(miette!("Javascript function failed")));
+        err
+    };
+
+    let is_error = cx.argument::<JsBoolean>(1)?.value(&mut cx);
+    let payload = cx.argument::<JsValue>(2)?;
+    if is_error {
+        let msg = payload.to_string(&mut cx).map_err(send_err)?.value(&mut cx);
+        let _ = sender.send(Err(miette!(msg)));
+        return Ok(cx.undefined());
+    }
+
+    let mut val = DataValue::Null;
+    js2value(&mut cx, payload, &mut val).map_err(send_err)?;
+    if let Err(err) = sender.send(Ok(val)) {
+        let msg = err.to_string();
+        let msg = cx.string(msg);
+        return cx.throw(msg);
+    }
+    Ok(cx.undefined())
+}
+
+fn unregister_function(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let db = get_db!(cx);
+    let name = cx.argument::<JsString>(1)?.value(&mut cx);
+    let removed = match db.unregister_function(&name) {
+        Ok(b) => b,
+        Err(msg) => {
+            let msg = cx.string(msg.to_string());
+            return cx.throw(msg);
+        }
+    };
+    Ok(cx.boolean(removed))
+}
+
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
     cx.export_function("open_db", open_db)?;
     cx.export_function("close_db", close_db)?;
     cx.export_function("query_db", query_db)?;
+    cx.export_function("query_db_chunked", query_db_chunked)?;
     cx.export_function("backup_db", backup_db)?;
     cx.export_function("restore_db", restore_db)?;
     cx.export_function("export_relations", export_relations)?;
@@ -786,6 +938,12 @@ fn main(mut cx: ModuleContext) -> NeonResult<()> {
         respond_to_named_rule_invocation,
     )?;
     cx.export_function("unregister_named_rule", unregister_named_rule)?;
+    cx.export_function("register_function", register_function)?;
+    cx.export_function(
+        "respond_to_function_invocation",
+        respond_to_function_invocation,
+    )?;
+    cx.export_function("unregister_function", unregister_function)?;
     cx.export_function("abort_tx", abort_tx)?;
     cx.export_function("commit_tx", commit_tx)?;
     cx.export_function("multi_transact", multi_transact)?;
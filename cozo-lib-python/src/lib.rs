@@ -50,6 +50,13 @@ fn py_to_value(ob: &PyAny) -> PyResult<DataValue> {
         DataValue::from(b.is_true())
     } else if let Ok(i) = ob.extract::<i64>() {
         DataValue::from(i)
+    } else if ob.get_type().name()? == "Decimal" {
+        let s = ob.str()?.to_str()?;
+        let d = s
+            .parse()
+            .or_else(|_| Decimal::from_scientific(s))
+            .map_err(|_| PyException::new_err(format!("Cannot convert {ob} into a decimal")))?;
+        DataValue::Decimal(d)
     } else if let Ok(f) = ob.extract::<f64>() {
         DataValue::from(f)
     } else if let Ok(s) = ob.extract::<String>() {
@@ -167,6 +174,15 @@ fn value_to_py(val: DataValue, py: Python<'_>) -> PyObject {
             }
         },
         DataValue::Json(JsonData(j)) => json_to_py(j, py),
+        DataValue::Decimal(d) => py
+            .import("decimal")
+            .and_then(|m| m.getattr("Decimal"))
+            .and_then(|c| c.call1((d.to_string(),)))
+            .map(|v| v.into_py(py))
+            .unwrap_or_else(|_| d.to_string().into_py(py)),
+        d @ (DataValue::Timestamp(_) | DataValue::Duration(_) | DataValue::Geometry(_)) => {
+            json_to_py(serde_json::Value::from(d), py)
+        }
     }
 }
 
@@ -202,6 +218,13 @@ struct CozoDbMulTx {
     tx: MultiTransaction,
 }
 
+/// Chunks of rows of a script, each a dict with the headers and at most `chunk_size` rows
+#[pyclass]
+struct CozoRowsIter {
+    iter: NamedRowsIter,
+    query: String,
+}
+
 const DB_CLOSED_MSG: &str = r##"{"ok":false,"message":"database closed"}"##;
 
 #[pymethods]
@@ -231,6 +254,33 @@ impl CozoDbPy {
             Err(PyException::new_err(DB_CLOSED_MSG))
         }
     }
+    pub fn run_script_iter(
+        &self,
+        py: Python<'_>,
+        query: &str,
+        params: &PyDict,
+        chunk_size: usize,
+    ) -> PyResult<CozoRowsIter> {
+        if let Some(db) = &self.db {
+            let params = convert_params(params)?;
+            match py.allow_threads(|| db.run_script_iter(query, params, chunk_size)) {
+                Ok(iter) => Ok(CozoRowsIter {
+                    iter,
+                    query: query.to_string(),
+                }),
+                Err(err) => {
+                    let reports = format_error_as_json(err, Some(query)).to_string();
+                    let json_mod = py.import("json")?;
+                    let loads_fn = json_mod.getattr("loads")?;
+                    let args = PyTuple::new(py, [PyString::new(py, &reports)]);
+                    let msg = loads_fn.call1(args)?;
+                    Err(PyException::new_err(PyObject::from(msg)))
+                }
+            }
+        } else {
+            Err(PyException::new_err(DB_CLOSED_MSG))
+        }
+    }
     pub fn register_callback(&self, rel: &str, callback: &PyAny) -> PyResult<u32> {
         if let Some(db) = &self.db {
             let cb: Py<PyAny> = callback.into();
@@ -279,6 +329,28 @@ impl CozoDbPy {
             Err(PyException::new_err(DB_CLOSED_MSG))
         }
     }
+    pub fn register_function(&self, name: String, arity: usize, callback: &PyAny) -> PyResult<()> {
+        if let Some(db) = &self.db {
+            let cb: Py<PyAny> = callback.into();
+            db.register_function(name, arity, move |args| -> Result<DataValue> {
+                Python::with_gil(|py| -> Result<DataValue> {
+                    let py_args = PyTuple::new(py, args.iter().map(|v| value_to_py(v.clone(), py)));
+                    let res = cb.as_ref(py).call1(py_args).into_diagnostic()?;
+                    py_to_value(res).into_diagnostic()
+                })
+            })
+            .map_err(report2py)
+        } else {
+            Err(PyException::new_err(DB_CLOSED_MSG))
+        }
+    }
+    pub fn unregister_function(&self, name: &str) -> PyResult<bool> {
+        if let Some(db) = &self.db {
+            db.unregister_function(name).map_err(report2py)
+        } else {
+            Ok(false)
+        }
+    }
     pub fn unregister_callback(&self, id: u32) -> bool {
         if let Some(db) = &self.db {
             db.unregister_callback(id)
@@ -395,9 +467,38 @@ impl CozoDbMulTx {
     }
 }
 
+#[pymethods]
+impl CozoRowsIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let this = &mut *slf;
+        match py.allow_threads(|| this.iter.next_chunk()) {
+            None => Ok(None),
+            Some(Ok(rows)) => {
+                let rows = rows_to_py_rows(rows, py);
+                let headers = this.iter.headers.clone().into_py(py);
+                Ok(Some(
+                    BTreeMap::from([("rows", rows), ("headers", headers)]).into_py(py),
+                ))
+            }
+            Some(Err(err)) => {
+                let reports = format_error_as_json(err, Some(&this.query)).to_string();
+                let json_mod = py.import("json")?;
+                let loads_fn = json_mod.getattr("loads")?;
+                let args = PyTuple::new(py, [PyString::new(py, &reports)]);
+                let msg = loads_fn.call1(args)?;
+                Err(PyException::new_err(PyObject::from(msg)))
+            }
+        }
+    }
+}
+
 #[pymodule]
 fn cozo_embedded(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<CozoDbPy>()?;
     m.add_class::<CozoDbMulTx>()?;
+    m.add_class::<CozoRowsIter>()?;
     Ok(())
 }